@@ -59,7 +59,7 @@ pub fn UploadModal<'a>(cx: Scope<'a>, props: UploadModalProps<'a>) -> View<DomNo
                 "Uploading..."
             },
             UploadState::Error(err) => view! { cx,
-                (err) br{}
+                pre { (err) }
                 button(on:click=back) { "Back" } button(on:click=|_| (props.on_close)()) { "Close" } 
             },
             UploadState::Success => view! { cx,
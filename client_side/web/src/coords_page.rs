@@ -3,7 +3,7 @@ use std::io::Read;
 use std::mem::forget;
 use std::sync::Arc;
 
-use common::api::{self, RunGcodeFile, DeleteGcodeFile, SavedPosition, Vec3};
+use common::api::{self, RunGcodeFile, DeleteGcodeFile, SavedPosition, Vec3, OffsetKind, AlignCoordinateOffset};
 use futures::future::{Fuse, FusedFuture};
 use itertools::Itertools;
 use reqwasm::websocket::{futures::WebSocket, Message};
@@ -22,7 +22,6 @@ use std::time::Duration;
 use sycamore::futures::{create_resource, spawn_local_scoped};
 use crate::models::offsets::OffsetModel;
 use crate::models::positions::PositionModel;
-use crate::request::{self, HttpMethod};
 use crate::status_header::GlobalInfo;
 use crate::utils::async_sycamore::{self, loading_view, try_loading_view};
 
@@ -77,24 +76,29 @@ pub fn PositionTable<'a>(cx: Scope<'a>, props: PositionTableProps<'a>) -> View<D
     }
 }
 #[derive(Prop)]
-pub struct OffsetTableProps<'a> {
-    data: &'a ReadSignal<HashMap<String, Vec3>>
+pub struct OffsetTableProps<'a, F> {
+    data: &'a ReadSignal<HashMap<String, Vec3>>,
+    on_select: &'a F,
 }
 #[component]
-pub fn OffsetTable<'a>(cx: Scope<'a>, props: OffsetTableProps<'a>) -> View<DomNode> {
-    let entries = create_memo(cx, || props.data.get().iter().map(|(key, value)| (key.clone(), value.clone())).collect_vec());
+pub fn OffsetTable<'a, F: Fn(String, Vec3)>(cx: Scope<'a>, props: OffsetTableProps<'a, F>) -> View<DomNode> {
+    let entries = create_memo(cx, || props.data.get().iter().map(|(key, value)| (key.clone(), value.clone())).sorted_by(|a, b| a.0.cmp(&b.0)).collect_vec());
+    let on_select = props.on_select;
     view! { cx,
         table(class="position_table") {
             Keyed(
                 iterable=entries,
                 key=|item| item.0.clone(),
-                view=|cx, item| view! { cx,
-                    tr {
-                        td {
-                            (item.0)
-                        }
-                        td {
-                            (format!("{:?}", item.1.0))
+                view=move |cx, item| {
+                    let (name, offset) = item.clone();
+                    view! { cx,
+                        tr(on:click=move |_| on_select(name.clone(), offset.clone())) {
+                            td {
+                                (item.0)
+                            }
+                            td {
+                                (format_position(&item.1.0))
+                            }
                         }
                     }
                 }
@@ -103,6 +107,23 @@ pub fn OffsetTable<'a>(cx: Scope<'a>, props: OffsetTableProps<'a>) -> View<DomNo
     }
 }
 
+pub(crate) fn format_position(values: &[f64; 3]) -> String {
+    format!("X{:.3} Y{:.3} Z{:.3}", values[0], values[1], values[2])
+}
+pub(crate) fn offset_kind(text: &str) -> OffsetKind {
+    match text {
+        "Workpiece" => OffsetKind::Workpiece,
+        _ => OffsetKind::Tool,
+    }
+}
+// Blank means the axis isn't given.
+pub(crate) fn parse_coordinate(axis: char, text: &str) -> anyhow::Result<Option<f64>> {
+    match text.trim() {
+        "" => Ok(None),
+        text => Ok(Some(text.parse().map_err(|_| anyhow::anyhow!("{} should be a number, not {:?}", axis, text))?)),
+    }
+}
+
 #[component]
 pub fn CoordinatePage(cx: Scope) -> View<DomNode> {
     try_loading_view(
@@ -140,6 +161,97 @@ pub fn CoordinatePage(cx: Scope) -> View<DomNode> {
                 Offset management...
             */
             let tool_offsets = create_memo(cx, || offset_model.get().as_ref().tools.clone());
+            let workpiece_offsets = create_memo(cx, || offset_model.get().as_ref().workpieces.clone());
+            let tool_names = create_memo(cx, || offset_model.get().tools.keys().cloned().sorted().collect_vec());
+            let workpiece_names = create_memo(cx, || offset_model.get().workpieces.keys().cloned().sorted().collect_vec());
+            let message = create_signal(cx, String::new());
+            let report = move |result: anyhow::Result<()>, success: &str| message.set(match result {
+                Ok(()) => success.to_string(),
+                Err(error) => format!("Error: {:#}", error),
+            });
+
+            let edit_kind = create_signal(cx, "Tool".to_string());
+            let edit_name = create_signal(cx, String::new());
+            let edit_values = create_ref(cx, [0, 1, 2].map(|_| create_signal(cx, "0".to_string())));
+            let select_tool = create_ref(cx, move |name: String, offset: Vec3| {
+                edit_kind.set("Tool".to_string());
+                edit_name.set(name);
+                for (value, signal) in offset.0.iter().zip(edit_values) {
+                    signal.set(value.to_string());
+                }
+            });
+            let select_workpiece = create_ref(cx, move |name: String, offset: Vec3| {
+                select_tool(name, offset);
+                edit_kind.set("Workpiece".to_string());
+            });
+            let save_offset = move |_| spawn_local_scoped(cx, async move {
+                let result = async {
+                    let name = edit_name.get().trim().to_string();
+                    if name.is_empty() {
+                        anyhow::bail!("Give the offset a name");
+                    }
+                    let mut offset = [0.0; 3];
+                    for ((value, signal), axis) in offset.iter_mut().zip(edit_values).zip(['X', 'Y', 'Z']) {
+                        *value = parse_coordinate(axis, &signal.get())?.unwrap_or(0.0);
+                    }
+                    offset_model.set(name, offset_kind(&edit_kind.get()), Vec3(offset)).await
+                }.await;
+                report(result, "Offset saved.");
+            });
+            let delete_offset = move |_| spawn_local_scoped(cx, async move {
+                let result = offset_model.delete(edit_name.get().trim().to_string(), offset_kind(&edit_kind.get())).await;
+                report(result, "Offset deleted.");
+            });
+
+            /*
+                Setting up a coordinate system: a machine position, a tool and workpiece pair, and where that
+                position should be in the pair's coordinates.
+            */
+            let position_choice = create_signal(cx, "current".to_string());
+            let saved_choices = create_memo(cx, || position_model.get().iter().enumerate().map(|(index, position)| (index.to_string(), position.label.clone())).collect_vec());
+            let chosen_position = create_memo(cx, move || match position_choice.get().as_str() {
+                "current" => global_info.grbl_info.get().is_some().then(get_current_position),
+                index => index.parse::<usize>().ok().and_then(|index| position_model.get().get(index).map(|position| position.position.clone())),
+            });
+            let chosen_tool = create_signal(cx, String::new());
+            let chosen_workpiece = create_signal(cx, String::new());
+            let update_kind = create_signal(cx, "Workpiece".to_string());
+            let references = create_ref(cx, [0, 1, 2].map(|_| create_signal(cx, String::new())));
+            let position_text = create_memo(cx, move || {
+                let Some(position) = chosen_position.get().as_ref().clone() else {
+                    return "No position chosen.".to_string();
+                };
+                let offsets = offset_model.get();
+                let in_system = match (offsets.tools.get(&*chosen_tool.get()), offsets.workpieces.get(&*chosen_workpiece.get())) {
+                    (Some(tool), Some(workpiece)) => format!(", which is {} for this tool and workpiece", format_position(&[0, 1, 2].map(|axis| position.0[axis] + tool.0[axis] + workpiece.0[axis]))),
+                    _ => String::new(),
+                };
+                format!("Machine position {}{}", format_position(&position.0), in_system)
+            });
+            let align = move |_| spawn_local_scoped(cx, async move {
+                let result = async {
+                    let machine_position = chosen_position.get().as_ref().clone().ok_or_else(|| anyhow::anyhow!("Choose a machine position"))?;
+                    let mut reference = [None; 3];
+                    for ((value, signal), axis) in reference.iter_mut().zip(references).zip(['X', 'Y', 'Z']) {
+                        *value = parse_coordinate(axis, &signal.get())?;
+                    }
+                    if reference.iter().all(Option::is_none) {
+                        anyhow::bail!("Enter at least one reference coordinate");
+                    }
+                    offset_model.align(AlignCoordinateOffset {
+                        machine_position,
+                        tool: chosen_tool.get().as_ref().clone(),
+                        workpiece: chosen_workpiece.get().as_ref().clone(),
+                        update: offset_kind(&update_kind.get()),
+                        reference,
+                    }).await
+                }.await;
+                report(result, "Offset updated.");
+            });
+            let apply = move |_| spawn_local_scoped(cx, async move {
+                let result = offset_model.apply(chosen_tool.get().as_ref().clone(), chosen_workpiece.get().as_ref().clone()).await;
+                report(result, "The machine's G54 now uses this tool and workpiece.");
+            });
             /*
             Styling
              */
@@ -154,6 +266,67 @@ pub fn CoordinatePage(cx: Scope) -> View<DomNode> {
                 div(class=css.get_class_name()) {
                     LabelledAdder(on_add=add_position, disabled=adding_disabled, action_text="Record Position")
                     PositionTable(data=position_model.signal())
+                    h3 { "Set up a coordinate system" }
+                    p {
+                        "1. Machine position: "
+                        select(bind:value=position_choice) {
+                            option(value="current") { "Current position" }
+                            Indexed(
+                                iterable=saved_choices,
+                                view=|cx, (index, label)| view! { cx, option(value=index) { (label) } }
+                            )
+                        }
+                        br {}
+                        (position_text.get())
+                    }
+                    p {
+                        "2. Tool: "
+                        select(bind:value=chosen_tool) {
+                            option(value="") { "Choose a tool" }
+                            Indexed(iterable=tool_names, view=|cx, name| view! { cx, option(value=name.clone()) { (name) } })
+                        }
+                        " Workpiece: "
+                        select(bind:value=chosen_workpiece) {
+                            option(value="") { "Choose a workpiece" }
+                            Indexed(iterable=workpiece_names, view=|cx, name| view! { cx, option(value=name.clone()) { (name) } })
+                        }
+                        " Update the "
+                        select(bind:value=update_kind) {
+                            option(value="Workpiece") { "workpiece" }
+                            option(value="Tool") { "tool" }
+                        }
+                        " offset"
+                    }
+                    p {
+                        "3. The position is at (leave blank to keep an axis as it is): "
+                        "X " input(type="text", size=8, bind:value=references[0])
+                        " Y " input(type="text", size=8, bind:value=references[1])
+                        " Z " input(type="text", size=8, bind:value=references[2])
+                    }
+                    p {
+                        "4. "
+                        button(on:click=align) { "Update offset" }
+                        " "
+                        button(on:click=apply) { "Use this tool and workpiece on the machine" }
+                    }
+                    p { (message.get()) }
+                    h3 { "Tools" }
+                    OffsetTable(data=tool_offsets, on_select=select_tool)
+                    h3 { "Workpieces" }
+                    OffsetTable(data=workpiece_offsets, on_select=select_workpiece)
+                    p {
+                        select(bind:value=edit_kind) {
+                            option(value="Tool") { "Tool" }
+                            option(value="Workpiece") { "Workpiece" }
+                        }
+                        " named " input(type="text", bind:value=edit_name)
+                        " X " input(type="text", size=8, bind:value=edit_values[0])
+                        " Y " input(type="text", size=8, bind:value=edit_values[1])
+                        " Z " input(type="text", size=8, bind:value=edit_values[2])
+                        " "
+                        button(on:click=save_offset) { "Save" }
+                        button(on:click=delete_offset) { "Delete" }
+                    }
                 }
             })
         }
@@ -16,7 +16,9 @@ use stylist::style;
 use web_sys::{KeyboardEvent, Event};
 use gloo_timers::future::sleep;
 use std::time::Duration;
-use crate::request::{self, HttpMethod};
+use sycamore::futures::spawn_local_scoped;
+use crate::models::command_history::{self, CommandHistoryModel};
+use crate::request;
 use crate::utils::async_sycamore;
 
 #[derive(Prop)]
@@ -94,6 +96,26 @@ pub fn DebugPage(cx: Scope) -> View<DomNode> {
     "#
     }.expect("CSS should work");
     log::debug!("CSS class: {}", css_style.get_class_name());
+    let history_style = style! { r#"
+        padding: 0.5em 2em;
+
+        .favourites .favourite {
+            display: inline-block;
+            margin-right: 0.5em;
+        }
+        .favourites .favourite button:first-child {
+            font-family: monospace;
+        }
+        .commands {
+            max-height: 20em;
+            overflow-y: auto;
+        }
+        .commands .command code {
+            cursor: pointer;
+            margin-right: 1em;
+        }
+    "#
+    }.expect("CSS should work");
     let (mut message_list_sender, message_list) = async_sycamore::create_channel(cx, vec![]);
     {
         async_sycamore::spawn_local_drop_with_context(cx, async move {
@@ -136,14 +158,77 @@ pub fn DebugPage(cx: Scope) -> View<DomNode> {
     });
     let input_value = create_signal(cx, String::new());
     let is_checked = create_signal(cx, true);
+    /*
+        Command history...
+    */
+    let user = create_signal(cx, command_history::remembered_user());
+    let known_users = create_signal(cx, Vec::<String>::new());
+    let history = CommandHistoryModel::new(cx, user);
+    let search = create_signal(cx, String::new());
+    let recalled = create_signal(cx, None::<usize>); // Index into the history while stepping with the arrow keys
+    let report = |result: anyhow::Result<()>| if let Err(e) = result {
+        log::debug!("ERROR: {:?}", e);
+    };
+    spawn_local_scoped(cx, async move {
+        match command_history::list_users().await {
+            Ok(users) => known_users.set(users),
+            Err(e) => log::debug!("ERROR: {:?}", e),
+        }
+    });
+    create_effect(cx, move || {
+        command_history::remember_user(&user.get());
+        recalled.set(None);
+        spawn_local_scoped(cx, async move { report(history.refresh().await) });
+    });
+    let send = create_ref(cx, move |command: String| spawn_local_scoped(cx, async move {
+        report(history.send(command).await);
+    }));
+    let set_favourite = create_ref(cx, move |command: String, favourite: bool| spawn_local_scoped(cx, async move {
+        report(history.set_favourite(command, favourite).await);
+    }));
     let keydown_handler = |event: Event| {
         let keyboard_event: KeyboardEvent = event.unchecked_into();
-        if keyboard_event.key() == "Enter" {
-            let line = &*input_value.get();
-            request::request_detached_with_body(HttpMethod::Post, api::SEND_RAW_GCODE, line);
-            input_value.set("".to_string());
+        let current = history.get();
+        let entries = &current.entries;
+        match keyboard_event.key().as_str() {
+            "Enter" => {
+                let line = input_value.get().trim().to_string();
+                if !line.is_empty() {
+                    send(line);
+                }
+                input_value.set("".to_string());
+                recalled.set(None);
+            }
+            "ArrowUp" if !entries.is_empty() => {
+                keyboard_event.prevent_default();
+                let index = recalled.get().map_or(0, |index| (index + 1).min(entries.len() - 1));
+                input_value.set(entries[index].command.clone());
+                recalled.set(Some(index));
+            }
+            "ArrowDown" => {
+                keyboard_event.prevent_default();
+                match *recalled.get() {
+                    Some(index) if index > 0 && index <= entries.len() => {
+                        input_value.set(entries[index - 1].command.clone());
+                        recalled.set(Some(index - 1));
+                    }
+                    _ => {
+                        input_value.set("".to_string());
+                        recalled.set(None);
+                    }
+                }
+            }
+            _ => (),
         }
     };
+    let favourites = create_memo(cx, || history.signal().get().favourites.clone());
+    let matching_entries = create_memo(cx, || {
+        let search = search.get().to_lowercase();
+        history.signal().get().entries.iter()
+            .filter(|entry| entry.command.to_lowercase().contains(&search))
+            .map(|entry| entry.command.clone())
+            .collect::<Vec<_>>()
+    });
     let list = create_memo(cx, || if *is_checked.get() {
         message_list_inner.get().iter().filter(|x| !x.ends_with(">") && !x.contains('?')).map(String::from).collect()
     } else {
@@ -180,6 +265,57 @@ pub fn DebugPage(cx: Scope) -> View<DomNode> {
             input(type="checkbox", bind:checked=is_checked)
             label { "Hide status queries." }
         }
+        div(class=history_style.get_class_name()) {
+            div {
+                label { "Command history for " }
+                input(type="text", list="command_users", placeholder="your name", bind:value=user)
+                datalist(id="command_users") {
+                    Indexed(
+                        iterable=known_users,
+                        view=|cx, name| view! { cx, option(value=name) }
+                    )
+                }
+                input(type="search", placeholder="Search", bind:value=search)
+            }
+            div(class="favourites") {
+                Keyed(
+                    iterable=favourites,
+                    key=|command| command.clone(),
+                    view=move |cx, command| {
+                        let resend_command = command.clone();
+                        let unpin_command = command.clone();
+                        view! { cx,
+                            span(class="favourite") {
+                                button(on:click=move |_| send(resend_command.clone())) { (command) }
+                                button(title="Unpin", on:click=move |_| set_favourite(unpin_command.clone(), false)) { "×" }
+                            }
+                        }
+                    }
+                )
+            }
+            div(class="commands") {
+                Keyed(
+                    iterable=matching_entries,
+                    key=|command| command.clone(),
+                    view=move |cx, command| {
+                        let recall_command = command.clone();
+                        let resend_command = command.clone();
+                        let pin_command = command.clone();
+                        let pinned = create_memo(cx, move || favourites.get().contains(&pin_command));
+                        let toggle_command = command.clone();
+                        view! { cx,
+                            div(class="command") {
+                                code(on:click=move |_| input_value.set(recall_command.clone())) { (command) }
+                                button(on:click=move |_| send(resend_command.clone())) { "Resend" }
+                                button(on:click=move |_| set_favourite(toggle_command.clone(), !*pinned.get())) {
+                                    (if *pinned.get() { "Unpin" } else { "Pin" })
+                                }
+                            }
+                        }
+                    }
+                )
+            }
+        }
         a(href="/") { "Go home!" }
     }
 }
\ No newline at end of file
@@ -1,6 +1,6 @@
-use std::{rc::Rc, cell::RefCell, cmp::{min, max}, fmt::Display};
+use std::{rc::Rc, cell::{Cell, RefCell}, cmp::{min, max}, fmt::Display};
 
-use common::api;
+use common::api::{self, HeightMap, PreviewMoveKind, ToolpathPreview};
 use itertools::Itertools;
 use js_sys::Math::{sin, cos};
 use quaternion_core::{Quaternion, QuaternionOps};
@@ -9,11 +9,15 @@ use sycamore::{prelude::*, futures::spawn_local_scoped};
 use wasm_bindgen::{JsCast, JsValue, prelude::Closure};
 use web_sys::{WebGl2RenderingContext, MouseEvent, WheelEvent};
 
-use crate::{render::{compile_shader, link_program, add_loop_callback}, request::{self, HttpMethod}};
+use crate::{models::job_history::{get_height_map, list_job_results}, render::{compile_shader, link_program, add_loop_callback}, request::client, status_header::GlobalInfo};
 
 #[derive(Prop)]
 pub struct InteractiveDisplayProps<'a> {
-    positions: &'a ReadSignal<Vec<[f32; 3]>>
+    preview: &'a ReadSignal<ToolpathPreview>,
+    executed_line: &'a ReadSignal<Option<u32>>, // Moves from lines up to here are drawn as done.
+    tool_position: &'a ReadSignal<Option<[f32; 3]>>, // In work coordinates.
+    selected_line: &'a Signal<Option<u32>>, // Set by clicking on the toolpath.
+    height_map: &'a ReadSignal<Option<HeightMap>>, // In work coordinates; drawn under the toolpath.
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -50,6 +54,198 @@ pub fn distance(a: &[f32; 3], b: &[f32; 3]) -> f32 {
     ((a[0]-b[0])*(a[0]-b[0]) + (a[1]-b[1])*(a[1]-b[1]) + (a[2]-b[2])*(a[2]-b[2])).sqrt()
 }
 
+// Floats per vertex: x, y, z, distance along the path, whether the move was rapid, and its line.
+const VERTEX_SIZE: usize = 6;
+// How far from a segment, in pixels, a click can be and still pick it.
+const CLICK_RADIUS: f32 = 8.0;
+
+// What the shaders need from a preview, worked out once rather than every frame.
+struct PreparedToolpath {
+    vertices: Vec<f32>,
+    bounds: MinMax,
+    distances: Vec<f32>,
+    lines: Vec<u32>,
+}
+fn prepare(preview: &ToolpathPreview) -> Option<PreparedToolpath> {
+    if preview.moves.is_empty() {
+        return None;
+    }
+    let axes = ['X', 'Y', 'Z'].map(|letter| preview.axis_letters.find(letter));
+    let position = |index: usize| {
+        let point = preview.point(index);
+        axes.map(|axis| axis.map_or(0.0, |axis| point[axis]))
+    };
+    let first = position(0);
+    let mut bounds = MinMax { min: first, max: first };
+    let mut last_position = first;
+    let mut accumulator = 0.0f64;
+    let mut vertices = Vec::with_capacity(preview.moves.len() * VERTEX_SIZE);
+    let mut distances = Vec::with_capacity(preview.moves.len());
+    for (index, preview_move) in preview.moves.iter().enumerate() {
+        let position = position(index);
+        bounds = enlarge_to(bounds, &position);
+        accumulator += distance(&last_position, &position) as f64;
+        last_position = position;
+        let rapid = if preview_move.kind == PreviewMoveKind::Rapid { 1.0 } else { 0.0 };
+        vertices.extend([position[0], position[1], position[2], accumulator as f32, rapid, preview_move.line as f32]);
+        distances.push(accumulator as f32);
+    }
+    Some(PreparedToolpath {
+        vertices,
+        bounds,
+        distances,
+        lines: preview.moves.iter().map(|preview_move| preview_move.line).collect(),
+    })
+}
+
+// The corners of what the feed, arc and probe moves sweep over, leaving out rapids above the work.
+fn cutting_bounds(preview: &ToolpathPreview) -> Option<MinMax> {
+    let axes = ['X', 'Y', 'Z'].map(|letter| preview.axis_letters.find(letter));
+    let position = |index: usize| {
+        let point = preview.point(index);
+        axes.map(|axis| axis.map_or(0.0, |axis| point[axis]))
+    };
+    let mut bounds: Option<MinMax> = None;
+    for (index, preview_move) in preview.moves.iter().enumerate() {
+        if preview_move.kind == PreviewMoveKind::Rapid {
+            continue;
+        }
+        // A move runs from the point before it.
+        for point in [position(index.max(1) - 1), position(index)] {
+            bounds = Some(bounds.map_or(MinMax { min: point, max: point }, |bounds| enlarge_to(bounds, &point)));
+        }
+    }
+    bounds
+}
+
+/*
+    Height maps
+*/
+// Floats per height map vertex: x, y, z and a color.
+const MESH_VERTEX_SIZE: usize = 6;
+
+pub fn height_range(map: &HeightMap) -> Option<(f64, f64)> {
+    map.heights.iter().flatten().fold(None, |range, height| Some(match range {
+        None => (*height, *height),
+        Some((low, high)) => (f64::min(low, *height), f64::max(high, *height)),
+    }))
+}
+// From low to high: blue, green, red.
+fn color_scale(fraction: f32) -> [f32; 3] {
+    let fraction = fraction.clamp(0.0, 1.0) * 2.0;
+    if fraction < 1.0 {
+        [0.0, fraction, 1.0 - fraction]
+    } else {
+        [fraction - 1.0, 2.0 - fraction, 0.0]
+    }
+}
+fn css_color(fraction: f32) -> String {
+    let [red, green, blue] = color_scale(fraction).map(|value| (value * 255.0).round() as u8);
+    format!("rgb({}, {}, {})", red, green, blue)
+}
+// The same height map in the coordinates the toolpath is drawn in.
+pub fn shift_height_map(map: &HeightMap, offset: [f64; 3]) -> HeightMap {
+    HeightMap {
+        xs: map.xs.iter().map(|x| x - offset[0]).collect(),
+        ys: map.ys.iter().map(|y| y - offset[1]).collect(),
+        heights: map.heights.iter().map(|row| row.iter().map(|z| z - offset[2]).collect()).collect(),
+    }
+}
+// How far, on each side, the cutting moves go past the height map; empty if it covers them.
+fn uncovered_sides(cutting: &MinMax, map: &HeightMap) -> Vec<String> {
+    let (Some(x_min), Some(x_max), Some(y_min), Some(y_max)) = (map.xs.first(), map.xs.last(), map.ys.first(), map.ys.last()) else {
+        return Vec::new();
+    };
+    [
+        ("-X", *x_min as f32 - cutting.min[0]),
+        ("+X", cutting.max[0] - *x_max as f32),
+        ("-Y", *y_min as f32 - cutting.min[1]),
+        ("+Y", cutting.max[1] - *y_max as f32),
+    ].into_iter()
+        .filter(|(_, distance)| *distance > 0.001)
+        .map(|(side, distance)| format!("{:.3} mm in {}", distance, side))
+        .collect()
+}
+
+// Two triangles for each cell of the grid, colored by height.
+struct PreparedHeightMap {
+    vertices: Vec<f32>,
+    bounds: MinMax,
+}
+fn prepare_height_map(map: &HeightMap) -> Option<PreparedHeightMap> {
+    if map.xs.len() < 2 || map.ys.len() < 2 {
+        return None;
+    }
+    let (low, high) = height_range(map)?;
+    let span = (high - low).max(1e-9);
+    let point = |row: usize, column: usize| [map.xs[column] as f32, map.ys[row] as f32, map.heights[row][column] as f32];
+    let corner = point(0, 0);
+    let mut bounds = MinMax { min: corner, max: corner };
+    let mut vertices = Vec::with_capacity((map.xs.len() - 1) * (map.ys.len() - 1) * 6 * MESH_VERTEX_SIZE);
+    for row in 0..map.ys.len() - 1 {
+        for column in 0..map.xs.len() - 1 {
+            for (row, column) in [(row, column), (row, column + 1), (row + 1, column + 1), (row, column), (row + 1, column + 1), (row + 1, column)] {
+                let position = point(row, column);
+                bounds = enlarge_to(bounds, &position);
+                vertices.extend(position);
+                vertices.extend(color_scale(((map.heights[row][column] - low) / span) as f32));
+            }
+        }
+    }
+    Some(PreparedHeightMap { vertices, bounds })
+}
+
+// The transformation the vertex shader applies, kept so that clicks can be matched to the toolpath.
+#[derive(Clone, Copy)]
+struct ViewTransform {
+    matrix: [[f32; 3]; 3],
+    offset: [f32; 3],
+    scale: [f32; 2],
+    width: f32,
+    height: f32,
+}
+impl ViewTransform {
+    // Pixel coordinates within the canvas; None if the point is behind the viewer.
+    fn to_screen(&self, point: &[f32]) -> Option<(f32, f32)> {
+        let transformed = [0, 1, 2].map(|row| (0..3).map(|column| self.matrix[row][column] * point[column]).sum::<f32>() + self.offset[row]);
+        if transformed[2] <= 0.0 {
+            return None;
+        }
+        let x = self.scale[0] * transformed[0] / transformed[2];
+        let y = self.scale[1] * transformed[1] / transformed[2];
+        Some(((x + 1.0) * 0.5 * self.width, (1.0 - y) * 0.5 * self.height))
+    }
+}
+fn distance_to_segment(point: (f32, f32), from: (f32, f32), to: (f32, f32)) -> f32 {
+    let direction = (to.0 - from.0, to.1 - from.1);
+    let offset = (point.0 - from.0, point.1 - from.1);
+    let length_squared = direction.0 * direction.0 + direction.1 * direction.1;
+    let along = if length_squared > 0.0 {
+        ((offset.0 * direction.0 + offset.1 * direction.1) / length_squared).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    (offset.0 - along * direction.0).hypot(offset.1 - along * direction.1)
+}
+// The line of the visible segment nearest to a click, if any is close enough.
+fn line_at(prepared: &PreparedToolpath, transform: &ViewTransform, click: (f32, f32), visible: impl Fn(&[f32]) -> bool) -> Option<u32> {
+    let mut best = None;
+    let mut best_distance = CLICK_RADIUS;
+    let mut previous = None;
+    for (index, vertex) in prepared.vertices.chunks_exact(VERTEX_SIZE).enumerate() {
+        let screen = transform.to_screen(vertex);
+        if let (Some(from), Some(to)) = (previous, screen) {
+            let distance = distance_to_segment(click, from, to);
+            if distance < best_distance && visible(vertex) {
+                best_distance = distance;
+                best = Some(prepared.lines[index]);
+            }
+        }
+        previous = screen;
+    }
+    best
+}
+
 #[component]
 pub fn InteractiveDisplay<'a>(cx: Scope<'a>, props: InteractiveDisplayProps<'a>) -> View<DomNode> {
     let css_style = style! { r#"
@@ -88,15 +284,21 @@ pub fn InteractiveDisplay<'a>(cx: Scope<'a>, props: InteractiveDisplayProps<'a>)
 
         in vec3 position;
         in float distance;
+        in float rapid;
+        in float line;
 
         out float depth;
         out float frag_distance;
+        flat out float frag_rapid;
+        flat out float frag_line;
 
         void main() {
             vec3 true_position = transformation * position + offset;
             gl_Position = vec4(vec3(scale, 0.5) * (transformation * position + offset), true_position.z);
             depth = position.z;
             frag_distance = distance;
+            frag_rapid = rapid;
+            frag_line = line;
         }
         "##,
     ).unwrap();
@@ -111,34 +313,97 @@ pub fn InteractiveDisplay<'a>(cx: Scope<'a>, props: InteractiveDisplayProps<'a>)
         precision highp float;
         in float depth;
         in float frag_distance;
+        flat in float frag_rapid;
+        flat in float frag_line;
         out vec4 outColor;
 
         uniform float depth_cutoff;
         uniform float distance_cutoff;
+        uniform float executed_line;
+        uniform float selected_line;
+        uniform float marker;
         
         void main() {
-            outColor = vec4(1, 1, 1, depth < depth_cutoff && frag_distance < distance_cutoff ? 1.0 : 0.05);
+            if (marker > 0.5) {
+                outColor = vec4(1, 0.85, 0, 1);
+                return;
+            }
+            float alpha = depth < depth_cutoff && frag_distance < distance_cutoff ? 1.0 : 0.05;
+            vec3 color = frag_line == selected_line ? vec3(0.2, 0.8, 1) : frag_line <= executed_line ? vec3(0.3, 1, 0.3) : vec3(1, 1, 1);
+            // Rapids are drawn dimmer and reddish, so the cuts stand out.
+            outColor = frag_rapid > 0.5 ? vec4(color * vec3(1, 0.4, 0.4), alpha * 0.4) : vec4(color, alpha);
         }
         "##,
     ).unwrap();
     let program = link_program(&context, &vert_shader, &frag_shader).unwrap();
+    // The height map has a program of its own, since it's colored per vertex and never cut off.
+    let mesh_vert_shader = compile_shader(
+        &context,
+        WebGl2RenderingContext::VERTEX_SHADER,
+        r##"#version 300 es
+        uniform mat3x3 transformation;
+        uniform vec3 offset;
+        uniform vec2 scale;
+
+        in vec3 position;
+        in vec3 color;
+
+        out vec3 frag_color;
+
+        void main() {
+            vec3 true_position = transformation * position + offset;
+            gl_Position = vec4(vec3(scale, 0.5) * true_position, true_position.z);
+            frag_color = color;
+        }
+        "##,
+    ).unwrap();
+    let mesh_frag_shader = compile_shader(
+        &context,
+        WebGl2RenderingContext::FRAGMENT_SHADER,
+        r##"#version 300 es
+        precision highp float;
+        in vec3 frag_color;
+        out vec4 outColor;
+
+        void main() {
+            outColor = vec4(frag_color, 0.6);
+        }
+        "##,
+    ).unwrap();
+    let mesh_program = link_program(&context, &mesh_vert_shader, &mesh_frag_shader).unwrap();
+    let mesh_position_location = context.get_attrib_location(&mesh_program, "position");
+    let mesh_color_location = context.get_attrib_location(&mesh_program, "color");
+    let mesh_buffer = context.create_buffer().ok_or("Failed to create buffer").unwrap();
+    let mesh_mat_location = context.get_uniform_location(&mesh_program, "transformation").unwrap();
+    let mesh_offset_location = context.get_uniform_location(&mesh_program, "offset").unwrap();
+    let mesh_scale_location = context.get_uniform_location(&mesh_program, "scale").unwrap();
     let position_attribute_location = context.get_attrib_location(&program, "position");
     let distance_attribute_location = context.get_attrib_location(&program, "distance");
+    let rapid_attribute_location = context.get_attrib_location(&program, "rapid");
+    let line_attribute_location = context.get_attrib_location(&program, "line");
     let buffer = context.create_buffer().ok_or("Failed to create buffer").unwrap();
+    let marker_buffer = context.create_buffer().ok_or("Failed to create buffer").unwrap();
 
     let mat_location = context.get_uniform_location(&program, "transformation").unwrap();
     let offset_location = context.get_uniform_location(&program, "offset").unwrap();
     let scale_location = context.get_uniform_location(&program, "scale").unwrap();
     let depth_cutoff_location = context.get_uniform_location(&program, "depth_cutoff").unwrap();
     let distance_cutoff_location = context.get_uniform_location(&program, "distance_cutoff").unwrap();
+    let executed_line_location = context.get_uniform_location(&program, "executed_line").unwrap();
+    let selected_line_location = context.get_uniform_location(&program, "selected_line").unwrap();
+    let marker_location = context.get_uniform_location(&program, "marker").unwrap();
 
     let current_position: Rc<RefCell<Quaternion<f32>>> = Rc::new(RefCell::new((0.0, [0.0, 1.0, 0.0])));
     let current_zoom: Rc<RefCell<f32> > = Rc::new(RefCell::new(1.0));
+    // How far the mouse has moved since the button went down; a click that rotated the view doesn't select.
+    let dragged = Rc::new(Cell::new(0.0f32));
 
     {
         let current_position = current_position.clone();
+        let dragged = dragged.clone();
         let mouse_closure: Closure<dyn FnMut(MouseEvent)> = Closure::new(move |value: MouseEvent| {
             if value.buttons() & 1 == 1 {
+                dragged.set(dragged.get() + (value.movement_x() as f32).hypot(value.movement_y() as f32));
                 let x_dif = value.movement_x() as f32 * 0.001;
                 let y_dif = value.movement_y() as f32 * 0.001;
                 let transformation: Quaternion<f32> = quaternion_core::exp([-y_dif, x_dif, 0.0]);
@@ -153,6 +418,12 @@ pub fn InteractiveDisplay<'a>(cx: Scope<'a>, props: InteractiveDisplayProps<'a>)
 
         mouse_closure.forget();    
     }
+    {
+        let dragged = dragged.clone();
+        let closure: Closure<dyn FnMut(MouseEvent)> = Closure::new(move |_: MouseEvent| dragged.set(0.0));
+        canvas.set_onmousedown(Some(closure.as_ref().unchecked_ref()));
+        closure.forget();
+    }
     {
         let current_zoom = current_zoom.clone();
         let closure: Closure<dyn FnMut(WheelEvent)> = Closure::new(move |value: WheelEvent| {
@@ -171,9 +442,57 @@ pub fn InteractiveDisplay<'a>(cx: Scope<'a>, props: InteractiveDisplayProps<'a>)
     let slider_value = create_signal(cx, "100".to_string());
     let time_value = create_signal(cx, "100".to_string());
 
-    let ref_signal = create_rc_signal_from_rc(props.positions.get());
-    let ref_signal_copy = ref_signal.clone();
-    create_effect(cx, move || ref_signal_copy.set_rc(props.positions.get()));
+    let prepared: Rc<RefCell<Option<PreparedToolpath>>> = Rc::new(RefCell::new(None));
+    let needs_upload = Rc::new(Cell::new(false));
+    {
+        let prepared = prepared.clone();
+        let needs_upload = needs_upload.clone();
+        create_effect(cx, move || {
+            *prepared.borrow_mut() = prepare(&props.preview.get());
+            needs_upload.set(true);
+        });
+    }
+    let mesh: Rc<RefCell<Option<PreparedHeightMap>>> = Rc::new(RefCell::new(None));
+    let mesh_needs_upload = Rc::new(Cell::new(false));
+    {
+        let mesh = mesh.clone();
+        let mesh_needs_upload = mesh_needs_upload.clone();
+        create_effect(cx, move || {
+            *mesh.borrow_mut() = props.height_map.get().as_ref().as_ref().and_then(prepare_height_map);
+            mesh_needs_upload.set(true);
+        });
+    }
+    // The loop and event handlers outlive this scope, so they get their own copies of the props.
+    let executed_line = Rc::new(Cell::new(None));
+    let tool_position = Rc::new(Cell::new(None));
+    let selected_line = create_rc_signal(None);
+    {
+        let (executed_line, tool_position) = (executed_line.clone(), tool_position.clone());
+        create_effect(cx, move || executed_line.set(*props.executed_line.get()));
+        create_effect(cx, move || tool_position.set(*props.tool_position.get()));
+        let selected_line = selected_line.clone();
+        create_effect(cx, move || props.selected_line.set(*selected_line.get()));
+    }
+    let view_transform: Rc<Cell<Option<ViewTransform>>> = Rc::new(Cell::new(None));
+    // The depth and travel cutoffs; only what's shown can be clicked on.
+    let cutoffs = Rc::new(Cell::new((f32::INFINITY, f32::INFINITY)));
+    {
+        let (prepared, view_transform, cutoffs, selected_line) = (prepared.clone(), view_transform.clone(), cutoffs.clone(), selected_line.clone());
+        let closure: Closure<dyn FnMut(MouseEvent)> = Closure::new(move |value: MouseEvent| {
+            if dragged.get() > CLICK_RADIUS {
+                return;
+            }
+            let prepared = prepared.borrow();
+            let (Some(prepared), Some(transform)) = (prepared.as_ref(), view_transform.get()) else {
+                return;
+            };
+            let (depth_cutoff, distance_cutoff) = cutoffs.get();
+            let click = (value.offset_x() as f32, value.offset_y() as f32);
+            selected_line.set(line_at(prepared, &transform, click, |vertex| vertex[2] < depth_cutoff && vertex[3] < distance_cutoff));
+        });
+        canvas.set_onclick(Some(closure.as_ref().unchecked_ref()));
+        closure.forget();
+    }
     let progress_value = create_rc_signal(100.0);
     let progress_value_copy = progress_value.clone();
     create_effect(cx, move || match (*slider_value.get()).parse::<f32>() {
@@ -198,6 +517,41 @@ pub fn InteractiveDisplay<'a>(cx: Scope<'a>, props: InteractiveDisplayProps<'a>)
     let bounds_signal = create_rc_signal(None);
     let bounds_signal_copy = bounds_signal.clone();
 
+    let vao = context
+        .create_vertex_array()
+        .ok_or("Could not create vertex array object").unwrap();
+    context.bind_vertex_array(Some(&vao));
+    context.bind_buffer(WebGl2RenderingContext::ARRAY_BUFFER, Some(&buffer));
+    let attributes = [(position_attribute_location, 3, 0), (distance_attribute_location, 1, 3), (rapid_attribute_location, 1, 4), (line_attribute_location, 1, 5)];
+    let set_attributes = |context: &WebGl2RenderingContext| for (location, size, offset) in attributes {
+        context.vertex_attrib_pointer_with_i32(
+            location as u32,
+            size,
+            WebGl2RenderingContext::FLOAT,
+            false,
+            (VERTEX_SIZE * 4) as i32,
+            offset * 4,
+        );
+        context.enable_vertex_attrib_array(location as u32);
+    };
+    set_attributes(&context);
+    // The tool is marked with a small cross, in a buffer of its own.
+    let marker_vao = context
+        .create_vertex_array()
+        .ok_or("Could not create vertex array object").unwrap();
+    context.bind_vertex_array(Some(&marker_vao));
+    context.bind_buffer(WebGl2RenderingContext::ARRAY_BUFFER, Some(&marker_buffer));
+    set_attributes(&context);
+    let mesh_vao = context
+        .create_vertex_array()
+        .ok_or("Could not create vertex array object").unwrap();
+    context.bind_vertex_array(Some(&mesh_vao));
+    context.bind_buffer(WebGl2RenderingContext::ARRAY_BUFFER, Some(&mesh_buffer));
+    for (location, offset) in [(mesh_position_location, 0), (mesh_color_location, 3)] {
+        context.vertex_attrib_pointer_with_i32(location as u32, 3, WebGl2RenderingContext::FLOAT, false, (MESH_VERTEX_SIZE * 4) as i32, offset * 4);
+        context.enable_vertex_attrib_array(location as u32);
+    }
+
     add_loop_callback(move |_| {
         let width = canvas.client_width() as u32;
         let height = canvas.client_height() as u32;
@@ -205,136 +559,142 @@ pub fn InteractiveDisplay<'a>(cx: Scope<'a>, props: InteractiveDisplayProps<'a>)
         canvas.set_height(height);
         context.viewport(0, 0, width as i32, height as i32);
 
-        context.use_program(Some(&program));
-
-        // let verticality = sin(t * 0.001);
-
-        //let vertices: [f32; 9] = [-1.0, 1.0, 0.0,    verticality as f32, -1.0, 0.0,     1.0, 1.0, 0.0];
-
-        let vertices_vec = &*ref_signal.get();
-        if vertices_vec.is_empty() {
-            return;
+        let prepared = prepared.borrow();
+        let mesh = mesh.borrow();
+        // The toolpath sets the cutoffs; the view takes in the height map as well.
+        let (bounds, view_bounds) = match (prepared.as_ref(), mesh.as_ref()) {
+            (Some(prepared), Some(mesh)) => (prepared.bounds, enlarge_to(enlarge_to(prepared.bounds, &mesh.bounds.min), &mesh.bounds.max)),
+            (Some(prepared), None) => (prepared.bounds, prepared.bounds),
+            (None, Some(mesh)) => (mesh.bounds, mesh.bounds),
+            (None, None) => return,
+        };
+        // Note that `Float32Array::view` is somewhat dangerous (hence the
+        // `unsafe`!). This is creating a raw view into our module's
+        // `WebAssembly.Memory` buffer, but if we allocate more pages for ourself
+        // (aka do a memory allocation in Rust) it'll cause the buffer to change,
+        // causing the `Float32Array` to be invalid.
+        //
+        // As a result, after `Float32Array::view` we have to be very careful not to
+        // do any memory allocations before it's dropped.
+        if needs_upload.replace(false) {
+            bounds_signal_copy.set(Some(bounds));
+            if let Some(prepared) = prepared.as_ref() {
+                context.bind_buffer(WebGl2RenderingContext::ARRAY_BUFFER, Some(&buffer));
+                unsafe {
+                    let positions_array_buf_view = js_sys::Float32Array::view(&prepared.vertices);
+
+                    context.buffer_data_with_array_buffer_view(
+                        WebGl2RenderingContext::ARRAY_BUFFER,
+                        &positions_array_buf_view,
+                        WebGl2RenderingContext::STATIC_DRAW,
+                    );
+                }
+            }
         }
-        let first = vertices_vec[0];
-        let bounds = vertices_vec.iter().fold(MinMax{ min: first, max: first }, enlarge_to);
-
-        log::debug!("{:?}", bounds);
-        
-        bounds_signal_copy.set(Some(bounds.clone()));
-
-        let max_dif = max_bounds_of(&bounds).max(0.001);
-        let center = center_of(&bounds);
-        // log::debug!("{:?} {:?} {}", bounds, center, max_dif);
-        let scale_factor = 1.0 / max_dif;
-
-        let (vertex_distances, mut total_distance) = 'vertex_distances: {
-            let vec = ref_signal.get();
-            let mut iterator = vec.iter();
-            let mut result = Vec::new();
-            let mut last_position = match iterator.next() {
-                Some(position) => *position,
-                None => break 'vertex_distances (result, 0.0)
-            };
-            let mut accumulator = 0.0;
-            result.push(0.0);
-            for position in iterator {
-                let distance = distance(&last_position, position);
-                accumulator += distance as f64;
-                result.push(accumulator as f32);
-                last_position = *position;
+        if mesh_needs_upload.replace(false) {
+            bounds_signal_copy.set(Some(bounds));
+            if let Some(mesh) = mesh.as_ref() {
+                context.bind_buffer(WebGl2RenderingContext::ARRAY_BUFFER, Some(&mesh_buffer));
+                unsafe {
+                    let mesh_view = js_sys::Float32Array::view(&mesh.vertices);
+                    context.buffer_data_with_array_buffer_view(
+                        WebGl2RenderingContext::ARRAY_BUFFER,
+                        &mesh_view,
+                        WebGl2RenderingContext::STATIC_DRAW,
+                    );
+                }
             }
-            (result, accumulator as f32)
-        };
-        total_distance += 0.01;
+        }
 
-        let vertices: Vec<f32> = ref_signal.get().iter()
-            .zip(vertex_distances)
-            .flat_map(|(pos, distance)| [pos[0], pos[1], pos[2], distance])
-            .collect();
+        let max_dif = max_bounds_of(&view_bounds).max(0.001);
+        let center = center_of(&view_bounds);
+        let scale_factor = 1.0 / max_dif;
 
         let aspect = (width as f32) / (height as f32);
 
         let true_center = quaternion_core::point_rotation(*current_position.borrow(), center);
         
         let dcm = quaternion_core::to_dcm(*current_position.borrow());
+        let matrix = [dcm[0][0] * scale_factor, dcm[0][1] * scale_factor, dcm[0][2] * scale_factor,
+                      dcm[1][0] * scale_factor, dcm[1][1] * scale_factor, dcm[1][2] * scale_factor,
+                      dcm[2][0] * scale_factor, dcm[2][1] * scale_factor, dcm[2][2] * scale_factor];
 
-        // log::debug!("{:?}", dcm);
+        let scale = *current_zoom.borrow();
+        let offset = [-true_center[0] * scale_factor, -true_center[1]  * scale_factor, -true_center[2]  * scale_factor + 2.0];
+        let screen_scale = [-scale * 1.5 / aspect, scale * 1.5];
+        view_transform.set(Some(ViewTransform {
+            matrix: dcm.map(|row| row.map(|value| value * scale_factor)),
+            offset,
+            scale: screen_scale,
+            width: width as f32,
+            height: height as f32,
+        }));
 
-        context.uniform_matrix3fv_with_f32_array(Some(&mat_location), true, 
-            &[dcm[0][0] * scale_factor, dcm[0][1] * scale_factor, dcm[0][2] * scale_factor,
-              dcm[1][0] * scale_factor, dcm[1][1] * scale_factor, dcm[1][2] * scale_factor,
-              dcm[2][0] * scale_factor, dcm[2][1] * scale_factor, dcm[2][2] * scale_factor]
-        );
+        context.clear_color(0.0, 0.0, 0.0, 1.0);
+        context.clear(WebGl2RenderingContext::COLOR_BUFFER_BIT);
 
-        let scale = *current_zoom.borrow();
+        // The height map goes first, so the toolpath is drawn over it.
+        if let Some(mesh) = mesh.as_ref() {
+            context.use_program(Some(&mesh_program));
+            context.uniform_matrix3fv_with_f32_array(Some(&mesh_mat_location), true, &matrix);
+            context.uniform3fv_with_f32_array(Some(&mesh_offset_location), &offset);
+            context.uniform2fv_with_f32_array(Some(&mesh_scale_location), &screen_scale);
+            context.bind_vertex_array(Some(&mesh_vao));
+            context.draw_arrays(WebGl2RenderingContext::TRIANGLES, 0, (mesh.vertices.len() / MESH_VERTEX_SIZE) as i32);
+        }
 
-        context.uniform3fv_with_f32_array(Some(&offset_location), &[-true_center[0] * scale_factor, -true_center[1]  * scale_factor, -true_center[2]  * scale_factor + 2.0]);
-        context.uniform2fv_with_f32_array(Some(&scale_location), &[-scale * 1.5 / aspect, scale * 1.5]);
+        context.use_program(Some(&program));
+        context.uniform_matrix3fv_with_f32_array(Some(&mat_location), true, &matrix);
+        context.uniform3fv_with_f32_array(Some(&offset_location), &offset);
+        context.uniform2fv_with_f32_array(Some(&scale_location), &screen_scale);
 
         let progress_value = *progress_value.get();
         let cutoff = bounds.min[2] * (1.0 - progress_value) + bounds.max[2] * progress_value;
         depth_shown_copy.set(format!("{} mm", cutoff));
 
-        let time_value = *time_progress_value.get();
-        let time_cutoff = time_value * total_distance;
-        time_shown_copy.set(format!("{} mm", time_cutoff));
-
-        context.uniform1f(Some(&depth_cutoff_location), cutoff);
-        context.uniform1f(Some(&distance_cutoff_location), time_cutoff);
-
-        context.bind_buffer(WebGl2RenderingContext::ARRAY_BUFFER, Some(&buffer));
-
-        // Note that `Float32Array::view` is somewhat dangerous (hence the
-        // `unsafe`!). This is creating a raw view into our module's
-        // `WebAssembly.Memory` buffer, but if we allocate more pages for ourself
-        // (aka do a memory allocation in Rust) it'll cause the buffer to change,
-        // causing the `Float32Array` to be invalid.
-        //
-        // As a result, after `Float32Array::view` we have to be very careful not to
-        // do any memory allocations before it's dropped.
-        unsafe {
-            let positions_array_buf_view = js_sys::Float32Array::view(&vertices);
-
-            context.buffer_data_with_array_buffer_view(
-                WebGl2RenderingContext::ARRAY_BUFFER,
-                &positions_array_buf_view,
-                WebGl2RenderingContext::STATIC_DRAW,
-            );
+        if let Some(prepared) = prepared.as_ref() {
+            let total_distance = prepared.distances.last().copied().unwrap_or(0.0) + 0.01;
+            let time_value = *time_progress_value.get();
+            let time_cutoff = time_value * total_distance;
+            cutoffs.set((cutoff, time_cutoff));
+            // The last point reached by the cutoff, to say which line of the file it's on.
+            let reached = prepared.distances.partition_point(|distance| *distance <= time_cutoff).max(1) - 1;
+            time_shown_copy.set(format!("{} mm (line {})", time_cutoff, prepared.lines[reached]));
+
+            context.uniform1f(Some(&depth_cutoff_location), cutoff);
+            context.uniform1f(Some(&distance_cutoff_location), time_cutoff);
+            // Lines are small whole numbers, so they compare exactly as floats; -1 matches nothing.
+            context.uniform1f(Some(&executed_line_location), executed_line.get().map_or(-1.0, |line| line as f32));
+            context.uniform1f(Some(&selected_line_location), (*selected_line.get()).map_or(-1.0, |line| line as f32));
+            context.uniform1f(Some(&marker_location), 0.0);
+
+            context.bind_vertex_array(Some(&vao));
+
+            let vert_count = (prepared.vertices.len() / VERTEX_SIZE) as i32;
+            context.draw_arrays(WebGl2RenderingContext::LINE_STRIP, 0, vert_count);
         }
 
-        //log::debug!("LOOPING!");
-
-        let vao = context
-            .create_vertex_array()
-            .ok_or("Could not create vertex array object").unwrap();
-        context.bind_vertex_array(Some(&vao));
-
-        context.vertex_attrib_pointer_with_i32(
-            position_attribute_location as u32,
-            3,
-            WebGl2RenderingContext::FLOAT,
-            false,
-            4 * 4,
-            0,
-        );
-        context.vertex_attrib_pointer_with_i32(
-            distance_attribute_location as u32,
-            1,
-            WebGl2RenderingContext::FLOAT,
-            false,
-            4 * 4,
-            3 * 4,
-        );
-        context.enable_vertex_attrib_array(position_attribute_location as u32);
-        context.enable_vertex_attrib_array(distance_attribute_location as u32);
-
-        context.bind_vertex_array(Some(&vao));
-
-        let vert_count = (vertices.len() / 4) as i32;
-        context.clear_color(0.0, 0.0, 0.0, 1.0);
-        context.clear(WebGl2RenderingContext::COLOR_BUFFER_BIT);
-    
-        context.draw_arrays(WebGl2RenderingContext::LINE_STRIP, 0, vert_count);
+        if let Some(tool) = tool_position.get() {
+            let size = max_dif * 0.03;
+            let marker: Vec<f32> = (0..3).flat_map(|axis| [-size, size].map(|delta| {
+                let mut point = tool;
+                point[axis] += delta;
+                [point[0], point[1], point[2], 0.0, 0.0, 0.0]
+            })).flatten().collect();
+            context.uniform1f(Some(&marker_location), 1.0);
+            context.bind_vertex_array(Some(&marker_vao));
+            context.bind_buffer(WebGl2RenderingContext::ARRAY_BUFFER, Some(&marker_buffer));
+            // As above, nothing may allocate while the view exists.
+            unsafe {
+                let marker_view = js_sys::Float32Array::view(&marker);
+                context.buffer_data_with_array_buffer_view(
+                    WebGl2RenderingContext::ARRAY_BUFFER,
+                    &marker_view,
+                    WebGl2RenderingContext::STREAM_DRAW,
+                );
+            }
+            context.draw_arrays(WebGl2RenderingContext::LINES, 0, 6);
+        }
     
     });
 
@@ -380,28 +740,163 @@ pub struct DisplayPageProps {
 
 #[component]
 pub fn DisplayPage(cx: Scope, props: DisplayPageProps) -> View<DomNode> {
-    let value = create_signal(cx, vec![]);
+    let global_info: &GlobalInfo = use_context(cx);
+    let value = create_signal(cx, ToolpathPreview::default());
+    let error = create_signal(cx, None::<String>);
+    let source_lines = create_signal(cx, Vec::<String>::new());
     let path = props.path.join("/").clone();
     let directory = if props.path.is_empty() {
         "".into()
     } else {
         props.path[..props.path.len() - 1].iter().map(|item| format!("/{}", item)).join("")
     };
-    spawn_local_scoped(cx, async {
-        let result = request::request_with_json(
-            HttpMethod::Post,
-            api::EXAMINE_LINES_IN_GCODE_FILE,
-            &api::ExamineGcodeFile {
-                path: path
+    // Only a job running this file has progress worth showing here.
+    let job_progress = {
+        let path = path.clone();
+        create_memo(cx, move || global_info.job_info.get().as_ref().as_ref()
+            .and_then(|job| job.progress.clone())
+            .filter(|progress| progress.path.as_ref() == Some(&path)))
+    };
+    let executed_line = create_memo(cx, || job_progress.get().as_ref().as_ref().map(|progress| progress.acknowledged_line as u32));
+    let tool_position = create_memo(cx, || global_info.grbl_info.get().as_ref().as_ref().map(|info| {
+        let position = info.work_position();
+        [0, 1, 2].map(|axis| position.get(axis).copied().unwrap_or(0.0) as f32)
+    }));
+    let selected_line = create_signal(cx, None::<u32>);
+    let preview_path = path.clone();
+    // With no file, only a height map is shown.
+    let has_file = !props.path.is_empty();
+    spawn_local_scoped(cx, async move {
+        if !has_file {
+            return;
+        }
+        match client().preview_gcode_file(&api::PreviewGcodeFile { path: preview_path, max_points: None }).await {
+            Ok(preview) => value.set(preview),
+            Err(message) => error.set(Some(message.to_string())),
+        }
+    });
+    let download_path = path.clone();
+    spawn_local_scoped(cx, async move {
+        if !has_file {
+            return;
+        }
+        if let Ok(text) = client().download_gcode_file(&download_path).await {
+            source_lines.set(text.lines().map(str::to_string).collect());
+        }
+    });
+    let selected_text = create_memo(cx, || match *selected_line.get() {
+        Some(line) => {
+            let text = source_lines.get().get(line.max(1) as usize - 1).cloned().unwrap_or_default();
+            format!("Line {}: {}", line, text)
+        }
+        None => "Click on the toolpath to see the line that made it.".to_string(),
+    });
+    let progress_text = create_memo(cx, || match job_progress.get().as_ref() {
+        Some(progress) => format!("Running: line {} of {} done, {} sent", progress.acknowledged_line, progress.total_lines, progress.sent_line),
+        None => "Not running.".to_string(),
+    });
+
+    /*
+        A probed height map, drawn under the toolpath. Maps are probed in machine coordinates, so they're
+        moved by the current work offset to line up with the file.
+    */
+    let result_jobs = create_signal(cx, Vec::<String>::new());
+    spawn_local_scoped(cx, async move {
+        match list_job_results().await {
+            Ok(jobs) => result_jobs.set(jobs),
+            Err(e) => log::debug!("ERROR: {:?}", e),
+        }
+    });
+    let height_map_job = create_signal(cx, String::new());
+    let height_map = create_signal(cx, None::<HeightMap>);
+    let height_map_message = create_signal(cx, String::new());
+    create_effect(cx, move || {
+        let job = height_map_job.get().as_ref().clone();
+        height_map.set(None);
+        height_map_message.set(String::new());
+        if job.is_empty() {
+            return;
+        }
+        spawn_local_scoped(cx, async move {
+            let result = get_height_map(&job).await;
+            // Another job may have been chosen while this one loaded.
+            if *height_map_job.get_untracked() != job {
+                return;
+            }
+            match result {
+                Ok(map) => height_map.set(Some(map)),
+                Err(error) => height_map_message.set(format!("Error: {:#}", error)),
             }
-        ).await.unwrap();
-        let result: Vec<[f32; 3]> = result.json().await.unwrap();
-        // let mut old_value = (*value.get()).clone();
-        // old_value.push([0.0, 0.0, 0.0]);
-        value.set(result);
+        });
     });
+    let work_offset = create_selector(cx, || global_info.grbl_info.get().as_ref().as_ref()
+        .map(|info| [0, 1, 2].map(|axis| info.work_coordinate_offset.get(axis).copied().unwrap_or(0.0))));
+    let shown_height_map = create_memo(cx, || height_map.get().as_ref().as_ref()
+        .map(|map| shift_height_map(map, work_offset.get().unwrap_or([0.0; 3]))));
+    let has_height_map = create_selector(cx, || height_map.get().is_some());
+    let height_map_stats = create_memo(cx, || {
+        let map = height_map.get();
+        let Some((map, (low, high))) = map.as_ref().as_ref().and_then(|map| Some((map, height_range(map)?))) else {
+            return String::new();
+        };
+        let mut text = format!(
+            "{} by {} points. The surface is from Z{:.3} to Z{:.3} in machine coordinates, so flat to within {:.3} mm.",
+            map.xs.len(), map.ys.len(), low, high, high - low
+        );
+        if work_offset.get().is_none() {
+            text += " The machine isn't connected, so the map is drawn without a work offset.";
+        }
+        text
+    });
+    let height_range_text = create_memo(cx, || height_map.get().as_ref().as_ref().and_then(height_range)
+        .map_or((String::new(), String::new()), |(low, high)| (format!("Z{:.3}", low), format!("Z{:.3}", high))));
+    let cutting = create_memo(cx, || cutting_bounds(&value.get()));
+    let coverage_text = create_memo(cx, || match (cutting.get().as_ref(), shown_height_map.get().as_ref()) {
+        (Some(cutting), Some(map)) => {
+            let sides = uncovered_sides(cutting, map);
+            if sides.is_empty() {
+                "The height map covers every cut in the file.".to_string()
+            } else {
+                format!("The cuts go past the height map by {}.", sides.join(", "))
+            }
+        }
+        _ => String::new(),
+    });
+    let legend_style = format!(
+        "display: inline-block; width: 12em; height: 1em; vertical-align: middle; background: linear-gradient(to right, {}, {}, {});",
+        css_color(0.0), css_color(0.5), css_color(1.0)
+    );
+    let legend_style = create_ref(cx, legend_style);
+
     view! { cx,
-        InteractiveDisplay(positions=value)
+        (match error.get().as_ref() {
+            Some(message) => view! { cx, p { "Couldn't show this file: " (message.clone()) } },
+            None => view! { cx, },
+        })
+        InteractiveDisplay(preview=value, executed_line=executed_line, tool_position=tool_position, selected_line=selected_line, height_map=shown_height_map)
+        br{}
+        (progress_text.get())
+        br{}
+        (selected_text.get())
+        br{}
+        "Height map from job: "
+        select(bind:value=height_map_job) {
+            option(value="") { "None" }
+            Indexed(iterable=result_jobs, view=|cx, job| view! { cx, option(value=job.clone()) { (job) } })
+        }
+        " " (height_map_message.get())
+        (if *has_height_map.get() {
+            view! { cx,
+                br{}
+                (height_map_stats.get())
+                br{}
+                "Low " (height_range_text.get().0.clone()) " " span(style=legend_style) {} " " (height_range_text.get().1.clone()) " high"
+                br{}
+                (coverage_text.get())
+            }
+        } else {
+            view! { cx, }
+        })
         br{}
         a(href=format!("/send_gcode{}", directory)) { "Back!" }
     }
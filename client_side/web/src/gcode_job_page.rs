@@ -3,15 +3,15 @@ use std::mem::forget;
 use std::path::PathBuf;
 use std::sync::Arc;
 
-use common::api::{self, RunGcodeFile, DeleteGcodeFile, DOWNLOAD_GCODE};
+use api_client::UploadGcodeFile;
+use common::api::{self, RunGcodeFile, DeleteGcodeFile, JobErrorPolicy};
 use futures::future::{Fuse, FusedFuture};
 use itertools::Itertools;
 use reqwasm::websocket::{futures::WebSocket, Message};
-use reqwasm::http::{FormData, Request};
 use sycamore::prelude::*;
 use sycamore::web::html::{input, form};
 use wasm_bindgen::{JsCast, JsValue};
-use wasm_bindgen_futures::spawn_local;
+use wasm_bindgen_futures::{spawn_local, JsFuture};
 use futures::stream::StreamExt;
 use futures::channel::oneshot;
 use futures::{select, FutureExt, Future};
@@ -22,15 +22,59 @@ use std::time::Duration;
 use sycamore::futures::{create_resource, spawn_local_scoped};
 use crate::components::modal_wrapper::use_modal_handler;
 use crate::components::upload_modal::UploadModal;
-use crate::request::{self, HttpMethod, HOST_NAME};
+use crate::models::command_history::remembered_user;
+use crate::request::{self, client};
 use crate::status_header::GlobalInfo;
 use crate::utils::async_sycamore;
+use crate::utils::time::format_seconds;
 use crate::components::folder_create_modal::FolderCreateModal;
 
+pub(crate) fn format_size(size: u64) -> String {
+    match size {
+        size if size < 1024 => format!("{} B", size),
+        size if size < 1024 * 1024 => format!("{:.1} KiB", size as f64 / 1024.0),
+        size => format!("{:.1} MiB", size as f64 / (1024.0 * 1024.0)),
+    }
+}
+// Bounds, tools and feeds, for hovering over the file's name.
+fn analysis_details(analysis: &api::GcodeAnalysis) -> String {
+    let mut details = Vec::new();
+    if let Some(bounds) = &analysis.bounds {
+        details.push(format!(
+            "X {:.2} to {:.2}, Y {:.2} to {:.2}, Z {:.2} to {:.2}",
+            bounds.min[0], bounds.max[0], bounds.min[1], bounds.max[1], bounds.min[2], bounds.max[2]
+        ));
+    }
+    details.push(format!("{} lines; cutting {:.0}, rapids {:.0}", analysis.line_count, analysis.cut_distance, analysis.rapid_distance));
+    if !analysis.tools.is_empty() {
+        details.push(format!("Tools: {}", analysis.tools.iter().map(|tool| format!("T{}", tool)).join(", ")));
+    }
+    if !analysis.feeds.is_empty() {
+        details.push(format!("Feeds: {}", analysis.feeds.iter().join(", ")));
+    }
+    for issue in &analysis.parse_errors {
+        details.push(format!("Line {}: {}", issue.line_number, issue.description));
+    }
+    details.join("\n")
+}
+fn last_run_summary(last_run: &Option<api::LastRun>) -> String {
+    match last_run {
+        None => "Never run".to_string(),
+        Some(run) => format!(
+            "{} {}{}",
+            run.time.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M"),
+            if run.completed { "completed" } else { "stopped" },
+            if run.error_count > 0 { format!(" with {} errors", run.error_count) } else { String::new() },
+        )
+    }
+}
+
 #[derive(Prop)]
 pub struct GcodeFileProps<'a, F: Fn() -> ()> {
     name: String,
+    info: api::GcodeFile,
     can_send_job: &'a ReadSignal<bool>,
+    error_policy: &'a ReadSignal<JobErrorPolicy>,
     on_delete: F,
     path: String,
 }
@@ -39,19 +83,30 @@ pub struct GcodeFileProps<'a, F: Fn() -> ()> {
 pub fn GcodeFile<'a, F: Fn() -> () + 'a>(cx: Scope<'a>, props: GcodeFileProps<'a, F>) -> View<DomNode> {
     let name = create_ref(cx, props.name);
     let path = create_ref(cx, props.path);
-    let run_callback = create_ref(cx, |_| {
-        request::request_detached_with_json(
-            HttpMethod::Post,
-            api::RUN_GCODE_FILE,
-            &RunGcodeFile { path: path.clone() }
-        );
+    let error_policy = props.error_policy;
+    let run_callback = create_ref(cx, move |_| {
+        let run = RunGcodeFile { path: path.clone(), error_policy: *error_policy.get() };
+        request::detached(async move { client().run_gcode_file(&run).await });
     });
     let on_delete = create_ref(cx, props.on_delete);
+    let info = props.info;
+    let details = info.analysis.as_ref().map_or(String::new(), analysis_details);
+    let size = info.size.map_or(String::new(), format_size);
+    let run_time = info.analysis.as_ref().map_or(String::new(), |analysis| format_seconds(analysis.estimated_seconds.round() as i64));
+    let problems = match &info.analysis {
+        Some(analysis) if analysis.parse_error_count > 0 => format!("{} parse errors", analysis.parse_error_count),
+        _ => String::new(),
+    };
+    let last_run = last_run_summary(&info.last_run);
     view! { cx,
         tr(class="gcode_line") {
-            td {
+            td(title=details) {
                 (name.clone()) " "
             }
+            td { (size) }
+            td { (run_time) }
+            td { (problems) }
+            td { (last_run) }
             td {
                 button(on:click=run_callback, disabled=!*props.can_send_job.get()) { "Run!" }
             }
@@ -62,7 +117,7 @@ pub fn GcodeFile<'a, F: Fn() -> () + 'a>(cx: Scope<'a>, props: GcodeFileProps<'a
                 a(href=format!("/view/{}", path)) { "View!" }
             }
             td {
-                a(href=format!("http://{}{}/{}", HOST_NAME, DOWNLOAD_GCODE, path)) { "Download!" }
+                a(href=client().gcode_file_url(path)) { "Download!" }
             }
         }
     }
@@ -86,6 +141,10 @@ pub fn GcodeDirectory<'a, F: Fn() -> () + 'a>(cx: Scope<'a>, props: GcodeDirecto
                 }
             }
             td {}
+            td {}
+            td {}
+            td {}
+            td {}
             td {
                 button(on:click=move |_| (props.on_delete)()) { "Delete directory!" }
             }
@@ -110,30 +169,57 @@ pub fn GCodePage<'a>(cx: Scope<'a>, path: Vec<String>) -> View<DomNode> {
     let parent_directory = create_ref(cx, path[..if path.is_empty() { 0 } else { path.len() - 1}].join("/"));
     let get_list = create_ref(cx, || async {
         // TODO: Probably want some sort of debounce here?
-        let result = request::request_with_json(
-            HttpMethod::Post,
-            api::LIST_GCODE_FILES,
-            &api::ListGcodeFiles {
-                prefix: directory.clone()
-            },
-        ).await.unwrap();
-        // TODO: Would be nice to wrap the un-jsoning in the request somehow...
-        let names: Vec<api::GcodeFile> = result.json().await.unwrap();
-        list.set(Some(names));
+        match client().list_gcode_files(&api::ListGcodeFiles { prefix: directory.clone() }).await {
+            Ok(names) => list.set(Some(names)),
+            Err(e) => log::debug!("ERROR: {:?}", e),
+        }
     });
+    let reject_invalid = create_signal(cx, false);
     let on_upload = create_ref(cx, Box::new(move |files: Vec<web_sys::File>| Box::new(async move {
+        // Anything worth telling the user about, one line per problem.
+        let mut problems = Vec::new();
         for file in files {
-            let form_data = FormData::new().unwrap();
-            form_data.append_with_str("filename", &format!("{}{}", directory, file.name())).unwrap();
-            form_data.append_with_blob_and_filename("file", &file, "filename.nc").unwrap();
-            let result = request::request_with_body(
-                HttpMethod::Post, 
-                api::UPLOAD_GCODE_FILE, 
-                form_data,
-            ).await;
+            let contents = match JsFuture::from(file.array_buffer()).await {
+                Ok(buffer) => js_sys::Uint8Array::new(&buffer).to_vec(),
+                Err(error) => {
+                    problems.push(format!("{}: couldn't read the file: {:?}", file.name(), error));
+                    continue
+                }
+            };
+            let uploader = Some(remembered_user()).filter(|uploader| !uploader.is_empty());
+            let upload = UploadGcodeFile {
+                path: format!("{}{}", directory, file.name()),
+                contents,
+                reject_invalid: *reject_invalid.get(),
+                uploader,
+            };
+            let report = match client().upload_gcode_file(upload).await {
+                Ok(report) => report,
+                Err(error) => {
+                    problems.push(format!("{}: {}", file.name(), error));
+                    continue
+                }
+            };
+            for uploaded in report.files {
+                if !uploaded.stored {
+                    problems.push(format!("{}: rejected with {} errors", uploaded.path, uploaded.error_count));
+                } else if uploaded.error_count > 0 {
+                    problems.push(format!("{}: {} errors", uploaded.path, uploaded.error_count));
+                }
+                for issue in uploaded.errors.iter().chain(&uploaded.warnings) {
+                    problems.push(format!("{} line {}: {}", uploaded.path, issue.line_number, issue.description));
+                }
+            }
+            for skipped in report.skipped {
+                problems.push(format!("{}: skipped", skipped));
+            }
         }
         get_list().await;
-        Ok(())
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems.join("\n"))
+        }
     }) as Box<dyn Future<Output=Result<(), String>> + 'a>));
     let on_close = create_ref(cx, Box::new(|| modal.clear_modal()));
     let open_upload_modal = create_ref(cx, move |_| {
@@ -146,15 +232,11 @@ pub fn GCodePage<'a>(cx: Scope<'a>, path: Vec<String>) -> View<DomNode> {
     let open_folder_modal = create_ref(cx, move |_| {
         modal.set_modal(cx, move || {
             let on_upload = create_ref(cx, move |dirname| async move {
-                request::request_with_json(
-                    HttpMethod::Post,
-                    api::CREATE_GCODE_DIRECTORY,
-                    &api::CreateGcodeDirectory {
-                        directory: format!("{}{}", directory, dirname)
-                    }
-                ).await.unwrap();
+                let created = client().create_gcode_directory(&api::CreateGcodeDirectory {
+                    directory: format!("{}{}", directory, dirname)
+                }).await;
                 get_list().await;
-                Ok(())
+                created.map_err(|error| error.to_string())
             });
             view! { cx,
                 FolderCreateModal(on_upload=on_upload, on_close=on_close.clone())
@@ -166,17 +248,21 @@ pub fn GCodePage<'a>(cx: Scope<'a>, path: Vec<String>) -> View<DomNode> {
         move || {
             let name = name.clone();
             spawn_local_scoped(cx, async move {
-                request::request_with_json(
-                    HttpMethod::Delete,  // Should really have method bundled in...
-                    api::DELETE_GCODE_FILE,
-                    &DeleteGcodeFile { path: name, is_directory }
-                ).await.unwrap();
+                if let Err(e) = client().delete_gcode_file(&DeleteGcodeFile { path: name, is_directory }).await {
+                    log::debug!("ERROR: {:?}", e);
+                }
                 get_list().await;
             });
         }
     });
     spawn_local_scoped(cx, get_list());
     let global_info: &GlobalInfo = use_context(cx);
+    let error_policy_value = create_signal(cx, "Abort".to_string());
+    let error_policy = create_memo(cx, || match error_policy_value.get().as_str() {
+        "Pause" => JobErrorPolicy::Pause,
+        "Continue" => JobErrorPolicy::Continue,
+        _ => JobErrorPolicy::Abort,
+    });
     //let list = create_memo(cx, move || (*list.get()).clone().unwrap_or(Vec::new()));
     view! { cx,
         (if list.get().is_none() {
@@ -188,6 +274,14 @@ pub fn GCodePage<'a>(cx: Scope<'a>, path: Vec<String>) -> View<DomNode> {
             let list = create_memo(cx, move || (*list.get()).clone().unwrap_or(Vec::new()));
             view! { cx, 
                 div(class="debug_page") {
+                    label {
+                        "On controller error: "
+                        select(bind:value=error_policy_value) {
+                            option(value="Abort") { "Stop the job" }
+                            option(value="Pause") { "Pause and ask" }
+                            option(value="Continue") { "Log and continue" }
+                        }
+                    }
                     table {
                         Indexed(
                             iterable=list,
@@ -196,7 +290,9 @@ pub fn GCodePage<'a>(cx: Scope<'a>, path: Vec<String>) -> View<DomNode> {
                                     view! { cx,
                                         GcodeFile(
                                             name=x.name.clone(),
+                                            info=x.clone(),
                                             can_send_job=global_info.is_idle,
+                                            error_policy=error_policy,
                                             on_delete=on_delete_factory(x.name.clone(), false),
                                             path=format!("{}{}", directory, x.name),
                                         )
@@ -222,7 +318,12 @@ pub fn GCodePage<'a>(cx: Scope<'a>, path: Vec<String>) -> View<DomNode> {
                 }
             }
         })
-        button(on:click=open_upload_modal) { "Upload" } br{}
+        button(on:click=open_upload_modal) { "Upload" }
+        label {
+            input(type="checkbox", bind:checked=reject_invalid) {}
+            "Reject files with errors"
+        }
+        br{}
         button(on:click=open_folder_modal) { "Add Folder" } br{} 
         a(href="/") { "Go home!" }
     }
@@ -0,0 +1,265 @@
+use chrono::{DateTime, Local, Utc};
+use common::api::{JobErrorPolicy, JobOutcome, JobRecord, ProbeExportFormat, QueryJobHistory, RestoreGcodeVersion, RunGcodeFile};
+use stylist::style;
+use sycamore::{prelude::*, futures::spawn_local_scoped};
+
+use crate::gcode_job_page::format_size;
+use crate::models::job_history::{get_job_record, query_job_history};
+use crate::request::client;
+use crate::status_header::GlobalInfo;
+use crate::utils::async_sycamore::try_loading_view;
+use crate::utils::time::format_duration;
+
+const OUTCOMES: [JobOutcome; 6] = [JobOutcome::Running, JobOutcome::Completed, JobOutcome::Stopped, JobOutcome::Reset, JobOutcome::Alarm, JobOutcome::Error];
+
+fn format_time(time: &DateTime<Utc>) -> String {
+    time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S").to_string()
+}
+fn format_run_time(start_time: &DateTime<Utc>, end_time: &Option<DateTime<Utc>>) -> String {
+    end_time.map_or(String::new(), |end_time| format_duration(end_time - *start_time))
+}
+fn policy_name(policy: JobErrorPolicy) -> &'static str {
+    match policy {
+        JobErrorPolicy::Abort => "stop on errors",
+        JobErrorPolicy::Pause => "pause on errors",
+        JobErrorPolicy::Continue => "continue past errors",
+    }
+}
+
+// Running the file again only repeats the job while the file is still the version the job ran.
+async fn is_unchanged(record: &JobRecord) -> anyhow::Result<bool> {
+    let versions = client().list_gcode_versions(&record.source.path).await?;
+    Ok(versions.last().is_some_and(|current| current.hash == record.source.version))
+}
+async fn run_again(record: &JobRecord) -> anyhow::Result<()> {
+    let message = RunGcodeFile { path: record.source.path.clone(), error_policy: record.error_policy };
+    Ok(client().run_gcode_file(&message).await?)
+}
+async fn restore_version(record: &JobRecord) -> anyhow::Result<()> {
+    let message = RestoreGcodeVersion { path: record.source.path.clone(), hash: record.source.version.clone() };
+    Ok(client().restore_gcode_version(&message).await?)
+}
+
+#[component]
+pub fn HistoryPage(cx: Scope) -> View<DomNode> {
+    try_loading_view(
+        cx,
+        view! { cx, "Loading..." },
+        move |err: anyhow::Error| view! { cx, (format!("Error: {:?}", err)) },
+        async move {
+            let global_info: &GlobalInfo = use_context(cx);
+            let message = create_signal(cx, String::new());
+
+            /*
+                The list of jobs
+            */
+            let path_filter = create_signal(cx, String::new());
+            let outcome_filter = create_signal(cx, String::new());
+            let build_query = move || QueryJobHistory {
+                path: Some(path_filter.get().trim().to_string()).filter(|path| !path.is_empty()),
+                outcomes: OUTCOMES.into_iter().find(|outcome| format!("{:?}", outcome) == *outcome_filter.get()).map(|outcome| vec![outcome]),
+                ..Default::default()
+            };
+            let jobs = create_signal(cx, query_job_history(&build_query()).await?);
+            let search = move |_| spawn_local_scoped(cx, async move {
+                match query_job_history(&build_query()).await {
+                    Ok(result) => {
+                        message.set(String::new());
+                        jobs.set(result);
+                    }
+                    Err(error) => message.set(format!("Error: {:#}", error)),
+                }
+            });
+
+            /*
+                The chosen job
+            */
+            let record = create_signal(cx, None::<JobRecord>);
+            let changed = create_signal(cx, false); // The chosen job's file has changed since it ran.
+            let show = move |id: String| spawn_local_scoped(cx, async move {
+                match get_job_record(&id).await {
+                    Ok(result) => {
+                        changed.set(false);
+                        record.set(Some(result));
+                    }
+                    Err(error) => message.set(format!("Error: {:#}", error)),
+                }
+            });
+            let show = create_ref(cx, show);
+            let run = move |_| spawn_local_scoped(cx, async move {
+                let Some(chosen) = record.get().as_ref().clone() else { return };
+                let result = match is_unchanged(&chosen).await {
+                    Ok(true) => run_again(&chosen).await,
+                    Ok(false) => {
+                        changed.set(true);
+                        message.set(format!("{} has changed since this job ran; restore the version it ran to run it again.", chosen.source.path));
+                        return;
+                    }
+                    Err(error) => Err(error),
+                };
+                message.set(match result {
+                    Ok(()) => format!("Started {} again.", chosen.source.path),
+                    Err(error) => format!("Error: {:#}", error),
+                });
+            });
+            let restore = move |_| spawn_local_scoped(cx, async move {
+                let Some(chosen) = record.get().as_ref().clone() else { return };
+                message.set(match restore_version(&chosen).await {
+                    Ok(()) => {
+                        changed.set(false);
+                        format!("Restored {} to the version this job ran.", chosen.source.path)
+                    }
+                    Err(error) => format!("Error: {:#}", error),
+                });
+            });
+            let errors = create_memo(cx, || record.get().as_ref().as_ref().map_or(Vec::new(), |record| record.errors.clone()));
+            let overrides = create_memo(cx, || record.get().as_ref().as_ref().map_or(Vec::new(), |record| record.overrides.clone()));
+            let artifacts = create_memo(cx, || record.get().as_ref().as_ref().map_or(Vec::new(), |record| {
+                record.artifacts.iter().map(|artifact| (
+                    client().result_url(&record.id, &artifact.name),
+                    format!("{} ({}, {})", artifact.name, format_size(artifact.size), artifact.content_type),
+                )).collect()
+            }));
+            let probe_exports = create_memo(cx, || match record.get().as_ref() {
+                Some(record) if record.artifacts.iter().any(|artifact| artifact.name == "probes.json") => {
+                    [(ProbeExportFormat::Csv, "CSV"), (ProbeExportFormat::DepthMap, "depth map"), (ProbeExportFormat::HeightMap, "height map")]
+                        .into_iter()
+                        .map(|(format, label)| (client().probes_export_url(&record.id, format), label))
+                        .collect()
+                }
+                _ => Vec::new(),
+            });
+
+            let css = style! { r#"
+                table {
+                    border-collapse: collapse;
+                }
+                td, th {
+                    padding: 0.2em 0.5em;
+                    text-align: left;
+                    vertical-align: top;
+                }
+                .Completed {
+                    color: #080;
+                }
+                .Error, .Alarm, .Reset {
+                    color: #c00;
+                }
+            "#}.unwrap();
+            Ok(view! { cx,
+                div(class=css.get_class_name()) {
+                    p {
+                        "File: " input(type="text", size=20, bind:value=path_filter)
+                        " Outcome: "
+                        select(bind:value=outcome_filter) {
+                            option(value="") { "Any" }
+                            option(value="Completed") { "Completed" }
+                            option(value="Stopped") { "Stopped" }
+                            option(value="Reset") { "Reset" }
+                            option(value="Alarm") { "Alarm" }
+                            option(value="Error") { "Error" }
+                            option(value="Running") { "Running" }
+                        }
+                        " "
+                        button(on:click=search) { "Search" }
+                    }
+                    p { (message.get()) }
+                    table {
+                        tr {
+                            th { "Started" } th { "File" } th { "Outcome" } th { "Run time" } th { "Errors" } th {}
+                        }
+                        Keyed(
+                            iterable=jobs,
+                            key=|job| job.id.clone(),
+                            view=move |cx, job| {
+                                let id = job.id.clone();
+                                view! { cx,
+                                    tr {
+                                        td { (format_time(&job.start_time)) }
+                                        td { (job.source.path.clone()) }
+                                        td(class=format!("{:?}", job.outcome)) { (format!("{:?}", job.outcome)) }
+                                        td { (format_run_time(&job.start_time, &job.end_time)) }
+                                        td { (job.error_count) }
+                                        td { button(on:click=move |_| show(id.clone())) { "Details" } }
+                                    }
+                                }
+                            }
+                        )
+                    }
+                    (match record.get().as_ref().clone() {
+                        Some(chosen) => view! { cx,
+                            h3 { (chosen.source.path.clone()) }
+                            p {
+                                (format!("Version {}, {}.", chosen.source.version, policy_name(chosen.error_policy)))
+                                br {}
+                                (format!("Started {}", format_time(&chosen.start_time)))
+                                (chosen.end_time.map_or(String::new(), |end_time| format!(", ended {}", format_time(&end_time))))
+                                br {}
+                                span(class=format!("{:?}", chosen.outcome)) { (format!("{:?}", chosen.outcome)) }
+                                (chosen.alarm.clone().map_or(String::new(), |alarm| format!(": {}", alarm)))
+                            }
+                            p {
+                                button(on:click=run, disabled=!*global_info.is_idle.get()) { "Run again" }
+                                (if *changed.get() {
+                                    view! { cx, " " button(on:click=restore) { "Restore that version" } }
+                                } else {
+                                    view! { cx, }
+                                })
+                            }
+                            h4 { "Errors" }
+                            table {
+                                tr { th { "Line" } th { "Error" } th { "Sent" } }
+                                Indexed(
+                                    iterable=errors,
+                                    view=|cx, error| view! { cx,
+                                        tr {
+                                            td { (error.line_number) }
+                                            td { (format!("error:{} {}", error.code, error.text)) }
+                                            td { (error.sent) }
+                                        }
+                                    }
+                                )
+                            }
+                            h4 { "Override changes" }
+                            table {
+                                tr { th { "Time" } th { "Feed" } th { "Rapid" } th { "Spindle" } }
+                                Indexed(
+                                    iterable=overrides,
+                                    view=|cx, change| view! { cx,
+                                        tr {
+                                            td { (format_time(&change.time)) }
+                                            td { (format!("{}%", change.feed)) }
+                                            td { (format!("{}%", change.rapid)) }
+                                            td { (format!("{}%", change.spindle)) }
+                                        }
+                                    }
+                                )
+                            }
+                            h4 { "Saved files" }
+                            ul {
+                                Indexed(
+                                    iterable=artifacts,
+                                    view=|cx, (url, label)| view! { cx, li { a(href=url) { (label) } } }
+                                )
+                            }
+                            (if probe_exports.get().is_empty() {
+                                view! { cx, }
+                            } else {
+                                view! { cx,
+                                    p {
+                                        "Export the probes as: "
+                                        Indexed(
+                                            iterable=probe_exports,
+                                            view=|cx, (url, label)| view! { cx, a(href=url, download="") { (label) } " " }
+                                        )
+                                    }
+                                }
+                            })
+                        },
+                        None => view! { cx, },
+                    })
+                }
+            })
+        }
+    )
+}
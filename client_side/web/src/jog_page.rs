@@ -1,105 +1,385 @@
-use std::io::Read;
-use std::mem::forget;
-use std::sync::Arc;
-
-use common::api;
-use futures::future::{Fuse, FusedFuture};
-use reqwasm::websocket::{futures::WebSocket, Message};
-use reqwasm::http::Request;
-use sycamore::prelude::*;
-use wasm_bindgen::{JsCast, JsValue};
-use wasm_bindgen_futures::spawn_local;
-use futures::stream::StreamExt;
-use futures::channel::oneshot;
-use futures::{select, FutureExt};
-use stylist::style;
-use web_sys::{KeyboardEvent, Event};
-use gloo_timers::future::sleep;
+use std::cell::RefCell;
 use std::time::Duration;
-use sycamore::futures::create_resource;
-use crate::request::{HttpMethod, self};
+
+use common::grbl::GrblState;
+use gloo_timers::future::sleep;
+use serde::{Deserialize, Serialize};
+use stylist::style;
+use sycamore::futures::spawn_local_scoped;
+use sycamore::prelude::*;
+use wasm_bindgen::{JsCast, prelude::Closure};
+use web_sys::{Element, Event, Gamepad, KeyboardEvent};
+use crate::request::client;
 use crate::status_header::GlobalInfo;
-use crate::utils::async_sycamore;
 
-async fn jog(x: f64, y: f64, z: f64) {
-    request::request_with_body(
-        HttpMethod::Post,
-        api::SEND_RAW_GCODE,
-        format!("$J=G21 G91 X{:.3} Y{:.3} Z{:.3} F6000.000", x, y, z)
-    ).await.unwrap();
+/*
+    Jogging, a step at a time or for as long as a key, button or gamepad stick is held. Held jogs are sent as
+short increments covering the time since the last one, as the Grbl docs suggest for joysticks, so the machine
+never runs far ahead of the input; letting go sends a jog cancel, which also drops the increments still queued.
+*/
+
+const PRESETS_STORAGE_KEY: &str = "cnc_jog_presets";
+const TICK: Duration = Duration::from_millis(100);
+const LEAD_SECONDS: f64 = 0.25;        // Motion queued by the first increment, so later ones arrive before it runs out.
+const MAX_INCREMENT_SECONDS: f64 = 0.5; // After a stall, don't queue up the whole gap.
+const DEAD_ZONE: f64 = 0.15;
+
+#[derive(Clone, Serialize, Deserialize, PartialEq)]
+pub struct JogPresets {
+    pub feeds: Vec<f64>, // mm/min
+    pub steps: Vec<f64>, // mm
+}
+impl Default for JogPresets {
+    fn default() -> Self {
+        JogPresets {
+            feeds: vec![100.0, 500.0, 1000.0, 3000.0, 6000.0],
+            steps: vec![0.01, 0.1, 1.0, 10.0, 100.0],
+        }
+    }
+}
+// The presets are remembered by the browser, like the debug page's user name.
+fn load_presets() -> JogPresets {
+    web_sys::window()
+        .and_then(|window| window.local_storage().ok().flatten())
+        .and_then(|storage| storage.get_item(PRESETS_STORAGE_KEY).ok().flatten())
+        .and_then(|text| serde_json::from_str::<JogPresets>(&text).ok())
+        .filter(|presets| !presets.feeds.is_empty() && !presets.steps.is_empty())
+        .unwrap_or_default()
+}
+fn save_presets(presets: &JogPresets) {
+    if let Some(storage) = web_sys::window().and_then(|window| window.local_storage().ok().flatten()) {
+        let _ = storage.set_item(PRESETS_STORAGE_KEY, &serde_json::to_string(presets).unwrap());
+    }
+}
+fn parse_preset_list(name: &str, text: &str) -> anyhow::Result<Vec<f64>> {
+    let mut values = Vec::new();
+    for part in text.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+        match part.parse::<f64>() {
+            Ok(value) if value.is_finite() && value > 0.0 => values.push(value),
+            _ => anyhow::bail!("{} presets should be positive numbers, not {:?}", name, part),
+        }
+    }
+    if values.is_empty() {
+        anyhow::bail!("Give at least one {} preset", name.to_lowercase());
+    }
+    values.sort_by(f64::total_cmp);
+    values.dedup();
+    Ok(values)
+}
+fn format_preset_list(values: &[f64]) -> String {
+    values.iter().map(f64::to_string).collect::<Vec<_>>().join(", ")
+}
+
+const DIRECTIONS: [(&str, [f64; 3]); 6] = [
+    ("X+", [1.0, 0.0, 0.0]),
+    ("X-", [-1.0, 0.0, 0.0]),
+    ("Y+", [0.0, 1.0, 0.0]),
+    ("Y-", [0.0, -1.0, 0.0]),
+    ("Z+", [0.0, 0.0, 1.0]),
+    ("Z-", [0.0, 0.0, -1.0]),
+];
+fn direction(name: &str) -> [f64; 3] {
+    DIRECTIONS.iter().find(|(direction, _)| *direction == name).map_or([0.0; 3], |(_, vector)| *vector)
+}
+fn key_direction(key: &str) -> Option<&'static str> {
+    match key {
+        "ArrowRight" => Some("X+"),
+        "ArrowLeft" => Some("X-"),
+        "ArrowUp" => Some("Y+"),
+        "ArrowDown" => Some("Y-"),
+        "PageUp" => Some("Z+"),
+        "PageDown" => Some("Z-"),
+        _ => None,
+    }
+}
+fn length(vector: [f64; 3]) -> f64 {
+    vector.iter().map(|value| value * value).sum::<f64>().sqrt()
+}
+
+fn jog_line(delta: [f64; 3], feed: f64) -> Option<String> {
+    let axes = ['X', 'Y', 'Z'].iter().zip(delta)
+        .filter(|(_, value)| value.abs() >= 0.0005)
+        .map(|(axis, value)| format!(" {}{:.3}", axis, value))
+        .collect::<String>();
+    (!axes.is_empty()).then(|| format!("$J=G21 G91{} F{:.0}", axes, feed))
+}
+async fn jog(delta: [f64; 3], feed: f64) {
+    if let Some(line) = jog_line(delta, feed) {
+        if let Err(e) = client().send_raw_gcode(&line).await {
+            log::debug!("ERROR: {:?}", e);
+        }
+    }
+}
+async fn cancel_jog() {
+    if let Err(e) = client().jog_cancel().await {
+        log::debug!("ERROR: {:?}", e);
+    }
+}
+
+// The first connected gamepad with a stick pushed: the left stick for X and Y, the right one for Z.
+fn gamepad_velocity() -> [f64; 3] {
+    let Some(gamepads) = web_sys::window().and_then(|window| window.navigator().get_gamepads().ok()) else {
+        return [0.0; 3];
+    };
+    for gamepad in gamepads.iter() {
+        let Ok(gamepad) = gamepad.dyn_into::<Gamepad>() else {
+            continue;  // Empty slots are null.
+        };
+        if !gamepad.connected() {
+            continue;
+        }
+        let axes = gamepad.axes();
+        let axis = |index: u32| {
+            let value = axes.get(index).as_f64().unwrap_or(0.0);
+            if value.abs() < DEAD_ZONE { 0.0 } else { value.signum() * (value.abs() - DEAD_ZONE) / (1.0 - DEAD_ZONE) }
+        };
+        // Sticks read negative when pushed up.
+        let velocity = [axis(0), -axis(1), -axis(3)];
+        if velocity != [0.0; 3] {
+            return velocity;
+        }
+    }
+    [0.0; 3]
+}
+
+// Key presses typed into the page's inputs aren't jogs.
+fn is_typing(event: &Event) -> bool {
+    event.target()
+        .and_then(|target| target.dyn_into::<Element>().ok())
+        .map_or(false, |element| matches!(element.tag_name().as_str(), "INPUT" | "TEXTAREA" | "SELECT"))
+}
+fn add_window_listener<'a>(cx: Scope<'a>, event: &'static str, handler: impl FnMut(Event) + 'static) {
+    let window = web_sys::window().unwrap();
+    let closure: Closure<dyn FnMut(Event)> = Closure::new(handler);
+    window.add_event_listener_with_callback(event, closure.as_ref().unchecked_ref()).unwrap();
+    on_cleanup(cx, move || {
+        let _ = window.remove_event_listener_with_callback(event, closure.as_ref().unchecked_ref());
+    });
+}
+
+#[derive(Prop)]
+pub struct PresetPickerProps<'a> {
+    values: &'a ReadSignal<Vec<f64>>,
+    chosen: &'a Signal<f64>,
+}
+#[component]
+pub fn PresetPicker<'a>(cx: Scope<'a>, props: PresetPickerProps<'a>) -> View<DomNode> {
+    let chosen = props.chosen;
+    view! { cx,
+        Indexed(
+            iterable=props.values,
+            view=move |cx, value| view! { cx,
+                button(class=if *chosen.get() == value { "selected" } else { "" }, on:click=move |_| chosen.set(value)) { (value) }
+            }
+        )
+    }
 }
 
 #[derive(Prop)]
 pub struct JogButtonProps<'a> {
-    name: String,
-    unit_vector: (f64, f64, f64),
-    scale: &'a ReadSignal<f64>,
+    direction: &'static str,
+    held: &'a RcSignal<Vec<&'static str>>,
+    disabled: &'a ReadSignal<bool>,
 }
-
+// Held for as long as the pointer is down on it.
 #[component]
 pub fn JogButton<'a>(cx: Scope<'a>, props: JogButtonProps<'a>) -> View<DomNode> {
-    let (x, y, z) = props.unit_vector;
-    let scale = props.scale;
+    let JogButtonProps { direction, held, disabled } = props;
+    let press = move |_| if !held.get().contains(&direction) {
+        held.modify().push(direction);
+    };
+    let release = move |_| if held.get().contains(&direction) {
+        held.modify().retain(|name| *name != direction);
+    };
     view! { cx,
-        button(on:click=move |_| {
-            let scale = *scale.get();
-            spawn_local(jog(x*scale,y*scale,z*scale))
-        }) { (props.name) }
+        button(disabled=*disabled.get(), on:pointerdown=press, on:pointerup=release, on:pointerleave=release) { (direction) }
     }
 }
 
-pub fn parse_f64_signal<'a>(cx: Scope<'a>, input: &'a ReadSignal<String>) -> &'a ReadSignal<f64> {
-    let result = create_signal(cx, 0.0);
+#[component]
+pub fn JogPage(cx: Scope) -> View<DomNode> {
+    let global_info: &GlobalInfo = use_context(cx);
+    let can_jog = create_memo(cx, || global_info.grbl_info.get().as_ref().as_ref()
+        .map_or(false, |info| matches!(info.state, GrblState::Idle | GrblState::Jog)));
+    let cannot_jog = create_memo(cx, || !*can_jog.get());
+
+    /*
+        Presets
+    */
+    let presets = create_signal(cx, load_presets());
+    let feeds = create_memo(cx, || presets.get().feeds.clone());
+    let steps = create_memo(cx, || presets.get().steps.clone());
+    let middle = |values: &[f64]| values[values.len() / 2];
+    let feed = create_signal(cx, middle(&presets.get_untracked().feeds));
+    let step = create_signal(cx, middle(&presets.get_untracked().steps));
+    let z_step = create_signal(cx, presets.get_untracked().steps[0].max(middle(&presets.get_untracked().steps) / 10.0));
+    let feeds_text = create_signal(cx, format_preset_list(&presets.get_untracked().feeds));
+    let steps_text = create_signal(cx, format_preset_list(&presets.get_untracked().steps));
+    let presets_message = create_signal(cx, String::new());
+    let save = move |_| {
+        let result = parse_preset_list("Feed", &feeds_text.get()).and_then(|feeds| Ok(JogPresets { feeds, steps: parse_preset_list("Step", &steps_text.get())? }));
+        match result {
+            Ok(new_presets) => {
+                save_presets(&new_presets);
+                feeds_text.set(format_preset_list(&new_presets.feeds));
+                steps_text.set(format_preset_list(&new_presets.steps));
+                presets.set(new_presets);
+                presets_message.set("Presets saved.".to_string());
+            }
+            Err(error) => presets_message.set(format!("Error: {}", error)),
+        }
+    };
+
+    /*
+        Input: the directions held down by keys and buttons, and the gamepad polled alongside.
+    */
+    let continuous = create_signal(cx, false);
+    let held = create_ref(cx, create_rc_signal(Vec::<&'static str>::new()));
+    {
+        let held = held.clone();
+        add_window_listener(cx, "keydown", move |event| {
+            let Some(event) = event.dyn_ref::<KeyboardEvent>() else { return };
+            let Some(direction) = key_direction(&event.key()).filter(|_| !is_typing(event)) else { return };
+            event.prevent_default();  // Arrows and page keys would scroll.
+            if !held.get().contains(&direction) {
+                held.modify().push(direction);
+            }
+        });
+    }
+    {
+        let held = held.clone();
+        add_window_listener(cx, "keyup", move |event| {
+            let Some(direction) = event.dyn_ref::<KeyboardEvent>().and_then(|event| key_direction(&event.key())) else { return };
+            if held.get().contains(&direction) {
+                held.modify().retain(|name| *name != direction);
+            }
+        });
+    }
+    {
+        // Key releases aren't seen once the page loses focus.
+        let held = held.clone();
+        add_window_listener(cx, "blur", move |_| held.set(Vec::new()));
+    }
+    create_effect(cx, move || if !*can_jog.get() {
+        held.set(Vec::new());
+    });
+
+    // In step mode, each new press is one step.
+    let previous = create_ref(cx, RefCell::new(Vec::new()));
     create_effect(cx, move || {
-        let x = input.get().parse();
-        match x {
-            Ok(v) => result.set(v),
-            Err(_) => {}
+        let now = held.get();
+        let pressed = now.iter().copied().filter(|name| !previous.borrow().contains(name)).collect::<Vec<_>>();
+        *previous.borrow_mut() = now.as_ref().clone();
+        if *continuous.get_untracked() || !*can_jog.get_untracked() {
+            return;
+        }
+        for name in pressed {
+            let distance = if name.starts_with('Z') { *z_step.get_untracked() } else { *step.get_untracked() };
+            let delta = direction(name).map(|value| value * distance);
+            spawn_local_scoped(cx, jog(delta, *feed.get_untracked()));
+        }
+    });
+
+    // Continuous jogging, at the chosen feed scaled by how far a stick is pushed.
+    spawn_local_scoped(cx, async move {
+        let mut last_sent: Option<f64> = None;
+        loop {
+            sleep(TICK).await;
+            let mut velocity = [0.0; 3];
+            if *can_jog.get_untracked() {
+                if *continuous.get_untracked() {
+                    for name in held.get_untracked().iter() {
+                        for (total, value) in velocity.iter_mut().zip(direction(name)) {
+                            *total += value;
+                        }
+                    }
+                }
+                if velocity == [0.0; 3] {
+                    velocity = gamepad_velocity();
+                }
+            }
+            let speed = length(velocity);
+            if speed == 0.0 {
+                if last_sent.take().is_some() {
+                    cancel_jog().await;
+                }
+                continue;
+            }
+            let now = js_sys::Date::now() / 1000.0;
+            let seconds = last_sent.map_or(LEAD_SECONDS, |last| (now - last).min(MAX_INCREMENT_SECONDS));
+            last_sent = Some(now);
+            let feed = *feed.get_untracked() * speed.min(1.0);
+            let distance = feed / 60.0 * seconds;
+            jog(velocity.map(|value| value / speed * distance), feed).await;
         }
     });
-    result
-}
 
-#[component]
-pub fn JogPage(cx: Scope) -> View<DomNode> {
     let css_style = style! { r#"
-        display: grid;
-        grid-template-columns: 10vw 10vw 10vw 10vw;
-        
-        div {
+        .jog_grid {
+            display: grid;
+            grid-template-columns: 10vw 10vw 10vw 10vw;
+        }
+        .jog_grid div {
             aspect-ratio: 1;
             display: flex;
             justify-content: center;
             align-items: center;
         }
+        .jog_grid button {
+            width: 80%;
+            height: 80%;
+            touch-action: none;
+        }
+        .selected {
+            font-weight: bold;
+            background-color: #cfe8ff;
+        }
     "#
     }.expect("CSS should work");
-    log::debug!("Jog CSS class: {}", css_style.get_class_name());
-    let value = create_signal(cx, "100.00".to_string());
-    let amt = parse_f64_signal(cx, value);
-    let valuez = create_signal(cx, "10.00".to_string());
-    let amtz = parse_f64_signal(cx, valuez);
-    view! { cx, 
+    let step_text = create_memo(cx, || format!("{} mm", step.get()));
+    let z_step_text = create_memo(cx, || format!("{} mm", z_step.get()));
+    view! { cx,
         div(class=css_style.get_class_name()) {
-            div {}
-            div { JogButton(name="Y+".to_string(), unit_vector=(0.0, 1.0, 0.0), scale=amt) }
-            div {}
-            div { JogButton(name="Z+".to_string(), unit_vector=(0.0, 0.0, 1.0), scale=amtz) }
-
-            div { JogButton(name="X-".to_string(), unit_vector=(-1.0, 0.0, 0.0), scale=amt) }
-            div {
-                input(type="text", bind:value=value)
-            }
-            div { JogButton(name="X+".to_string(), unit_vector=(1.0, 0.0, 0.0), scale=amt) }
-            div {
-                input(type="text", bind:value=valuez)
+            p {
+                "Feed (mm/min): " PresetPicker(values=feeds, chosen=feed)
+                br {}
+                "Step: " PresetPicker(values=steps, chosen=step)
+                br {}
+                "Z step: " PresetPicker(values=steps, chosen=z_step)
+                br {}
+                label {
+                    input(type="checkbox", bind:checked=continuous)
+                    " Jog while held instead of stepping"
+                }
             }
+            div(class="jog_grid") {
+                div {}
+                div { JogButton(direction="Y+", held=held, disabled=cannot_jog) }
+                div {}
+                div { JogButton(direction="Z+", held=held, disabled=cannot_jog) }
 
-            div {}
-            div { JogButton(name="Y-".to_string(), unit_vector=(0.0, -1.0, 0.0), scale=amt) }
-            div {}
-            div { JogButton(name="Z-".to_string(), unit_vector=(0.0, 0.0, -1.0), scale=amtz) }
+                div { JogButton(direction="X-", held=held, disabled=cannot_jog) }
+                div { (if *continuous.get() { "Held".to_string() } else { step_text.get().to_string() }) }
+                div { JogButton(direction="X+", held=held, disabled=cannot_jog) }
+                div { (if *continuous.get() { "Held".to_string() } else { z_step_text.get().to_string() }) }
+
+                div {}
+                div { JogButton(direction="Y-", held=held, disabled=cannot_jog) }
+                div {}
+                div { JogButton(direction="Z-", held=held, disabled=cannot_jog) }
+            }
+            p {
+                (if *can_jog.get() { "" } else { "Jogging is available while the machine is idle or jogging. " })
+                "The arrow keys jog X and Y, and Page Up and Page Down jog Z. A gamepad's left stick jogs X and Y and its right stick Z, faster the further they're pushed."
+            }
+            p {
+                "Feed presets " input(type="text", bind:value=feeds_text)
+                " Step presets " input(type="text", bind:value=steps_text)
+                " "
+                button(on:click=save) { "Save presets" }
+                " " (presets_message.get())
+            }
         }
         a(href="/") { "Go home!" }
     }
-}
\ No newline at end of file
+}
@@ -9,13 +9,15 @@ mod request;
 mod components;
 mod models;
 mod coords_page;
+mod settings_page;
+mod probing_page;
+mod history_page;
 pub mod render;
 
 use common::api;
 use display_page::DisplayPage;
 use gloo_timers::future::sleep;
 use jog_page::JogPage;
-use request::HttpMethod;
 use status_header::GlobalInfo;
 use status_header::global_info;
 use sycamore::futures::spawn_local_scoped;
@@ -50,6 +52,9 @@ use crate::components::modal_wrapper::ModalWrapper;
 use crate::components::modal_wrapper::install_modal_handler;
 use crate::components::modal_wrapper::use_modal_handler;
 use crate::coords_page::CoordinatePage;
+use crate::settings_page::SettingsPage;
+use crate::probing_page::ProbingPage;
+use crate::history_page::HistoryPage;
 
 #[derive(Route)]
 enum AppRoutes {
@@ -65,6 +70,12 @@ enum AppRoutes {
     Coordinates,
     #[to("/jog")]
     Jog,
+    #[to("/settings")]
+    Settings,
+    #[to("/probe")]
+    Probing,
+    #[to("/history")]
+    History,
     #[to("/view/<path..>")]
     DisplayGCode {
         path: Vec<String>
@@ -80,7 +91,10 @@ impl AppRoutes {
             AppRoutes::SendGcode { .. } => "Send GCode".to_string(),
             AppRoutes::Coordinates => "Coordinates".to_string(),
             AppRoutes::Jog => "Jog".to_string(),
-            AppRoutes::DisplayGCode { path } => format!("View - {}", path.last().map_or("??", |s| &s)),
+            AppRoutes::Settings => "Controller Settings".to_string(),
+            AppRoutes::Probing => "Probing".to_string(),
+            AppRoutes::History => "Job History".to_string(),
+            AppRoutes::DisplayGCode { path } => format!("View - {}", path.last().map_or("Height maps", |s| &s)),
             AppRoutes::NotFound => "404".to_string(),
         }
     }
@@ -107,6 +121,22 @@ fn IndexPage(cx: Scope) -> View<DomNode> {
             a(href="/jog") {
                 "Jog"
             }      
+            br {}
+            a(href="/settings") {
+                "Controller settings"
+            }
+            br {}
+            a(href="/probe") {
+                "Probing"
+            }
+            br {}
+            a(href="/history") {
+                "Job history"
+            }
+            br {}
+            a(href="/view/") {
+                "Height maps"
+            }
         }
     }
 }
@@ -181,6 +211,15 @@ fn main() {
                                 AppRoutes::Jog => view! { cx,
                                     JogPage
                                 },
+                                AppRoutes::Settings => view! { cx,
+                                    SettingsPage
+                                },
+                                AppRoutes::Probing => view! { cx,
+                                    ProbingPage
+                                },
+                                AppRoutes::History => view! { cx,
+                                    HistoryPage
+                                },
                                 AppRoutes::NotFound => view! { cx,
                                     NotFound
                                 },
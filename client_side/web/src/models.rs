@@ -1,2 +1,6 @@
+pub mod command_history;
+pub mod controller_settings;
+pub mod job_history;
 pub mod offsets;
-pub mod positions;
\ No newline at end of file
+pub mod positions;
+pub mod probing;
@@ -0,0 +1,64 @@
+use std::rc::Rc;
+
+use anyhow::Context;
+use api_client::ClientResult;
+use common::api::{self, CommandHistory};
+use sycamore::prelude::*;
+
+use crate::request::client;
+
+const USER_STORAGE_KEY: &str = "cnc_command_user";
+
+// The name is remembered by the browser; the history itself lives on the server.
+pub fn remembered_user() -> String {
+    web_sys::window()
+        .and_then(|window| window.local_storage().ok().flatten())
+        .and_then(|storage| storage.get_item(USER_STORAGE_KEY).ok().flatten())
+        .unwrap_or_default()
+}
+pub fn remember_user(user: &str) {
+    if let Some(storage) = web_sys::window().and_then(|window| window.local_storage().ok().flatten()) {
+        let _ = storage.set_item(USER_STORAGE_KEY, user);
+    }
+}
+
+pub async fn list_users() -> anyhow::Result<Vec<String>> {
+    Ok(client().command_history_users().await.context("listing users")?)
+}
+
+#[derive(Clone)]
+pub struct CommandHistoryModel<'a> {
+    user: &'a ReadSignal<String>,
+    data: &'a Signal<CommandHistory>,
+}
+
+impl<'a> CommandHistoryModel<'a> {
+    pub fn new(cx: Scope<'a>, user: &'a ReadSignal<String>) -> &'a CommandHistoryModel<'a> {
+        create_ref(cx, CommandHistoryModel {
+            user,
+            data: create_signal(cx, CommandHistory::default()),
+        })
+    }
+    pub fn get(&self) -> Rc<CommandHistory> {
+        self.data.get()
+    }
+    pub fn signal(&self) -> &ReadSignal<CommandHistory> {
+        self.data
+    }
+    fn update(&self, result: ClientResult<CommandHistory>) -> anyhow::Result<()> {
+        self.data.set(result.context("updating command history")?);
+        Ok(())
+    }
+    pub async fn refresh(&self) -> anyhow::Result<()> {
+        let user = self.user.get().to_string();
+        self.update(client().command_history(&api::QueryCommandHistory { user }).await)
+    }
+    pub async fn send(&self, command: String) -> anyhow::Result<()> {
+        let user = self.user.get().to_string();
+        self.update(client().send_command(&api::SendCommand { user, command }).await)
+    }
+    pub async fn set_favourite(&self, command: String, favourite: bool) -> anyhow::Result<()> {
+        let user = self.user.get().to_string();
+        self.update(client().favourite_command(&api::SetFavouriteCommand { user, command, favourite }).await)
+    }
+}
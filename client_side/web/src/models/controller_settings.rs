@@ -0,0 +1,61 @@
+use std::rc::Rc;
+
+use anyhow::Context;
+use common::{api::SettingsSnapshot, grbl::GrblSetting};
+use sycamore::prelude::*;
+
+use crate::request::client;
+
+#[derive(Clone)]
+pub struct ControllerSettingsModel<'a> {
+    current: &'a Signal<Vec<GrblSetting>>,
+    snapshot: &'a Signal<Option<SettingsSnapshot>>,
+}
+
+impl<'a> ControllerSettingsModel<'a> {
+    pub async fn new(cx: Scope<'a>) -> anyhow::Result<&'a ControllerSettingsModel<'a>> {
+        let model = create_ref(cx, ControllerSettingsModel {
+            current: create_signal(cx, Vec::new()),
+            snapshot: create_signal(cx, None),
+        });
+        model.snapshot.set(client().settings_snapshot().await.context("getting settings snapshot")?);
+        Ok(model)
+    }
+    pub fn get(&self) -> Rc<Vec<GrblSetting>> {
+        self.current.get()
+    }
+    pub fn signal(&self) -> &ReadSignal<Vec<GrblSetting>> {
+        self.current
+    }
+    pub fn snapshot(&self) -> &ReadSignal<Option<SettingsSnapshot>> {
+        self.snapshot
+    }
+    // Asks the controller; fails while a job is running.
+    pub async fn read(&self) -> anyhow::Result<()> {
+        self.current.set(client().controller_settings().await.context("reading settings")?);
+        Ok(())
+    }
+    pub async fn write(&self, settings: Vec<GrblSetting>) -> anyhow::Result<()> {
+        match client().write_controller_settings(&settings).await {
+            Ok(read_back) => {
+                self.current.set(read_back);
+                Ok(())
+            }
+            Err(error) => {
+                // Some settings may have been written before the failure.
+                if let Err(e) = self.read().await {
+                    log::debug!("ERROR: {:?}", e);
+                }
+                Err(error).context("writing settings")
+            }
+        }
+    }
+    pub async fn save_snapshot(&self) -> anyhow::Result<()> {
+        let settings = self.current.get().as_ref().clone();
+        if settings.is_empty() {
+            anyhow::bail!("Read the settings from the controller first");
+        }
+        self.snapshot.set(client().save_settings_snapshot(&settings).await.context("saving snapshot")?);
+        Ok(())
+    }
+}
@@ -0,0 +1,19 @@
+use anyhow::Context;
+use common::api::{HeightMap, JobRecord, JobSummary, QueryJobHistory};
+
+use crate::request::client;
+
+pub async fn query_job_history(query: &QueryJobHistory) -> anyhow::Result<Vec<JobSummary>> {
+    Ok(client().job_history(query).await.context("querying job history")?)
+}
+pub async fn get_job_record(id: &str) -> anyhow::Result<JobRecord> {
+    Ok(client().job_record(id).await.context("getting job record")?)
+}
+// The ids of every job that saved results, newest first.
+pub async fn list_job_results() -> anyhow::Result<Vec<String>> {
+    Ok(client().list_results().await.context("listing job results")?)
+}
+// Fails unless the job's probes were taken on a grid.
+pub async fn get_height_map(id: &str) -> anyhow::Result<HeightMap> {
+    Ok(client().height_map(id).await.context("getting height map")?)
+}
@@ -5,7 +5,7 @@ use chrono::Offset;
 use common::api::{Offsets, self, OffsetKind, Vec3};
 use sycamore::prelude::*;
 
-use crate::request::{request, HttpMethod, request_with_json};
+use crate::request::client;
 
 #[derive(Clone)]
 pub struct OffsetModel<'a> {
@@ -14,9 +14,9 @@ pub struct OffsetModel<'a> {
 
 impl<'a> OffsetModel<'a> {
     pub async fn new(cx: Scope<'a>) -> anyhow::Result<&'a OffsetModel<'a>> {
-        let initial_data = request(HttpMethod::Get, api::OFFSETS).await.context("getting offset data")?;
+        let initial_data = client().offsets().await.context("getting offset data")?;
         Ok(create_ref(cx, OffsetModel {
-            data: create_signal(cx, initial_data.json().await.context("reading response json")?)
+            data: create_signal(cx, initial_data)
         }))
     }
     pub fn get(&self) -> Rc<Offsets> {
@@ -26,26 +26,27 @@ impl<'a> OffsetModel<'a> {
         self.data
     }
     pub async fn set(&self, name: String, offset_kind: OffsetKind, offset: Vec3) -> anyhow::Result<()> {
-        self.data.set(request_with_json(
-            HttpMethod::Put,
-            api::OFFSETS,
-            &api::SetCoordinateOffset {
-                name,
-                offset_kind,
-                offset,
-            }
-        ).await.context("setting offset data")?.json().await?);
+        self.data.set(client().set_offset(&api::SetCoordinateOffset {
+            name,
+            offset_kind,
+            offset,
+        }).await.context("setting offset data")?);
         Ok(())
     }
     pub async fn delete(&self, name: String, offset_kind: OffsetKind) -> anyhow::Result<()> {
-        self.data.set(request_with_json(
-            HttpMethod::Delete,
-            api::OFFSETS,
-            &api::DeleteCoordinateOffset {
-                name,
-                offset_kind,
-            }
-        ).await.context("setting offset data")?.json().await?);
+        self.data.set(client().delete_offset(&api::DeleteCoordinateOffset {
+            name,
+            offset_kind,
+        }).await.context("setting offset data")?);
+        Ok(())
+    }
+    pub async fn align(&self, request: api::AlignCoordinateOffset) -> anyhow::Result<()> {
+        self.data.set(client().align_offset(&request).await.context("aligning offset")?);
+        Ok(())
+    }
+    // Makes the pair the controller's G54, so that jobs run in it.
+    pub async fn apply(&self, tool: String, workpiece: String) -> anyhow::Result<()> {
+        client().apply_offsets(&api::ApplyCoordinateOffsets { tool, workpiece }).await.context("applying offsets")?;
         Ok(())
     }
 }
\ No newline at end of file
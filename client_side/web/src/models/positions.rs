@@ -5,7 +5,7 @@ use chrono::Offset;
 use common::api::{Offsets, self, OffsetKind, Vec3, SavedPosition};
 use sycamore::prelude::*;
 
-use crate::request::{request, HttpMethod, request_with_json};
+use crate::request::client;
 
 #[derive(Clone)]
 pub struct PositionModel<'a> {
@@ -14,9 +14,9 @@ pub struct PositionModel<'a> {
 
 impl<'a> PositionModel<'a> {
     pub async fn new(cx: Scope<'a>) -> anyhow::Result<&'a PositionModel<'a>> {
-        let initial_data = request(HttpMethod::Get, api::POSITIONS).await.context("getting position data")?;
+        let initial_data = client().positions().await.context("getting position data")?;
         Ok(create_ref(cx, PositionModel {
-            data: create_signal(cx, initial_data.json().await.context("reading response json")?)
+            data: create_signal(cx, initial_data)
         }))
     }
     pub fn get(&self) -> Rc<Vec<SavedPosition>> {
@@ -26,14 +26,10 @@ impl<'a> PositionModel<'a> {
         self.data
     }
     pub async fn add(&self, label: String, position: Vec3) -> anyhow::Result<()> {
-        self.data.set(request_with_json(
-            HttpMethod::Post,
-            api::POSITIONS,
-            &api::SavedPosition {
-                label,
-                position
-            }
-        ).await.context("setting offset data")?.json().await?);
+        self.data.set(client().add_position(&api::SavedPosition {
+            label,
+            position
+        }).await.context("setting offset data")?);
         Ok(())
     }
 }
\ No newline at end of file
@@ -0,0 +1,9 @@
+use anyhow::Context;
+use common::api::{ProbeRoutineResult, RunProbeRoutine};
+
+use crate::request::client;
+
+// Runs as a job, so this only answers once the routine is done.
+pub async fn run_probe_routine(routine: &RunProbeRoutine) -> anyhow::Result<ProbeRoutineResult> {
+    Ok(client().run_probe_routine(routine).await.context("running probe routine")?)
+}
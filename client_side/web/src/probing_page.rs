@@ -0,0 +1,265 @@
+use common::api::{AlignCoordinateOffset, ProbeApproach, ProbeAxis, ProbeRoutine, ProbeRoutineResult, RunProbeRoutine, Vec3};
+use itertools::Itertools;
+use stylist::style;
+use sycamore::{prelude::*, futures::spawn_local_scoped};
+
+use crate::coords_page::{format_position, offset_kind, parse_coordinate};
+use crate::models::offsets::OffsetModel;
+use crate::models::probing::run_probe_routine;
+use crate::status_header::GlobalInfo;
+use crate::utils::async_sycamore::try_loading_view;
+
+fn parse_number(name: &str, text: &str) -> anyhow::Result<f64> {
+    text.trim().parse().map_err(|_| anyhow::anyhow!("The {} should be a number, not {:?}", name, text))
+}
+fn approach(text: &str) -> ProbeApproach {
+    match text {
+        "Negative" => ProbeApproach::Negative,
+        _ => ProbeApproach::Positive,
+    }
+}
+fn instructions(routine: &str) -> &'static str {
+    match routine {
+        "edge" => "Jog the tool down beside the workpiece, below its top, facing the edge to find. The tool returns to where it started.",
+        "corner" => "Jog the tool down outside the corner, diagonally off it and below the top. Each face is probed the inset distance past the corner.",
+        "bore" => "Jog the tool down into the bore, roughly centered. The tool is left at the center.",
+        _ => "Lay the touch plate on the surface and jog the tool above it, within the maximum travel.",
+    }
+}
+fn format_found(result: &ProbeRoutineResult) -> String {
+    let mut text = ['X', 'Y', 'Z'].iter().zip(result.found.iter())
+        .filter_map(|(axis, value)| value.map(|value| format!("{}{:.3}", axis, value)))
+        .join(" ");
+    if let Some(diameter) = result.diameter {
+        text += &format!(", diameter {:.3}", diameter);
+    }
+    text
+}
+
+#[component]
+pub fn ProbingPage(cx: Scope) -> View<DomNode> {
+    try_loading_view(
+        cx,
+        view! { cx, "Loading..." },
+        move |err: anyhow::Error| view! { cx, (format!("Error: {:?}", err)) },
+        async move {
+            let global_info: &GlobalInfo = use_context(cx);
+            let offset_model = OffsetModel::new(cx).await?;
+            let message = create_signal(cx, String::new());
+
+            /*
+                The routine and its parameters
+            */
+            let routine = create_signal(cx, "z".to_string());
+            let plate_thickness = create_signal(cx, "0".to_string());
+            let edge_axis = create_signal(cx, "X".to_string());
+            let edge_approach = create_signal(cx, "Positive".to_string());
+            let corner_x_approach = create_signal(cx, "Positive".to_string());
+            let corner_y_approach = create_signal(cx, "Positive".to_string());
+            let corner_inset = create_signal(cx, "10".to_string());
+            let tool_diameter = create_signal(cx, "6".to_string());
+            let max_travel = create_signal(cx, "20".to_string());
+            let fast_feed = create_signal(cx, "300".to_string());
+            let slow_feed = create_signal(cx, "50".to_string());
+            let retract = create_signal(cx, "2".to_string());
+            let build_request = move || -> anyhow::Result<RunProbeRoutine> {
+                let routine = match routine.get().as_str() {
+                    "edge" => ProbeRoutine::Edge {
+                        axis: if *edge_axis.get() == "Y" { ProbeAxis::Y } else { ProbeAxis::X },
+                        approach: approach(&edge_approach.get()),
+                    },
+                    "corner" => ProbeRoutine::Corner {
+                        x_approach: approach(&corner_x_approach.get()),
+                        y_approach: approach(&corner_y_approach.get()),
+                        inset: parse_number("inset", &corner_inset.get())?,
+                    },
+                    "bore" => ProbeRoutine::BoreCenter,
+                    _ => ProbeRoutine::ZTouchOff { plate_thickness: parse_number("plate thickness", &plate_thickness.get())? },
+                };
+                Ok(RunProbeRoutine {
+                    routine,
+                    tool_diameter: parse_number("tool diameter", &tool_diameter.get())?,
+                    max_travel: parse_number("maximum travel", &max_travel.get())?,
+                    fast_feed: parse_number("fast feed", &fast_feed.get())?,
+                    slow_feed: parse_number("slow feed", &slow_feed.get())?,
+                    retract: parse_number("retract distance", &retract.get())?,
+                })
+            };
+            let probe_state = create_memo(cx, || match global_info.grbl_info.get().as_ref() {
+                Some(info) if info.probe => "triggered",
+                Some(_) => "open",
+                None => "unknown",
+            });
+
+            /*
+                Running it
+            */
+            let running = create_signal(cx, false);
+            let result = create_signal(cx, None::<ProbeRoutineResult>);
+            let can_run = create_memo(cx, || *global_info.is_idle.get() && !*running.get());
+            let run = move |_| spawn_local_scoped(cx, async move {
+                running.set(true);
+                message.set("Probing...".to_string());
+                let outcome = async {
+                    let request = build_request()?;
+                    run_probe_routine(&request).await
+                }.await;
+                match outcome {
+                    Ok(found) => {
+                        message.set(format!("Found {}.", format_found(&found)));
+                        result.set(Some(found));
+                    }
+                    Err(error) => message.set(format!("Error: {:#}", error)),
+                }
+                running.set(false);
+            });
+
+            /*
+                Storing the result: the feature's machine position becomes the given coordinates of the chosen
+                tool and workpiece, changing one of their offsets on the axes that were measured.
+            */
+            let tool_names = create_memo(cx, || offset_model.get().tools.keys().cloned().sorted().collect_vec());
+            let workpiece_names = create_memo(cx, || offset_model.get().workpieces.keys().cloned().sorted().collect_vec());
+            let chosen_tool = create_signal(cx, String::new());
+            let chosen_workpiece = create_signal(cx, String::new());
+            let update_kind = create_signal(cx, "Workpiece".to_string());
+            let references = create_ref(cx, [0, 1, 2].map(|_| create_signal(cx, "0".to_string())));
+            let store = move |_| spawn_local_scoped(cx, async move {
+                let outcome = async {
+                    let found = result.get().as_ref().as_ref().map(|result| result.found).ok_or_else(|| anyhow::anyhow!("Run a routine first"))?;
+                    let mut reference = [None; 3];
+                    for (((value, signal), axis), found) in reference.iter_mut().zip(references).zip(['X', 'Y', 'Z']).zip(found) {
+                        if found.is_some() {
+                            *value = Some(parse_coordinate(axis, &signal.get())?.ok_or_else(|| anyhow::anyhow!("Enter the {} coordinate of the feature", axis))?);
+                        }
+                    }
+                    offset_model.align(AlignCoordinateOffset {
+                        machine_position: Vec3(found.map(|value| value.unwrap_or(0.0))),
+                        tool: chosen_tool.get().as_ref().clone(),
+                        workpiece: chosen_workpiece.get().as_ref().clone(),
+                        update: offset_kind(&update_kind.get()),
+                        reference,
+                    }).await
+                }.await;
+                message.set(match outcome {
+                    Ok(()) => "Offset updated.".to_string(),
+                    Err(error) => format!("Error: {:#}", error),
+                });
+            });
+            let has_result = create_memo(cx, || result.get().is_some());
+            let measured = create_memo(cx, || result.get().as_ref().as_ref().map_or([false; 3], |result| result.found.map(|value| value.is_some())));
+            let contacts = create_memo(cx, || result.get().as_ref().as_ref().map_or(Vec::new(), |result| result.contacts.clone()));
+
+            let css = style! { r#"
+                .instructions {
+                    font-style: italic;
+                }
+                .triggered {
+                    color: #c00;
+                    font-weight: bold;
+                }
+            "#}.unwrap();
+            Ok(view! { cx,
+                div(class=css.get_class_name()) {
+                    p {
+                        "Routine: "
+                        select(bind:value=routine) {
+                            option(value="z") { "Z touch-off" }
+                            option(value="edge") { "Edge" }
+                            option(value="corner") { "Corner" }
+                            option(value="bore") { "Bore center" }
+                        }
+                    }
+                    p(class="instructions") { (instructions(&routine.get())) }
+                    p {
+                        (match routine.get().as_str() {
+                            "edge" => view! { cx,
+                                "Axis "
+                                select(bind:value=edge_axis) {
+                                    option(value="X") { "X" }
+                                    option(value="Y") { "Y" }
+                                }
+                                " moving towards "
+                                select(bind:value=edge_approach) {
+                                    option(value="Positive") { "+" }
+                                    option(value="Negative") { "-" }
+                                }
+                            },
+                            "corner" => view! { cx,
+                                "Moving towards X"
+                                select(bind:value=corner_x_approach) {
+                                    option(value="Positive") { "+" }
+                                    option(value="Negative") { "-" }
+                                }
+                                " and Y"
+                                select(bind:value=corner_y_approach) {
+                                    option(value="Positive") { "+" }
+                                    option(value="Negative") { "-" }
+                                }
+                                " Inset " input(type="text", size=6, bind:value=corner_inset)
+                            },
+                            "bore" => view! { cx, },
+                            _ => view! { cx,
+                                "Plate thickness " input(type="text", size=6, bind:value=plate_thickness)
+                            },
+                        })
+                    }
+                    p {
+                        "Tool diameter " input(type="text", size=6, bind:value=tool_diameter)
+                        " Maximum travel " input(type="text", size=6, bind:value=max_travel)
+                        " Retract " input(type="text", size=6, bind:value=retract)
+                        br {}
+                        "Feed: fast " input(type="text", size=6, bind:value=fast_feed)
+                        " slow " input(type="text", size=6, bind:value=slow_feed)
+                    }
+                    p {
+                        "Probe: "
+                        span(class=if *probe_state.get() == "triggered" { "triggered" } else { "" }) { (probe_state.get()) }
+                        " "
+                        button(on:click=run, disabled=!*can_run.get()) { "Run" }
+                    }
+                    p { (message.get()) }
+                    (if *has_result.get() {
+                        view! { cx,
+                            h3 { "Contacts" }
+                            ul {
+                                Indexed(
+                                    iterable=contacts,
+                                    view=|cx, contact| view! { cx, li { (format_position(&contact.0)) } }
+                                )
+                            }
+                            h3 { "Store as an offset" }
+                            p {
+                                "Tool: "
+                                select(bind:value=chosen_tool) {
+                                    option(value="") { "Choose a tool" }
+                                    Indexed(iterable=tool_names, view=|cx, name| view! { cx, option(value=name.clone()) { (name) } })
+                                }
+                                " Workpiece: "
+                                select(bind:value=chosen_workpiece) {
+                                    option(value="") { "Choose a workpiece" }
+                                    Indexed(iterable=workpiece_names, view=|cx, name| view! { cx, option(value=name.clone()) { (name) } })
+                                }
+                                " Update the "
+                                select(bind:value=update_kind) {
+                                    option(value="Workpiece") { "workpiece" }
+                                    option(value="Tool") { "tool" }
+                                }
+                                " offset"
+                            }
+                            p {
+                                "The feature is at "
+                                (if measured.get()[0] { view! { cx, "X " input(type="text", size=8, bind:value=references[0]) " " } } else { view! { cx, } })
+                                (if measured.get()[1] { view! { cx, "Y " input(type="text", size=8, bind:value=references[1]) " " } } else { view! { cx, } })
+                                (if measured.get()[2] { view! { cx, "Z " input(type="text", size=8, bind:value=references[2]) " " } } else { view! { cx, } })
+                                button(on:click=store) { "Update offset" }
+                            }
+                        }
+                    } else {
+                        view! { cx, }
+                    })
+                }
+            })
+        }
+    )
+}
@@ -1,10 +1,21 @@
-use reqwasm::{websocket::futures::WebSocket, http::{Request, Response}, Error};
-use wasm_bindgen::JsValue;
+use std::{marker::PhantomData, sync::Arc};
+
+use api_client::{Client, ClientResult, wasm::ReqwasmConnection};
+use common::{api, websocket::{MachineConnection, MachineConnectionRx, MACHINE_CONNECTION_CHANNEL}};
+use protocol_util::{
+    base::{Channel, ChannelFuture},
+    channel_allocator::TypedChannelAllocator,
+    communication_context::Context,
+    generic::Receivable,
+    receiver::{create_listener_full, FullListenerCreation},
+    sender::Sender,
+    spawner::Spawner,
+};
+use reqwasm::websocket::{futures::WebSocket, Message};
 use wasm_bindgen_futures::spawn_local;
-use futures::{FutureExt, Future};
-use serde::Serialize;
+use futures::{channel::mpsc, Future, SinkExt, StreamExt};
 
-pub const HOST_NAME: &str = {
+const HOST_NAME: &str = {
     // Looks for a CNC_HOST_NAME option during compilation.
     let env_var = option_env!("CNC_HOST_NAME");
     match env_var {
@@ -13,48 +24,55 @@ pub const HOST_NAME: &str = {
     }
 };
 
-pub enum HttpMethod {
-    Get,
-    Post,
-    Delete,
-    Put,
-}
-impl HttpMethod {
-    fn request_url(&self, path: &str) -> Request {
-        let true_path = format!("http://{}{}", HOST_NAME, path);
-        match self {
-            HttpMethod::Get => Request::get(&true_path),
-            HttpMethod::Post => Request::post(&true_path),
-            HttpMethod::Put => Request::put(&true_path),
-            HttpMethod::Delete => Request::delete(&true_path),
-        }
-    }
+pub fn client() -> Client<ReqwasmConnection> {
+    api_client::wasm::client(format!("http://{}", HOST_NAME))
 }
 
 pub fn open_websocket(path: &str) -> WebSocket {
     WebSocket::open(&format!("ws://{}{}", HOST_NAME, path)).unwrap()
 }
 
-pub fn request(method: HttpMethod, path: &str) -> impl Future<Output=Result<Response, Error>> + 'static {
-    method.request_url(path).send()
-}
-pub fn request_with_json(method: HttpMethod, path: &str, body: &impl Serialize) -> impl Future<Output=Result<Response, Error>> + 'static {
-    method.request_url(path)
-    .body(serde_json::to_string(&body).unwrap())
-    .header("Content-Type", "application/json")
-    .send()
-}
-pub fn request_with_body(method: HttpMethod, path: &str, body: impl Into<JsValue>) -> impl Future<Output=Result<Response, Error>> + 'static {
-    method.request_url(path)
-    .body(body)
-    .send()
-}
-pub fn request_detached(method: HttpMethod, path: &str) {
-    spawn_local(request(method, path).map(|_| ()));
+// Opens the MACHINE_CONNECTION websocket and waits for the server's root object; None if the socket
+// closes first. The socket stays open until the server closes it.
+pub async fn machine_connection() -> Option<MachineConnectionRx> {
+    let (mut ws_send, mut ws_receive) = open_websocket(api::MACHINE_CONNECTION).split();
+    let (out_sender, mut out_receiver) = mpsc::unbounded();
+    let FullListenerCreation { future, controller, sender: receiver } = create_listener_full();
+    let context = Context {
+        channel_allocator: Arc::new(TypedChannelAllocator::new()),
+        controller,
+        sender: Sender::new(out_sender),
+        spawner: Spawner::new(|future| spawn_local(future)),
+    };
+    spawn_local(future);
+    // Messages on the socket are "<channel>:<json>".
+    spawn_local(async move {
+        while let Some(Ok(message)) = ws_receive.next().await {
+            if let Message::Text(text) = message {
+                if let Some((channel, message)) = text.split_once(':') {
+                    if let Ok(channel) = channel.parse() {
+                        receiver.send(channel, message.into());
+                    }
+                }
+            }
+        }
+    });
+    spawn_local(async move {
+        while let Some((channel, message)) = out_receiver.next().await {
+            if ws_send.send(Message::Text(format!("{}:{}", channel, message))).await.is_err() {
+                break;
+            }
+        }
+    });
+    let root = ChannelFuture::<MachineConnection>(Channel(MACHINE_CONNECTION_CHANNEL, PhantomData));
+    root.receive_in_context(&context).await.ok()
 }
-pub fn request_detached_with_json(method: HttpMethod, path: &str, body: &impl Serialize) {
-    spawn_local(request_with_json(method, path, body).map(|_| ()));
+
+// For buttons, where nothing waits on the answer; failures are only logged.
+pub fn detached<T>(call: impl Future<Output=ClientResult<T>> + 'static) {
+    spawn_local(async move {
+        if let Err(error) = call.await {
+            log::debug!("ERROR: {:?}", error);
+        }
+    });
 }
-pub fn request_detached_with_body(method: HttpMethod, path: &str, body: impl Into<JsValue>) {
-    spawn_local(request_with_body(method, path, body).map(|_| ()));
-}
\ No newline at end of file
@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+
+use chrono::Local;
+use common::grbl::{parse_settings_text, setting_info, validate_setting, GrblSetting};
+use stylist::style;
+use sycamore::{prelude::*, futures::spawn_local_scoped};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::HtmlInputElement;
+
+use crate::models::controller_settings::ControllerSettingsModel;
+use crate::request::client;
+use crate::utils::async_sycamore::try_loading_view;
+
+// Controllers pad values differently ("10" and "10.000"), so numbers are compared by value.
+fn same_value(a: &str, b: &str) -> bool {
+    match (a.trim().parse::<f64>(), b.trim().parse::<f64>()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a.trim() == b.trim(),
+    }
+}
+
+async fn read_file_text(input: &HtmlInputElement) -> anyhow::Result<(String, String)> {
+    let file = input.files().and_then(|files| files.item(0)).ok_or_else(|| anyhow::anyhow!("Choose a backup file"))?;
+    let text = JsFuture::from(file.text()).await.map_err(|e| anyhow::anyhow!("Couldn't read the file: {:?}", e))?;
+    Ok((file.name(), text.as_string().unwrap_or_default()))
+}
+
+#[component]
+pub fn SettingsPage(cx: Scope) -> View<DomNode> {
+    try_loading_view(
+        cx,
+        view! { cx, "Loading..." },
+        move |err: anyhow::Error| view! { cx, (format!("Error: {:?}", err)) },
+        async move {
+            let model = ControllerSettingsModel::new(cx).await?;
+            let message = create_signal(cx, String::new());
+            let report = move |result: anyhow::Result<()>, success: &str| message.set(match result {
+                Ok(()) => success.to_string(),
+                Err(error) => format!("Error: {:#}", error),
+            });
+            // Values typed in (or loaded from a backup) that differ from the controller's.
+            let edits = create_signal(cx, HashMap::<u32, String>::new());
+            // Bumped to rebuild the rows when the edits are replaced from outside them.
+            let revision = create_signal(cx, 0u32);
+            let reset_edits = move |new_edits: HashMap<u32, String>| {
+                edits.set(new_edits);
+                revision.set(*revision.get_untracked() + 1);
+            };
+            let read = move || spawn_local_scoped(cx, async move {
+                let result = model.read().await;
+                if result.is_ok() {
+                    reset_edits(HashMap::new());
+                }
+                report(result, "Settings read from the controller.");
+            });
+            read();
+
+            let write = move |_| spawn_local_scoped(cx, async move {
+                let result = async {
+                    let pending = edits.get().as_ref().clone();
+                    if pending.is_empty() {
+                        anyhow::bail!("Nothing has been changed");
+                    }
+                    let mut settings = Vec::new();
+                    for (index, value) in pending.into_iter() {
+                        let value = validate_setting(index, &value).map_err(|e| anyhow::anyhow!(e))?;
+                        settings.push(GrblSetting { index, value });
+                    }
+                    settings.sort_by_key(|setting| setting.index);
+                    model.write(settings).await
+                }.await;
+                if result.is_ok() {
+                    reset_edits(HashMap::new());
+                }
+                report(result, "Settings written.");
+            });
+            let save_snapshot = move |_| spawn_local_scoped(cx, async move {
+                report(model.save_snapshot().await, "Snapshot saved.");
+            });
+            let backup_input = create_node_ref(cx);
+            let load_backup = move |_| spawn_local_scoped(cx, async move {
+                let result = async {
+                    let node: DomNode = backup_input.get_raw();
+                    let input: HtmlInputElement = node.unchecked_into();
+                    let (name, text) = read_file_text(&input).await?;
+                    let loaded = parse_settings_text(&text).map_err(|e| anyhow::anyhow!("{}: {}", name, e))?;
+                    let current = model.get();
+                    let mut new_edits = HashMap::new();
+                    let mut unknown = Vec::new();
+                    for setting in loaded {
+                        match current.iter().find(|existing| existing.index == setting.index) {
+                            Some(existing) if !same_value(&existing.value, &setting.value) => { new_edits.insert(setting.index, setting.value); }
+                            Some(_) => (),
+                            None => unknown.push(format!("${}", setting.index)),
+                        }
+                    }
+                    let count = new_edits.len();
+                    reset_edits(new_edits);
+                    let mut text = format!("Loaded {}: {} settings differ from the controller. Review them, then write the changes.", name, count);
+                    if !unknown.is_empty() {
+                        text += &format!(" Skipped settings the controller doesn't have: {}.", unknown.join(", "));
+                    }
+                    message.set(text);
+                    Ok(())
+                }.await;
+                if result.is_err() {
+                    report(result, "");
+                }
+            });
+
+            let snapshot_text = create_memo(cx, || match model.snapshot().get().as_ref() {
+                Some(snapshot) => format!("Compared against the snapshot from {}.", snapshot.time.with_timezone(&Local).format("%Y-%m-%d %H:%M")),
+                None => "No snapshot has been saved yet.".to_string(),
+            });
+            let rows = create_memo(cx, || {
+                let revision = *revision.get();
+                model.signal().get().iter().map(|setting| (setting.clone(), revision)).collect::<Vec<_>>()
+            });
+            let edit_count = create_memo(cx, || edits.get().len());
+            let backup_url = client().settings_backup_url();
+
+            let css = style! { r#"
+                table {
+                    border-collapse: collapse;
+                }
+                td, th {
+                    padding: 0.2em 0.5em;
+                    text-align: left;
+                    vertical-align: top;
+                }
+                .changed {
+                    background-color: #fff3b0;
+                }
+                .edited {
+                    background-color: #cfe8ff;
+                }
+                .invalid {
+                    background-color: #ffc9c9;
+                }
+                .description {
+                    font-size: small;
+                }
+            "#}.unwrap();
+            Ok(view! { cx,
+                div(class=css.get_class_name()) {
+                    p {
+                        button(on:click=move |_| read()) { "Read from controller" }
+                        " "
+                        button(on:click=write, disabled=*edit_count.get() == 0) { (format!("Write {} changes", edit_count.get())) }
+                        " "
+                        button(on:click=move |_| reset_edits(HashMap::new())) { "Discard changes" }
+                        " "
+                        button(on:click=save_snapshot) { "Save as snapshot" }
+                    }
+                    p {
+                        a(href=backup_url, download="") { "Download backup" }
+                        " | Load backup: "
+                        input(ref=backup_input, type="file", accept=".txt,text/plain", on:change=load_backup)
+                    }
+                    p { (snapshot_text.get()) " Highlighted: " span(class="changed") { "differs from snapshot" } " " span(class="edited") { "changed here" } " " span(class="invalid") { "invalid" } }
+                    p { (message.get()) }
+                    table {
+                        tr {
+                            th { "Setting" } th { "Name" } th { "Value" } th { "Unit" } th { "Snapshot" } th { "Description" }
+                        }
+                        Keyed(
+                            iterable=rows,
+                            key=|(setting, revision)| (setting.index, setting.value.clone(), *revision),
+                            view=move |cx, (setting, _)| {
+                                let GrblSetting { index, value } = setting;
+                                let info = setting_info(index);
+                                let text = create_signal(cx, edits.get_untracked().get(&index).cloned().unwrap_or_else(|| value.clone()));
+                                let controller_value = create_ref(cx, value);
+                                create_effect(cx, move || {
+                                    let wanted = (!same_value(&text.get(), controller_value)).then(|| text.get().as_ref().clone());
+                                    if edits.get_untracked().get(&index) != wanted.as_ref() {
+                                        let mut updated = edits.get_untracked().as_ref().clone();
+                                        match wanted {
+                                            Some(wanted) => updated.insert(index, wanted),
+                                            None => updated.remove(&index),
+                                        };
+                                        edits.set(updated);
+                                    }
+                                });
+                                let edited = create_memo(cx, move || !same_value(&text.get(), controller_value));
+                                let error = create_memo(cx, move || if *edited.get() { validate_setting(index, &text.get()).err() } else { None });
+                                let snapshot_value = create_memo(cx, move || model.snapshot().get().as_ref().as_ref()
+                                    .and_then(|snapshot| snapshot.settings.iter().find(|saved| saved.index == index).map(|saved| saved.value.clone())));
+                                let class = create_memo(cx, move || {
+                                    let changed = snapshot_value.get().as_ref().as_ref().map_or(false, |saved| !same_value(saved, controller_value));
+                                    [(changed, "changed"), (*edited.get(), "edited"), (error.get().is_some(), "invalid")]
+                                        .into_iter().filter(|(active, _)| *active).map(|(_, name)| name).collect::<Vec<_>>().join(" ")
+                                });
+                                view! { cx,
+                                    tr(class=class.get()) {
+                                        td { (format!("${}", index)) }
+                                        td { (info.map_or("Unknown setting", |info| info.name)) }
+                                        td { input(type="text", size=10, bind:value=text) }
+                                        td { (info.map_or("", |info| info.unit)) }
+                                        td { (snapshot_value.get().as_ref().clone().unwrap_or_default()) }
+                                        td(class="description") {
+                                            (info.map_or("", |info| info.description))
+                                            (error.get().as_ref().clone().map(|error| format!(" {}", error)).unwrap_or_default())
+                                        }
+                                    }
+                                }
+                            }
+                        )
+                    }
+                }
+            })
+        }
+    )
+}
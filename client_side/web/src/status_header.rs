@@ -2,26 +2,25 @@ use std::mem::forget;
 use std::sync::Arc;
 
 use chrono::Utc;
-use common::api::{self, OverrideControl, JobStatus};
+use api_client::OverrideStep;
+use common::api::JobStatus;
 use futures::future::Fuse;
 use itertools::Itertools;
-use reqwasm::websocket::{futures::WebSocket, Message};
-use reqwasm::http::Request;
 use sycamore::prelude::*;
 use wasm_bindgen::{JsCast, JsValue};
 use wasm_bindgen_futures::spawn_local;
 use futures::stream::StreamExt;
 use futures::channel::oneshot;
-use futures::{select, FutureExt};
+use futures::select;
 use stylist::style;
 use web_sys::{KeyboardEvent, Event};
 use gloo_timers::future::sleep;
 use chrono::Duration;
 use sycamore::futures::spawn_local_scoped;
-use common::grbl::{GrblState, GrblFullInfo};
+use common::grbl::{GrblState, GrblFullInfo, AlarmRecovery};
 
 use crate::mdc::IconButton;
-use crate::request::{self, HttpMethod};
+use crate::request::{self, client};
 use crate::utils::time::{format_duration, second_pulse, elapsed_seconds_since, format_seconds};
 
 pub struct GlobalInfo<'a> {
@@ -35,40 +34,32 @@ pub fn global_info<'a>(cx: Scope<'a>) -> &'a GlobalInfo<'a> {
     let job_info = create_signal(cx, None);
     let grbl_info = create_signal(cx, None);
     spawn_local_scoped(cx, async move {
-        let mut ws = request::open_websocket(api::LISTEN_TO_JOB_STATUS);
-        let mut ws2 = request::open_websocket(api::LISTEN_TO_MACHINE_STATUS);
-        let mut ws_next = ws.next().fuse();
-        let mut ws2_next = ws2.next().fuse();
+        let Some(connection) = request::machine_connection().await else {
+            log::debug!("ERROR: the machine connection closed before it was set up");
+            return;
+        };
+        // The header has no use for the debug stream; its messages are dropped as they arrive.
+        drop(connection.debug_stream);
+        grbl_info.set(Some(connection.status.value));
+        job_info.set(connection.job.value);
+        let mut status_updates = connection.status.update_stream.fuse();
+        let mut job_updates = connection.job.update_stream.fuse();
+        let mut state_changes = connection.state_changes.fuse();
         loop {
             select! {
-                next_message = &mut ws_next => {
-                    ws_next = ws.next().fuse();
-                    match next_message {
-                        Some(Ok(Message::Text(ws_message))) => {
-                            let job_status: Option<JobStatus> = serde_json::from_str(&ws_message).unwrap();
-                            job_info.set(job_status);
-                        }
-                        _ => break
-                    }
+                status = status_updates.next() => match status {
+                    Some(status) => grbl_info.set(Some(status)),
+                    None => break,
                 },
-                next_message = &mut ws2_next => {
-                    ws2_next = ws2.next().fuse();
-                    match next_message {
-                        Some(Ok(Message::Text(ws_message))) => {
-                            let value: common::grbl::GrblFullInfo = serde_json::from_str(&ws_message).unwrap();
-                            grbl_info.set(Some(value));
-                        }
-                        _ => break
-                    }
+                job_status = job_updates.next() => match job_status {
+                    Some(job_status) => job_info.set(job_status),
+                    None => break,
+                },
+                change = state_changes.next() => if let Some(change) = change {
+                    log::debug!("Machine state changed: {:?} -> {:?}", change.from, change.to);
                 },
             }
         }
-        //drop(ws);
-        ws.close(None, None).unwrap();
-        ws2.close(None, None).unwrap();
-        //let (forever_send, forever) = oneshot::channel::<()>();
-        //forever.await.unwrap();
-        //log::debug!("Oooh, bye!");
     });
     create_ref(cx, GlobalInfo {
         grbl_info,
@@ -78,15 +69,21 @@ pub fn global_info<'a>(cx: Scope<'a>) -> &'a GlobalInfo<'a> {
 }
 
 
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OverrideKind {
+    Feed,
+    Spindle,
+}
+
 #[derive(Prop)]
-pub struct PercentOverrideControllerProps<'a, F: Fn(&GrblFullInfo) -> String> {
-    urls: OverrideControl<'a>,
+pub struct PercentOverrideControllerProps<F: Fn(&GrblFullInfo) -> String> {
+    kind: OverrideKind,
     heading: String,
     getter: F,
 }
 
 #[component]
-pub fn PercentOverrideController<'a, F: Fn(&GrblFullInfo) -> String + 'a>(cx: Scope<'a>, props: PercentOverrideControllerProps<'a, F>) -> View<DomNode> {
+pub fn PercentOverrideController<'a, F: Fn(&GrblFullInfo) -> String + 'a>(cx: Scope<'a>, props: PercentOverrideControllerProps<F>) -> View<DomNode> {
     let css_style = style! { r#"
         display: flex;
         align-items: center;
@@ -99,16 +96,22 @@ pub fn PercentOverrideController<'a, F: Fn(&GrblFullInfo) -> String + 'a>(cx: Sc
     }.expect("CSS should work");
 
     let global_info: &GlobalInfo = use_context(cx);
-    let callback_for_url = move |url: &'a str| {
+    let kind = props.kind;
+    let callback_for_step = move |step: OverrideStep| {
         create_ref(cx, move || {
-            request::request_detached(HttpMethod::Post, url)
+            request::detached(async move {
+                match kind {
+                    OverrideKind::Feed => client().feed_override(step).await,
+                    OverrideKind::Spindle => client().spindle_override(step).await,
+                }
+            })
         })
     };
-    let feed_reset = callback_for_url(props.urls.reset);
-    let feed_increase_10 = callback_for_url(props.urls.plus_10);
-    let feed_increase_1 = callback_for_url(props.urls.plus_1);
-    let feed_decrease_1 = callback_for_url(props.urls.minus_1);
-    let feed_decrease_10 = callback_for_url(props.urls.minus_10);
+    let feed_reset = callback_for_step(OverrideStep::Reset);
+    let feed_increase_10 = callback_for_step(OverrideStep::Plus10);
+    let feed_increase_1 = callback_for_step(OverrideStep::Plus1);
+    let feed_decrease_1 = callback_for_step(OverrideStep::Minus1);
+    let feed_decrease_10 = callback_for_step(OverrideStep::Minus10);
 
     view! { cx,
         div(class=css_style.get_class_name()) {
@@ -139,10 +142,30 @@ pub fn OverrideController(cx: Scope) -> View<DomNode> {
         align-items: flex-end;
     "#
     }.expect("CSS should work");
+    let global_info: &GlobalInfo = use_context(cx);
+    let live_message = create_memo(cx, || {
+        let format_optional = |value: Option<f64>| value.map_or("?".to_string(), |v| format!("{:.0}", v));
+        match global_info.grbl_info.get().as_ref() {
+            Some(grbl_info) => format!(
+                "Feed {} Spindle {} ({:?}{}{}) Buffer {}/{}{}{}",
+                format_optional(grbl_info.current_feed),
+                format_optional(grbl_info.current_spindle),
+                grbl_info.accessories.spindle,
+                if grbl_info.accessories.flood_coolant { ", flood" } else { "" },
+                if grbl_info.accessories.mist_coolant { ", mist" } else { "" },
+                grbl_info.planner_blocks_available.map_or("?".to_string(), |v| v.to_string()),
+                grbl_info.rx_bytes_available.map_or("?".to_string(), |v| v.to_string()),
+                grbl_info.line_number.map_or(String::new(), |v| format!(" Line {}", v)),
+                if grbl_info.pins.any_limit() { " LIMIT" } else { "" },
+            ),
+            None => String::new(),
+        }
+    });
     view! { cx, 
         div(class=css_style.get_class_name()) {
-            PercentOverrideController(urls=api::FEED_OVERRIDE, heading="Feed override:".into(), getter=|v| v.feed_override.to_string())
-            // PercentOverrideController(url_slug="spindle".into(), heading="Spindle override:".into(), getter=|v| v.spindle_override.to_string())
+            (live_message.get())
+            PercentOverrideController(kind=OverrideKind::Feed, heading="Feed override:".into(), getter=|v| v.feed_override.to_string())
+            // PercentOverrideController(kind=OverrideKind::Spindle, heading="Spindle override:".into(), getter=|v| v.spindle_override.to_string())
         }
     }
 }
@@ -169,34 +192,20 @@ pub fn LeftStatusHeader(cx: Scope) -> View<DomNode> {
         (&*global_info.grbl_info.get()).as_ref().map_or(true, |v| if let GrblState::Hold{..} = v.state { false } else { true })
     });
     let on_click = create_ref(cx, || {
-        let url = if *in_motion.get() {
-            api::COMMAND_PAUSE
+        if *in_motion.get() {
+            request::detached(async { client().pause().await });
         } else {
-            api::COMMAND_RESUME
-        };
-        request::request_detached(
-            HttpMethod::Post,
-            url
-        );
+            request::detached(async { client().resume().await });
+        }
     });
     let stop = create_ref(cx, || {
-        request::request_detached(
-            HttpMethod::Post,
-            api::COMMAND_STOP
-        );
+        request::detached(async { client().stop().await });
     });
     let reset = create_ref(cx, || {
-        request::request_detached(
-            HttpMethod::Post,
-            api::COMMAND_RESET
-        );
+        request::detached(async { client().reset().await });
     });
     let unlock = create_ref(cx, || {
-        request::request_detached_with_body(
-            HttpMethod::Post,
-            api::SEND_RAW_GCODE,
-            "$X"
-        );
+        request::detached(async { client().recover_from_alarm(AlarmRecovery::Unlock).await });
     });
     let home_disabled = create_selector(cx, || {
         (&*global_info.grbl_info.get()).as_ref().map_or(true, |v| {
@@ -214,17 +223,10 @@ pub fn LeftStatusHeader(cx: Scope) -> View<DomNode> {
         })
     });
     let home = create_ref(cx, || {
-        request::request_detached_with_body(
-            HttpMethod::Post,
-            api::SEND_RAW_GCODE,
-            "$H"
-        );
+        request::detached(async { client().send_raw_gcode("$H").await });
     });
     let shutdown = create_ref(cx, || {
-        request::request_detached(
-            HttpMethod::Post,
-            api::SHUTDOWN,
-        );
+        request::detached(async { client().shutdown().await });
     });
     let button_kind = create_selector(cx, || {
         if *in_motion.get() {
@@ -250,12 +252,30 @@ pub fn LeftStatusHeader(cx: Scope) -> View<DomNode> {
     });
     let unlock_disabled = create_selector(cx, || {
         (&*global_info.grbl_info.get()).as_ref().map_or(true, |v| {
-            match v.state {
-                GrblState::Alarm => false,
-                _ => true,
-            }            
+            match &v.alarm {
+                Some(alarm) => alarm.requires_reset || !alarm.recovery.contains(&AlarmRecovery::Unlock),
+                None => true,
+            }
         })
     });
+    let spindle_stop_disabled = create_selector(cx, || {
+        (&*global_info.grbl_info.get()).as_ref().map_or(true, |v| !matches!(v.state, GrblState::Hold{ code: 0 }))
+    });
+    let coolant_disabled = create_selector(cx, || {
+        (&*global_info.grbl_info.get()).as_ref().map_or(true, |v| !matches!(v.state, GrblState::Idle | GrblState::Run | GrblState::Hold{..}))
+    });
+    let toggle_spindle_stop = create_ref(cx, || {
+        request::detached(async { client().toggle_spindle_stop().await });
+    });
+    let toggle_flood_coolant = create_ref(cx, || {
+        request::detached(async { client().toggle_flood_coolant().await });
+    });
+    let toggle_mist_coolant = create_ref(cx, || {
+        request::detached(async { client().toggle_mist_coolant().await });
+    });
+    let safety_door = create_ref(cx, || {
+        request::detached(async { client().safety_door().await });
+    });
     let job_start = create_signal(cx, None);
     let job_time = elapsed_seconds_since(cx, job_start);
     let title_message = create_memo(cx, || {
@@ -271,11 +291,17 @@ pub fn LeftStatusHeader(cx: Scope) -> View<DomNode> {
                         format!("Idle")
                     }
                 };
+                let alarm_string = match &grbl_info.alarm {
+                    Some(alarm) if alarm.requires_reset => format!(" Alarm (reset to continue): {}", alarm.text),
+                    Some(alarm) => format!(" Alarm: {}", alarm.text),
+                    None => String::new(),
+                };
                 format!(
-                    "{:?} [{}] {}",
+                    "{:?} [{}] {}{}",
                     grbl_info.state,
                     grbl_info.work_position().iter().map(|v| format!("{:.3}", v)).collect_vec().join(", "),
                     job_string,
+                    alarm_string,
                 )
             }
             None => {
@@ -294,6 +320,12 @@ pub fn LeftStatusHeader(cx: Scope) -> View<DomNode> {
                 IconButton(icon_name=create_signal(cx, "cancel".to_string()), on_click=reset)
                 IconButton(icon_name=create_signal(cx, "power_settings_new".to_string()), on_click=shutdown)
             }
+            div {
+                IconButton(icon_name=create_signal(cx, "mode_fan_off".to_string()), on_click=toggle_spindle_stop, disabled=spindle_stop_disabled)
+                IconButton(icon_name=create_signal(cx, "water_drop".to_string()), on_click=toggle_flood_coolant, disabled=coolant_disabled)
+                IconButton(icon_name=create_signal(cx, "shower".to_string()), on_click=toggle_mist_coolant, disabled=coolant_disabled)
+                IconButton(icon_name=create_signal(cx, "sensor_door".to_string()), on_click=safety_door)
+            }
         }
     }
 }
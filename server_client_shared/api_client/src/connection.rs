@@ -0,0 +1,88 @@
+use async_trait::async_trait;
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::error::{ClientError, ClientResult};
+
+/*
+    What a backend has to provide: send one request and hand back the status and body. Everything about
+particular endpoints lives in Client, so every backend gets each of them the same way.
+*/
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HttpMethod {
+    Get,
+    Post,
+    Delete,
+    Put,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum FormValue {
+    Text(String),
+    File { file_name: String, contents: Vec<u8> },
+}
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct FormPart {
+    pub name: String,
+    pub value: FormValue,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum RequestBody {
+    Empty,
+    Json(String),
+    Text(String),
+    Multipart(Vec<FormPart>),
+}
+impl RequestBody {
+    // The API's bodies all have string keys, so they always serialize.
+    pub fn json(value: &impl Serialize) -> Self {
+        RequestBody::Json(serde_json::to_string(value).unwrap())
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct HttpRequest {
+    pub method: HttpMethod,
+    pub path: String, // From the server's root, starting with a slash
+    pub body: RequestBody,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+impl HttpResponse {
+    // The server answers failures with its reason as plain text.
+    pub fn check(self) -> ClientResult<Self> {
+        let message = || String::from_utf8_lossy(&self.body).into_owned();
+        match self.status {
+            200..=299 => Ok(self),
+            400 => Err(ClientError::Rejected(message())),
+            status => Err(ClientError::Server { status, message: message() }),
+        }
+    }
+    pub fn json<T: DeserializeOwned>(self) -> ClientResult<T> {
+        serde_json::from_slice(&self.check()?.body).map_err(|error| ClientError::Decode(error.to_string()))
+    }
+    pub fn text(self) -> ClientResult<String> {
+        String::from_utf8(self.check()?.body).map_err(|error| ClientError::Decode(error.to_string()))
+    }
+    pub fn bytes(self) -> ClientResult<Vec<u8>> {
+        Ok(self.check()?.body)
+    }
+    // For endpoints that only answer with an acknowledgement like "Ok!".
+    pub fn empty(self) -> ClientResult<()> {
+        self.check().map(|_| ())
+    }
+}
+
+// Browser futures can't be sent between threads, so neither can the wasm backend's.
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+pub trait HttpConnection {
+    async fn send(&self, request: HttpRequest) -> ClientResult<HttpResponse>;
+    // Where a path from the server's root can be reached, for links the browser follows itself.
+    fn url(&self, path: &str) -> String;
+}
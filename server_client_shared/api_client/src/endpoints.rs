@@ -0,0 +1,416 @@
+use common::{api::{self, *}, grbl::{AlarmRecovery, GrblSetting}};
+
+use crate::{Client, connection::{FormPart, FormValue, HttpConnection, HttpMethod, RequestBody}, error::{ClientError, ClientResult}};
+
+// What UPLOAD_GCODE_FILE takes, sent as a multipart form.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct UploadGcodeFile {
+    pub path: String, // Where to store it; a zip or tar archive is unpacked into a directory of its name.
+    pub contents: Vec<u8>,
+    pub reject_invalid: bool,
+    pub uploader: Option<String>,
+}
+impl UploadGcodeFile {
+    fn into_form(self) -> Vec<FormPart> {
+        let text = |name: &str, value: String| FormPart { name: name.to_string(), value: FormValue::Text(value) };
+        let mut parts = vec![
+            text("filename", self.path),
+            FormPart { name: "file".to_string(), value: FormValue::File { file_name: "file.nc".to_string(), contents: self.contents } },
+        ];
+        if self.reject_invalid {
+            parts.push(text("reject_invalid", "true".to_string()));
+        }
+        if let Some(uploader) = self.uploader {
+            parts.push(text("uploader", uploader));
+        }
+        parts
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OverrideStep {
+    Reset,
+    Plus10,
+    Plus1,
+    Minus1,
+    Minus10,
+}
+impl OverrideStep {
+    fn path(self, control: &OverrideControl<'static>) -> &'static str {
+        match self {
+            OverrideStep::Reset => control.reset,
+            OverrideStep::Plus10 => control.plus_10,
+            OverrideStep::Plus1 => control.plus_1,
+            OverrideStep::Minus1 => control.minus_1,
+            OverrideStep::Minus10 => control.minus_10,
+        }
+    }
+}
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RapidStep {
+    Reset,
+    Half,
+    Quarter,
+}
+impl RapidStep {
+    fn path(self) -> &'static str {
+        match self {
+            RapidStep::Reset => RAPID_OVERRIDE.reset,
+            RapidStep::Half => RAPID_OVERRIDE.half,
+            RapidStep::Quarter => RAPID_OVERRIDE.quarter,
+        }
+    }
+}
+
+impl<C: HttpConnection> Client<C> {
+    async fn get(&self, path: impl Into<String>) -> ClientResult<crate::HttpResponse> {
+        self.send(HttpMethod::Get, path.into(), RequestBody::Empty).await
+    }
+    async fn post(&self, path: impl Into<String>) -> ClientResult<crate::HttpResponse> {
+        self.send(HttpMethod::Post, path.into(), RequestBody::Empty).await
+    }
+    async fn with_json(&self, method: HttpMethod, path: impl Into<String>, body: &impl serde::Serialize) -> ClientResult<crate::HttpResponse> {
+        self.send(method, path.into(), RequestBody::json(body)).await
+    }
+
+    /*
+        Job
+    */
+    pub async fn run_gcode_file(&self, request: &RunGcodeFile) -> ClientResult<()> {
+        self.with_json(HttpMethod::Post, api::RUN_GCODE_FILE, request).await?.empty()
+    }
+    pub async fn upload_gcode_file(&self, upload: UploadGcodeFile) -> ClientResult<UploadReport> {
+        self.send(HttpMethod::Post, api::UPLOAD_GCODE_FILE.into(), RequestBody::Multipart(upload.into_form())).await?.json()
+    }
+    pub async fn create_gcode_directory(&self, request: &CreateGcodeDirectory) -> ClientResult<()> {
+        self.with_json(HttpMethod::Post, api::CREATE_GCODE_DIRECTORY, request).await?.empty()
+    }
+    pub async fn delete_gcode_file(&self, request: &DeleteGcodeFile) -> ClientResult<()> {
+        self.with_json(HttpMethod::Delete, api::DELETE_GCODE_FILE, request).await?.empty()
+    }
+    pub async fn move_gcode_file(&self, request: &MoveGcodeFile) -> ClientResult<()> {
+        self.with_json(HttpMethod::Post, api::MOVE_GCODE_FILE, request).await?.empty()
+    }
+    pub async fn copy_gcode_file(&self, request: &CopyGcodeFile) -> ClientResult<()> {
+        self.with_json(HttpMethod::Post, api::COPY_GCODE_FILE, request).await?.empty()
+    }
+    pub async fn list_gcode_files(&self, request: &ListGcodeFiles) -> ClientResult<Vec<GcodeFile>> {
+        self.with_json(HttpMethod::Post, api::LIST_GCODE_FILES, request).await?.json()
+    }
+    pub async fn examine_lines_in_gcode_file(&self, request: &ExamineGcodeFile) -> ClientResult<Vec<[f32; 3]>> {
+        self.with_json(HttpMethod::Post, api::EXAMINE_LINES_IN_GCODE_FILE, request).await?.json()
+    }
+    pub async fn preview_gcode_file(&self, request: &PreviewGcodeFile) -> ClientResult<ToolpathPreview> {
+        let bytes = self.with_json(HttpMethod::Post, api::PREVIEW_GCODE_FILE, request).await?.bytes()?;
+        ToolpathPreview::from_bytes(&bytes).map_err(ClientError::Decode)
+    }
+    pub async fn download_gcode_file(&self, path: &str) -> ClientResult<String> {
+        self.get(gcode_file_path(path)).await?.text()
+    }
+    pub async fn list_gcode_versions(&self, path: &str) -> ClientResult<Vec<GcodeVersion>> {
+        self.get(format!("{}/{}", api::LIST_GCODE_VERSIONS, path)).await?.json()
+    }
+    pub async fn download_gcode_version(&self, path: &str, hash: &str) -> ClientResult<String> {
+        self.get(format!("{}/{}/{}", api::DOWNLOAD_GCODE_VERSION, hash, path)).await?.text()
+    }
+    // As a unified diff.
+    pub async fn diff_gcode_versions(&self, request: &DiffGcodeVersions) -> ClientResult<String> {
+        self.with_json(HttpMethod::Post, api::DIFF_GCODE_VERSIONS, request).await?.text()
+    }
+    pub async fn restore_gcode_version(&self, request: &RestoreGcodeVersion) -> ClientResult<()> {
+        self.with_json(HttpMethod::Post, api::RESTORE_GCODE_VERSION, request).await?.empty()
+    }
+    pub async fn list_trash(&self) -> ClientResult<Vec<TrashEntry>> {
+        self.get(api::LIST_TRASH).await?.json()
+    }
+    pub async fn restore_from_trash(&self, request: &RestoreTrashEntry) -> ClientResult<()> {
+        self.with_json(HttpMethod::Post, api::RESTORE_FROM_TRASH, request).await?.empty()
+    }
+    pub async fn purge_trash(&self, request: &PurgeTrash) -> ClientResult<()> {
+        self.with_json(HttpMethod::Post, api::PURGE_TRASH, request).await?.empty()
+    }
+
+    /*
+        Debug utilities
+    */
+    pub async fn send_raw_gcode(&self, line: &str) -> ClientResult<()> {
+        self.send(HttpMethod::Post, api::SEND_RAW_GCODE.into(), RequestBody::Text(line.to_string())).await?.empty()
+    }
+    pub async fn command_history(&self, request: &QueryCommandHistory) -> ClientResult<CommandHistory> {
+        self.with_json(HttpMethod::Post, api::COMMAND_HISTORY, request).await?.json()
+    }
+    pub async fn command_history_users(&self) -> ClientResult<Vec<String>> {
+        self.get(api::COMMAND_HISTORY_USERS).await?.json()
+    }
+    pub async fn send_command(&self, request: &SendCommand) -> ClientResult<CommandHistory> {
+        self.with_json(HttpMethod::Post, api::SEND_COMMAND, request).await?.json()
+    }
+    pub async fn favourite_command(&self, request: &SetFavouriteCommand) -> ClientResult<CommandHistory> {
+        self.with_json(HttpMethod::Post, api::FAVOURITE_COMMAND, request).await?.json()
+    }
+    pub async fn list_machine_logs(&self) -> ClientResult<Vec<MachineLogFile>> {
+        self.get(api::LIST_MACHINE_LOGS).await?.json()
+    }
+    pub async fn download_machine_log(&self, name: &str) -> ClientResult<String> {
+        self.get(format!("{}/{}", api::DOWNLOAD_MACHINE_LOG, name)).await?.text()
+    }
+    pub async fn query_machine_logs(&self, request: &QueryMachineLog) -> ClientResult<MachineLogQueryResult> {
+        self.with_json(HttpMethod::Post, api::QUERY_MACHINE_LOGS, request).await?.json()
+    }
+
+    /*
+        Commands
+    */
+    pub async fn pause(&self) -> ClientResult<()> {
+        self.post(api::COMMAND_PAUSE).await?.empty()
+    }
+    pub async fn resume(&self) -> ClientResult<()> {
+        self.post(api::COMMAND_RESUME).await?.empty()
+    }
+    pub async fn stop(&self) -> ClientResult<()> {
+        self.post(api::COMMAND_STOP).await?.empty()
+    }
+    pub async fn reset(&self) -> ClientResult<()> {
+        self.post(api::COMMAND_RESET).await?.empty()
+    }
+    pub async fn recover_from_alarm(&self, recovery: AlarmRecovery) -> ClientResult<()> {
+        self.with_json(HttpMethod::Post, api::COMMAND_RECOVER_FROM_ALARM, &recovery).await?.empty()
+    }
+    pub async fn feed_override(&self, step: OverrideStep) -> ClientResult<()> {
+        self.post(step.path(&FEED_OVERRIDE)).await?.empty()
+    }
+    pub async fn spindle_override(&self, step: OverrideStep) -> ClientResult<()> {
+        self.post(step.path(&SPINDLE_OVERRIDE)).await?.empty()
+    }
+    pub async fn rapid_override(&self, step: RapidStep) -> ClientResult<()> {
+        self.post(step.path()).await?.empty()
+    }
+    // These are rejected if the machine's state doesn't allow them.
+    pub async fn toggle_spindle_stop(&self) -> ClientResult<()> {
+        self.post(api::COMMAND_TOGGLE_SPINDLE_STOP).await?.empty()
+    }
+    pub async fn toggle_flood_coolant(&self) -> ClientResult<()> {
+        self.post(api::COMMAND_TOGGLE_FLOOD_COOLANT).await?.empty()
+    }
+    pub async fn toggle_mist_coolant(&self) -> ClientResult<()> {
+        self.post(api::COMMAND_TOGGLE_MIST_COOLANT).await?.empty()
+    }
+    pub async fn safety_door(&self) -> ClientResult<()> {
+        self.post(api::COMMAND_SAFETY_DOOR).await?.empty()
+    }
+    pub async fn jog_cancel(&self) -> ClientResult<()> {
+        self.post(api::COMMAND_JOG_CANCEL).await?.empty()
+    }
+    pub async fn run_macro(&self, index: u8) -> ClientResult<()> {
+        self.post(format!("{}/{}", api::COMMAND_MACRO, index)).await?.empty()
+    }
+
+    /*
+        Controller settings
+    */
+    pub async fn controller_settings(&self) -> ClientResult<Vec<GrblSetting>> {
+        self.get(api::CONTROLLER_SETTINGS).await?.json()
+    }
+    // Answers with the settings read back.
+    pub async fn write_controller_settings(&self, settings: &[GrblSetting]) -> ClientResult<Vec<GrblSetting>> {
+        self.with_json(HttpMethod::Post, api::CONTROLLER_SETTINGS, &settings).await?.json()
+    }
+    pub async fn settings_snapshot(&self) -> ClientResult<Option<SettingsSnapshot>> {
+        self.get(api::SETTINGS_SNAPSHOT).await?.json()
+    }
+    pub async fn save_settings_snapshot(&self, settings: &[GrblSetting]) -> ClientResult<Option<SettingsSnapshot>> {
+        self.with_json(HttpMethod::Put, api::SETTINGS_SNAPSHOT, &settings).await?.json()
+    }
+    pub async fn download_settings_backup(&self) -> ClientResult<String> {
+        self.get(api::DOWNLOAD_SETTINGS_BACKUP).await?.text()
+    }
+
+    /*
+        Job results
+    */
+    pub async fn list_results(&self) -> ClientResult<Vec<String>> {
+        self.get(api::LIST_RESULTS).await?.json()
+    }
+    pub async fn download_result(&self, job: &str, name: &str) -> ClientResult<Vec<u8>> {
+        self.get(result_path(job, name)).await?.bytes()
+    }
+    pub async fn list_artifacts(&self, job: &str) -> ClientResult<Vec<JobArtifact>> {
+        self.get(format!("{}/{}", api::LIST_ARTIFACTS, job)).await?.json()
+    }
+    async fn export_probes(&self, job: &str, format: ProbeExportFormat) -> ClientResult<crate::HttpResponse> {
+        self.get(probes_export_path(job, format)).await
+    }
+    pub async fn probes_csv(&self, job: &str) -> ClientResult<String> {
+        self.export_probes(job, ProbeExportFormat::Csv).await?.text()
+    }
+    pub async fn depth_map(&self, job: &str) -> ClientResult<Vec<DepthMapPoint>> {
+        self.export_probes(job, ProbeExportFormat::DepthMap).await?.json()
+    }
+    pub async fn height_map(&self, job: &str) -> ClientResult<HeightMap> {
+        self.export_probes(job, ProbeExportFormat::HeightMap).await?.json()
+    }
+    pub async fn job_history(&self, query: &QueryJobHistory) -> ClientResult<Vec<JobSummary>> {
+        self.with_json(HttpMethod::Post, api::JOB_HISTORY, query).await?.json()
+    }
+    pub async fn job_record(&self, id: &str) -> ClientResult<JobRecord> {
+        self.get(format!("{}/{}", api::JOB_RECORD, id)).await?.json()
+    }
+
+    /*
+        Coordinates
+    */
+    pub async fn offsets(&self) -> ClientResult<Offsets> {
+        self.get(api::OFFSETS).await?.json()
+    }
+    pub async fn set_offset(&self, request: &SetCoordinateOffset) -> ClientResult<Offsets> {
+        self.with_json(HttpMethod::Put, api::OFFSETS, request).await?.json()
+    }
+    pub async fn delete_offset(&self, request: &DeleteCoordinateOffset) -> ClientResult<Offsets> {
+        self.with_json(HttpMethod::Delete, api::OFFSETS, request).await?.json()
+    }
+    pub async fn align_offset(&self, request: &AlignCoordinateOffset) -> ClientResult<Offsets> {
+        self.with_json(HttpMethod::Post, api::ALIGN_OFFSET, request).await?.json()
+    }
+    pub async fn apply_offsets(&self, request: &ApplyCoordinateOffsets) -> ClientResult<()> {
+        self.with_json(HttpMethod::Post, api::APPLY_OFFSETS, request).await?.empty()
+    }
+    pub async fn positions(&self) -> ClientResult<Vec<SavedPosition>> {
+        self.get(api::POSITIONS).await?.json()
+    }
+    pub async fn add_position(&self, position: &SavedPosition) -> ClientResult<Vec<SavedPosition>> {
+        self.with_json(HttpMethod::Post, api::POSITIONS, position).await?.json()
+    }
+
+    /*
+        Probing
+    */
+    // Runs as a job, so this only answers once the routine is done.
+    pub async fn run_probe_routine(&self, request: &RunProbeRoutine) -> ClientResult<ProbeRoutineResult> {
+        self.with_json(HttpMethod::Post, api::RUN_PROBE_ROUTINE, request).await?.json()
+    }
+
+    /*
+        Misc
+    */
+    pub async fn shutdown(&self) -> ClientResult<String> {
+        self.post(api::SHUTDOWN).await?.text()
+    }
+
+    /*
+        Links, for downloads the browser saves itself
+    */
+    pub fn gcode_file_url(&self, path: &str) -> String {
+        self.connection().url(&gcode_file_path(path))
+    }
+    pub fn result_url(&self, job: &str, name: &str) -> String {
+        self.connection().url(&result_path(job, name))
+    }
+    pub fn probes_export_url(&self, job: &str, format: ProbeExportFormat) -> String {
+        self.connection().url(&probes_export_path(job, format))
+    }
+    pub fn settings_backup_url(&self) -> String {
+        self.connection().url(api::DOWNLOAD_SETTINGS_BACKUP)
+    }
+}
+
+fn gcode_file_path(path: &str) -> String {
+    format!("{}/{}", api::DOWNLOAD_GCODE, path)
+}
+fn result_path(job: &str, name: &str) -> String {
+    format!("{}/{}/{}", api::DOWNLOAD_RESULTS, job, name)
+}
+fn probes_export_path(job: &str, format: ProbeExportFormat) -> String {
+    format!("{}/{}/{}", api::EXPORT_PROBES, job, format.as_str())
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use async_trait::async_trait;
+    use futures::executor::block_on;
+
+    use crate::{HttpRequest, HttpResponse};
+    use super::*;
+
+    // Answers every request the same way and keeps what was sent.
+    struct FakeConnection {
+        status: u16,
+        body: &'static str,
+        sent: Mutex<Vec<HttpRequest>>,
+    }
+    #[async_trait]
+    impl HttpConnection for FakeConnection {
+        async fn send(&self, request: HttpRequest) -> ClientResult<HttpResponse> {
+            self.sent.lock().unwrap().push(request);
+            Ok(HttpResponse { status: self.status, body: self.body.as_bytes().to_vec() })
+        }
+        fn url(&self, path: &str) -> String {
+            format!("http://cnc:3000{}", path)
+        }
+    }
+    fn client(status: u16, body: &'static str) -> Client<FakeConnection> {
+        Client::new(FakeConnection { status, body, sent: Mutex::new(Vec::new()) })
+    }
+    fn sent(client: &Client<FakeConnection>) -> Vec<(HttpMethod, String)> {
+        client.connection().sent.lock().unwrap().iter().map(|request| (request.method, request.path.clone())).collect()
+    }
+
+    #[test]
+    fn endpoints_build_their_paths() {
+        let client = client(200, "Ok!");
+        block_on(async {
+            client.jog_cancel().await.unwrap();
+            client.spindle_override(OverrideStep::Minus10).await.unwrap();
+            client.rapid_override(RapidStep::Half).await.unwrap();
+            client.run_macro(2).await.unwrap();
+            client.download_gcode_version("parts/plate.nc", "abc123").await.unwrap();
+            client.probes_csv("job_7").await.unwrap();
+        });
+        assert_eq!(sent(&client), vec![
+            (HttpMethod::Post, "/command/jog_cancel".to_string()),
+            (HttpMethod::Post, "/command/override/spindle/minus10".to_string()),
+            (HttpMethod::Post, "/command/override/rapid/half".to_string()),
+            (HttpMethod::Post, "/command/macro/2".to_string()),
+            (HttpMethod::Get, "/job/versions/download/abc123/parts/plate.nc".to_string()),
+            (HttpMethod::Get, "/results/probes/job_7/csv".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn links_share_the_endpoints_paths() {
+        let client = client(200, "");
+        assert_eq!(client.gcode_file_url("parts/plate.nc"), "http://cnc:3000/job/download_file/parts/plate.nc");
+        assert_eq!(client.probes_export_url("job_7", ProbeExportFormat::DepthMap), "http://cnc:3000/results/probes/job_7/depth_map");
+        block_on(client.probes_csv("job_7")).unwrap();
+        assert_eq!(client.probes_export_url("job_7", ProbeExportFormat::Csv), format!("http://cnc:3000{}", sent(&client)[0].1));
+    }
+
+    #[test]
+    fn bodies_are_json_text_or_forms() {
+        let client = client(200, r#"{"files":[],"skipped":[]}"#);
+        block_on(async {
+            client.delete_gcode_file(&DeleteGcodeFile { path: "a.nc".into(), is_directory: false }).await.unwrap();
+            client.send_raw_gcode("G0 X1").await.unwrap();
+            let upload = UploadGcodeFile { path: "a.nc".into(), contents: b"G0 X1".to_vec(), reject_invalid: true, uploader: None };
+            assert_eq!(client.upload_gcode_file(upload).await.unwrap(), UploadReport { files: Vec::new(), skipped: Vec::new() });
+        });
+        let sent = client.connection().sent.lock().unwrap().clone();
+        assert_eq!(sent[0].method, HttpMethod::Delete);
+        assert_eq!(sent[0].body, RequestBody::Json(r#"{"path":"a.nc","is_directory":false}"#.into()));
+        assert_eq!(sent[1].body, RequestBody::Text("G0 X1".into()));
+        let RequestBody::Multipart(parts) = &sent[2].body else { panic!("not a form: {:?}", sent[2].body) };
+        let names: Vec<_> = parts.iter().map(|part| part.name.as_str()).collect();
+        assert_eq!(names, ["filename", "file", "reject_invalid"]);
+    }
+
+    #[test]
+    fn failures_become_typed_errors() {
+        let machine_busy = block_on(client(400, "A job is running").toggle_flood_coolant());
+        assert_eq!(machine_busy, Err(ClientError::Rejected("A job is running".into())));
+        let crashed = block_on(client(500, "oops").list_trash());
+        assert_eq!(crashed, Err(ClientError::Server { status: 500, message: "oops".into() }));
+        assert!(matches!(block_on(client(200, "not json").list_results()), Err(ClientError::Decode(_))));
+        assert!(matches!(block_on(client(200, "TPV0").preview_gcode_file(&PreviewGcodeFile { path: "a.nc".into(), max_points: None })), Err(ClientError::Decode(_))));
+    }
+}
@@ -0,0 +1,24 @@
+use std::fmt;
+
+pub type ClientResult<T> = Result<T, ClientError>;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum ClientError {
+    Connection(String), // The request never got an answer.
+    Rejected(String),   // A 400, with the server's reason; usually the machine's state or a bad argument.
+    Server { status: u16, message: String },
+    Decode(String),     // The answer wasn't what the endpoint gives.
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Connection(message) => write!(f, "couldn't reach the server: {}", message),
+            ClientError::Rejected(message) => write!(f, "{}", message),
+            ClientError::Server { status, message } => write!(f, "server error {}: {}", status, message),
+            ClientError::Decode(message) => write!(f, "unexpected answer from the server: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
@@ -0,0 +1,34 @@
+/*
+    A typed client for the server's HTTP API. Each endpoint is a method on Client, written once with its
+request and response types; the backends (reqwest for native code, reqwasm in the browser) only know how
+to send a request. The websocket endpoints aren't covered.
+*/
+
+mod connection;
+mod endpoints;
+mod error;
+#[cfg(feature = "native")]
+pub mod native;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+pub use connection::{FormPart, FormValue, HttpConnection, HttpMethod, HttpRequest, HttpResponse, RequestBody};
+pub use endpoints::{OverrideStep, RapidStep, UploadGcodeFile};
+pub use error::{ClientError, ClientResult};
+
+#[derive(Clone, Debug)]
+pub struct Client<C> {
+    connection: C,
+}
+
+impl<C: HttpConnection> Client<C> {
+    pub fn new(connection: C) -> Self {
+        Client { connection }
+    }
+    pub fn connection(&self) -> &C {
+        &self.connection
+    }
+    async fn send(&self, method: HttpMethod, path: String, body: RequestBody) -> ClientResult<HttpResponse> {
+        self.connection.send(HttpRequest { method, path, body }).await
+    }
+}
@@ -0,0 +1,53 @@
+use async_trait::async_trait;
+use reqwest::multipart::{Form, Part};
+
+use crate::{Client, FormValue, HttpConnection, HttpMethod, HttpRequest, HttpResponse, RequestBody, error::{ClientError, ClientResult}};
+
+#[derive(Clone, Debug)]
+pub struct ReqwestConnection {
+    client: reqwest::Client,
+    base_url: String, // Like "http://cnc:3000", without a trailing slash
+}
+
+impl ReqwestConnection {
+    pub fn new(base_url: impl Into<String>) -> Self {
+        ReqwestConnection { client: reqwest::Client::new(), base_url: base_url.into() }
+    }
+}
+
+pub fn client(base_url: impl Into<String>) -> Client<ReqwestConnection> {
+    Client::new(ReqwestConnection::new(base_url))
+}
+
+fn connection_error(error: reqwest::Error) -> ClientError {
+    ClientError::Connection(error.to_string())
+}
+
+#[async_trait]
+impl HttpConnection for ReqwestConnection {
+    async fn send(&self, request: HttpRequest) -> ClientResult<HttpResponse> {
+        let url = self.url(&request.path);
+        let builder = match request.method {
+            HttpMethod::Get => self.client.get(url),
+            HttpMethod::Post => self.client.post(url),
+            HttpMethod::Put => self.client.put(url),
+            HttpMethod::Delete => self.client.delete(url),
+        };
+        let builder = match request.body {
+            RequestBody::Empty => builder,
+            RequestBody::Json(json) => builder.header("Content-Type", "application/json").body(json),
+            RequestBody::Text(text) => builder.body(text),
+            RequestBody::Multipart(parts) => builder.multipart(parts.into_iter().fold(Form::new(), |form, part| match part.value {
+                FormValue::Text(text) => form.text(part.name, text),
+                FormValue::File { file_name, contents } => form.part(part.name, Part::bytes(contents).file_name(file_name)),
+            })),
+        };
+        let response = builder.send().await.map_err(connection_error)?;
+        let status = response.status().as_u16();
+        let body = response.bytes().await.map_err(connection_error)?.to_vec();
+        Ok(HttpResponse { status, body })
+    }
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+}
@@ -0,0 +1,67 @@
+use async_trait::async_trait;
+use js_sys::{Array, Uint8Array};
+use reqwasm::http::Request;
+use web_sys::{Blob, FormData};
+
+use crate::{Client, FormPart, FormValue, HttpConnection, HttpMethod, HttpRequest, HttpResponse, RequestBody, error::{ClientError, ClientResult}};
+
+#[derive(Clone, Debug)]
+pub struct ReqwasmConnection {
+    base_url: String, // Like "http://localhost:3000", without a trailing slash
+}
+
+impl ReqwasmConnection {
+    pub fn new(base_url: impl Into<String>) -> Self {
+        ReqwasmConnection { base_url: base_url.into() }
+    }
+}
+
+pub fn client(base_url: impl Into<String>) -> Client<ReqwasmConnection> {
+    Client::new(ReqwasmConnection::new(base_url))
+}
+
+fn connection_error(error: reqwasm::Error) -> ClientError {
+    ClientError::Connection(error.to_string())
+}
+fn form_data(parts: Vec<FormPart>) -> ClientResult<FormData> {
+    let failed = |_| ClientError::Connection("couldn't build the form".to_string());
+    let form = FormData::new().map_err(failed)?;
+    for part in parts {
+        match part.value {
+            FormValue::Text(text) => form.append_with_str(&part.name, &text).map_err(failed)?,
+            FormValue::File { file_name, contents } => {
+                let blob = Blob::new_with_u8_array_sequence(&Array::of1(&Uint8Array::from(&contents[..]))).map_err(failed)?;
+                form.append_with_blob_and_filename(&part.name, &blob, &file_name).map_err(failed)?;
+            }
+        }
+    }
+    Ok(form)
+}
+
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+impl HttpConnection for ReqwasmConnection {
+    async fn send(&self, request: HttpRequest) -> ClientResult<HttpResponse> {
+        let url = self.url(&request.path);
+        let builder = match request.method {
+            HttpMethod::Get => Request::get(&url),
+            HttpMethod::Post => Request::post(&url),
+            HttpMethod::Put => Request::put(&url),
+            HttpMethod::Delete => Request::delete(&url),
+        };
+        let builder = match request.body {
+            RequestBody::Empty => builder,
+            RequestBody::Json(json) => builder.header("Content-Type", "application/json").body(json),
+            RequestBody::Text(text) => builder.body(text),
+            // The browser picks the multipart boundary, so no Content-Type is set here.
+            RequestBody::Multipart(parts) => builder.body(form_data(parts)?),
+        };
+        let response = builder.send().await.map_err(connection_error)?;
+        let status = response.status();
+        let body = response.binary().await.map_err(connection_error)?;
+        Ok(HttpResponse { status, body })
+    }
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+}
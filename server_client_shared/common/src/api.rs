@@ -20,11 +20,39 @@ pub struct RapidOverride<'a> {
 pub struct JobStatus {
     pub start_time: chrono::DateTime<Utc>,
     pub message: String,
+    #[serde(default)]
+    pub progress: Option<JobProgress>,
+}
+// How far a job has got through its file. Line numbers are 1-based lines of the file; 0 means none yet.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug, Default)]
+pub struct JobProgress {
+    pub path: Option<String>, // The G-code file being run, if there is one.
+    pub total_lines: usize,
+    pub sent_line: usize,
+    pub acknowledged_line: usize, // The controller has taken every line up to here.
+}
+
+// What a job does when the controller rejects one of its lines with error:N.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum JobErrorPolicy {
+    #[default]
+    Abort,    // Feed hold, then stop the job.
+    Pause,    // Feed hold and wait for the operator to resume or stop.
+    Continue, // Log the error and keep going.
+}
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct JobLineError {
+    pub line_number: usize,
+    pub code: u64,
+    pub text: String,
+    pub sent: String, // Exactly what was sent for the line, without the trailing newline.
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct RunGcodeFile {
     pub path: String,
+    #[serde(default)]
+    pub error_policy: JobErrorPolicy,
 }
 #[derive(Serialize, Deserialize)]
 pub struct DeleteGcodeFile {
@@ -39,35 +67,428 @@ pub struct ListGcodeFiles {
 pub struct CreateGcodeDirectory {
     pub directory: String,
 }
-#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub struct GcodeFile {
     pub name: String,
     pub is_file: bool,
+    // The rest is only given for files.
+    #[serde(default)]
+    pub size: Option<u64>,
+    #[serde(default)]
+    pub modified: Option<chrono::DateTime<Utc>>,
+    #[serde(default)]
+    pub analysis: Option<GcodeAnalysis>,
+    #[serde(default)]
+    pub last_run: Option<LastRun>,
+}
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct GcodeBounds {
+    pub min: [f64; 3],
+    pub max: [f64; 3],
+}
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct GcodeParseIssue {
+    pub line_number: usize,
+    pub description: String,
+}
+// Distances are in the file's own units; times assume the programmed feeds are reached.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct GcodeAnalysis {
+    pub line_count: usize,
+    pub parse_errors: Vec<GcodeParseIssue>, // Only the first few; see parse_error_count.
+    pub parse_error_count: usize,
+    pub bounds: Option<GcodeBounds>,
+    pub cut_distance: f64,
+    pub rapid_distance: f64,
+    pub estimated_seconds: f64,
+    pub tools: Vec<u32>,
+    pub feeds: Vec<f64>,
+    #[serde(default)]
+    pub warnings: Vec<GcodeParseIssue>, // Lines that parse but probably don't do what was meant.
+}
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct LastRun {
+    pub time: chrono::DateTime<Utc>,
+    pub completed: bool, // False if it was stopped or failed.
+    pub error_count: usize,
+    #[serde(default)]
+    pub version: Option<String>, // The GcodeVersion hash that ran.
+}
+// One version of a file in the G-code library; the newest is the file as it is now.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct GcodeVersion {
+    pub hash: String, // SHA-256 of the contents, in hex.
+    pub time: chrono::DateTime<Utc>,
+    pub uploader: Option<String>, // None if the server found the file changed without an upload.
+    pub restored_from: Option<String>,
+    pub size: u64,
+}
+#[derive(Serialize, Deserialize)]
+pub struct DiffGcodeVersions {
+    pub path: String,
+    pub from: String,
+    pub to: Option<String>, // Defaults to the current version.
+}
+#[derive(Serialize, Deserialize)]
+pub struct RestoreGcodeVersion {
+    pub path: String,
+    pub hash: String,
+}
+// Which version of which file a job ran.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct JobSource {
+    pub path: String,
+    pub version: String,
+}
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum JobOutcome {
+    Running,   // Also left by a server that stopped before the job ended.
+    Completed,
+    Stopped,   // By the operator.
+    Reset,
+    Alarm,
+    Error,     // Ended by a line the controller rejected.
+}
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct OverrideChange {
+    pub time: chrono::DateTime<Utc>,
+    pub feed: u8, // Percentages
+    pub rapid: u8,
+    pub spindle: u8,
+}
+// Everything known about one run of a file; saved as record.json in the job's results directory.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct JobRecord {
+    pub id: String, // The results directory, as given to DOWNLOAD_RESULTS
+    pub source: JobSource,
+    pub error_policy: JobErrorPolicy,
+    pub start_time: chrono::DateTime<Utc>,
+    pub end_time: Option<chrono::DateTime<Utc>>,
+    pub outcome: JobOutcome,
+    pub alarm: Option<String>,
+    pub errors: Vec<JobLineError>,
+    pub overrides: Vec<OverrideChange>, // The first is at the start, if the overrides weren't all 100%.
+    pub artifacts: Vec<JobArtifact>,    // Files saved with the job
+}
+// A file saved with a job, downloaded from DOWNLOAD_RESULTS/<job>/<name>.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct JobArtifact {
+    pub name: String,
+    pub content_type: String,
+    pub size: u64,
+}
+// Ways to download a job's probe results; see EXPORT_PROBES.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ProbeExportFormat {
+    Csv,      // x,y,z,success for every probe
+    DepthMap, // A JSON list of DepthMapPoint
+    HeightMap,
+}
+impl ProbeExportFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProbeExportFormat::Csv => "csv",
+            ProbeExportFormat::DepthMap => "depth_map",
+            ProbeExportFormat::HeightMap => "height_map",
+        }
+    }
+}
+// As read by gcode-playground: how far the surface at (x, y) is above the first successful probe.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct DepthMapPoint {
+    pub x: f64,
+    pub y: f64,
+    pub depth_offset: f64,
+}
+// Probed surface heights on a grid, in machine coordinates; heights[row][column] is at (xs[column], ys[row]).
+// Both xs and ys are increasing.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct HeightMap {
+    pub xs: Vec<f64>,
+    pub ys: Vec<f64>,
+    pub heights: Vec<Vec<f64>>,
+}
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct JobSummary {
+    pub id: String,
+    pub source: JobSource,
+    pub start_time: chrono::DateTime<Utc>,
+    pub end_time: Option<chrono::DateTime<Utc>>,
+    pub outcome: JobOutcome,
+    pub error_count: usize,
+}
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct QueryJobHistory {
+    pub path: Option<String>, // Part of the file's path
+    pub outcomes: Option<Vec<JobOutcome>>, // All outcomes if absent
+    pub start: Option<chrono::DateTime<Utc>>, // Of the job
+    pub end: Option<chrono::DateTime<Utc>>,
+    pub limit: Option<usize>,
+}
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct MachineLogQueryResult {
+    pub entries: Vec<MachineLogEntry>, // Oldest first
+    pub truncated: bool, // More entries matched than the limit allowed
+}
+// Renames or moves; "to" must not exist yet.
+#[derive(Serialize, Deserialize)]
+pub struct MoveGcodeFile {
+    pub from: String,
+    pub to: String,
+}
+// Copies a file or, recursively, a directory; "to" must not exist yet.
+#[derive(Serialize, Deserialize)]
+pub struct CopyGcodeFile {
+    pub from: String,
+    pub to: String,
+}
+// Something deleted from the G-code library.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct TrashEntry {
+    pub id: String,
+    pub name: String,
+    pub original_path: Option<String>, // Unknown for entries deleted before this was recorded.
+    pub deleted_at: chrono::DateTime<Utc>,
+    pub is_directory: bool,
+}
+#[derive(Serialize, Deserialize)]
+pub struct RestoreTrashEntry {
+    pub id: String,
+    pub destination: Option<String>, // Defaults to the original path.
+}
+#[derive(Serialize, Deserialize)]
+pub struct PurgeTrash {
+    pub older_than_days: u32,
+}
+// What UPLOAD_GCODE_FILE did with each file it was given; archives give one entry per file inside.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct UploadReport {
+    pub files: Vec<UploadedFile>,
+    pub skipped: Vec<String>, // Archive entries that weren't plain files or would land outside the target.
+}
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct UploadedFile {
+    pub path: String,
+    pub stored: bool,  // False if it was rejected for its errors.
+    pub is_gcode: bool, // Other files, like setup sheets, aren't checked.
+    pub errors: Vec<GcodeParseIssue>, // Only the first few; see error_count.
+    pub error_count: usize,
+    pub warnings: Vec<GcodeParseIssue>,
 }
 #[derive(Serialize, Deserialize)]
 pub struct ExamineGcodeFile {
     pub path: String,
 }
+#[derive(Serialize, Deserialize)]
+pub struct PreviewGcodeFile {
+    pub path: String,
+    pub max_points: Option<usize>, // The server picks a budget if absent.
+}
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PreviewMoveKind {
+    Rapid,
+    Feed,
+    Arc, // Arcs are split into several points, all with the arc's line.
+    Probe,
+}
+// How the toolpath got to a point.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PreviewMove {
+    pub kind: PreviewMoveKind,
+    pub line: u32, // 1-based; 0 for the starting point.
+    pub feed: Option<f32>,
+}
+/*
+    The points a toolpath passes through, sent as binary since big files have hundreds of thousands of them.
+The layout (little endian) is: b"TPV1", the axis count n as u32, n axis letters, the point count m as u32,
+then n * m f32 coordinates point by point, then m moves of a u8 kind, a u32 line, and an f32 feed (NaN if
+unknown). Coordinates come first and together so they can go to the GPU as they are.
+*/
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct ToolpathPreview {
+    pub axis_letters: String,
+    pub coordinates: Vec<f32>, // axis_letters.len() per point
+    pub moves: Vec<PreviewMove>, // One per point
+}
+const PREVIEW_MAGIC: &[u8; 4] = b"TPV1";
+impl PreviewMoveKind {
+    fn to_byte(self) -> u8 {
+        match self {
+            PreviewMoveKind::Rapid => 0,
+            PreviewMoveKind::Feed => 1,
+            PreviewMoveKind::Arc => 2,
+            PreviewMoveKind::Probe => 3,
+        }
+    }
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(PreviewMoveKind::Rapid),
+            1 => Some(PreviewMoveKind::Feed),
+            2 => Some(PreviewMoveKind::Arc),
+            3 => Some(PreviewMoveKind::Probe),
+            _ => None,
+        }
+    }
+}
+impl ToolpathPreview {
+    pub fn axis_count(&self) -> usize {
+        self.axis_letters.len()
+    }
+    pub fn point(&self, index: usize) -> &[f32] {
+        let axes = self.axis_count();
+        &self.coordinates[index * axes..(index + 1) * axes]
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(12 + self.axis_letters.len() + 4 * self.coordinates.len() + 9 * self.moves.len());
+        bytes.extend_from_slice(PREVIEW_MAGIC);
+        bytes.extend_from_slice(&(self.axis_letters.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(self.axis_letters.as_bytes());
+        bytes.extend_from_slice(&(self.moves.len() as u32).to_le_bytes());
+        for coordinate in &self.coordinates {
+            bytes.extend_from_slice(&coordinate.to_le_bytes());
+        }
+        for preview_move in &self.moves {
+            bytes.push(preview_move.kind.to_byte());
+            bytes.extend_from_slice(&preview_move.line.to_le_bytes());
+            bytes.extend_from_slice(&preview_move.feed.unwrap_or(f32::NAN).to_le_bytes());
+        }
+        bytes
+    }
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self, String> {
+        fn take<'a>(bytes: &mut &'a [u8], count: usize) -> Result<&'a [u8], String> {
+            if bytes.len() < count {
+                return Err("toolpath preview is cut short".to_string());
+            }
+            let (taken, rest) = bytes.split_at(count);
+            *bytes = rest;
+            Ok(taken)
+        }
+        fn take_u32(bytes: &mut &[u8]) -> Result<u32, String> {
+            Ok(u32::from_le_bytes(take(bytes, 4)?.try_into().unwrap()))
+        }
+        fn take_f32(bytes: &mut &[u8]) -> Result<f32, String> {
+            Ok(f32::from_le_bytes(take(bytes, 4)?.try_into().unwrap()))
+        }
+        if take(&mut bytes, 4)? != PREVIEW_MAGIC {
+            return Err("not a toolpath preview".to_string());
+        }
+        let axis_count = take_u32(&mut bytes)? as usize;
+        let axis_letters = String::from_utf8(take(&mut bytes, axis_count)?.to_vec()).map_err(|_| "bad axis letters".to_string())?;
+        let point_count = take_u32(&mut bytes)? as usize;
+        // The counts come from the data, so on 32 bit targets the length they imply can overflow.
+        let expected_length = axis_count.checked_mul(4)
+            .and_then(|point_size| point_size.checked_add(9))
+            .and_then(|point_size| point_size.checked_mul(point_count))
+            .ok_or_else(|| "toolpath preview is too long".to_string())?;
+        if bytes.len() != expected_length {
+            return Err("toolpath preview is the wrong length".to_string());
+        }
+        let coordinates = (0..point_count * axis_count).map(|_| take_f32(&mut bytes)).collect::<Result<_, _>>()?;
+        let moves = (0..point_count).map(|_| {
+            let kind = PreviewMoveKind::from_byte(take(&mut bytes, 1)?[0]).ok_or_else(|| "bad move kind".to_string())?;
+            let line = take_u32(&mut bytes)?;
+            let feed = Some(take_f32(&mut bytes)?).filter(|feed| !feed.is_nan());
+            Ok(PreviewMove { kind, line, feed })
+        }).collect::<Result<_, String>>()?;
+        Ok(ToolpathPreview { axis_letters, coordinates, moves })
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MachineLogKind {
+    Sent,
+    Received,
+    Warning,
+    Comment,
+}
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct MachineLogEntry {
+    pub time: chrono::DateTime<Utc>,
+    pub kind: MachineLogKind,
+    pub message: String,
+}
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct MachineLogFile {
+    pub name: String,
+    pub size: u64,
+}
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct QueryMachineLog {
+    pub start: Option<chrono::DateTime<Utc>>,
+    pub end: Option<chrono::DateTime<Utc>>,
+    pub kinds: Option<Vec<MachineLogKind>>, // All kinds if absent
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct CommandHistoryEntry {
+    pub command: String,
+    pub time: chrono::DateTime<Utc>,
+}
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Debug)]
+pub struct CommandHistory {
+    pub entries: Vec<CommandHistoryEntry>, // Newest first, each command once
+    pub favourites: Vec<String>,
+}
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct QueryCommandHistory {
+    pub user: String,
+}
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SendCommand {
+    pub user: String,
+    pub command: String,
+}
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SetFavouriteCommand {
+    pub user: String,
+    pub command: String,
+    pub favourite: bool,
+}
 
 //////
 // Job
 //////
 pub const RUN_GCODE_FILE: &str = "/job/run_file";
+// Multipart: "file", "filename", and optionally "reject_invalid" = "true" and "uploader". Zip and tar
+// archives are unpacked into a directory named after the archive. Answers with an UploadReport.
 pub const UPLOAD_GCODE_FILE: &str = "/job/upload_file";
 pub const CREATE_GCODE_DIRECTORY: &str = "/job/create_directory";
 pub const DELETE_GCODE_FILE: &str = "/job/delete_file";
 pub const LIST_GCODE_FILES: &str = "/job/list_files";
 pub const EXAMINE_LINES_IN_GCODE_FILE: &str = "/job/examine_lines_in_file";
+pub const PREVIEW_GCODE_FILE: &str = "/job/preview"; // Takes a PreviewGcodeFile; answers with a binary ToolpathPreview
 pub const DOWNLOAD_GCODE: &str = "/job/download_file";
+pub const MOVE_GCODE_FILE: &str = "/job/move_file";
+pub const COPY_GCODE_FILE: &str = "/job/copy_file";
+// Earlier versions of files
+pub const LIST_GCODE_VERSIONS: &str = "/job/versions/list"; // Followed by /<path>; oldest first
+pub const DOWNLOAD_GCODE_VERSION: &str = "/job/versions/download"; // Followed by /<hash>/<path>
+pub const DIFF_GCODE_VERSIONS: &str = "/job/versions/diff"; // Answers with a unified diff
+pub const RESTORE_GCODE_VERSION: &str = "/job/versions/restore";
+// Deleted files
+pub const LIST_TRASH: &str = "/trash";
+pub const RESTORE_FROM_TRASH: &str = "/trash/restore";
+pub const PURGE_TRASH: &str = "/trash/purge";
 
 /////
 // Debug utilities
 /////
 pub const SEND_RAW_GCODE: &str = "/debug/send";
+// Command history, kept per user on the server
+pub const COMMAND_HISTORY: &str = "/debug/history"; // Takes a QueryCommandHistory; answers with a CommandHistory
+pub const COMMAND_HISTORY_USERS: &str = "/debug/history/users";
+pub const SEND_COMMAND: &str = "/debug/history/send"; // Like SEND_RAW_GCODE, but takes a SendCommand and records it
+pub const FAVOURITE_COMMAND: &str = "/debug/history/favourite"; // Takes a SetFavouriteCommand
 // Status
 pub const LISTEN_TO_RAW_MACHINE: &str = "/debug/listen_raw";
-pub const LISTEN_TO_JOB_STATUS: &str = "/debug/listen_status";
-pub const LISTEN_TO_MACHINE_STATUS: &str = "/debug/listen_position";
+pub const LISTEN_TO_JOB_STATUS: &str = "/debug/listen_status"; // Left for the React frontend; the web client watches MACHINE_CONNECTION
+pub const MACHINE_CONNECTION: &str = "/connection"; // Carries a websocket::MachineConnection
+// Persistent log
+pub const LIST_MACHINE_LOGS: &str = "/debug/logs";
+pub const DOWNLOAD_MACHINE_LOG: &str = "/debug/logs/download";
+pub const QUERY_MACHINE_LOGS: &str = "/debug/logs/query";
 
 //////
 // Commands
@@ -76,6 +497,7 @@ pub const COMMAND_PAUSE: &str = "/command/pause";
 pub const COMMAND_RESUME: &str = "/command/resume";
 pub const COMMAND_STOP: &str = "/command/stop";
 pub const COMMAND_RESET: &str = "/command/reset";
+pub const COMMAND_RECOVER_FROM_ALARM: &str = "/command/recover_from_alarm"; // Takes a grbl::AlarmRecovery
 pub const FEED_OVERRIDE: OverrideControl = OverrideControl {
     reset: "/command/override/feed/reset",
     plus_10: "/command/override/feed/plus10",
@@ -95,13 +517,40 @@ pub const RAPID_OVERRIDE: RapidOverride = RapidOverride {
     half: "/command/override/rapid/half",
     quarter: "/command/override/rapid/quarter",
 };
+// These respond with 400 and a reason if the machine's current state doesn't allow them.
+pub const COMMAND_TOGGLE_SPINDLE_STOP: &str = "/command/toggle_spindle_stop"; // Only while held
+pub const COMMAND_TOGGLE_FLOOD_COOLANT: &str = "/command/coolant/toggle_flood";
+pub const COMMAND_TOGGLE_MIST_COOLANT: &str = "/command/coolant/toggle_mist";
+pub const COMMAND_SAFETY_DOOR: &str = "/command/safety_door";
+pub const COMMAND_JOG_CANCEL: &str = "/command/jog_cancel"; // Stops a jog and drops the jogs queued after it
+pub const COMMAND_MACRO: &str = "/command/macro"; // Followed by /<index>, 0 to 3; FluidNC only
+
+///////
+// Controller settings
+///////
+// GET reads "$$" from the controller; POST validates and writes a Vec<grbl::GrblSetting>, then answers
+// with the settings read back. Both need the machine to be free of jobs.
+pub const CONTROLLER_SETTINGS: &str = "/settings";
+pub const SETTINGS_SNAPSHOT: &str = "/settings/snapshot"; // GET answers with an Option<SettingsSnapshot>; PUT takes a Vec<grbl::GrblSetting>
+pub const DOWNLOAD_SETTINGS_BACKUP: &str = "/settings/backup"; // The controller's settings as "$<index>=<value>" lines
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct SettingsSnapshot {
+    pub time: chrono::DateTime<Utc>,
+    pub settings: Vec<crate::grbl::GrblSetting>,
+}
 
 ///////
 // Job Results
 ///////
 
 pub const LIST_RESULTS: &str = "/results";
-pub const DOWNLOAD_RESULTS: &str = "/results/download";
+pub const DOWNLOAD_RESULTS: &str = "/results/download"; // Followed by /<job>/<name>; just /<job> gives its probes.json
+pub const LIST_ARTIFACTS: &str = "/results/artifacts"; // Followed by /<job>; answers with a list of JobArtifact
+// Followed by /<job>/<format>, as given by ProbeExportFormat::as_str. Height maps need the probes to form a grid.
+pub const EXPORT_PROBES: &str = "/results/probes";
+pub const JOB_HISTORY: &str = "/job/history"; // Takes a QueryJobHistory; answers with a list of JobSummary, newest first
+pub const JOB_RECORD: &str = "/job/history/record"; // Followed by /<id>
 
 ///////
 // MISC
@@ -121,7 +570,7 @@ called bed coordinates. We then consider two sorts of offsets:
 Generally, we expect tool coordinates to be consistent except for the Z height and bed coordinates to vary. This
 can express the ideas of having multiple spindles pretty easily.
 */
-#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 #[serde(transparent)]
 pub struct Vec3(pub [f64; 3]);
 #[derive(Serialize, Deserialize, Default, Clone)]
@@ -129,7 +578,7 @@ pub struct Offsets {
     pub tools: HashMap<String, Vec3>,
     pub workpieces: HashMap<String, Vec3>,
 }
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
 pub enum OffsetKind {
     Tool,  // Machine coordinates -> Bed coordinates (via tool)
     Workpiece, // Bed coordinates -> Work coordinates
@@ -151,9 +600,75 @@ pub struct SavedPosition {
     pub label: String,
     pub position: Vec3,
 }
+// Changes one offset of a tool and workpiece pair so that, with both applied, the machine position lands on
+// the reference coordinates. Axes without a reference keep their offset.
+#[derive(Serialize, Deserialize)]
+pub struct AlignCoordinateOffset {
+    pub machine_position: Vec3,
+    pub tool: String,
+    pub workpiece: String,
+    pub update: OffsetKind, // The other one must already exist.
+    pub reference: [Option<f64>; 3],
+}
+#[derive(Serialize, Deserialize)]
+pub struct ApplyCoordinateOffsets {
+    pub tool: String,
+    pub workpiece: String,
+}
 
 pub const OFFSETS: &str = "/coords/offsets";
 pub const POSITIONS: &str = "/coords/positions";
+pub const ALIGN_OFFSET: &str = "/coords/align"; // Answers with the updated Offsets
+pub const APPLY_OFFSETS: &str = "/coords/apply"; // Sets the controller's G54 to a tool and workpiece pair
+
+/*
+    Probing routines. Each runs as a job starting from wherever the tool is, and finds a feature in machine
+coordinates: a surface height, an edge, a corner or the center of a bore.
+*/
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ProbeAxis {
+    X,
+    Y,
+}
+// The direction the tool moves to reach the surface.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ProbeApproach {
+    Positive,
+    Negative,
+}
+impl ProbeApproach {
+    pub fn sign(&self) -> f64 {
+        match self {
+            ProbeApproach::Positive => 1.0,
+            ProbeApproach::Negative => -1.0,
+        }
+    }
+}
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(tag = "kind")]
+pub enum ProbeRoutine {
+    ZTouchOff { plate_thickness: f64 }, // Probes down onto a plate lying on the surface.
+    Edge { axis: ProbeAxis, approach: ProbeApproach },
+    // Starts outside the corner at probing depth; inset is how far past the corner each face is probed.
+    Corner { x_approach: ProbeApproach, y_approach: ProbeApproach, inset: f64 },
+    BoreCenter, // Starts inside the bore at probing depth.
+}
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct RunProbeRoutine {
+    pub routine: ProbeRoutine,
+    pub tool_diameter: f64,
+    pub max_travel: f64, // How far each search may go
+    pub fast_feed: f64,  // mm/min, for finding the surface
+    pub slow_feed: f64,  // mm/min, for measuring it
+    pub retract: f64,    // How far to back off between passes
+}
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ProbeRoutineResult {
+    pub found: [Option<f64>; 3], // Machine coordinates of the feature, on the axes the routine measures
+    pub contacts: Vec<Vec3>,     // Where the probe triggered on each slow pass
+    pub diameter: Option<f64>,   // Of a bore
+}
+pub const RUN_PROBE_ROUTINE: &str = "/probe/run"; // Takes a RunProbeRoutine; answers with a ProbeRoutineResult when done
 
 /*
 post! {
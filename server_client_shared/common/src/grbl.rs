@@ -1,3 +1,4 @@
+use chrono::{DateTime, Utc};
 use serde::{Serialize, Deserialize};
 
 // See: the Real-time Status Reports section at:  https://github.com/gnea/grbl/blob/master/doc/markdown/interface.md
@@ -14,6 +15,84 @@ pub enum GrblState {
     Home,
     Sleep,
 }
+// Ways out of an alarm that the server may offer, depending on the alarm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AlarmRecovery {
+    Unlock, // $X
+    Home,   // $H
+}
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ActiveAlarm {
+    pub code: Option<u64>, // None if the machine was found in alarm without reporting why (e.g. on startup)
+    pub text: String,
+    pub requires_reset: bool, // The controller must be reset before any recovery will be accepted.
+    pub recovery: Vec<AlarmRecovery>,
+}
+// Input pins reported as triggered by "Pn:"; Grbl leaves the field out when none are.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GrblPins {
+    pub x_limit: bool,
+    pub y_limit: bool,
+    pub z_limit: bool,
+    pub a_limit: bool,
+    pub b_limit: bool,
+    pub c_limit: bool,
+    pub probe: bool,
+    pub door: bool,
+    pub hold: bool,
+    pub soft_reset: bool,
+    pub cycle_start: bool,
+}
+impl GrblPins {
+    pub fn from_report(pins: &str) -> Self {
+        let has = |letter| pins.contains(letter);
+        GrblPins {
+            x_limit: has('X'),
+            y_limit: has('Y'),
+            z_limit: has('Z'),
+            a_limit: has('A'),
+            b_limit: has('B'),
+            c_limit: has('C'),
+            probe: has('P'),
+            door: has('D'),
+            hold: has('H'),
+            soft_reset: has('R'),
+            cycle_start: has('S'),
+        }
+    }
+    pub fn any_limit(&self) -> bool {
+        self.x_limit || self.y_limit || self.z_limit || self.a_limit || self.b_limit || self.c_limit
+    }
+}
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SpindleDirection {
+    #[default]
+    Off,
+    Clockwise,        // M3
+    CounterClockwise, // M4
+}
+// Spindle and coolant state reported by "A:".
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GrblAccessories {
+    pub spindle: SpindleDirection,
+    pub flood_coolant: bool,
+    pub mist_coolant: bool,
+}
+impl GrblAccessories {
+    pub fn from_report(accessories: &str) -> Self {
+        GrblAccessories {
+            spindle: if accessories.contains('S') {
+                SpindleDirection::Clockwise
+            } else if accessories.contains('C') {
+                SpindleDirection::CounterClockwise
+            } else {
+                SpindleDirection::Off
+            },
+            flood_coolant: accessories.contains('F'),
+            mist_coolant: accessories.contains('M'),
+        }
+    }
+}
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GrblFullInfo {
     pub state: GrblState,
@@ -23,9 +102,145 @@ pub struct GrblFullInfo {
     pub spindle_override: u8,
     pub rapid_override: u8,
     pub probe: bool,
+    #[serde(default)]
+    pub alarm: Option<ActiveAlarm>,
+    // None until the controller has reported them (they can be disabled in Grbl's $10 mask).
+    #[serde(default)]
+    pub current_feed: Option<f64>,
+    #[serde(default)]
+    pub current_spindle: Option<f64>,
+    #[serde(default)]
+    pub planner_blocks_available: Option<u64>,
+    #[serde(default)]
+    pub rx_bytes_available: Option<u64>,
+    #[serde(default)]
+    pub line_number: Option<u64>, // Of the block being executed, if it had an N word.
+    #[serde(default)]
+    pub pins: GrblPins,
+    #[serde(default)]
+    pub accessories: GrblAccessories,
 }
 impl GrblFullInfo {
     pub fn work_position(&self) -> Vec<f64> {
         self.machine_position.iter().zip(self.work_coordinate_offset.iter()).map(|(x, y)| x - y).collect()
     }
-}
\ No newline at end of file
+}
+// Sent as soon as the machine is seen to move between states (e.g. Idle to Run), ignoring sub-state codes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MachineStateChange {
+    pub time: DateTime<Utc>,
+    pub from: GrblState,
+    pub to: GrblState,
+}
+
+/*
+    Controller settings, as listed by "$$" and changed with "$<index>=<value>". Descriptions follow the
+settings section of the Grbl 1.1 docs; the axis settings include a fourth (A) axis, which stock Grbl
+leaves out but the larger boards report.
+*/
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GrblSetting {
+    pub index: u32,
+    pub value: String, // As the controller reports it
+}
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GrblSettingKind {
+    Boolean,
+    Integer { min: i64, max: i64 },
+    Decimal, // Non-negative
+}
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GrblSettingInfo {
+    pub index: u32,
+    pub name: &'static str,
+    pub unit: &'static str,
+    pub kind: GrblSettingKind,
+    pub description: &'static str,
+}
+const fn setting(index: u32, name: &'static str, unit: &'static str, kind: GrblSettingKind, description: &'static str) -> GrblSettingInfo {
+    GrblSettingInfo { index, name, unit, kind, description }
+}
+const MASK: GrblSettingKind = GrblSettingKind::Integer { min: 0, max: 255 };
+use GrblSettingKind::{Boolean, Decimal};
+pub const GRBL_SETTINGS: &[GrblSettingInfo] = &[
+    setting(0, "Step pulse time", "microseconds", GrblSettingKind::Integer { min: 3, max: 255 }, "Length of each step pulse; keep it as short as the stepper drivers reliably accept."),
+    setting(1, "Step idle delay", "milliseconds", GrblSettingKind::Integer { min: 0, max: 255 }, "How long the steppers stay enabled after motion ends; 255 keeps them enabled."),
+    setting(2, "Step pulse invert", "mask", MASK, "Inverts the step signal of each axis whose bit is set (X = 1, Y = 2, Z = 4, A = 8)."),
+    setting(3, "Step direction invert", "mask", MASK, "Inverts the direction signal of each axis whose bit is set, so the axis moves the other way."),
+    setting(4, "Invert step enable pin", "boolean", Boolean, "Inverts the stepper enable pin for drivers that are enabled by a high signal."),
+    setting(5, "Invert limit pins", "boolean", Boolean, "Inverts the limit switch inputs; normally they are pulled high and triggered by a switch to ground."),
+    setting(6, "Invert probe pin", "boolean", Boolean, "Inverts the probe input."),
+    setting(10, "Status report options", "mask", MASK, "Fields included in status reports: 1 reports machine instead of work position, 2 adds buffer state."),
+    setting(11, "Junction deviation", "mm", Decimal, "How quickly the machine may take corners; larger values corner faster but harder."),
+    setting(12, "Arc tolerance", "mm", Decimal, "Largest distance between an arc and the straight segments it is cut into."),
+    setting(13, "Report in inches", "boolean", Boolean, "Reports positions and rates in inches instead of millimeters."),
+    setting(20, "Soft limits enable", "boolean", Boolean, "Rejects motion outside the max travel settings. Requires homing."),
+    setting(21, "Hard limits enable", "boolean", Boolean, "Stops everything with an alarm when a limit switch is triggered."),
+    setting(22, "Homing cycle enable", "boolean", Boolean, "Enables the $H homing cycle; the machine then starts locked until homed."),
+    setting(23, "Homing direction invert", "mask", MASK, "Homes each axis whose bit is set toward its negative end instead of its positive end."),
+    setting(24, "Homing locate feed rate", "mm/min", Decimal, "Slow rate used to find the exact position of the limit switches."),
+    setting(25, "Homing search seek rate", "mm/min", Decimal, "Fast rate used to first find the limit switches."),
+    setting(26, "Homing switch debounce delay", "milliseconds", GrblSettingKind::Integer { min: 0, max: 65535 }, "Time to let the switches settle during homing."),
+    setting(27, "Homing switch pull-off distance", "mm", Decimal, "How far to back off the switches after homing; must clear them."),
+    setting(30, "Maximum spindle speed", "RPM", Decimal, "Spindle speed given full PWM output."),
+    setting(31, "Minimum spindle speed", "RPM", Decimal, "Spindle speed given the lowest PWM output."),
+    setting(32, "Laser mode enable", "boolean", Boolean, "Moves continuously through spindle speed changes, for lasers."),
+    setting(100, "X steps per mm", "steps/mm", Decimal, "Steps the X axis takes to move one millimeter."),
+    setting(101, "Y steps per mm", "steps/mm", Decimal, "Steps the Y axis takes to move one millimeter."),
+    setting(102, "Z steps per mm", "steps/mm", Decimal, "Steps the Z axis takes to move one millimeter."),
+    setting(103, "A steps per mm", "steps/mm", Decimal, "Steps the A axis takes to move one unit."),
+    setting(110, "X max rate", "mm/min", Decimal, "Fastest the X axis may move; also the rate of rapid moves."),
+    setting(111, "Y max rate", "mm/min", Decimal, "Fastest the Y axis may move; also the rate of rapid moves."),
+    setting(112, "Z max rate", "mm/min", Decimal, "Fastest the Z axis may move; also the rate of rapid moves."),
+    setting(113, "A max rate", "mm/min", Decimal, "Fastest the A axis may move; also the rate of rapid moves."),
+    setting(120, "X acceleration", "mm/sec^2", Decimal, "Acceleration of the X axis."),
+    setting(121, "Y acceleration", "mm/sec^2", Decimal, "Acceleration of the Y axis."),
+    setting(122, "Z acceleration", "mm/sec^2", Decimal, "Acceleration of the Z axis."),
+    setting(123, "A acceleration", "mm/sec^2", Decimal, "Acceleration of the A axis."),
+    setting(130, "X max travel", "mm", Decimal, "Length of the X axis, for soft limits and the homing search distance."),
+    setting(131, "Y max travel", "mm", Decimal, "Length of the Y axis, for soft limits and the homing search distance."),
+    setting(132, "Z max travel", "mm", Decimal, "Length of the Z axis, for soft limits and the homing search distance."),
+    setting(133, "A max travel", "mm", Decimal, "Length of the A axis, for soft limits and the homing search distance."),
+];
+pub fn setting_info(index: u32) -> Option<&'static GrblSettingInfo> {
+    GRBL_SETTINGS.iter().find(|info| info.index == index)
+}
+// Checks a value before it is sent, giving it back as it should be written.
+pub fn validate_setting(index: u32, value: &str) -> Result<String, String> {
+    let value = value.trim();
+    let info = setting_info(index);
+    let name = info.map_or_else(|| format!("${}", index), |info| format!("${} ({})", index, info.name));
+    let number: f64 = value.parse().ok().filter(|number: &f64| number.is_finite())
+        .ok_or_else(|| format!("{} should be a number, not {:?}", name, value))?;
+    match info.map(|info| info.kind) {
+        Some(GrblSettingKind::Boolean) if number != 0.0 && number != 1.0 => Err(format!("{} should be 0 or 1", name)),
+        Some(GrblSettingKind::Integer { min, max }) if number.fract() != 0.0 || number < min as f64 || number > max as f64 =>
+            Err(format!("{} should be a whole number from {} to {}", name, min, max)),
+        Some(GrblSettingKind::Boolean | GrblSettingKind::Integer { .. }) => Ok(format!("{}", number as i64)),
+        _ if number < 0.0 => Err(format!("{} can't be negative", name)),
+        _ => Ok(value.to_string()),
+    }
+}
+// Settings backups are plain "$<index>=<value>" lines, as "$$" prints them.
+pub fn format_settings_text(settings: &[GrblSetting]) -> String {
+    settings.iter().map(|setting| format!("${}={}\n", setting.index, setting.value)).collect()
+}
+// Blank lines, "ok" and anything in brackets or parentheses (messages and comments) are skipped.
+pub fn parse_settings_text(text: &str) -> Result<Vec<GrblSetting>, String> {
+    let mut settings = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line == "ok" || line.starts_with('[') || line.starts_with('(') || line.starts_with(';') {
+            continue;
+        }
+        let setting = line.strip_prefix('$')
+            .and_then(|line| line.split_once('='))
+            .and_then(|(index, value)| Some(GrblSetting {
+                index: index.trim().parse().ok()?,
+                value: value.split(['(', ';']).next().unwrap_or_default().trim().to_string(),
+            }))
+            .ok_or_else(|| format!("line {} is not a setting: {:?}", number + 1, line))?;
+        settings.push(setting);
+    }
+    Ok(settings)
+}
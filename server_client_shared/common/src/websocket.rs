@@ -1,12 +1,61 @@
-use protocol_util::{protocol_type, types::Primitive, base::{Callback, ChannelStream, ChannelCoFuture}};
+use protocol_util::{protocol_type, types::{self, Primitive}, base::{Callback, ChannelStream, ChannelCoFuture}};
+use serde::{Serialize, Deserialize};
 
+use crate::{api::{JobStatus, MachineLogEntry}, grbl::{AlarmRecovery, GrblFullInfo, MachineStateChange}};
+
+// A current value and its later updates, until the receiver sends stop.
 #[protocol_type]
 struct Watchable<T> {
     value: T,
     update_stream: ChannelStream<T>,
-    stop: ChannelCoFuture<()>,
+    stop: ChannelCoFuture<types::Tuple<()>>,
 }
 
 pub enum EntryType {
     File, Directory
 }
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverrideCommand {
+    FeedReset,
+    FeedPlus10,
+    FeedPlus1,
+    FeedMinus1,
+    FeedMinus10,
+    SpindleReset,
+    SpindlePlus10,
+    SpindlePlus1,
+    SpindleMinus1,
+    SpindleMinus10,
+    RapidReset,
+    RapidHalf,
+    RapidQuarter,
+}
+// Everything the COMMAND_* routes can do.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum MachineCommand {
+    Pause,
+    Resume,
+    Stop,
+    Reset,
+    RecoverFromAlarm(AlarmRecovery),
+    Override(OverrideCommand),
+    ToggleSpindleStop,
+    ToggleFloodCoolant,
+    ToggleMistCoolant,
+    SafetyDoor,
+    Macro(u8),
+}
+
+// The server sends this on MACHINE_CONNECTION_CHANNEL as soon as the MACHINE_CONNECTION websocket opens.
+#[protocol_type]
+struct MachineConnection {
+    status: Watchable<Primitive<GrblFullInfo>>,
+    job: Watchable<Primitive<Option<JobStatus>>>,  // Not "job_status": the Tx type would name its parameter JobStatus.
+    state_changes: ChannelStream<Primitive<MachineStateChange>>,
+    debug_stream: ChannelStream<Primitive<MachineLogEntry>>,
+    // Fails with the reason if the machine can't carry the command out in its current state.
+    command: Callback<Primitive<MachineCommand>, Primitive<Result<(), String>>>,
+}
+// The server's first outgoing channel, carrying a ChannelFuture<MachineConnection>.
+pub const MACHINE_CONNECTION_CHANNEL: u64 = u64::MAX;
@@ -19,7 +19,7 @@ async fn main() {
     create_dir_all("../test_data/deleted_gcode").await.unwrap();
 
     let command_port = machine_mock::socat_port::port_to_command(|input, output| {
-        machine_mock::slow::trivial_machine(input, output)
+        machine_mock::simulated::simulated_machine(Default::default(), input, output)
     }).await.unwrap();
     let mut child = Command::new("cargo")
         .arg("run")
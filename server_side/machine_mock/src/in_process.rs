@@ -0,0 +1,47 @@
+use std::future::Future;
+
+use tokio::io::{duplex, split, DuplexStream, ReadHalf, WriteHalf};
+
+// Bytes that may be in flight in either direction before writes wait on the other side.
+const PIPE_CAPACITY: usize = 4096;
+
+/*
+    In-memory alternative to socat_port: runs the machine on one end of a duplex pipe and returns
+the other end, in the shape a serial port would be opened. The machine sees end-of-file once both
+returned halves are dropped, which is expected to end its task.
+*/
+// Should only be called from within a tokio runtime.
+pub fn port_to_machine<F: Future<Output=()> + Send + 'static>(f: impl FnOnce(ReadHalf<DuplexStream>, WriteHalf<DuplexStream>) -> F) -> (ReadHalf<DuplexStream>, WriteHalf<DuplexStream>) {
+    let (host, machine) = duplex(PIPE_CAPACITY);
+    let (machine_input, machine_output) = split(machine);
+    tokio::spawn(f(machine_input, machine_output));
+    split(host)
+}
+
+#[cfg(test)]
+mod test {
+    use std::pin::pin;
+
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn basic_port_to_machine_test() {
+        for _ in 0..1000 {
+            // Simple echo port
+            let (input, mut output) = port_to_machine(|input, output| async move {
+                let mut input = pin!(input);
+                let mut output = pin!(output);
+                while let Ok(value) = input.read_u8().await {
+                    let value = if (b'a'..=b'y').contains(&value) { value + 1 } else { value };
+                    output.write_u8(value).await.unwrap();
+                }
+            });
+            let mut input_lines = BufReader::new(input).lines();
+            output.write_all(b"abc\n").await.unwrap();
+            let result = input_lines.next_line().await.unwrap();
+            assert_eq!(result, Some("bcd".into()));
+        }
+    }
+}
@@ -1,5 +1,7 @@
 pub mod trivial;
 pub mod slow;
+pub mod simulated;
+pub mod in_process;
 #[cfg(feature = "socat")]
 pub mod socat_port;
 
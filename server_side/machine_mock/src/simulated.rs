@@ -0,0 +1,1317 @@
+/*
+    A simulated Grbl controller. Unlike the trivial machines, it interprets the G-code it is sent:
+lines are parsed into a planner buffer, executed over (simulated) time, and reflected in status
+reports. Realtime bytes, errors and alarms behave as they do on Grbl 1.1 closely enough to test
+streaming, pausing, probing and alarm recovery against.
+*/
+use std::{collections::{BTreeMap, VecDeque}, pin::pin, sync::Arc, time::Duration};
+
+use tokio::{io::{AsyncRead, AsyncWrite, AsyncReadExt, AsyncWriteExt}, select, time::{interval, Instant, MissedTickBehavior}};
+
+use self::{line::{parse_line, parse_words, Line, Word}, motion::{Block, Purpose, Rate, radius_to_offset}};
+
+pub mod line;
+pub mod motion;
+
+const GREETING: &str = "Grbl 1.1h ['$' for help]";
+const TICK: Duration = Duration::from_millis(5);
+
+// Surface touched by the probe, given as the machine Z height of the surface at a machine XY position.
+#[derive(Clone)]
+pub struct ProbeSurface(Arc<dyn Fn(f64, f64) -> f64 + Send + Sync>);
+impl ProbeSurface {
+    pub fn none() -> Self {
+        Self::height_map(|_, _| f64::NEG_INFINITY)
+    }
+    pub fn flat(height: f64) -> Self {
+        Self::height_map(move |_, _| height)
+    }
+    pub fn height_map(height: impl Fn(f64, f64) -> f64 + Send + Sync + 'static) -> Self {
+        ProbeSurface(Arc::new(height))
+    }
+    pub fn is_triggered(&self, position: &[f64]) -> bool {
+        position[2] <= (self.0)(position[0], position[1])
+    }
+}
+
+#[derive(Clone)]
+pub struct SimulatedMachineConfig {
+    pub axis_letters: Vec<u8>,        // At least XYZ, in that order.
+    pub travel_min: Vec<f64>,         // Machine coordinates; the home position is travel_max.
+    pub travel_max: Vec<f64>,
+    pub soft_limits: bool,
+    pub rapid_rate: f64,              // mm/min
+    pub planner_blocks: usize,
+    pub rx_buffer_size: usize,
+    pub hold_deceleration: Duration,  // Time spent in Hold:1 before reaching Hold:0.
+    pub probe_surface: ProbeSurface,
+    pub start_in_alarm: bool,         // As a machine with homing enabled does.
+}
+impl Default for SimulatedMachineConfig {
+    fn default() -> Self {
+        SimulatedMachineConfig {
+            axis_letters: b"XYZ".to_vec(),
+            travel_min: vec![-300.0, -300.0, -100.0],
+            travel_max: vec![0.0, 0.0, 0.0],
+            soft_limits: true,
+            rapid_rate: 3000.0,
+            planner_blocks: 15,
+            rx_buffer_size: 128,
+            hold_deceleration: Duration::from_millis(20),
+            probe_surface: ProbeSurface::flat(-50.0),
+            start_in_alarm: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    Run,
+    Hold(Option<f64>), // Seconds of deceleration left, if still stopping.
+    Jog,
+    Alarm,
+    Door(Option<f64>),
+    Home,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Motion {
+    Rapid,
+    Linear,
+    Arc { clockwise: bool },
+    Probe { toward: bool, required: bool },
+    Cancel,
+}
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Spindle {
+    Off,
+    Clockwise,
+    Counterclockwise,
+}
+#[derive(Debug, Clone, PartialEq)]
+struct ModalState {
+    motion: Motion,
+    plane: (usize, usize),
+    inches: bool,
+    absolute: bool,
+    coordinate_system: usize,
+    feed: Option<f64>, // mm/min
+    spindle: Spindle,
+    spindle_speed: f64,
+    flood: bool,
+    mist: bool,
+}
+impl Default for ModalState {
+    fn default() -> Self {
+        ModalState {
+            motion: Motion::Rapid,
+            plane: (0, 1),
+            inches: false,
+            absolute: true,
+            coordinate_system: 0,
+            feed: None,
+            spindle: Spindle::Off,
+            spindle_speed: 0.0,
+            flood: false,
+            mist: false,
+        }
+    }
+}
+
+// When the "ok" for an accepted line should be sent.
+enum Completion {
+    Now,
+    AfterMotion,
+    Never,
+}
+
+struct GCodeAction {
+    modal: ModalState,
+    coordinate_update: Option<(usize, Vec<f64>)>,
+    g92_offset: Option<Vec<f64>>,
+    block: Option<Block>,
+    pause: bool,
+    program_end: bool,
+}
+
+pub struct SimulatedMachine {
+    config: SimulatedMachineConfig,
+    state: State,
+    position: Vec<f64>,
+    modal: ModalState,
+    coordinate_systems: [Vec<f64>; 6],
+    g92_offset: Vec<f64>,
+    planner: VecDeque<Block>,
+    partial_line: Vec<u8>,
+    lines: VecDeque<Vec<u8>>,
+    awaiting_ok: bool,
+    awaiting_reset: bool,
+    feed_override: u8,
+    rapid_override: u8,
+    spindle_override: u8,
+    spindle_stopped: bool,
+    wco_counter: u8,
+    override_counter: u8,
+    last_probe: Option<(Vec<f64>, bool)>,
+    settings: BTreeMap<u32, String>,
+    output: Vec<u8>,
+}
+
+impl SimulatedMachine {
+    pub fn new(config: SimulatedMachineConfig) -> Self {
+        assert!(config.axis_letters.len() >= 3, "the simulated machine needs at least XYZ axes");
+        let axes = config.axis_letters.len();
+        let settings = default_settings(&config);
+        let mut machine = SimulatedMachine {
+            state: if config.start_in_alarm { State::Alarm } else { State::Idle },
+            position: home_position(&config),
+            modal: ModalState::default(),
+            coordinate_systems: Default::default(),
+            g92_offset: vec![0.0; axes],
+            planner: VecDeque::new(),
+            partial_line: Vec::new(),
+            lines: VecDeque::new(),
+            awaiting_ok: false,
+            awaiting_reset: false,
+            feed_override: 100,
+            rapid_override: 100,
+            spindle_override: 100,
+            spindle_stopped: false,
+            wco_counter: 0,
+            override_counter: 0,
+            last_probe: None,
+            settings,
+            output: Vec::new(),
+            config,
+        };
+        machine.coordinate_systems = std::array::from_fn(|_| vec![0.0; axes]);
+        machine.greet();
+        machine
+    }
+
+    /*
+        Public interface
+    */
+    pub fn receive_byte(&mut self, byte: u8) {
+        match byte {
+            b'?' => self.report_status(),
+            b'!' => self.feed_hold(),
+            b'~' => self.cycle_start(),
+            0x18 => self.reset(),
+            0x84 => self.safety_door(),
+            0x85 => self.jog_cancel(),
+            0x90..=0x9D => self.override_speed(byte),
+            0x9E => {
+                if self.state == State::Hold(None) {
+                    self.spindle_stopped = !self.spindle_stopped;
+                    self.override_counter = 0;
+                }
+            }
+            0xA0 | 0xA1 => {
+                if matches!(self.state, State::Idle | State::Run | State::Hold(_)) {
+                    if byte == 0xA0 {
+                        self.modal.flood = !self.modal.flood;
+                    } else {
+                        self.modal.mist = !self.modal.mist;
+                    }
+                    self.override_counter = 0;
+                }
+            }
+            // Grbl ignores other extended bytes; carriage returns are treated as whitespace.
+            0x80..=0xFF | b'\r' => (),
+            _ if self.rx_used() >= self.config.rx_buffer_size => (), // Overflow; the byte is lost.
+            b'\n' => {
+                self.lines.push_back(std::mem::take(&mut self.partial_line));
+                self.process_lines();
+            }
+            _ => self.partial_line.push(byte),
+        }
+    }
+    pub fn receive(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.receive_byte(*byte);
+        }
+    }
+    pub fn advance(&mut self, elapsed: Duration) {
+        let mut remaining = elapsed.as_secs_f64();
+        if let State::Hold(Some(left)) | State::Door(Some(left)) = &mut self.state {
+            *left -= remaining;
+            if *left <= 0.0 {
+                self.state = match self.state {
+                    State::Hold(_) => State::Hold(None),
+                    _ => State::Door(None),
+                };
+            }
+        }
+        while remaining > 0.0 && matches!(self.state, State::Run | State::Jog | State::Home) {
+            let Some(block) = self.planner.front() else { break };
+            let speed = self.block_speed(block);
+            let block = self.planner.front_mut().unwrap();
+            let previous = block.progress;
+            let step = (speed * remaining).min(block.length - block.progress);
+            block.progress += step;
+            remaining -= if speed > 0.0 { step / speed } else { remaining };
+            let contact = match block.purpose {
+                Purpose::Probe { toward, .. } => find_contact(block, previous, toward, &self.config.probe_surface),
+                _ => None,
+            };
+            if let Some(contact) = contact {
+                block.progress = contact;
+                self.position = block.position();
+                let block = self.planner.pop_front().unwrap();
+                self.finish_block(block, true);
+            } else if block.is_done() {
+                self.position = block.end.clone();
+                let block = self.planner.pop_front().unwrap();
+                self.finish_block(block, false);
+            } else {
+                self.position = block.position();
+            }
+            // Lines waiting on the planner to drain may start new motion within the same interval.
+            self.settle_if_drained();
+            self.process_lines();
+        }
+        self.settle_if_drained();
+        self.process_lines();
+    }
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+    pub fn machine_position(&self) -> &[f64] {
+        &self.position
+    }
+    pub fn work_position(&self) -> Vec<f64> {
+        let offset = self.work_coordinate_offset();
+        self.position.iter().zip(offset.iter()).map(|(position, offset)| position - offset).collect()
+    }
+
+    /*
+        Realtime commands
+    */
+    fn feed_hold(&mut self) {
+        let deceleration = self.config.hold_deceleration.as_secs_f64();
+        match self.state {
+            State::Run => self.state = State::Hold(Some(deceleration)),
+            State::Jog => self.jog_cancel(),
+            State::Idle => self.state = State::Hold(None),
+            _ => (),
+        }
+    }
+    fn cycle_start(&mut self) {
+        match self.state {
+            State::Hold(None) | State::Door(None) | State::Idle => {
+                self.spindle_stopped = false;
+                self.state = if self.planner.is_empty() { State::Idle } else { State::Run };
+                self.process_lines();
+            }
+            _ => (),
+        }
+    }
+    fn safety_door(&mut self) {
+        let deceleration = self.config.hold_deceleration.as_secs_f64();
+        match self.state {
+            State::Run => self.state = State::Door(Some(deceleration)),
+            State::Hold(left) => self.state = State::Door(left),
+            State::Jog => {
+                self.jog_cancel();
+                self.state = State::Door(None);
+            }
+            State::Idle => self.state = State::Door(None),
+            _ => (),
+        }
+    }
+    fn jog_cancel(&mut self) {
+        if self.state == State::Jog {
+            self.planner.clear();
+            self.state = State::Idle;
+            self.process_lines();
+        }
+    }
+    fn override_speed(&mut self, byte: u8) {
+        fn adjust(value: u8, change: i16) -> u8 {
+            (value as i16 + change).clamp(10, 200) as u8
+        }
+        match byte {
+            0x90 => self.feed_override = 100,
+            0x91 => self.feed_override = adjust(self.feed_override, 10),
+            0x92 => self.feed_override = adjust(self.feed_override, -10),
+            0x93 => self.feed_override = adjust(self.feed_override, 1),
+            0x94 => self.feed_override = adjust(self.feed_override, -1),
+            0x95 => self.rapid_override = 100,
+            0x96 => self.rapid_override = 50,
+            0x97 => self.rapid_override = 25,
+            0x99 => self.spindle_override = 100,
+            0x9A => self.spindle_override = adjust(self.spindle_override, 10),
+            0x9B => self.spindle_override = adjust(self.spindle_override, -10),
+            0x9C => self.spindle_override = adjust(self.spindle_override, 1),
+            0x9D => self.spindle_override = adjust(self.spindle_override, -1),
+            _ => return, // 0x98 (extra-low rapid) is not supported.
+        }
+        self.override_counter = 0;
+    }
+    fn reset(&mut self) {
+        let in_motion = matches!(
+            self.state,
+            State::Run | State::Jog | State::Home | State::Hold(Some(_)) | State::Door(Some(_))
+        );
+        if in_motion {
+            self.output_line("ALARM:3");
+        }
+        let alarmed = in_motion || self.awaiting_reset || self.state == State::Alarm;
+        self.planner.clear();
+        self.partial_line.clear();
+        self.lines.clear();
+        self.awaiting_ok = false;
+        self.awaiting_reset = false;
+        self.modal = ModalState::default();
+        self.g92_offset.iter_mut().for_each(|value| *value = 0.0);
+        self.feed_override = 100;
+        self.rapid_override = 100;
+        self.spindle_override = 100;
+        self.spindle_stopped = false;
+        self.state = if alarmed { State::Alarm } else { State::Idle };
+        self.greet();
+    }
+    fn greet(&mut self) {
+        self.wco_counter = 0;
+        self.override_counter = 0;
+        self.output_line(GREETING);
+        if self.state == State::Alarm {
+            self.output_line("[MSG:'$H'|'$X' to unlock]");
+        }
+    }
+    fn report_status(&mut self) {
+        let busy = !matches!(self.state, State::Idle | State::Alarm);
+        let state = match self.state {
+            State::Idle => "Idle".to_string(),
+            State::Run => "Run".to_string(),
+            State::Hold(left) => format!("Hold:{}", left.is_some() as u8),
+            State::Jog => "Jog".to_string(),
+            State::Alarm => "Alarm".to_string(),
+            State::Door(left) => format!("Door:{}", if left.is_some() { 2 } else { 0 }),
+            State::Home => "Home".to_string(),
+        };
+        let mut report = format!(
+            "<{}|MPos:{}|Bf:{},{}",
+            state,
+            format_values(&self.position),
+            self.config.planner_blocks - self.planner.len().min(self.config.planner_blocks),
+            self.config.rx_buffer_size - self.rx_used(),
+        );
+        let moving = matches!(self.state, State::Run | State::Jog | State::Home);
+        if let Some(line_number) = self.planner.front().and_then(|block| block.line_number).filter(|_| moving) {
+            report += &format!("|Ln:{}", line_number);
+        }
+        let feed = match self.planner.front() {
+            Some(block) if moving && block.rate != Rate::Time => self.block_speed(block) * 60.0,
+            _ => 0.0,
+        };
+        report += &format!("|FS:{:.0},{:.0}", feed, self.spindle_output());
+        if self.config.probe_surface.is_triggered(&self.position) {
+            report += "|Pn:P";
+        }
+        if self.wco_counter == 0 {
+            report += &format!("|WCO:{}", format_values(&self.work_coordinate_offset()));
+            self.wco_counter = if busy { 10 } else { 30 };
+        } else {
+            self.wco_counter -= 1;
+        }
+        if self.override_counter == 0 {
+            report += &format!("|Ov:{},{},{}", self.feed_override, self.rapid_override, self.spindle_override);
+            let accessories = [
+                (self.modal.spindle == Spindle::Clockwise && !self.spindle_stopped, 'S'),
+                (self.modal.spindle == Spindle::Counterclockwise && !self.spindle_stopped, 'C'),
+                (self.modal.flood, 'F'),
+                (self.modal.mist, 'M'),
+            ].into_iter().filter(|(active, _)| *active).map(|(_, letter)| letter).collect::<String>();
+            if !accessories.is_empty() {
+                report += &format!("|A:{}", accessories);
+            }
+            self.override_counter = if busy { 10 } else { 20 };
+        } else {
+            self.override_counter -= 1;
+        }
+        report.push('>');
+        self.output_line(&report);
+    }
+
+    /*
+        Line processing
+    */
+    fn process_lines(&mut self) {
+        while !self.awaiting_reset {
+            if self.awaiting_ok {
+                if !self.is_settled() {
+                    return;
+                }
+                self.awaiting_ok = false;
+                self.output_line("ok");
+            }
+            let Some(raw) = self.lines.front() else { return };
+            let line = parse_line(raw);
+            if let Ok(line) = &line {
+                if !self.can_execute(line) {
+                    return;
+                }
+            }
+            self.lines.pop_front();
+            match line.and_then(|line| self.execute(line)) {
+                Ok(Completion::Now) => self.output_line("ok"),
+                Ok(Completion::AfterMotion) => self.awaiting_ok = true,
+                Ok(Completion::Never) => (),
+                Err(code) => self.output_line(&format!("error:{}", code)),
+            }
+        }
+    }
+    fn can_execute(&self, line: &Line) -> bool {
+        let has_room = self.planner.len() < self.config.planner_blocks;
+        match line {
+            Line::Empty => true,
+            Line::System(command) if command.starts_with("J=") => has_room,
+            Line::System(_) => true,
+            Line::GCode(words) if requires_sync(words) => self.is_settled(),
+            Line::GCode(words) if words.iter().any(|word| self.axis_index(word.letter).is_some()) => has_room,
+            Line::GCode(_) => true,
+        }
+    }
+    fn execute(&mut self, line: Line) -> Result<Completion, u64> {
+        match line {
+            Line::Empty => Ok(Completion::Now),
+            Line::System(command) => self.execute_system(&command),
+            Line::GCode(_) if matches!(self.state, State::Alarm | State::Jog) => Err(9),
+            Line::GCode(words) => {
+                let action = self.plan_gcode(&words)?;
+                Ok(self.apply_gcode(action))
+            }
+        }
+    }
+    fn execute_system(&mut self, command: &str) -> Result<Completion, u64> {
+        let idle = self.state == State::Idle;
+        let idle_or_alarm = idle || self.state == State::Alarm;
+        if let Some(jog) = command.strip_prefix("J=") {
+            return match self.state {
+                State::Alarm => Err(9),
+                State::Idle | State::Jog => {
+                    let block = self.plan_jog(&parse_words(jog.as_bytes())?)?;
+                    self.push_block(block);
+                    Ok(Completion::Now)
+                }
+                _ => Err(8),
+            };
+        }
+        match command {
+            "" => {
+                self.output_line("[HLP:$$ $# $G $I $N $x=val $Nx=line $J=line $SLP $C $X $H ~ ! ? ctrl-x]");
+                Ok(Completion::Now)
+            }
+            "X" => {
+                if self.state == State::Alarm {
+                    self.state = State::Idle;
+                    self.output_line("[MSG:Caution: Unlocked]");
+                }
+                Ok(Completion::Now)
+            }
+            "H" if idle_or_alarm => {
+                let home = home_position(&self.config);
+                self.planner.push_back(Block::line(
+                    self.position.clone(), home, Rate::Unaffected(self.config.rapid_rate), Purpose::Home,
+                ));
+                self.state = State::Home;
+                Ok(Completion::AfterMotion)
+            }
+            "$" if idle_or_alarm => {
+                let lines: Vec<String> = self.settings.iter().map(|(key, value)| format!("${}={}", key, value)).collect();
+                lines.iter().for_each(|line| self.output_line(line));
+                Ok(Completion::Now)
+            }
+            "#" => {
+                let mut lines: Vec<String> = self.coordinate_systems.iter().enumerate()
+                    .map(|(index, offset)| format!("[G{}:{}]", 54 + index, format_values(offset)))
+                    .collect();
+                lines.push(format!("[G92:{}]", format_values(&self.g92_offset)));
+                let (probe_position, probe_success) = self.last_probe.clone().unwrap_or((vec![0.0; self.axis_count()], false));
+                lines.push(format!("[PRB:{}:{}]", format_values(&probe_position), probe_success as u8));
+                lines.iter().for_each(|line| self.output_line(line));
+                Ok(Completion::Now)
+            }
+            "G" => {
+                let state = self.parser_state();
+                self.output_line(&state);
+                Ok(Completion::Now)
+            }
+            "I" => {
+                self.output_line("[VER:1.1h.20190825:Simulated]");
+                self.output_line(&format!("[OPT:V,{},{}]", self.config.planner_blocks, self.config.rx_buffer_size));
+                Ok(Completion::Now)
+            }
+            "H" | "$" => Err(8),
+            _ => {
+                let (key, value) = command.split_once('=').ok_or(3u64)?;
+                let key: u32 = key.parse().map_err(|_| 3u64)?;
+                let value: f64 = value.parse().map_err(|_| 2u64)?;
+                if !self.settings.contains_key(&key) {
+                    return Err(3);
+                }
+                if !idle_or_alarm {
+                    return Err(8);
+                }
+                if value < 0.0 {
+                    return Err(4);
+                }
+                self.settings.insert(key, format_setting(key, value));
+                Ok(Completion::Now)
+            }
+        }
+    }
+    fn plan_gcode(&self, words: &[Word]) -> Result<GCodeAction, u64> {
+        const GROUP_MOTION: u32 = 1 << 0;
+        const GROUP_PLANE: u32 = 1 << 1;
+        const GROUP_DISTANCE: u32 = 1 << 2;
+        const GROUP_UNITS: u32 = 1 << 3;
+        const GROUP_COORDINATES: u32 = 1 << 4;
+        const GROUP_NON_MODAL: u32 = 1 << 5;
+        const GROUP_IGNORED: u32 = 1 << 6;
+        const GROUP_STOPPING: u32 = 1 << 7;
+        const GROUP_SPINDLE: u32 = 1 << 8;
+        fn claim(groups: &mut u32, group: u32) -> Result<(), u64> {
+            if *groups & group != 0 {
+                return Err(21);
+            }
+            *groups |= group;
+            Ok(())
+        }
+        fn set_once(slot: &mut Option<f64>, value: f64) -> Result<(), u64> {
+            if slot.replace(value).is_some() {
+                return Err(25);
+            }
+            Ok(())
+        }
+
+        let mut modal = self.modal.clone();
+        let mut groups = 0;
+        let mut explicit_motion = None;
+        let mut non_modal = None;
+        let mut machine_coordinates = false;
+        let mut stopping = None;
+        let (mut feed, mut speed, mut p, mut l, mut r, mut line_number) = (None, None, None, None, None, None);
+        let mut axis_words = vec![None; self.axis_count()];
+        let mut offset_words = [None; 3];
+        for word in words {
+            match word.letter {
+                b'G' => match word.code() {
+                    0 | 10 | 20 | 30 | 382..=385 | 800 => {
+                        claim(&mut groups, GROUP_MOTION)?;
+                        explicit_motion = Some(match word.code() {
+                            0 => Motion::Rapid,
+                            10 => Motion::Linear,
+                            20 => Motion::Arc { clockwise: true },
+                            30 => Motion::Arc { clockwise: false },
+                            382 => Motion::Probe { toward: true, required: true },
+                            383 => Motion::Probe { toward: true, required: false },
+                            384 => Motion::Probe { toward: false, required: true },
+                            385 => Motion::Probe { toward: false, required: false },
+                            _ => Motion::Cancel,
+                        });
+                    }
+                    40 | 100 | 920 | 921 => {
+                        claim(&mut groups, GROUP_NON_MODAL)?;
+                        non_modal = Some(word.code());
+                    }
+                    530 => machine_coordinates = true,
+                    170 | 180 | 190 => {
+                        claim(&mut groups, GROUP_PLANE)?;
+                        modal.plane = match word.code() {
+                            170 => (0, 1),
+                            180 => (2, 0),
+                            _ => (1, 2),
+                        };
+                    }
+                    200 | 210 => {
+                        claim(&mut groups, GROUP_UNITS)?;
+                        modal.inches = word.code() == 200;
+                    }
+                    900 | 910 => {
+                        claim(&mut groups, GROUP_DISTANCE)?;
+                        modal.absolute = word.code() == 900;
+                    }
+                    540 | 550 | 560 | 570 | 580 | 590 => {
+                        claim(&mut groups, GROUP_COORDINATES)?;
+                        modal.coordinate_system = ((word.code() - 540) / 10) as usize;
+                    }
+                    // Feed rate mode, cutter compensation and tool length offsets are accepted and ignored.
+                    940 | 400 | 490 => claim(&mut groups, GROUP_IGNORED)?,
+                    _ => return Err(20),
+                },
+                b'M' => match word.code() {
+                    0 | 10 | 20 | 300 => {
+                        claim(&mut groups, GROUP_STOPPING)?;
+                        stopping = Some(word.code());
+                    }
+                    30 | 40 | 50 => {
+                        claim(&mut groups, GROUP_SPINDLE)?;
+                        modal.spindle = match word.code() {
+                            30 => Spindle::Clockwise,
+                            40 => Spindle::Counterclockwise,
+                            _ => Spindle::Off,
+                        };
+                    }
+                    70 => modal.mist = true,
+                    80 => modal.flood = true,
+                    90 => {
+                        modal.mist = false;
+                        modal.flood = false;
+                    }
+                    _ => return Err(20),
+                },
+                b'F' => set_once(&mut feed, word.value)?,
+                b'S' => set_once(&mut speed, word.value)?,
+                b'P' => set_once(&mut p, word.value)?,
+                b'L' => set_once(&mut l, word.value)?,
+                b'R' => set_once(&mut r, word.value)?,
+                b'N' => set_once(&mut line_number, word.value)?,
+                b'T' => (),
+                b'I' | b'J' | b'K' => set_once(&mut offset_words[(word.letter - b'I') as usize], word.value)?,
+                letter => match self.axis_index(letter) {
+                    Some(index) => set_once(&mut axis_words[index], word.value)?,
+                    None => return Err(20),
+                },
+            }
+        }
+        let scale = if modal.inches { 25.4 } else { 1.0 };
+        let axis_words: Vec<Option<f64>> = axis_words.into_iter().map(|word| word.map(|value| value * scale)).collect();
+        let has_axis_words = axis_words.iter().any(Option::is_some);
+        if let Some(feed) = feed {
+            if feed < 0.0 {
+                return Err(4);
+            }
+            modal.feed = Some(feed * scale);
+        }
+        if let Some(speed) = speed {
+            if speed < 0.0 {
+                return Err(4);
+            }
+            modal.spindle_speed = speed;
+        }
+        if let Some(number) = line_number {
+            if !(1.0..=9_999_999.0).contains(&number) {
+                return Err(27);
+            }
+        }
+
+        let mut action = GCodeAction {
+            modal,
+            coordinate_update: None,
+            g92_offset: None,
+            block: None,
+            pause: matches!(stopping, Some(0) | Some(10)),
+            program_end: matches!(stopping, Some(20) | Some(300)),
+        };
+        let planned = self.planned_position();
+        let system_offset = |action: &GCodeAction| -> Vec<f64> {
+            let system = action.coordinate_update.as_ref()
+                .filter(|(index, _)| *index == action.modal.coordinate_system)
+                .map_or(&self.coordinate_systems[action.modal.coordinate_system], |(_, offset)| offset);
+            system.iter().zip(action.g92_offset.as_ref().unwrap_or(&self.g92_offset)).map(|(a, b)| a + b).collect()
+        };
+        let mut axis_words_used = false;
+        let mut p_used = false;
+        match non_modal {
+            Some(40) => {
+                let seconds = p.ok_or(28u64)?;
+                if seconds < 0.0 {
+                    return Err(4);
+                }
+                p_used = true;
+                action.block = Some(Block::dwell(planned.clone(), seconds));
+            }
+            Some(100) => {
+                let l = l.ok_or(28u64)?;
+                let index = p.ok_or(28u64)?;
+                if index.fract() != 0.0 || !(0.0..=6.0).contains(&index) {
+                    return Err(29);
+                }
+                p_used = true;
+                if !has_axis_words {
+                    return Err(26);
+                }
+                let index = if index == 0.0 { action.modal.coordinate_system } else { index as usize - 1 };
+                let mut offset = self.coordinate_systems[index].clone();
+                for (axis, value) in axis_words.iter().enumerate() {
+                    if let Some(value) = value {
+                        offset[axis] = match l as i64 {
+                            2 => *value,
+                            20 => planned[axis] - self.g92_offset[axis] - value,
+                            _ => return Err(20),
+                        };
+                    }
+                }
+                axis_words_used = true;
+                action.coordinate_update = Some((index, offset));
+            }
+            Some(920) => {
+                if !has_axis_words {
+                    return Err(26);
+                }
+                let system = &self.coordinate_systems[action.modal.coordinate_system];
+                let mut offset = self.g92_offset.clone();
+                for (axis, value) in axis_words.iter().enumerate() {
+                    if let Some(value) = value {
+                        offset[axis] = planned[axis] - system[axis] - value;
+                    }
+                }
+                axis_words_used = true;
+                action.g92_offset = Some(offset);
+            }
+            Some(_) => action.g92_offset = Some(vec![0.0; self.axis_count()]),
+            None => (),
+        }
+        if l.is_some() && non_modal != Some(100) {
+            return Err(36);
+        }
+        if p.is_some() && !p_used {
+            return Err(36);
+        }
+
+        if let Some(motion) = explicit_motion {
+            action.modal.motion = motion;
+        }
+        let performs_motion = if axis_words_used {
+            if explicit_motion.is_some() {
+                return Err(24);
+            }
+            false
+        } else if has_axis_words {
+            if action.modal.motion == Motion::Cancel {
+                return Err(31);
+            }
+            true
+        } else {
+            match explicit_motion {
+                Some(Motion::Arc { .. }) | Some(Motion::Probe { .. }) => return Err(26),
+                _ => false,
+            }
+        };
+        let is_arc = matches!(action.modal.motion, Motion::Arc { .. }) && performs_motion;
+        if !is_arc && (r.is_some() || offset_words.iter().any(Option::is_some)) {
+            return Err(36);
+        }
+        if machine_coordinates && !(performs_motion && matches!(action.modal.motion, Motion::Rapid | Motion::Linear)) {
+            return Err(30);
+        }
+        if !performs_motion {
+            return Ok(action);
+        }
+
+        let offset = system_offset(&action);
+        let mut target = planned.clone();
+        for (axis, value) in axis_words.iter().enumerate() {
+            if let Some(value) = value {
+                target[axis] = if machine_coordinates {
+                    *value
+                } else if action.modal.absolute {
+                    value + offset[axis]
+                } else {
+                    planned[axis] + value
+                };
+            }
+        }
+        let feed = || action.modal.feed.filter(|feed| *feed > 0.0).ok_or(22u64);
+        let mut block = match action.modal.motion {
+            Motion::Rapid => Block::line(planned, target, Rate::Rapid, Purpose::Motion),
+            Motion::Linear => Block::line(planned, target, Rate::Feed(feed()?), Purpose::Motion),
+            Motion::Probe { toward, required } => {
+                if target == planned {
+                    return Err(33);
+                }
+                Block::line(planned, target, Rate::Feed(feed()?), Purpose::Probe { toward, required })
+            }
+            Motion::Arc { clockwise } => {
+                let feed = feed()?;
+                let axes = action.modal.plane;
+                let center_offset = match r {
+                    Some(radius) => radius_to_offset(
+                        (planned[axes.0], planned[axes.1]), (target[axes.0], target[axes.1]), radius * scale, clockwise,
+                    ).ok_or(33u64)?,
+                    None => {
+                        let first = offset_words[axes.0].map(|value| value * scale);
+                        let second = offset_words[axes.1].map(|value| value * scale);
+                        if first.is_none() && second.is_none() {
+                            return Err(35);
+                        }
+                        let offset = (first.unwrap_or(0.0), second.unwrap_or(0.0));
+                        let start_radius = offset.0.hypot(offset.1);
+                        let end_radius = (target[axes.0] - planned[axes.0] - offset.0)
+                            .hypot(target[axes.1] - planned[axes.1] - offset.1);
+                        let difference = (end_radius - start_radius).abs();
+                        if difference > 0.005 && (difference > 0.5 || difference > 0.001 * end_radius) {
+                            return Err(33);
+                        }
+                        offset
+                    }
+                };
+                Block::arc(planned, target, axes, center_offset, clockwise, feed)
+            }
+            Motion::Cancel => unreachable!("checked above"),
+        };
+        block.line_number = line_number.map(|number| number as u64);
+        action.block = Some(block);
+        Ok(action)
+    }
+    fn apply_gcode(&mut self, action: GCodeAction) -> Completion {
+        self.modal = action.modal;
+        if let Some((index, offset)) = action.coordinate_update {
+            self.coordinate_systems[index] = offset;
+            self.wco_counter = 0;
+        }
+        if let Some(offset) = action.g92_offset {
+            self.g92_offset = offset;
+            self.wco_counter = 0;
+        }
+        let mut completion = Completion::Now;
+        if let Some(block) = action.block {
+            match block.purpose {
+                Purpose::Dwell => completion = Completion::AfterMotion,
+                Purpose::Probe { toward, .. } => {
+                    if self.config.probe_surface.is_triggered(&self.position) == toward {
+                        self.raise_alarm(4, false);
+                        return Completion::Now;
+                    }
+                    completion = Completion::AfterMotion;
+                }
+                _ => (),
+            }
+            if block.purpose != Purpose::Dwell && !self.within_travel(&block.end) {
+                self.raise_alarm(2, true);
+                return Completion::Never;
+            }
+            self.push_block(block);
+        }
+        if action.pause {
+            self.state = State::Hold(None);
+        }
+        if action.program_end {
+            let coordinate_system = 0;
+            self.modal = ModalState { coordinate_system, ..ModalState::default() };
+            self.g92_offset.iter_mut().for_each(|value| *value = 0.0);
+            self.wco_counter = 0;
+        }
+        completion
+    }
+    fn plan_jog(&self, words: &[Word]) -> Result<Block, u64> {
+        let mut inches = self.modal.inches;
+        let mut absolute = self.modal.absolute;
+        let mut machine_coordinates = false;
+        let mut feed = None;
+        let mut axis_words = vec![None; self.axis_count()];
+        for word in words {
+            match (word.letter, word.code()) {
+                (b'G', 200) | (b'G', 210) => inches = word.code() == 200,
+                (b'G', 900) | (b'G', 910) => absolute = word.code() == 900,
+                (b'G', 530) => machine_coordinates = true,
+                (b'F', _) => feed = Some(word.value),
+                (letter, _) => match self.axis_index(letter) {
+                    Some(index) => axis_words[index] = Some(word.value),
+                    None => return Err(16),
+                },
+            }
+        }
+        let scale = if inches { 25.4 } else { 1.0 };
+        let feed = feed.filter(|feed| *feed > 0.0).ok_or(22u64)? * scale;
+        if axis_words.iter().all(Option::is_none) {
+            return Err(26);
+        }
+        let planned = self.planned_position();
+        let offset = self.work_coordinate_offset();
+        let mut target = planned.clone();
+        for (axis, value) in axis_words.iter().enumerate() {
+            if let Some(value) = value {
+                let value = value * scale;
+                target[axis] = if machine_coordinates {
+                    value
+                } else if absolute {
+                    value + offset[axis]
+                } else {
+                    planned[axis] + value
+                };
+            }
+        }
+        if !self.within_travel(&target) {
+            return Err(15);
+        }
+        Ok(Block::line(planned, target, Rate::Unaffected(feed), Purpose::Jog))
+    }
+
+    /*
+        Planner and motion
+    */
+    fn push_block(&mut self, block: Block) {
+        let jog = block.purpose == Purpose::Jog;
+        self.planner.push_back(block);
+        if self.state == State::Idle {
+            self.state = if jog { State::Jog } else { State::Run };
+        }
+    }
+    // A block ends either by running its full length or, for a probe, by touching the surface first.
+    fn finish_block(&mut self, block: Block, touched: bool) {
+        if let Purpose::Probe { required, .. } = block.purpose {
+            self.finish_probe(touched, required);
+        }
+    }
+    fn finish_probe(&mut self, success: bool, required: bool) {
+        self.output_line(&format!("[PRB:{}:{}]", format_values(&self.position), success as u8));
+        self.last_probe = Some((self.position.clone(), success));
+        if !success && required {
+            self.raise_alarm(5, false);
+        }
+    }
+    fn raise_alarm(&mut self, code: u64, critical: bool) {
+        self.planner.clear();
+        self.state = State::Alarm;
+        self.output_line(&format!("ALARM:{}", code));
+        if critical {
+            self.output_line("[MSG:Reset to continue]");
+            self.awaiting_reset = true;
+        }
+    }
+    fn block_speed(&self, block: &Block) -> f64 {
+        // In units per second.
+        let per_minute = match block.rate {
+            Rate::Rapid => self.config.rapid_rate * self.rapid_override as f64 / 100.0,
+            Rate::Feed(feed) => (feed * self.feed_override as f64 / 100.0).min(self.config.rapid_rate),
+            Rate::Unaffected(feed) => feed.min(self.config.rapid_rate),
+            Rate::Time => 60.0,
+        };
+        per_minute / 60.0
+    }
+
+    /*
+        Helpers
+    */
+    fn settle_if_drained(&mut self) {
+        if self.planner.is_empty() && matches!(self.state, State::Run | State::Jog | State::Home) {
+            self.state = State::Idle;
+        }
+    }
+    fn is_settled(&self) -> bool {
+        self.planner.is_empty() && matches!(self.state, State::Idle | State::Alarm)
+    }
+    fn rx_used(&self) -> usize {
+        self.partial_line.len() + self.lines.iter().map(|line| line.len() + 1).sum::<usize>()
+    }
+    fn axis_count(&self) -> usize {
+        self.config.axis_letters.len()
+    }
+    fn axis_index(&self, letter: u8) -> Option<usize> {
+        self.config.axis_letters.iter().position(|axis| *axis == letter)
+    }
+    fn planned_position(&self) -> Vec<f64> {
+        self.planner.back().map_or_else(|| self.position.clone(), |block| block.end.clone())
+    }
+    fn work_coordinate_offset(&self) -> Vec<f64> {
+        let system = &self.coordinate_systems[self.modal.coordinate_system];
+        system.iter().zip(self.g92_offset.iter()).map(|(a, b)| a + b).collect()
+    }
+    fn within_travel(&self, position: &[f64]) -> bool {
+        !self.config.soft_limits || position.iter().enumerate().all(|(axis, value)| {
+            self.config.travel_min.get(axis).is_none_or(|min| *value >= min - 1e-6)
+                && self.config.travel_max.get(axis).is_none_or(|max| *value <= max + 1e-6)
+        })
+    }
+    fn spindle_output(&self) -> f64 {
+        if self.modal.spindle == Spindle::Off || self.spindle_stopped {
+            0.0
+        } else {
+            self.modal.spindle_speed * self.spindle_override as f64 / 100.0
+        }
+    }
+    fn parser_state(&self) -> String {
+        let motion = match self.modal.motion {
+            Motion::Rapid => "G0",
+            Motion::Linear => "G1",
+            Motion::Arc { clockwise: true } => "G2",
+            Motion::Arc { clockwise: false } => "G3",
+            Motion::Probe { toward: true, required: true } => "G38.2",
+            Motion::Probe { toward: true, required: false } => "G38.3",
+            Motion::Probe { toward: false, required: true } => "G38.4",
+            Motion::Probe { toward: false, required: false } => "G38.5",
+            Motion::Cancel => "G80",
+        };
+        let plane = match self.modal.plane {
+            (0, 1) => "G17",
+            (2, 0) => "G18",
+            _ => "G19",
+        };
+        let spindle = match self.modal.spindle {
+            Spindle::Clockwise => "M3",
+            Spindle::Counterclockwise => "M4",
+            Spindle::Off => "M5",
+        };
+        let coolant = match (self.modal.mist, self.modal.flood) {
+            (false, false) => "M9".to_string(),
+            (true, false) => "M7".to_string(),
+            (false, true) => "M8".to_string(),
+            (true, true) => "M7 M8".to_string(),
+        };
+        format!(
+            "[GC:{} G{} {} {} {} G94 {} {} T0 F{} S{}]",
+            motion,
+            54 + self.modal.coordinate_system,
+            plane,
+            if self.modal.inches { "G20" } else { "G21" },
+            if self.modal.absolute { "G90" } else { "G91" },
+            spindle,
+            coolant,
+            self.modal.feed.unwrap_or(0.0),
+            self.modal.spindle_speed,
+        )
+    }
+    fn output_line(&mut self, line: &str) {
+        self.output.extend_from_slice(line.as_bytes());
+        self.output.extend_from_slice(b"\r\n");
+    }
+}
+
+// Lines that Grbl only executes once the planner has drained.
+fn requires_sync(words: &[Word]) -> bool {
+    words.iter().any(|word| match word.letter {
+        b'G' => matches!(word.code(), 40 | 100 | 382..=385 | 920 | 921),
+        b'M' => matches!(word.code(), 0 | 10 | 20 | 300),
+        _ => false,
+    })
+}
+
+// Searches the most recent step of a probing move for the point at which the probe's state flipped.
+fn find_contact(block: &Block, previous: f64, toward: bool, surface: &ProbeSurface) -> Option<f64> {
+    let stops = |progress: f64| surface.is_triggered(&block.position_at(progress)) == toward;
+    if !stops(block.progress) {
+        return None;
+    }
+    let (mut low, mut high) = (previous, block.progress);
+    for _ in 0..48 {
+        let middle = (low + high) / 2.0;
+        if stops(middle) {
+            high = middle;
+        } else {
+            low = middle;
+        }
+    }
+    Some(high)
+}
+
+// Axes without configured travel home to zero.
+fn home_position(config: &SimulatedMachineConfig) -> Vec<f64> {
+    (0..config.axis_letters.len()).map(|axis| config.travel_max.get(axis).copied().unwrap_or(0.0)).collect()
+}
+
+fn format_values(values: &[f64]) -> String {
+    values.iter().map(|value| format!("{:.3}", value)).collect::<Vec<_>>().join(",")
+}
+
+fn format_setting(key: u32, value: f64) -> String {
+    if key >= 100 || matches!(key, 11 | 12 | 24 | 25 | 27) {
+        format!("{:.3}", value)
+    } else {
+        format!("{}", value as u64)
+    }
+}
+
+fn default_settings(config: &SimulatedMachineConfig) -> BTreeMap<u32, String> {
+    let mut settings: BTreeMap<u32, String> = [
+        (0, 10.0), (1, 25.0), (2, 0.0), (3, 0.0), (4, 0.0), (5, 0.0), (6, 0.0), (10, 1.0), (11, 0.010),
+        (12, 0.002), (13, 0.0), (20, config.soft_limits as u8 as f64), (21, 0.0), (22, 1.0), (23, 0.0),
+        (24, 25.0), (25, 500.0), (26, 250.0), (27, 1.0), (30, 1000.0), (31, 0.0), (32, 0.0),
+    ].into_iter().map(|(key, value)| (key, format_setting(key, value))).collect();
+    for axis in 0..config.axis_letters.len() {
+        let axis_offset = axis as u32;
+        let travel = config.travel_max.get(axis).zip(config.travel_min.get(axis)).map_or(0.0, |(max, min)| max - min);
+        settings.insert(100 + axis_offset, format_setting(100, 250.0));
+        settings.insert(110 + axis_offset, format_setting(110, config.rapid_rate));
+        settings.insert(120 + axis_offset, format_setting(120, 10.0));
+        settings.insert(130 + axis_offset, format_setting(130, travel));
+    }
+    settings
+}
+
+// Runs a simulated machine against the given streams, advancing its clock in real (tokio) time.
+pub async fn simulated_machine(config: SimulatedMachineConfig, input: impl AsyncRead, output: impl AsyncWrite) {
+    let mut input = pin!(input);
+    let mut output = pin!(output);
+    let mut machine = SimulatedMachine::new(config);
+    let mut ticker = interval(TICK);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut last_advance = Instant::now();
+    let mut buffer = [0u8; 256];
+    loop {
+        let received = select! {
+            read = input.read(&mut buffer) => match read {
+                Ok(0) | Err(_) => return,
+                Ok(count) => count,
+            },
+            _ = ticker.tick() => 0,
+        };
+        let now = Instant::now();
+        machine.advance(now - last_advance);
+        last_advance = now;
+        machine.receive(&buffer[..received]);
+        let pending = machine.take_output();
+        if !pending.is_empty() && (output.write_all(&pending).await.is_err() || output.flush().await.is_err()) {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn take_lines(machine: &mut SimulatedMachine) -> Vec<String> {
+        String::from_utf8(machine.take_output()).unwrap().lines().map(str::to_string).collect()
+    }
+    fn new_machine(config: SimulatedMachineConfig) -> SimulatedMachine {
+        let mut machine = SimulatedMachine::new(config);
+        assert_eq!(take_lines(&mut machine), vec![GREETING.to_string()]);
+        machine
+    }
+    fn status(machine: &mut SimulatedMachine) -> String {
+        machine.receive(b"?");
+        take_lines(machine).pop().unwrap()
+    }
+
+    #[test]
+    fn moves_take_time() {
+        let mut machine = new_machine(Default::default());
+        machine.receive(b"G1 X-10 F600\n");
+        assert_eq!(take_lines(&mut machine), vec!["ok"]);
+        assert!(status(&mut machine).starts_with("<Run|MPos:0.000,0.000,0.000|Bf:14,128|FS:600,0|WCO:"));
+        machine.advance(Duration::from_millis(500));
+        assert_eq!(machine.machine_position(), &[-5.0, 0.0, 0.0]);
+        machine.advance(Duration::from_millis(600));
+        assert_eq!(machine.machine_position(), &[-10.0, 0.0, 0.0]);
+        assert!(status(&mut machine).starts_with("<Idle|MPos:-10.000,0.000,0.000|"));
+    }
+
+    #[test]
+    fn planner_blocks_further_lines_when_full() {
+        let mut machine = new_machine(SimulatedMachineConfig { planner_blocks: 2, ..Default::default() });
+        machine.receive(b"G0 X-1\nG0 X-2\nG0 X-3\n");
+        assert_eq!(take_lines(&mut machine), vec!["ok", "ok"]);
+        machine.advance(Duration::from_millis(25)); // 1 mm at 3000 mm/min is 20 ms.
+        assert_eq!(take_lines(&mut machine), vec!["ok"]);
+    }
+
+    #[test]
+    fn dwell_acknowledges_after_motion_completes() {
+        let mut machine = new_machine(Default::default());
+        machine.receive(b"G0 X-50\nG4 P0.5\n");
+        assert_eq!(take_lines(&mut machine), vec!["ok"]);
+        machine.advance(Duration::from_millis(1000));
+        assert!(take_lines(&mut machine).is_empty());
+        machine.advance(Duration::from_millis(600));
+        assert_eq!(take_lines(&mut machine), vec!["ok"]);
+    }
+
+    #[test]
+    fn hold_stops_motion_until_resumed() {
+        let mut machine = new_machine(Default::default());
+        machine.receive(b"G1 X-10 F600\n!");
+        machine.advance(Duration::from_millis(10));
+        assert!(status(&mut machine).starts_with("<Hold:1|"));
+        machine.advance(Duration::from_millis(100));
+        assert!(status(&mut machine).starts_with("<Hold:0|MPos:0.000,0.000,0.000|"));
+        machine.receive(b"~");
+        machine.advance(Duration::from_millis(100));
+        assert_eq!(machine.machine_position(), &[-1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn reset_in_motion_raises_alarm() {
+        let mut machine = new_machine(Default::default());
+        machine.receive(b"G0 X-100\n");
+        machine.advance(Duration::from_millis(100));
+        machine.receive(&[0x18]);
+        assert_eq!(
+            take_lines(&mut machine),
+            vec!["ok", "ALARM:3", GREETING, "[MSG:'$H'|'$X' to unlock]"]
+        );
+        machine.receive(b"G0 X0\n$X\nG0 X0\n");
+        assert_eq!(take_lines(&mut machine), vec!["error:9", "[MSG:Caution: Unlocked]", "ok", "ok"]);
+    }
+
+    #[test]
+    fn soft_limits_raise_critical_alarm() {
+        let mut machine = new_machine(Default::default());
+        machine.receive(b"G0 X10\nG0 X-1\n");
+        assert_eq!(take_lines(&mut machine), vec!["ALARM:2", "[MSG:Reset to continue]"]);
+        machine.receive(&[0x18]);
+        assert_eq!(take_lines(&mut machine), vec![GREETING, "[MSG:'$H'|'$X' to unlock]"]);
+    }
+
+    #[test]
+    fn reports_errors_for_bad_input() {
+        let mut machine = new_machine(Default::default());
+        machine.receive(b"G1 X-1\nG0 G1 X-1\nG7\nX\n$Q\n$J=G91 X1000 F100\n");
+        assert_eq!(
+            take_lines(&mut machine),
+            vec!["error:22", "error:21", "error:20", "error:2", "error:3", "error:15"]
+        );
+    }
+
+    #[test]
+    fn probe_stops_at_surface() {
+        let mut machine = new_machine(SimulatedMachineConfig {
+            probe_surface: ProbeSurface::height_map(|x, _| -20.0 + x / 10.0),
+            ..Default::default()
+        });
+        machine.receive(b"G0 X-50\nG38.2 Z-90 F600\n");
+        machine.advance(Duration::from_secs(10));
+        let lines = take_lines(&mut machine);
+        assert_eq!(lines, vec!["ok", "[PRB:-50.000,0.000,-25.000:1]", "ok"]);
+        assert!(status(&mut machine).contains("|Pn:P"));
+    }
+
+    #[test]
+    fn failed_probe_raises_alarm() {
+        let mut machine = new_machine(Default::default());
+        machine.receive(b"G38.2 Z-10 F6000\n");
+        machine.advance(Duration::from_secs(1));
+        assert_eq!(take_lines(&mut machine), vec!["[PRB:0.000,0.000,-10.000:0]", "ALARM:5", "ok"]);
+    }
+
+    #[test]
+    fn work_coordinates_follow_offsets() {
+        let mut machine = new_machine(Default::default());
+        machine.receive(b"G10 L2 P1 X-100 Y-50\nG0 X10 Y10\n");
+        machine.advance(Duration::from_secs(10));
+        assert_eq!(machine.machine_position(), &[-90.0, -40.0, 0.0]);
+        assert_eq!(machine.work_position(), vec![10.0, 10.0, 0.0]);
+        machine.receive(b"G10 L20 P1 Z5\n");
+        assert_eq!(machine.work_position(), vec![10.0, 10.0, 5.0]);
+    }
+
+    #[test]
+    fn overrides_scale_feed() {
+        let mut machine = new_machine(Default::default());
+        machine.receive(&[0x92, 0x92, 0x92, 0x92, 0x92]);
+        machine.receive(b"G1 X-10 F600\n");
+        machine.advance(Duration::from_secs(1));
+        assert_eq!(machine.machine_position(), &[-5.0, 0.0, 0.0]);
+        assert!(status(&mut machine).contains("|Ov:50,100,100"));
+    }
+
+    #[tokio::test]
+    async fn driver_answers_over_streams() {
+        let (host, machine) = tokio::io::duplex(1024);
+        let (machine_input, machine_output) = tokio::io::split(machine);
+        tokio::spawn(simulated_machine(Default::default(), machine_input, machine_output));
+        let (mut host_input, mut host_output) = tokio::io::split(host);
+        host_output.write_all(b"G0 X-1\n").await.unwrap();
+        let mut received = Vec::new();
+        while !received.ends_with(b"ok\r\n") {
+            let mut buffer = [0u8; 64];
+            let count = host_input.read(&mut buffer).await.unwrap();
+            received.extend_from_slice(&buffer[..count]);
+        }
+        assert_eq!(received, format!("{}\r\nok\r\n", GREETING).as_bytes());
+    }
+}
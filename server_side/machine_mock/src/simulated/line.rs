@@ -0,0 +1,96 @@
+/*
+    Tokenizing of lines received by the simulated machine. Only does what Grbl's own parser does
+before interpreting a block: strip spaces and comments, uppercase, and split into letter/value
+words, reporting the same error codes Grbl would for malformed input.
+*/
+
+// Grbl's LINE_BUFFER_SIZE; longer lines are rejected with error:11.
+pub const LINE_BUFFER_SIZE: usize = 80;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Word {
+    pub letter: u8,
+    pub value: f64,
+}
+impl Word {
+    // Integer code scaled by ten, so that G38.2 becomes 382 and G1 becomes 10.
+    pub fn code(&self) -> i64 {
+        (self.value * 10.0).round() as i64
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Line {
+    Empty,
+    GCode(Vec<Word>),
+    System(String), // Everything after the leading '$'
+}
+
+pub fn parse_line(raw: &[u8]) -> Result<Line, u64> {
+    let mut stripped = Vec::with_capacity(raw.len());
+    let mut in_comment = false;
+    for &byte in raw {
+        match byte {
+            b')' if in_comment => in_comment = false,
+            _ if in_comment => (),
+            b'(' => in_comment = true,
+            b';' => break,
+            b' ' | b'\t' | b'\r' => (),
+            _ => stripped.push(byte.to_ascii_uppercase()),
+        }
+    }
+    if stripped.len() > LINE_BUFFER_SIZE {
+        return Err(11);
+    }
+    match stripped.first() {
+        None => Ok(Line::Empty),
+        Some(b'$') => Ok(Line::System(String::from_utf8_lossy(&stripped[1..]).into_owned())),
+        Some(_) => parse_words(&stripped).map(Line::GCode),
+    }
+}
+
+pub fn parse_words(mut input: &[u8]) -> Result<Vec<Word>, u64> {
+    let mut words = Vec::new();
+    while let Some((&letter, rest)) = input.split_first() {
+        if !letter.is_ascii_uppercase() {
+            return Err(1);
+        }
+        let length = rest
+            .iter()
+            .take_while(|c| c.is_ascii_digit() || matches!(c, b'.' | b'-' | b'+'))
+            .count();
+        let value = std::str::from_utf8(&rest[..length])
+            .ok()
+            .and_then(|number| number.parse::<f64>().ok())
+            .ok_or(2u64)?;
+        words.push(Word { letter, value });
+        input = &rest[length..];
+    }
+    Ok(words)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn strips_comments_and_whitespace() {
+        assert_eq!(
+            parse_line(b"g1 x1.5 (move over) y-2 ; trailing"),
+            Ok(Line::GCode(vec![
+                Word { letter: b'G', value: 1.0 },
+                Word { letter: b'X', value: 1.5 },
+                Word { letter: b'Y', value: -2.0 },
+            ]))
+        );
+        assert_eq!(parse_line(b"  (only a comment)"), Ok(Line::Empty));
+        assert_eq!(parse_line(b"$j=g91 x1 f100"), Ok(Line::System("J=G91X1F100".to_string())));
+    }
+
+    #[test]
+    fn reports_grbl_error_codes() {
+        assert_eq!(parse_line(b"1.5"), Err(1));
+        assert_eq!(parse_line(b"G1 X"), Err(2));
+        assert_eq!(parse_line(&[b'G'; 100]), Err(11));
+    }
+}
@@ -0,0 +1,166 @@
+use std::f64::consts::PI;
+
+// Grbl's ARC_ANGULAR_TRAVEL_EPSILON; below this a full circle is assumed.
+const ARC_ANGULAR_TRAVEL_EPSILON: f64 = 5e-7;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rate {
+    Rapid,
+    Feed(f64),       // mm/min; subject to the feed override
+    Unaffected(f64), // mm/min; jogging and homing ignore overrides
+    Time,            // length is measured in seconds rather than mm
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Geometry {
+    Line,
+    Arc {
+        axes: (usize, usize),
+        center: (f64, f64),
+        radius: f64,
+        start_angle: f64,
+        sweep: f64, // signed; negative for clockwise
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Purpose {
+    Motion,
+    Jog,
+    Home,
+    Dwell,
+    Probe { toward: bool, required: bool },
+}
+
+/*
+    A single entry in the simulated planner buffer. Motion is modelled without acceleration:
+the block is traversed at a constant rate from start to end.
+*/
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub start: Vec<f64>,
+    pub end: Vec<f64>,
+    pub geometry: Geometry,
+    pub rate: Rate,
+    pub purpose: Purpose,
+    pub length: f64,
+    pub progress: f64,
+    pub line_number: Option<u64>,
+}
+impl Block {
+    pub fn line(start: Vec<f64>, end: Vec<f64>, rate: Rate, purpose: Purpose) -> Self {
+        let length = distance(&start, &end);
+        Block { start, end, geometry: Geometry::Line, rate, purpose, length, progress: 0.0, line_number: None }
+    }
+    pub fn dwell(position: Vec<f64>, seconds: f64) -> Self {
+        Block {
+            start: position.clone(),
+            end: position,
+            geometry: Geometry::Line,
+            rate: Rate::Time,
+            purpose: Purpose::Dwell,
+            length: seconds,
+            progress: 0.0,
+            line_number: None,
+        }
+    }
+    // Center is given relative to start, as with the IJK words.
+    pub fn arc(start: Vec<f64>, end: Vec<f64>, axes: (usize, usize), offset: (f64, f64), clockwise: bool, feed: f64) -> Self {
+        let center = (start[axes.0] + offset.0, start[axes.1] + offset.1);
+        let from = (start[axes.0] - center.0, start[axes.1] - center.1);
+        let to = (end[axes.0] - center.0, end[axes.1] - center.1);
+        let radius = from.0.hypot(from.1);
+        let mut sweep = (from.0 * to.1 - from.1 * to.0).atan2(from.0 * to.0 + from.1 * to.1);
+        if clockwise {
+            if sweep >= -ARC_ANGULAR_TRAVEL_EPSILON {
+                sweep -= 2.0 * PI;
+            }
+        } else if sweep <= ARC_ANGULAR_TRAVEL_EPSILON {
+            sweep += 2.0 * PI;
+        }
+        let linear_travel = (0..start.len())
+            .filter(|axis| *axis != axes.0 && *axis != axes.1)
+            .map(|axis| (end[axis] - start[axis]).powi(2))
+            .sum::<f64>();
+        let length = ((sweep * radius).powi(2) + linear_travel).sqrt();
+        Block {
+            start,
+            end,
+            geometry: Geometry::Arc { axes, center, radius, start_angle: from.1.atan2(from.0), sweep },
+            rate: Rate::Feed(feed),
+            purpose: Purpose::Motion,
+            length,
+            progress: 0.0,
+            line_number: None,
+        }
+    }
+    pub fn is_done(&self) -> bool {
+        self.progress >= self.length
+    }
+    pub fn position_at(&self, progress: f64) -> Vec<f64> {
+        if self.length <= 0.0 || progress >= self.length {
+            return self.end.clone();
+        }
+        let fraction = progress / self.length;
+        let mut position: Vec<f64> = self
+            .start
+            .iter()
+            .zip(self.end.iter())
+            .map(|(start, end)| start + (end - start) * fraction)
+            .collect();
+        if let Geometry::Arc { axes, center, radius, start_angle, sweep } = self.geometry {
+            let angle = start_angle + sweep * fraction;
+            position[axes.0] = center.0 + radius * angle.cos();
+            position[axes.1] = center.1 + radius * angle.sin();
+        }
+        position
+    }
+    pub fn position(&self) -> Vec<f64> {
+        self.position_at(self.progress)
+    }
+}
+
+pub fn distance(start: &[f64], end: &[f64]) -> f64 {
+    start.iter().zip(end.iter()).map(|(start, end)| (end - start).powi(2)).sum::<f64>().sqrt()
+}
+
+// Converts an R word into the IJK-style offset of the center from the start, following Grbl.
+pub fn radius_to_offset(start: (f64, f64), end: (f64, f64), radius: f64, clockwise: bool) -> Option<(f64, f64)> {
+    let x = end.0 - start.0;
+    let y = end.1 - start.1;
+    let discriminant = 4.0 * radius * radius - x * x - y * y;
+    if discriminant < 0.0 || (x == 0.0 && y == 0.0) {
+        return None;
+    }
+    let mut h = -discriminant.sqrt() / x.hypot(y);
+    if clockwise {
+        h = -h;
+    }
+    if radius < 0.0 {
+        h = -h;
+    }
+    Some((0.5 * (x - y * h), 0.5 * (y + x * h)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn assert_close(left: &[f64], right: &[f64]) {
+        assert!(distance(left, right) < 1e-9, "{:?} != {:?}", left, right);
+    }
+
+    #[test]
+    fn quarter_arc_passes_through_midpoint() {
+        let block = Block::arc(vec![10.0, 0.0, 0.0], vec![0.0, 10.0, 0.0], (0, 1), (-10.0, 0.0), false, 100.0);
+        assert!((block.length - 5.0 * PI).abs() < 1e-9);
+        let half = 0.5f64.sqrt() * 10.0;
+        assert_close(&block.position_at(block.length / 2.0), &[half, half, 0.0]);
+    }
+
+    #[test]
+    fn radius_matches_offset_form() {
+        assert_eq!(radius_to_offset((0.0, 0.0), (10.0, 0.0), 5.0, true), Some((5.0, 0.0)));
+        assert_eq!(radius_to_offset((0.0, 0.0), (10.0, 0.0), 4.0, true), None);
+    }
+}
@@ -1,4 +1,5 @@
 use {
+    machine_mock::{in_process::port_to_machine, simulated::{simulated_machine, SimulatedMachineConfig}},
     std::time::Duration,
     tokio::{
         io::{split, stdin, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
@@ -31,6 +32,11 @@ pub async fn open_and_reset_arduino_like_serial(path: &str) -> (impl AsyncRead,
     split(port)
 }
 
+// Runs a simulated machine in-process instead of opening a port; see machine_mock::simulated.
+pub fn open_simulated_machine(config: SimulatedMachineConfig) -> (impl AsyncRead, impl AsyncWrite) {
+    port_to_machine(move |input, output| simulated_machine(config, input, output))
+}
+
 pub async fn as_terminal<Reader: AsyncRead + Unpin, Writer: AsyncWrite + Unpin>(
     reader: Reader,
     mut writer: Writer,
@@ -1,6 +1,8 @@
 pub mod display;
 pub mod parser;
 pub mod geometry;
+pub mod analysis;
+pub mod preview;
 
 #[derive(Debug)]
 pub struct AxisValues(pub Vec<(usize, f64)>); //(axis, value) pairs
@@ -0,0 +1,348 @@
+use std::f64::consts::PI;
+
+use common::api::{GcodeAnalysis, GcodeBounds, GcodeParseIssue};
+
+use super::{parser::{parse_generalized_line, GeneralizedLine}, AxisValues, ArcPlane, CoordinateMode, GCodeCommand, GCodeFormatSpecification, GCodeModal, MoveMode, OffsetAxisValues, Orientation};
+
+/*
+    A single pass over a G-code file, gathering what the file browser shows about it. Only the XYZ axes
+are tracked. Positions are unknown until a line sets them, and moves from an unknown position count
+for nothing. Bounds only take the end points of arcs into account.
+*/
+
+const MAX_REPORTED_PARSE_ERRORS: usize = 20;
+const MAX_REPORTED_WARNINGS: usize = 20;
+// For axes whose rapid rate the controller hasn't told us; a typical value for a small router.
+pub const ASSUMED_RAPID_RATE: f64 = 1000.0; // Units per minute.
+
+pub struct GcodeAnalyzer<'a> {
+    spec: &'a GCodeFormatSpecification,
+    rapid_rates: [f64; 3], // Each axis's maximum rate ($110 to $112), in units per minute.
+    analysis: GcodeAnalysis,
+    position: [Option<f64>; 3],
+    bounds: Option<GcodeBounds>,
+    rapid: bool, // The motion mode that moves without G0 or G1 use.
+    incremental: bool,
+    arc_plane: ArcPlane,
+    feed: Option<f64>,
+    cut_minutes: f64,
+    rapid_minutes: f64,
+    warned_no_feed: bool,
+    warned_machine_coordinates: bool,
+}
+
+fn distance(from: &[Option<f64>; 3], to: &[Option<f64>; 3]) -> f64 {
+    from.iter().zip(to).map(|(from, to)| match (from, to) {
+        (Some(from), Some(to)) => (to - from).powi(2),
+        _ => 0.0,
+    }).sum::<f64>().sqrt()
+}
+fn axis_value(values: &[(usize, f64)], axis: usize) -> Option<f64> {
+    values.iter().find(|(index, _)| *index == axis).map(|(_, value)| *value)
+}
+// T words, outside of comments.
+fn tool_numbers(line: &str) -> Vec<u32> {
+    let code = line.split(';').next().unwrap();
+    let mut tools = Vec::new();
+    let mut in_comment = false;
+    for (index, c) in code.char_indices() {
+        match c {
+            '(' => in_comment = true,
+            ')' => in_comment = false,
+            'T' | 't' if !in_comment => {
+                if let Ok(tool) = code[index + 1..].split(|c: char| !c.is_ascii_digit()).next().unwrap().parse() {
+                    tools.push(tool);
+                }
+            }
+            _ => (),
+        }
+    }
+    tools
+}
+
+impl<'a> GcodeAnalyzer<'a> {
+    pub fn new(spec: &'a GCodeFormatSpecification, rapid_rates: [f64; 3]) -> Self {
+        GcodeAnalyzer {
+            spec,
+            rapid_rates,
+            analysis: GcodeAnalysis {
+                line_count: 0,
+                parse_errors: Vec::new(),
+                parse_error_count: 0,
+                bounds: None,
+                cut_distance: 0.0,
+                rapid_distance: 0.0,
+                estimated_seconds: 0.0,
+                tools: Vec::new(),
+                feeds: Vec::new(),
+                warnings: Vec::new(),
+            },
+            position: [None; 3],
+            bounds: None,
+            rapid: true,
+            incremental: false,
+            arc_plane: ArcPlane::XY,
+            feed: None,
+            cut_minutes: 0.0,
+            rapid_minutes: 0.0,
+            warned_no_feed: false,
+            warned_machine_coordinates: false,
+        }
+    }
+    pub fn line(&mut self, text: &str) {
+        self.analysis.line_count += 1;
+        for tool in tool_numbers(text) {
+            if !self.analysis.tools.contains(&tool) {
+                self.analysis.tools.push(tool);
+            }
+        }
+        match parse_generalized_line(self.spec, text) {
+            Ok(GeneralizedLine::Line(line)) => {
+                for modal in &line.modals {
+                    self.modal(modal);
+                }
+                if let Some(command) = &line.command {
+                    self.command(command);
+                }
+            }
+            Ok(_) => (),
+            Err(error) => {
+                self.analysis.parse_error_count += 1;
+                if self.analysis.parse_errors.len() < MAX_REPORTED_PARSE_ERRORS {
+                    self.analysis.parse_errors.push(GcodeParseIssue {
+                        line_number: self.analysis.line_count,
+                        description: error.description,
+                    });
+                }
+            }
+        }
+    }
+    pub fn finish(mut self) -> GcodeAnalysis {
+        self.analysis.tools.sort();
+        self.analysis.feeds.sort_by(|a, b| a.total_cmp(b));
+        self.analysis.bounds = self.bounds;
+        self.analysis.estimated_seconds += 60.0 * (self.cut_minutes + self.rapid_minutes);
+        self.analysis
+    }
+
+    fn modal(&mut self, modal: &GCodeModal) {
+        match modal {
+            GCodeModal::SetFeedrate(feed) => {
+                self.feed = Some(*feed);
+                if !self.analysis.feeds.contains(feed) {
+                    self.analysis.feeds.push(*feed);
+                }
+            }
+            GCodeModal::SetArcPlane(plane) => self.arc_plane = *plane,
+            GCodeModal::SetCoordinateMode(mode) => self.incremental = matches!(mode, CoordinateMode::Incremental),
+            _ => (),
+        }
+    }
+    fn command(&mut self, command: &GCodeCommand) {
+        match command {
+            GCodeCommand::Move { mode, position, machine_coordinates } => {
+                match mode {
+                    MoveMode::Rapid => self.rapid = true,
+                    MoveMode::Controlled => self.rapid = false,
+                    MoveMode::Unspecified => (),
+                }
+                if *machine_coordinates {
+                    if !self.warned_machine_coordinates {
+                        self.warned_machine_coordinates = true;
+                        self.warn("G53 moves are in machine coordinates; check they're safe for this setup");
+                    }
+                    // Nothing here relates machine coordinates to the work coordinates used everywhere else.
+                    for (axis, _) in &position.0 {
+                        if let Some(value) = self.position.get_mut(*axis) {
+                            *value = None;
+                        }
+                    }
+                    return;
+                }
+                let target = self.target(position);
+                let length = distance(&self.position, &target);
+                if self.rapid {
+                    self.analysis.rapid_distance += length;
+                    self.rapid_minutes += self.rapid_time(&target);
+                } else {
+                    self.cut(length);
+                }
+                self.move_to(target);
+            }
+            GCodeCommand::Probe { position, .. } => {
+                let target = self.target(position);
+                self.cut(distance(&self.position, &target));
+                self.move_to(target);
+            }
+            GCodeCommand::ArcMove { orientation, position, offsets, revolutions } => {
+                let target = self.target(position);
+                let length = self.arc_length(&target, offsets, *orientation, revolutions.unwrap_or(0));
+                self.rapid = false;
+                self.cut(length);
+                self.move_to(target);
+            }
+            GCodeCommand::Dwell { duration } => self.analysis.estimated_seconds += duration,
+            GCodeCommand::SetWorkCoordinateTo(position) => {
+                // G10 L20 renames the current position along the axes it mentions.
+                for (axis, value) in &position.0 {
+                    if let Some(coordinate) = self.position.get_mut(*axis) {
+                        *coordinate = Some(*value);
+                    }
+                }
+            }
+        }
+    }
+    fn target(&self, position: &AxisValues) -> [Option<f64>; 3] {
+        let mut target = self.position;
+        for (axis, value) in &position.0 {
+            if let Some(coordinate) = target.get_mut(*axis) {
+                *coordinate = match (self.incremental, *coordinate) {
+                    (false, _) => Some(*value),
+                    (true, Some(coordinate)) => Some(coordinate + value),
+                    (true, None) => None,
+                };
+            }
+        }
+        target
+    }
+    // Rapids go as fast as the slowest axis allows, each axis moving at no more than its own rate.
+    fn rapid_time(&self, target: &[Option<f64>; 3]) -> f64 {
+        self.position.iter().zip(target).zip(self.rapid_rates).map(|((from, to), rate)| match (from, to) {
+            (Some(from), Some(to)) if rate > 0.0 => (to - from).abs() / rate,
+            _ => 0.0,
+        }).fold(0.0, f64::max)
+    }
+    fn cut(&mut self, length: f64) {
+        self.analysis.cut_distance += length;
+        match self.feed.filter(|feed| *feed > 0.0) {
+            Some(feed) => self.cut_minutes += length / feed,
+            None if !self.warned_no_feed => {
+                self.warned_no_feed = true;
+                self.warn("cutting move without a feed rate; the controller will refuse it");
+            }
+            None => (),
+        }
+    }
+    fn warn(&mut self, description: &str) {
+        if self.analysis.warnings.len() < MAX_REPORTED_WARNINGS {
+            self.analysis.warnings.push(GcodeParseIssue {
+                line_number: self.analysis.line_count,
+                description: description.to_string(),
+            });
+        }
+    }
+    fn move_to(&mut self, target: [Option<f64>; 3]) {
+        self.position = target;
+        if let [Some(x), Some(y), Some(z)] = target {
+            let point = [x, y, z];
+            match &mut self.bounds {
+                Some(bounds) => for (axis, value) in point.into_iter().enumerate() {
+                    bounds.min[axis] = bounds.min[axis].min(value);
+                    bounds.max[axis] = bounds.max[axis].max(value);
+                },
+                None => self.bounds = Some(GcodeBounds { min: point, max: point }),
+            }
+        }
+    }
+    fn arc_length(&self, target: &[Option<f64>; 3], offsets: &OffsetAxisValues, orientation: Orientation, revolutions: u64) -> f64 {
+        let (i, j, k) = match self.arc_plane {
+            ArcPlane::XY => (0, 1, 2),
+            ArcPlane::ZX => (2, 0, 1),
+            ArcPlane::YZ => (1, 2, 0),
+        };
+        let (Some(start_i), Some(start_j), Some(end_i), Some(end_j)) = (self.position[i], self.position[j], target[i], target[j]) else {
+            return 0.0
+        };
+        let center = (
+            start_i + axis_value(&offsets.0, i).unwrap_or(0.0),
+            start_j + axis_value(&offsets.0, j).unwrap_or(0.0),
+        );
+        let radius = (start_i - center.0).hypot(start_j - center.1);
+        let start_angle = (start_j - center.1).atan2(start_i - center.0);
+        let end_angle = (end_j - center.1).atan2(end_i - center.0);
+        let mut sweep = match orientation {
+            Orientation::Counterclockwise => end_angle - start_angle,
+            Orientation::Clockwise => start_angle - end_angle,
+        };
+        if sweep <= 0.0 {
+            sweep += 2.0 * PI;  // Matching end points make a full circle.
+        }
+        sweep += revolutions as f64 * 2.0 * PI;
+        let helix = match (self.position[k], target[k]) {
+            (Some(start), Some(end)) => end - start,
+            _ => 0.0,
+        };
+        (radius * sweep).hypot(helix)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn analyze_with_rates(program: &str, rapid_rates: [f64; 3]) -> GcodeAnalysis {
+        let spec = GCodeFormatSpecification {
+            axis_letters: b"XYZA".to_vec(),
+            offset_axis_letters: b"IJK".to_vec(),
+            float_digits: 3,
+        };
+        let mut analyzer = GcodeAnalyzer::new(&spec, rapid_rates);
+        for line in program.lines() {
+            analyzer.line(line);
+        }
+        analyzer.finish()
+    }
+    fn analyze(program: &str) -> GcodeAnalysis {
+        analyze_with_rates(program, [ASSUMED_RAPID_RATE; 3])
+    }
+
+    #[test]
+    fn measures_straight_moves() {
+        let analysis = analyze("G0 X0 Y0 Z5\nG1 Z0 F100\nX30 Y40\nG0 Z5");
+        assert_eq!(analysis.line_count, 4);
+        assert_eq!(analysis.cut_distance, 55.0);
+        assert_eq!(analysis.rapid_distance, 5.0);
+        assert_eq!(analysis.feeds, vec![100.0]);
+        assert_eq!(analysis.bounds, Some(GcodeBounds { min: [0.0, 0.0, 0.0], max: [30.0, 40.0, 5.0] }));
+        assert!((analysis.estimated_seconds - (55.0 / 100.0 + 5.0 / ASSUMED_RAPID_RATE) * 60.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn rapids_take_as_long_as_the_slowest_axis() {
+        let analysis = analyze_with_rates("G0 X0 Y0 Z0\nX300 Y100 Z10", [3000.0, 2000.0, 500.0]);
+        assert_eq!(analysis.rapid_distance, (300.0f64.powi(2) + 100.0f64.powi(2) + 10.0f64.powi(2)).sqrt());
+        // X needs 0.1 minutes, Y 0.05 and Z 0.02.
+        assert!((analysis.estimated_seconds - 6.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn moves_from_unknown_positions_count_for_nothing() {
+        let analysis = analyze("G1 X10 F100\nG91\nY5");
+        assert_eq!(analysis.cut_distance, 0.0);
+        assert_eq!(analysis.bounds, None);
+    }
+
+    #[test]
+    fn measures_arcs() {
+        let analysis = analyze("G0 X10 Y0 Z0\nG3 X-10 Y0 I-10 J0 F100");
+        assert!((analysis.cut_distance - 10.0 * PI).abs() < 1e-9);
+        let analysis = analyze("G0 X10 Y0 Z0\nG2 X10 Y0 I-10 J0 F100");
+        assert!((analysis.cut_distance - 20.0 * PI).abs() < 1e-9);
+    }
+
+    #[test]
+    fn warns_once_about_cutting_without_feed() {
+        let analysis = analyze("G0 X0 Y0 Z0\nG1 X1\nX2\nG1 X3 F100");
+        assert_eq!(analysis.warnings.iter().map(|issue| issue.line_number).collect::<Vec<_>>(), vec![2]);
+    }
+
+    #[test]
+    fn reports_parse_errors_and_tools() {
+        let analysis = analyze("T2 M6\n(T5 is in a comment)\nG1 X1 Q4");
+        assert_eq!(analysis.tools, vec![2]);
+        assert_eq!(analysis.parse_error_count, 2);
+        assert_eq!(analysis.parse_errors.iter().map(|issue| issue.line_number).collect::<Vec<_>>(), vec![1, 3]);
+        assert_eq!(tool_numbers("G0 X0 ; T7"), Vec::<u32>::new());
+        assert_eq!(tool_numbers("(T5) T3"), vec![3]);
+    }
+}
@@ -40,7 +40,7 @@ pub enum GCodePositionError {
     InvalidArc,
 }
 
-fn update_position(last_position: &mut HashMap<usize, f64>, axis_value: &AxisValues) -> Result<(), GCodePositionError> {
+pub fn update_position(last_position: &mut HashMap<usize, f64>, axis_value: &AxisValues) -> Result<(), GCodePositionError> {
     for (coord, value) in &axis_value.0 {
         let old = last_position.insert(*coord, *value);
         if old.is_none() {
@@ -49,7 +49,7 @@ fn update_position(last_position: &mut HashMap<usize, f64>, axis_value: &AxisVal
     }
     Ok(())
 }
-fn arc_indices(plane: ArcPlane, orientation: Orientation) -> (usize, usize) {
+pub fn arc_indices(plane: ArcPlane, orientation: Orientation) -> (usize, usize) {
     // Returns indices (i, j) such that the arc will be a CCW arc in the projection v -> (v_i, v_j)
     let (i, j) = match plane {  // First get how CCW arcs would be done...
         ArcPlane::XY => (0, 1),
@@ -85,7 +85,7 @@ fn angle_of(x: (f64, f64)) -> f64 {
     x.1.atan2(x.0)
 }
 
-fn arc_points(start: &mut HashMap<usize, f64>, end: &AxisValues, offsets: &OffsetAxisValues, tolerance: f64, arc_indices: (usize, usize), revolutions: u64) -> Result<impl Iterator<Item=AxisValues> + 'static, GCodePositionError> {
+pub fn arc_points(start: &mut HashMap<usize, f64>, end: &AxisValues, offsets: &OffsetAxisValues, tolerance: f64, arc_indices: (usize, usize), revolutions: u64) -> Result<impl Iterator<Item=AxisValues> + 'static, GCodePositionError> {
     let position_clone = start.clone();
     let arc_start = (
         *start.get(&arc_indices.0).ok_or(GCodePositionError::BadAxis(arc_indices.0))?,
@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+
+use common::api::{PreviewMove, PreviewMoveKind, ToolpathPreview};
+
+use super::{geometry::{arc_indices, arc_points, get_first_position, update_position, GCodePositionError}, ArcPlane, AxisValues, GCodeCommand, GCodeFormatSpecification, GCodeLine, GCodeModal, MoveMode};
+
+/*
+    Toolpaths for the viewer, with each point tagged by the move that reached it. Positions are worked out
+the same way as geometry::as_lines_simple: each axis starts at the first value the program gives it, and
+arcs become chords within ARC_TOLERANCE. Axes the program never mentions stay at 0.
+
+    Decimation drops points from the middle of nearly straight runs of moves that share a kind and feed,
+loosening its tolerance until the point budget is met. A kept point stands for the run that ends there,
+so it keeps the line of the last move in the run.
+*/
+
+const ARC_TOLERANCE: f64 = 0.1;
+const INITIAL_TOLERANCE: f32 = 0.001;
+// Bounds the work of checking a run; longer straight runs just keep an extra point.
+const MAX_RUN: usize = 64;
+
+struct PreviewBuilder {
+    axis_count: usize,
+    preview: ToolpathPreview,
+}
+impl PreviewBuilder {
+    fn push(&mut self, position: &HashMap<usize, f64>, preview_move: PreviewMove) {
+        let point = (0..self.axis_count).map(|axis| position.get(&axis).copied().unwrap_or(0.0) as f32);
+        self.preview.coordinates.extend(point);
+        self.preview.moves.push(preview_move);
+    }
+    fn push_values(&mut self, values: &AxisValues, preview_move: PreviewMove) {
+        self.push(&values.0.iter().copied().collect(), preview_move)
+    }
+}
+
+// Takes parsed lines along with their line numbers.
+pub fn toolpath_preview(spec: &GCodeFormatSpecification, program: &[(u32, GCodeLine)]) -> Result<ToolpathPreview, GCodePositionError> {
+    let mut position: HashMap<_, _> = get_first_position(program.iter().map(|(_, line)| line)).0.into_iter().collect();
+    let mut builder = PreviewBuilder {
+        axis_count: spec.axis_letters.len(),
+        preview: ToolpathPreview {
+            axis_letters: String::from_utf8_lossy(&spec.axis_letters).into_owned(),
+            ..Default::default()
+        },
+    };
+    builder.push(&position, PreviewMove { kind: PreviewMoveKind::Rapid, line: 0, feed: None });
+    let mut rapid = true;
+    let mut arc_plane = ArcPlane::XY;
+    let mut feed = None;
+    for (line_number, gcode) in program {
+        for modal in &gcode.modals {
+            match modal {
+                GCodeModal::SetFeedrate(value) => feed = Some(*value as f32),
+                GCodeModal::SetArcPlane(plane) => arc_plane = *plane,
+                _ => (),
+            }
+        }
+        let preview_move = |kind| PreviewMove { kind, line: *line_number, feed };
+        match &gcode.command {
+            Some(GCodeCommand::Move { mode, position: target, .. }) => {
+                match mode {
+                    MoveMode::Rapid => rapid = true,
+                    MoveMode::Controlled => rapid = false,
+                    MoveMode::Unspecified => (),
+                }
+                update_position(&mut position, target)?;
+                let kind = if rapid { PreviewMoveKind::Rapid } else { PreviewMoveKind::Feed };
+                builder.push(&position, preview_move(kind));
+            }
+            Some(GCodeCommand::Probe { position: target, .. }) => {
+                update_position(&mut position, target)?;
+                builder.push(&position, preview_move(PreviewMoveKind::Probe));
+            }
+            Some(GCodeCommand::ArcMove { orientation, position: target, offsets, revolutions }) => {
+                rapid = false;
+                let indices = arc_indices(arc_plane, *orientation);
+                // The first point is where the arc starts, which is already there.
+                for point in arc_points(&mut position, target, offsets, ARC_TOLERANCE, indices, revolutions.unwrap_or(0))?.skip(1) {
+                    builder.push_values(&point, preview_move(PreviewMoveKind::Arc));
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(builder.preview)
+}
+
+// Distance from point to the segment between from and to.
+fn deviation(point: &[f32], from: &[f32], to: &[f32]) -> f32 {
+    let direction: Vec<f32> = to.iter().zip(from).map(|(to, from)| to - from).collect();
+    let offset: Vec<f32> = point.iter().zip(from).map(|(point, from)| point - from).collect();
+    let length_squared: f32 = direction.iter().map(|value| value * value).sum();
+    let along = if length_squared > 0.0 {
+        (offset.iter().zip(&direction).map(|(a, b)| a * b).sum::<f32>() / length_squared).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    offset.iter().zip(&direction).map(|(offset, direction)| (offset - along * direction).powi(2)).sum::<f32>().sqrt()
+}
+fn same_kind(a: &PreviewMove, b: &PreviewMove) -> bool {
+    a.kind == b.kind && a.feed == b.feed
+}
+// Indices of the points to keep.
+fn kept_points(preview: &ToolpathPreview, tolerance: f32) -> Vec<usize> {
+    let count = preview.moves.len();
+    let mut kept = vec![0];
+    let mut anchor = 0;
+    let mut end = 1;
+    while end < count {
+        let next = end + 1;
+        let extends = next < count
+            && next - anchor <= MAX_RUN
+            && same_kind(&preview.moves[end], &preview.moves[next])
+            && (anchor + 1..next).all(|index| deviation(preview.point(index), preview.point(anchor), preview.point(next)) <= tolerance);
+        if !extends {
+            kept.push(end);
+            anchor = end;
+        }
+        end = next;
+    }
+    kept
+}
+fn extent(preview: &ToolpathPreview) -> f32 {
+    (0..preview.axis_count()).map(|axis| {
+        let values = preview.coordinates.iter().skip(axis).step_by(preview.axis_count());
+        let (min, max) = values.fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), value| (min.min(*value), max.max(*value)));
+        max - min
+    }).fold(0.0, f32::max)
+}
+
+pub fn decimate(preview: ToolpathPreview, max_points: usize) -> ToolpathPreview {
+    let max_points = max_points.max(2); // The ends are always kept.
+    if preview.moves.len() <= max_points || preview.axis_count() == 0 {
+        return preview;
+    }
+    let extent = extent(&preview);
+    let mut tolerance = INITIAL_TOLERANCE;
+    let mut kept = kept_points(&preview, tolerance);
+    while kept.len() > max_points && tolerance < extent {
+        tolerance *= 4.0;
+        kept = kept_points(&preview, tolerance);
+    }
+    if kept.len() > max_points {
+        // Runs are limited in length, so even the loosest tolerance can leave too many; thin out evenly.
+        let last = kept.pop().unwrap();
+        let stride = kept.len().div_ceil(max_points - 1);
+        kept = kept.into_iter().step_by(stride).collect();
+        kept.push(last);
+    }
+    let mut decimated = ToolpathPreview { axis_letters: preview.axis_letters.clone(), ..Default::default() };
+    for index in kept {
+        decimated.coordinates.extend_from_slice(preview.point(index));
+        decimated.moves.push(preview.moves[index]);
+    }
+    decimated
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cnc::gcode::parser::{parse_generalized_line, GeneralizedLine};
+
+    fn spec() -> GCodeFormatSpecification {
+        GCodeFormatSpecification {
+            axis_letters: b"XYZA".to_vec(),
+            offset_axis_letters: b"IJK".to_vec(),
+            float_digits: 3,
+        }
+    }
+    fn preview(program: &str) -> ToolpathPreview {
+        let spec = spec();
+        let lines: Vec<_> = program.lines().enumerate().filter_map(|(index, line)| match parse_generalized_line(&spec, line) {
+            Ok(GeneralizedLine::Line(line)) => Some((index as u32 + 1, line)),
+            _ => None,
+        }).collect();
+        toolpath_preview(&spec, &lines).unwrap()
+    }
+
+    #[test]
+    fn tags_moves_with_their_lines() {
+        let preview = preview("G0 X0 Y0 Z5\nG1 Z0 F100\nX10\nG0 Z5");
+        assert_eq!(preview.axis_letters, "XYZA");
+        assert_eq!(preview.moves.len(), 5);
+        assert_eq!(preview.point(3), &[10.0, 0.0, 0.0, 0.0]);
+        assert_eq!(preview.moves[2], PreviewMove { kind: PreviewMoveKind::Feed, line: 2, feed: Some(100.0) });
+        assert_eq!(preview.moves[3], PreviewMove { kind: PreviewMoveKind::Feed, line: 3, feed: Some(100.0) });
+        assert_eq!(preview.moves[4].kind, PreviewMoveKind::Rapid);
+    }
+
+    #[test]
+    fn splits_arcs() {
+        let preview = preview("G0 X10 Y0 Z0\nG3 X-10 Y0 I-10 J0 F100");
+        assert!(preview.moves.len() > 3);
+        assert!(preview.moves[2..].iter().all(|preview_move| preview_move.kind == PreviewMoveKind::Arc && preview_move.line == 2));
+        let end = preview.point(preview.moves.len() - 1);
+        assert!((end[0] + 10.0).abs() < 1e-4 && end[1].abs() < 1e-4);
+    }
+
+    #[test]
+    fn decimation_merges_straight_runs_of_the_same_feed() {
+        let program: String = (0..=100).map(|x| format!("G1 X{} Y0 Z0 F100\n", x)).collect::<String>() + "G1 X100 Y10 F200\n";
+        let decimated = decimate(preview(&program), 10);
+        assert_eq!(decimated.moves.len(), 4);
+        assert_eq!(decimated.point(2), &[100.0, 0.0, 0.0, 0.0]);
+        assert_eq!(decimated.moves[2].line, 101);
+        assert_eq!(decimated.moves[3].feed, Some(200.0));
+    }
+
+    #[test]
+    fn decimation_meets_the_budget() {
+        let program: String = (0..1000).map(|i| format!("G1 X{} Y{} Z0 F100\n", i % 7, i % 3)).collect();
+        let original = preview(&program);
+        let decimated = decimate(original.clone(), 100);
+        assert!(decimated.moves.len() <= 100);
+        assert_eq!(decimated.moves.last(), original.moves.last());
+    }
+
+    #[test]
+    fn previews_survive_encoding() {
+        let original = preview("G0 X0 Y0 Z5\nG1 Z0 F100\nG2 X10 Y0 I5 J0");
+        assert_eq!(ToolpathPreview::from_bytes(&original.to_bytes()), Ok(original.clone()));
+        let bytes = original.to_bytes();
+        assert!(ToolpathPreview::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+}
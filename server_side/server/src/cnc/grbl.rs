@@ -2,4 +2,6 @@ pub mod messages;
 pub mod new_machine;
 pub mod parser;
 pub mod handler;
-pub mod standard_handler;
\ No newline at end of file
+pub mod realtime;
+pub mod standard_handler;
+pub mod session_recording;
\ No newline at end of file
@@ -1,7 +1,8 @@
 use async_trait::async_trait;
+use common::grbl::GrblSetting;
 use tokio::sync::oneshot;
 
-use super::messages::{ProbeEvent, GrblStateInfo};
+use super::{messages::{ProbeEvent, GrblStateInfo}, realtime::RealtimeCommand};
 
 
 #[derive(Clone, Debug)]
@@ -26,6 +27,10 @@ pub enum WriteRequest {
         result_line: oneshot::Sender<Result<(), LineError>>, // gives error code on failure
         result: oneshot::Sender<Result<ProbeEvent, ProbeError>>, // gives error code on failure
     },
+    Settings {
+        data: Vec<u8>,                                                // "$$" and a newline
+        result: oneshot::Sender<Result<Vec<GrblSetting>, LineError>>, // the settings listed before the "ok"
+    },
 }
 #[derive(Debug)]
 pub enum SpeedOverride {
@@ -45,6 +50,25 @@ pub enum SpeedOverride {
     SpindleIncrease1,
     SpindleDecrease1,
 }
+impl SpeedOverride {
+    pub fn command(&self) -> RealtimeCommand {
+        match self {
+            SpeedOverride::FeedReset => RealtimeCommand::FeedOverrideReset,
+            SpeedOverride::FeedIncrease10 => RealtimeCommand::FeedOverridePlusTen,
+            SpeedOverride::FeedDecrease10 => RealtimeCommand::FeedOverrideMinusTen,
+            SpeedOverride::FeedIncrease1 => RealtimeCommand::FeedOverridePlusOne,
+            SpeedOverride::FeedDecrease1 => RealtimeCommand::FeedOverrideMinusOne,
+            SpeedOverride::RapidReset => RealtimeCommand::RapidOverrideReset,
+            SpeedOverride::RapidHalf => RealtimeCommand::RapidOverrideHalf,
+            SpeedOverride::RapidQuarter => RealtimeCommand::RapidOverrideQuarter,
+            SpeedOverride::SpindleReset => RealtimeCommand::SpindleOverrideReset,
+            SpeedOverride::SpindleIncrease10 => RealtimeCommand::SpindleOverridePlusTen,
+            SpeedOverride::SpindleDecrease10 => RealtimeCommand::SpindleOverrideMinusTen,
+            SpeedOverride::SpindleIncrease1 => RealtimeCommand::SpindleOverridePlusOne,
+            SpeedOverride::SpindleDecrease1 => RealtimeCommand::SpindleOverrideMinusOne,
+        }
+    }
+}
 #[derive(Debug)]
 pub enum ImmediateRequest {
     Status {
@@ -54,6 +78,7 @@ pub enum ImmediateRequest {
     FeedResume,
     Reset,
     OverrideSpeed(SpeedOverride),
+    Realtime(RealtimeCommand),  // Any other realtime command; the handler is responsible for checking it's safe to send.
 }
 
 #[allow(unused_variables)]
@@ -63,6 +88,7 @@ pub trait Handler {
     fn after_send(&self, bytes: Vec<u8>) {}
     fn after_receive(&self, line: String) {}
     fn warn(&self, message: String) {}
+    fn after_status(&self, state: &GrblStateInfo) {}
     async fn on_alarm(&self, index: u64) {}
     async fn after_reset(&self) {}
 
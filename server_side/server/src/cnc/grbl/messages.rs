@@ -2,8 +2,8 @@ use std::borrow::Cow;
 
 use ndarray::Array1;
 pub use common::grbl::GrblState;
-use common::grbl::GrblFullInfo;
-use serde::Serialize;
+use common::grbl::{GrblFullInfo, GrblPins, GrblAccessories, GrblSetting};
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum GrblPosition {
@@ -16,10 +16,25 @@ pub struct GrblResidualStatus {
     pub feed_override: u8,
     pub rapid_override: u8,
     pub spindle_override: u8,
+    pub current_feed: Option<f64>,
+    pub current_spindle: Option<f64>,
+    pub planner: Option<u64>,
+    pub rx_bytes: Option<u64>,
+    pub accessory_state: String,
 }
 impl GrblResidualStatus {
     pub fn new() -> Self {
-        GrblResidualStatus { work_coordinate_offset: None, feed_override: 100, rapid_override: 100, spindle_override: 100 }
+        GrblResidualStatus {
+            work_coordinate_offset: None,
+            feed_override: 100,
+            rapid_override: 100,
+            spindle_override: 100,
+            current_feed: None,
+            current_spindle: None,
+            planner: None,
+            rx_bytes: None,
+            accessory_state: String::new(),
+        }
     }
 }
 #[derive(Debug, Clone, PartialEq)]
@@ -71,10 +86,21 @@ impl GrblStatus {
                 None => *received = residual.clone()
             }
         }
+        let overrides_reported = self.feed_override.is_some();
         residual_sync(&mut self.feed_override, &mut residual.feed_override);
         residual_sync(&mut self.rapid_override, &mut residual.rapid_override);
         residual_sync(&mut self.spindle_override, &mut residual.spindle_override);
         residual_sync_optional(&mut self.work_coordinate_offset, &mut residual.work_coordinate_offset);
+        residual_sync_optional(&mut self.current_feed, &mut residual.current_feed);
+        residual_sync_optional(&mut self.current_spindle, &mut residual.current_spindle);
+        residual_sync_optional(&mut self.planner, &mut residual.planner);
+        residual_sync_optional(&mut self.rx_bytes, &mut residual.rx_bytes);
+        // Accessories are reported alongside overrides, and left out then if all are off.
+        if overrides_reported && self.accessory_state.is_none() {
+            self.accessory_state = Some(String::new());
+        }
+        residual_sync(&mut self.accessory_state, &mut residual.accessory_state);
+        // Pins and the line number are left out whenever there's nothing to report, so aren't carried over.
     }
     pub fn to_state_with_residual(mut self, residual: &mut GrblResidualStatus) -> GrblStateInfo {
         // Precondition: either this or the residual needs to have a work coordinate offset.
@@ -89,7 +115,14 @@ impl GrblStatus {
             feed_override: self.feed_override.unwrap(),
             rapid_override: self.rapid_override.unwrap(),
             spindle_override: self.spindle_override.unwrap(),
-            probe: self.pins.map_or(false, |inner| inner.contains('P')),
+            probe: self.pins.as_ref().map_or(false, |inner| inner.contains('P')),
+            current_feed: self.current_feed,
+            current_spindle: self.current_spindle,
+            planner_blocks_available: self.planner,
+            rx_bytes_available: self.rx_bytes,
+            line_number: self.line_number,
+            pins: self.pins.as_deref().map(GrblPins::from_report).unwrap_or_default(),
+            accessories: GrblAccessories::from_report(self.accessory_state.as_deref().unwrap_or("")),
         }
     }
 }
@@ -104,6 +137,13 @@ pub struct GrblStateInfo {
     pub rapid_override: u8,
     pub spindle_override: u8,
     pub probe: bool,
+    pub current_feed: Option<f64>,
+    pub current_spindle: Option<f64>,
+    pub planner_blocks_available: Option<u64>,
+    pub rx_bytes_available: Option<u64>,
+    pub line_number: Option<u64>,
+    pub pins: GrblPins,
+    pub accessories: GrblAccessories,
 }
 impl GrblStateInfo {
     pub fn to_full_info(self) -> GrblFullInfo {
@@ -115,6 +155,14 @@ impl GrblStateInfo {
             rapid_override: self.rapid_override,
             spindle_override: self.spindle_override,
             probe: self.probe,
+            alarm: None,
+            current_feed: self.current_feed,
+            current_spindle: self.current_spindle,
+            planner_blocks_available: self.planner_blocks_available,
+            rx_bytes_available: self.rx_bytes_available,
+            line_number: self.line_number,
+            pins: self.pins,
+            accessories: self.accessories,
         }
     }
 }
@@ -132,7 +180,7 @@ mod array_serializer {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ProbeEvent {
     pub success: bool,
     #[serde(with="array_serializer")]
@@ -147,6 +195,7 @@ pub enum GrblMessage {
     GrblAlarm(u64),
     GrblOk,
     GrblGreeting,
+    Setting(GrblSetting), // One line of the "$$" listing
     Unrecognized(String),
 }
 impl GrblMessage {
@@ -205,4 +254,46 @@ impl GrblMessage {
             _ => Cow::Owned(format!("Unknown error:{}", index))
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use common::grbl::SpindleDirection;
+    use ndarray::array;
+
+    use super::*;
+
+    fn status(configure: impl FnOnce(&mut GrblStatus)) -> GrblStatus {
+        let mut status = GrblStatus::new(GrblState::Idle, GrblPosition::Machine(array![0.0, 0.0, 0.0]));
+        status.work_coordinate_offset = Some(array![0.0, 0.0, 0.0]);
+        configure(&mut status);
+        status
+    }
+
+    #[test]
+    fn residual_carries_buffer_and_accessories_between_reports() {
+        let mut residual = GrblResidualStatus::new();
+        let first = status(|status| {
+            status.planner = Some(15);
+            status.rx_bytes = Some(128);
+            status.feed_override = Some(100);
+            status.accessory_state = Some("SF".to_string());
+            status.pins = Some("PZ".to_string());
+            status.line_number = Some(20);
+        }).to_state_with_residual(&mut residual);
+        assert_eq!(first.accessories, GrblAccessories { spindle: SpindleDirection::Clockwise, flood_coolant: true, mist_coolant: false });
+        assert!(first.pins.probe && first.pins.z_limit && !first.pins.x_limit);
+        assert!(first.probe);
+
+        let second = status(|_| {}).to_state_with_residual(&mut residual);
+        assert_eq!(second.planner_blocks_available, Some(15));
+        assert_eq!(second.rx_bytes_available, Some(128));
+        assert_eq!(second.accessories.spindle, SpindleDirection::Clockwise);
+        assert_eq!(second.pins, GrblPins::default());
+        assert_eq!(second.line_number, None);
+
+        // Overrides reported without accessories means everything was switched off.
+        let third = status(|status| status.feed_override = Some(100)).to_state_with_residual(&mut residual);
+        assert_eq!(third.accessories, GrblAccessories::default());
+    }
+}
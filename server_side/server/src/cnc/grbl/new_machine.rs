@@ -26,13 +26,29 @@ use {
         time::{sleep, Sleep},
     },
 };
-use super::handler::{Handler, SpeedOverride};
+use common::grbl::GrblSetting;
+use super::handler::Handler;
 pub use super::handler::{LineError, ProbeError, WriteRequest, ImmediateRequest};
 
+// A line waiting on its "ok" or error.
+enum WaitingLine {
+    Plain(oneshot::Sender<Result<(), LineError>>),
+    Settings(oneshot::Sender<Result<Vec<GrblSetting>, LineError>>),
+}
+impl WaitingLine {
+    fn finish(self, result: Result<(), LineError>, settings: Vec<GrblSetting>) {
+        match self {
+            WaitingLine::Plain(channel) => drop(channel.send(result)),
+            WaitingLine::Settings(channel) => drop(channel.send(result.map(|()| settings))),
+        }
+    }
+}
+
 struct MachineThread<'a, Write: MachineWriter, H: Handler> {
     writer: Write,
     handler: &'a H,
-    waiting_ok: VecDeque<oneshot::Sender<Result<(), LineError>>>,
+    waiting_ok: VecDeque<WaitingLine>,
+    received_settings: Vec<GrblSetting>,  // Listed since the last "ok" or error.
     waiting_probe: VecDeque<oneshot::Sender<Result<ProbeEvent, ProbeError>>>,
     waiting_status: VecDeque<oneshot::Sender<GrblStateInfo>>,
     residual_status: GrblResidualStatus,
@@ -60,6 +76,7 @@ impl<'a, Write: MachineWriter, H: Handler> MachineThread<'a, Write, H> {
             }
             GrblMessage::StatusEvent(status_event) => {
                 let state = status_event.to_state_with_residual(&mut self.residual_status);
+                self.handler.after_status(&state);
                 for waiting in self.waiting_status.drain(..) {
                     drop(waiting.send(state.clone())); // Don't worry about if it actually sent;
                 }
@@ -70,9 +87,10 @@ impl<'a, Write: MachineWriter, H: Handler> MachineThread<'a, Write, H> {
                     format!("Error received: {}!", GrblMessage::get_error_text(index)),
                 );
                 let next_result = self.waiting_ok.pop_front();
+                let settings = std::mem::take(&mut self.received_settings);
                 // TODO: DEAL WITH ERRORS FROM PROBING!
                 match next_result {
-                    Some(channel) => drop(channel.send(Err(LineError::Grbl(index)))),
+                    Some(waiting) => waiting.finish(Err(LineError::Grbl(index)), settings),
                     None => self.handler.warn(
                         "received error without listener".to_string(),
                     ),
@@ -90,8 +108,9 @@ impl<'a, Write: MachineWriter, H: Handler> MachineThread<'a, Write, H> {
             GrblMessage::GrblOk => {
                 self.writer.pop_received_line().await.unwrap().map(|v| self.log_send(v));
                 let next_result = self.waiting_ok.pop_front();
+                let settings = std::mem::take(&mut self.received_settings);
                 match next_result {
-                    Some(channel) => drop(channel.send(Ok(()))),
+                    Some(waiting) => waiting.finish(Ok(()), settings),
                     None => self.handler.warn(
                         "received ok without listener".to_string(),
                     ),
@@ -100,6 +119,7 @@ impl<'a, Write: MachineWriter, H: Handler> MachineThread<'a, Write, H> {
             GrblMessage::GrblGreeting => self.handler.warn(
                 "received unexpected greeting!".to_string(),
             ),
+            GrblMessage::Setting(setting) => self.received_settings.push(setting),
             GrblMessage::Unrecognized(line) => {
                 self.handler.warn(format!("Unrecognized line: {:?}", line))
             }
@@ -109,7 +129,7 @@ impl<'a, Write: MachineWriter, H: Handler> MachineThread<'a, Write, H> {
         match request {
             WriteRequest::Plain { data, result } => {
                 self.writer.enqueue_line(data).await.unwrap().map(|v| self.log_send(v));
-                self.waiting_ok.push_back(result);
+                self.waiting_ok.push_back(WaitingLine::Plain(result));
             }
             WriteRequest::Probe {
                 data,
@@ -117,9 +137,13 @@ impl<'a, Write: MachineWriter, H: Handler> MachineThread<'a, Write, H> {
                 result,
             } => {
                 self.writer.enqueue_line(data).await.unwrap().map(|v| self.log_send(v));
-                self.waiting_ok.push_back(result_line);
+                self.waiting_ok.push_back(WaitingLine::Plain(result_line));
                 self.waiting_probe.push_back(result);
             }
+            WriteRequest::Settings { data, result } => {
+                self.writer.enqueue_line(data).await.unwrap().map(|v| self.log_send(v));
+                self.waiting_ok.push_back(WaitingLine::Settings(result));
+            }
         }
     }
     async fn immediate_send(&mut self, request: ImmediateRequest) {
@@ -138,22 +162,10 @@ impl<'a, Write: MachineWriter, H: Handler> MachineThread<'a, Write, H> {
                 self.send_immediate(vec![0x18]).await;
             },
             ImmediateRequest::OverrideSpeed(change) => {
-                let byte = match change {
-                    SpeedOverride::FeedReset => 0x90,
-                    SpeedOverride::FeedIncrease10 => 0x91,
-                    SpeedOverride::FeedDecrease10 => 0x92,
-                    SpeedOverride::FeedIncrease1 => 0x93,
-                    SpeedOverride::FeedDecrease1 => 0x94,
-                    SpeedOverride::RapidReset => 0x95,
-                    SpeedOverride::RapidHalf => 0x96,
-                    SpeedOverride::RapidQuarter => 0x97,
-                    SpeedOverride::SpindleReset => 0x99,
-                    SpeedOverride::SpindleIncrease10 => 0x9A,
-                    SpeedOverride::SpindleDecrease10 => 0x9B,
-                    SpeedOverride::SpindleIncrease1 => 0x9C,
-                    SpeedOverride::SpindleDecrease1 => 0x9D,
-                };
-                self.send_immediate(vec![byte]).await;
+                self.send_immediate(vec![change.command().byte()]).await;
+            }
+            ImmediateRequest::Realtime(command) => {
+                self.send_immediate(vec![command.byte()]).await;
             }
         }
     }
@@ -161,8 +173,9 @@ impl<'a, Write: MachineWriter, H: Handler> MachineThread<'a, Write, H> {
         self.writer.clear_waiting();
         // Clear out all expected results. They're not coming.
         for waiting in self.waiting_ok.drain(..) {
-            drop(waiting.send(Err(LineError::Reset)));
+            waiting.finish(Err(LineError::Reset), Vec::new());
         }
+        self.received_settings.clear();
         for waiting in self.waiting_probe.drain(..) {
             drop(waiting.send(Err(ProbeError::Reset)));
         }
@@ -170,14 +183,18 @@ impl<'a, Write: MachineWriter, H: Handler> MachineThread<'a, Write, H> {
         if !self.waiting_status.is_empty() {
             self.send_immediate(vec![b'?']).await;
         }
+        self.handler.after_reset().await;
     }
     async fn wait_for_greeting<Read: AsyncBufRead + Unpin>(&mut self, lines_reader: &mut Lines<Read>) -> Result<(), std::io::Error> {
         loop {
             match lines_reader.next_line().await {
                 Ok(Some(line)) => {
                     self.handler.after_receive(line.clone());
-                    if let GrblMessage::GrblGreeting = parse_grbl_line(&line) {
-                        return Ok(())
+                    match parse_grbl_line(&line) {
+                        GrblMessage::GrblGreeting => return Ok(()),
+                        // Resetting while in motion raises an alarm just before the greeting.
+                        GrblMessage::GrblAlarm(index) => self.handler.on_alarm(index).await,
+                        _ => (),
                     }
                 }
                 Ok(None) => return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "unexpected eof!")),
@@ -198,6 +215,7 @@ where
         writer,
         handler: &handler,
         waiting_ok: Default::default(),
+        received_settings: Vec::new(),
         waiting_probe: Default::default(),
         waiting_status: Default::default(),
         residual_status: GrblResidualStatus::new(),
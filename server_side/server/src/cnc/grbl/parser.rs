@@ -3,6 +3,7 @@ use ndarray::array;
 use nom::error::ErrorKind;
 use {
     super::messages::*,
+    common::grbl::GrblSetting,
     ndarray::Array1,
     nom::{
         self,
@@ -393,6 +394,16 @@ where
         .map(GrblMessage::GrblAlarm)
         .parse(input)
 }
+fn parse_grbl_setting<'a, Error: 'a + ParseError<&'a str>>(
+    input: &'a str,
+) -> IResult<&'a str, GrblMessage, Error>
+where
+    Error: FromExternalError<&'a str, ParseIntError>,
+{
+    preceded(tag("$"), separated_pair(parse_u64, tag("="), all))
+        .map(|(index, value): (u64, &str)| GrblMessage::Setting(GrblSetting { index: index as u32, value: value.to_string() }))
+        .parse(input)
+}
 fn parse_grbl_greeting<'a, Error: 'a + ParseError<&'a str>>(
     input: &'a str,
 ) -> IResult<&'a str, GrblMessage, Error> {
@@ -414,6 +425,7 @@ where
         parse_grbl_ok,
         parse_grbl_error,
         parse_grbl_alarm,
+        parse_grbl_setting,
         parse_grbl_greeting,
         all.map(|msg: &str| GrblMessage::Unrecognized(msg.to_string())),
     ))
@@ -560,4 +572,10 @@ mod tests {
         let result = parse_grbl_line(input);
         assert_eq!(result, GrblMessage::ProbeEvent(ProbeEvent { success: true, position: array![697.0, 150.0, -31.000, 0.0] }))
     }
+
+    #[test]
+    fn test_parse_setting() {
+        assert_eq!(parse_grbl_line("$110=3000.000"), GrblMessage::Setting(GrblSetting { index: 110, value: "3000.000".to_string() }));
+        assert!(matches!(parse_grbl_line("$N0="), GrblMessage::Unrecognized(_)));
+    }
 }
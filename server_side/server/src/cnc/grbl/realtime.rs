@@ -1,9 +1,17 @@
+// Single-byte commands that Grbl acts on as soon as they're received, outside the line buffer.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RealtimeCommand {
     Reset = 0x18,
     StatusReport = b'?',
     CycleStart = b'~',
     FeedHold = b'!',
+    SafetyDoor = 0x84,
     JogCancel = 0x85,
+    Macro0 = 0x87, // Macros are FluidNC only
+    Macro1 = 0x88,
+    Macro2 = 0x89,
+    Macro3 = 0x8A,
     FeedOverrideReset = 0x90,
     FeedOverridePlusTen = 0x91,
     FeedOverrideMinusTen = 0x92,
@@ -18,6 +26,22 @@ pub enum RealtimeCommand {
     SpindleOverridePlusOne = 0x9C,
     SpindleOverrideMinusOne = 0x9D,
     ToggleSpindleStop = 0x9E, //Only in HOLD state
+    ToggleFloodCoolant = 0xA0,
+    ToggleMistCoolant = 0xA1,
+}
+impl RealtimeCommand {
+    pub fn byte(self) -> u8 {
+        self as u8
+    }
+    pub fn macro_index(index: u8) -> Option<RealtimeCommand> {
+        match index {
+            0 => Some(RealtimeCommand::Macro0),
+            1 => Some(RealtimeCommand::Macro1),
+            2 => Some(RealtimeCommand::Macro2),
+            3 => Some(RealtimeCommand::Macro3),
+            _ => None,
+        }
+    }
 }
 
 
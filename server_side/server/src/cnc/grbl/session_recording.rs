@@ -0,0 +1,265 @@
+/*
+    Recording of what passes over the serial line, as seen through MachineDebugEvent, so that a
+session with the real machine can later be replayed against run_machine_with_handler. During a
+replay, the recording plays the machine's side with the original timing and checks that the server
+sends the same bytes it sent originally.
+*/
+use std::{path::Path, time::Duration};
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::File,
+    io::{duplex, split, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter, DuplexStream, ReadHalf, WriteHalf},
+    select, spawn,
+    sync::oneshot,
+    time::{sleep_until, Instant},
+};
+
+use crate::util::history_broadcast::{self, ReceiverError};
+
+use super::{messages::GrblMessage, parser::parse_grbl_line, standard_handler::MachineDebugEvent};
+
+// Bytes that may be in flight in either direction of a replay before writes wait on the other side.
+const PIPE_CAPACITY: usize = 4096;
+// Longest a recorded event may sit in the write buffer before it reaches the file.
+const FLUSH_DELAY: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind")]
+pub enum SessionEvent {
+    // Offsets are microseconds since the recording started.
+    Sent { offset_us: u64, bytes: Vec<u8> },
+    Received { offset_us: u64, line: String },
+    // The recorder fell behind the debug stream and lost this many events.
+    Gap { offset_us: u64, missed: usize },
+}
+impl SessionEvent {
+    pub fn offset(&self) -> Duration {
+        match self {
+            SessionEvent::Sent { offset_us, .. } | SessionEvent::Received { offset_us, .. } | SessionEvent::Gap { offset_us, .. } => {
+                Duration::from_micros(*offset_us)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SessionRecording {
+    pub events: Vec<SessionEvent>,
+}
+impl SessionRecording {
+    // Recordings are stored as one JSON-encoded SessionEvent per line.
+    pub async fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let mut lines = BufReader::new(File::open(path).await?).lines();
+        let mut events = Vec::new();
+        while let Some(line) = lines.next_line().await? {
+            if !line.trim().is_empty() {
+                events.push(serde_json::from_str(&line)?);
+            }
+        }
+        Ok(SessionRecording { events })
+    }
+    pub fn received_lines(&self) -> impl Iterator<Item=(Duration, &str)> {
+        self.events.iter().filter_map(|event| match event {
+            SessionEvent::Received { line, .. } => Some((event.offset(), line.as_str())),
+            SessionEvent::Sent { .. } | SessionEvent::Gap { .. } => None,
+        })
+    }
+    pub fn parsed_received_lines(&self) -> impl Iterator<Item=(Duration, GrblMessage)> + '_ {
+        self.received_lines().map(|(offset, line)| (offset, parse_grbl_line(line)))
+    }
+}
+
+fn offset_since(start: DateTime<Local>, time: DateTime<Local>) -> u64 {
+    (time - start).num_microseconds().unwrap_or(0).max(0) as u64
+}
+
+/*
+    Writes sent and received events to the given file until the debug stream closes. If the stream lags,
+a gap marker takes the place of the missed events so the rest of the session is still kept; replays
+stop at the gap. Writes are flushed once FLUSH_DELAY has passed since the first unflushed one, so a
+busy session isn't flushed per line but an idle one is never left sitting in the buffer.
+*/
+pub async fn record_session(mut events: history_broadcast::Receiver<MachineDebugEvent>, path: impl AsRef<Path>) -> anyhow::Result<()> {
+    let mut file = BufWriter::new(File::create(path).await?);
+    let start = Local::now();
+    let mut flush_at = None;
+    loop {
+        let received = select! {
+            received = events.recv() => received,
+            _ = sleep_until(flush_at.unwrap_or_else(Instant::now)), if flush_at.is_some() => {
+                file.flush().await?;
+                flush_at = None;
+                continue;
+            }
+        };
+        let event = match received {
+            Ok(MachineDebugEvent::Sent(time, bytes)) => SessionEvent::Sent { offset_us: offset_since(start, time), bytes },
+            Ok(MachineDebugEvent::Received(time, line)) => SessionEvent::Received { offset_us: offset_since(start, time), line },
+            Ok(MachineDebugEvent::Warning(..) | MachineDebugEvent::Comment(..)) => continue,
+            Err(ReceiverError::Lagged(missed)) => SessionEvent::Gap { offset_us: offset_since(start, Local::now()), missed },
+            Err(ReceiverError::Closed) => break,
+        };
+        let mut line = serde_json::to_string(&event)?;
+        line.push('\n');
+        file.write_all(line.as_bytes()).await?;
+        flush_at.get_or_insert_with(|| Instant::now() + FLUSH_DELAY);
+    }
+    file.flush().await?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ReplayOptions {
+    // Status polls depend on wall-clock timing, so usually shouldn't be required to match.
+    pub ignore_status_queries: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReplayMismatch {
+    Diverged { index: usize, expected: Vec<u8>, actual: Vec<u8> },
+    Disconnected { index: usize },
+    // The recording lost events here, so nothing after it can be compared.
+    Gap { index: usize },
+}
+
+/*
+    Plays the machine's side of a recording over an in-memory pipe, returning the server's side of the
+pipe and the eventual result of comparing what the server sent. Received lines are written at their
+recorded offsets, but never before the bytes recorded ahead of them have been sent by the server.
+The pipe is kept open after the replay ends so that the server doesn't see a disconnect.
+*/
+// Should only be called from within a tokio runtime.
+pub fn replay_session(
+    recording: SessionRecording,
+    options: ReplayOptions,
+) -> (ReadHalf<DuplexStream>, WriteHalf<DuplexStream>, oneshot::Receiver<Result<(), ReplayMismatch>>) {
+    let (host, machine) = duplex(PIPE_CAPACITY);
+    let (mut input, mut output) = split(machine);
+    let (result_tx, result_rx) = oneshot::channel();
+    spawn(async move {
+        let start = Instant::now();
+        let is_compared = |byte: &u8| !(options.ignore_status_queries && *byte == b'?');
+        let result = async {
+            for (index, event) in recording.events.into_iter().enumerate() {
+                match event {
+                    SessionEvent::Sent { bytes, .. } => {
+                        let expected: Vec<u8> = bytes.into_iter().filter(is_compared).collect();
+                        let mut actual = Vec::with_capacity(expected.len());
+                        while actual.len() < expected.len() {
+                            let byte = input.read_u8().await.map_err(|_| ReplayMismatch::Disconnected { index })?;
+                            if is_compared(&byte) {
+                                actual.push(byte);
+                            }
+                        }
+                        if actual != expected {
+                            return Err(ReplayMismatch::Diverged { index, expected, actual });
+                        }
+                    }
+                    SessionEvent::Received { offset_us, line } => {
+                        sleep_until(start + Duration::from_micros(offset_us)).await;
+                        output.write_all(format!("{}\r\n", line).as_bytes()).await.map_err(|_| ReplayMismatch::Disconnected { index })?;
+                    }
+                    SessionEvent::Gap { .. } => return Err(ReplayMismatch::Gap { index }),
+                }
+            }
+            Ok(())
+        }.await;
+        drop(result_tx.send(result));
+        // Hold the pipe open until the server hangs up.
+        let mut discard = [0u8; 256];
+        while let Ok(count) = input.read(&mut discard).await {
+            if count == 0 {
+                break;
+            }
+        }
+    });
+    let (reader, writer) = split(host);
+    (reader, writer, result_rx)
+}
+
+#[cfg(test)]
+mod test {
+    use tempdir::TempDir;
+
+    use crate::cnc::{gcode::GCodeFormatSpecification, grbl::{new_machine::run_machine_with_handler, standard_handler::StandardHandler}, machine_writer::BufferCountingWriter};
+
+    use super::*;
+
+    fn recording() -> SessionRecording {
+        SessionRecording {
+            events: vec![
+                SessionEvent::Received { offset_us: 0, line: "Grbl 1.1h ['$' for help]".to_string() },
+                SessionEvent::Sent { offset_us: 1000, bytes: b"G0X1\n".to_vec() },
+                SessionEvent::Received { offset_us: 5000, line: "ok".to_string() },
+            ]
+        }
+    }
+    async fn replay_line(line: &'static str) -> Result<(), ReplayMismatch> {
+        let (reader, writer, result) = replay_session(recording(), ReplayOptions { ignore_status_queries: true });
+        let parts = StandardHandler::create(GCodeFormatSpecification {
+            axis_letters: b"XYZ".to_vec(),
+            offset_axis_letters: b"IJK".to_vec(),
+            float_digits: 3,
+        });
+        let machine = run_machine_with_handler(parts.handler, BufferCountingWriter::new(writer, 112), BufReader::new(reader));
+        let job = async {
+            let job = parts.immediate_handle.get_job_handle().await.unwrap();
+            drop(unsafe { job.send_gcode_raw(line.as_bytes().to_vec()).await });
+            result.await.unwrap()
+        };
+        select! {
+            _ = machine => panic!("machine loop exited"),
+            result = job => result,
+        }
+    }
+
+    #[tokio::test]
+    async fn replay_accepts_matching_session() {
+        assert_eq!(replay_line("G0X1\n").await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn replay_reports_divergence() {
+        assert_eq!(
+            replay_line("G0X2\n").await,
+            Err(ReplayMismatch::Diverged { index: 1, expected: b"G0X1\n".to_vec(), actual: b"G0X2\n".to_vec() })
+        );
+    }
+
+    #[tokio::test]
+    async fn recording_round_trips_through_file() {
+        let directory = TempDir::new("session_recording").unwrap();
+        let path = directory.path().join("session.jsonl");
+        let sender = history_broadcast::Sender::new(16);
+        let receiver = sender.subscribe_with_history_count(0);
+        sender.send(MachineDebugEvent::Received(Local::now(), "ok".to_string()));
+        sender.send(MachineDebugEvent::Warning(Local::now(), "not recorded".to_string()));
+        sender.send(MachineDebugEvent::Sent(Local::now(), vec![b'?']));
+        drop(sender);
+        record_session(receiver, &path).await.unwrap();
+        let loaded = SessionRecording::load(&path).await.unwrap();
+        assert_eq!(loaded.received_lines().map(|(_, line)| line).collect::<Vec<_>>(), vec!["ok"]);
+        assert!(matches!(&loaded.events[1], SessionEvent::Sent { bytes, .. } if bytes == b"?"));
+        assert!(matches!(loaded.parsed_received_lines().next(), Some((_, GrblMessage::GrblOk))));
+    }
+
+    #[tokio::test]
+    async fn lagging_recording_keeps_a_gap_marker() {
+        let directory = TempDir::new("session_recording").unwrap();
+        let path = directory.path().join("session.jsonl");
+        let sender = history_broadcast::Sender::new(2);
+        let receiver = sender.subscribe_with_history_count(0);
+        for line in ["one", "two", "three", "four"] {
+            sender.send(MachineDebugEvent::Received(Local::now(), line.to_string()));
+        }
+        drop(sender);
+        record_session(receiver, &path).await.unwrap();
+        let loaded = SessionRecording::load(&path).await.unwrap();
+        assert!(matches!(loaded.events[0], SessionEvent::Gap { missed: 2, .. }));
+        assert_eq!(loaded.received_lines().map(|(_, line)| line).collect::<Vec<_>>(), vec!["three", "four"]);
+        let (_reader, _writer, result) = replay_session(loaded, ReplayOptions::default());
+        assert_eq!(result.await.unwrap(), Err(ReplayMismatch::Gap { index: 0 }));
+    }
+}
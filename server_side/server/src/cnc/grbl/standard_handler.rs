@@ -7,14 +7,14 @@ use std::{sync::Arc, future::IntoFuture, cell::{RefCell, Cell}, pin::Pin, time::
 
 use crate::{cnc::{gcode::{GCodeLine, GCodeFormatSpecification}}, util::{local_generation_counter::LocalGenerationCounter, fixed_rb::{FixedRb}, history_broadcast, format_bytes::format_byte_string, future_or_pending::FutureOrPending}};
 
-use super::{handler::{Handler, SpeedOverride}, new_machine::{LineError, WriteRequest, ProbeError, ImmediateRequest}, messages::{ProbeEvent, GrblStateInfo}};
+use super::{handler::{Handler, SpeedOverride}, realtime::RealtimeCommand, new_machine::{LineError, WriteRequest, ProbeError, ImmediateRequest}, messages::{ProbeEvent, GrblStateInfo, GrblMessage}};
 use async_trait::async_trait;
 use chrono::{DateTime, Local, Utc};
-use common::grbl::GrblState;
+use common::grbl::{GrblState, ActiveAlarm, AlarmRecovery, GrblSetting};
 use futures::{Future, io::Write, FutureExt, future::OptionFuture, pin_mut};
 use serde::Serialize;
 use tokio::{sync::{mpsc, oneshot, watch}, select, spawn, runtime::Handle, time::{sleep, timeout}};
-use common::api::JobStatus;
+use common::api::{JobProgress, JobStatus};
 
 #[derive(Debug)]
 pub enum Message {
@@ -22,6 +22,9 @@ pub enum Message {
     Write(WriteRequest),
     Comment(String),
     SetStatus(JobStatus),
+    SetProgress(JobProgress),
+    Pause,
+    Stop,  // Feed hold, then end the job once the machine has halted.
 }
 #[derive(Debug)]
 pub enum ImmediateMessage {
@@ -33,6 +36,10 @@ pub enum ImmediateMessage {
     Reset,
     OverrideSpeed(SpeedOverride),
     InitiateJob(oneshot::Sender<Option<JobHandle>>),
+    GetAlarm(oneshot::Sender<watch::Receiver<Option<ActiveAlarm>>>),
+    RecoverFromAlarm(AlarmRecovery, oneshot::Sender<Result<oneshot::Receiver<Result<(), LineError>>, AlarmRecoveryError>>),
+    // The state, if given, comes from a status report requested for this command.
+    Realtime(RealtimeCommand, Option<GrblState>, oneshot::Sender<Result<(), RealtimeCommandError>>),
 }
 // ... if we wanted, we could go further and refactor out this logging functionality ...
 #[derive(Clone, Debug)]
@@ -47,6 +54,87 @@ pub enum MachineDebugEvent {
 #[derive(Debug)]
 pub struct JobFail;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlarmRecoveryError {
+    NoAlarm,
+    ResetRequired,
+    NotOffered,
+    Busy,
+}
+impl std::fmt::Display for AlarmRecoveryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AlarmRecoveryError::NoAlarm => write!(f, "The machine is not in an alarm state."),
+            AlarmRecoveryError::ResetRequired => write!(f, "The machine must be reset before recovering from this alarm."),
+            AlarmRecoveryError::NotOffered => write!(f, "That recovery is not safe for this alarm."),
+            AlarmRecoveryError::Busy => write!(f, "A job is still running or being stopped."),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RealtimeCommandError {
+    UnknownState,            // No status report has been received yet.
+    NotAllowed(GrblState),   // The controller would ignore (or misbehave on) the command in this state.
+    Unsupported,             // Has a dedicated ImmediateHandle method that keeps the handler's state in step.
+}
+impl std::fmt::Display for RealtimeCommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RealtimeCommandError::UnknownState => write!(f, "The machine's state isn't known yet."),
+            RealtimeCommandError::NotAllowed(state) => write!(f, "That command can't be used while the machine is in {:?}.", state),
+            RealtimeCommandError::Unsupported => write!(f, "That command must be sent through its own endpoint."),
+        }
+    }
+}
+
+// States in which Grbl acts on each command; see the realtime command section of the Grbl 1.1 interface docs.
+fn check_realtime_command(command: RealtimeCommand, state: Option<&GrblState>) -> Result<(), RealtimeCommandError> {
+    let allowed = |state: &GrblState| match command {
+        RealtimeCommand::ToggleSpindleStop => matches!(state, GrblState::Hold { code: 0 }),
+        RealtimeCommand::ToggleFloodCoolant | RealtimeCommand::ToggleMistCoolant =>
+            matches!(state, GrblState::Idle | GrblState::Run | GrblState::Hold { .. }),
+        RealtimeCommand::Macro0 | RealtimeCommand::Macro1 | RealtimeCommand::Macro2 | RealtimeCommand::Macro3 =>
+            matches!(state, GrblState::Idle),
+        _ => true,
+    };
+    match command {
+        // Opening the door must always work, whatever we think the state is.
+        RealtimeCommand::SafetyDoor => Ok(()),
+        RealtimeCommand::Reset | RealtimeCommand::StatusReport | RealtimeCommand::CycleStart | RealtimeCommand::FeedHold => Err(RealtimeCommandError::Unsupported),
+        _ => {
+            let state = state.ok_or(RealtimeCommandError::UnknownState)?;
+            if allowed(state) { Ok(()) } else { Err(RealtimeCommandError::NotAllowed(state.clone())) }
+        }
+    }
+}
+
+// Hard limits and resets in motion lose the machine position, so only homing is offered for them.
+// Grbl only accepts commands after critical alarms (hard and soft limits) once it has been reset.
+fn describe_alarm(code: u64) -> ActiveAlarm {
+    ActiveAlarm {
+        code: Some(code),
+        text: GrblMessage::get_alarm_text(code).into_owned(),
+        requires_reset: matches!(code, 1 | 2),
+        recovery: match code {
+            1 | 3 => vec![AlarmRecovery::Home],
+            _ => vec![AlarmRecovery::Unlock, AlarmRecovery::Home],
+        },
+    }
+}
+fn describe_unexplained_alarm() -> ActiveAlarm {
+    ActiveAlarm {
+        code: None,
+        text: "Machine is locked; home or unlock it to continue.".to_string(),
+        requires_reset: false,
+        recovery: vec![AlarmRecovery::Unlock, AlarmRecovery::Home],
+    }
+}
+
+// Lines still waiting to be written are dropped when a job is stopped; they never run, as after a reset.
+fn line_result(result: Result<Result<(), LineError>, oneshot::error::RecvError>) -> Result<(), LineError> {
+    result.unwrap_or(Err(LineError::Reset))
+}
 
 #[derive(Debug)]
 pub struct JobHandle {
@@ -55,18 +143,28 @@ pub struct JobHandle {
     start_time: chrono::DateTime<Utc>,
 }
 impl JobHandle {
+    // The exact bytes that send_gcode would write for a line.
+    pub fn format_gcode(&self, gcode: &GCodeLine) -> Vec<u8> {
+        format!("{}\n", self.format_specification.format_line(gcode)).into_bytes()
+    }
     pub async fn send_gcode(&self, gcode: GCodeLine) -> Result<impl Future<Output=Result<(), LineError>>, JobFail> {
-        let bytes = format!("{}\n", self.format_specification.format_line(&gcode)).into_bytes();
+        let bytes = self.format_gcode(&gcode);
         unsafe {  // Safe because we just formatted it.
             self.send_gcode_raw(bytes).await
         }
     }
     pub async fn send_probe_gcode(&self, gcode: GCodeLine) -> Result<(impl Future<Output=Result<(), LineError>>, impl Future<Output=Result<ProbeEvent, ProbeError>>), JobFail>  {
-        let bytes = format!("{}\n", self.format_specification.format_line(&gcode)).into_bytes();
+        let bytes = self.format_gcode(&gcode);
         let (line_tx, line_rx) = oneshot::channel();
         let (probe_tx, probe_rx) = oneshot::channel();
         self.sender.send(Message::Write(WriteRequest::Probe { data: bytes, result_line: line_tx, result: probe_tx })).await.map_err(|_| JobFail)?;
-        Ok((line_rx.map(Result::unwrap), probe_rx.map(Result::unwrap)))
+        Ok((line_rx.map(line_result), probe_rx.map(Result::unwrap)))
+    }
+    // Lists the controller's settings with "$$".
+    pub async fn read_settings(&self) -> Result<impl Future<Output=Result<Vec<GrblSetting>, LineError>>, JobFail> {
+        let (tx, rx) = oneshot::channel();
+        self.sender.send(Message::Write(WriteRequest::Settings { data: b"$$\n".to_vec(), result: tx })).await.map_err(|_| JobFail)?;
+        Ok(rx.map(|result| result.unwrap_or(Err(LineError::Reset))))
     }
     pub async fn send_comment(&self, message: String) -> Result<(), JobFail> {
         self.sender.send(Message::Comment(message)).await.map_err(|_| JobFail)?;
@@ -81,7 +179,20 @@ impl JobHandle {
         Ok(self.request_state().await?.await)
     }
     pub async fn set_status(&self, status: String) -> Result<(), JobFail> {
-        self.sender.send(Message::SetStatus(JobStatus { start_time: self.start_time, message: status })).await.map_err(|_| JobFail)?;
+        self.sender.send(Message::SetStatus(JobStatus { start_time: self.start_time, message: status, progress: None })).await.map_err(|_| JobFail)?;
+        Ok(())
+    }
+    pub async fn set_progress(&self, progress: JobProgress) -> Result<(), JobFail> {
+        self.sender.send(Message::SetProgress(progress)).await.map_err(|_| JobFail)?;
+        Ok(())
+    }
+    pub async fn pause(&self) -> Result<(), JobFail> {
+        self.sender.send(Message::Pause).await.map_err(|_| JobFail)?;
+        Ok(())
+    }
+    // Ends the job like ImmediateHandle::stop; later sends on this handle fail.
+    pub async fn stop(&self) -> Result<(), JobFail> {
+        self.sender.send(Message::Stop).await.map_err(|_| JobFail)?;
         Ok(())
     }
     /*
@@ -92,7 +203,7 @@ impl JobHandle {
         let (tx, rx) = oneshot::channel();
         self.sender.send(Message::Write(WriteRequest::Plain { data: bytes, result: tx })).await.map_err(|_| JobFail)?;
 
-        Ok(rx.map(Result::unwrap))
+        Ok(rx.map(line_result))
     }
 
 }
@@ -147,6 +258,29 @@ impl ImmediateHandle {
         self.sender.send(ImmediateMessage::GetJobStatus(tx)).await.unwrap();
         rx.await.unwrap()
     }
+    pub async fn subscribe_alarm(&self) -> watch::Receiver<Option<ActiveAlarm>> {
+        let (tx, rx) = oneshot::channel();
+        self.sender.send(ImmediateMessage::GetAlarm(tx)).await.unwrap();
+        rx.await.unwrap()
+    }
+    pub async fn send_realtime(&self, command: RealtimeCommand) -> Result<(), RealtimeCommandError> {
+        // Stopping the spindle before a hold has finished would stop it under load, and the last
+        // status report may be from before the hold completed, so ask again.
+        let fresh_state = match command {
+            RealtimeCommand::ToggleSpindleStop => Some(self.get_state().await.state),
+            _ => None,
+        };
+        let (tx, rx) = oneshot::channel();
+        self.sender.send(ImmediateMessage::Realtime(command, fresh_state, tx)).await.unwrap();
+        rx.await.unwrap()
+    }
+    // The inner result is the controller's response to the unlock or homing command.
+    pub async fn recover_from_alarm(&self, recovery: AlarmRecovery) -> Result<Result<(), LineError>, AlarmRecoveryError> {
+        let (tx, rx) = oneshot::channel();
+        self.sender.send(ImmediateMessage::RecoverFromAlarm(recovery, tx)).await.unwrap();
+        let line_result = rx.await.unwrap()?;
+        Ok(line_result.await.unwrap_or(Err(LineError::Reset)))
+    }
 }
 
 struct HandlerPrivateState {
@@ -165,6 +299,9 @@ pub struct StandardHandler {
 
     debug_stream: history_broadcast::Sender<MachineDebugEvent>,
     job_status: watch::Sender<Option<JobStatus>>,
+    alarm: watch::Sender<Option<ActiveAlarm>>,
+    stop_for_alarm: Cell<bool>,  // Set by on_alarm; the run loop then ends the job.
+    last_state: RefCell<Option<GrblState>>,  // From the latest status report.
 }
 pub struct StandardHandlerParts {
     pub handler: StandardHandler,
@@ -196,6 +333,9 @@ impl StandardHandler {
 
                 debug_stream: debug_tx,
                 job_status: watch::channel(None).0,
+                alarm: watch::channel(None).0,
+                stop_for_alarm: Cell::new(false),
+                last_state: RefCell::new(None),
             },
             immediate_handle: ImmediateHandle { sender: immediate_tx },
             debug_rx
@@ -215,6 +355,27 @@ impl StandardHandler {
         private.job_receiver = None;
     }
 
+    fn start_alarm_recovery(&self, private: &HandlerPrivateState, is_halting: bool, recovery: AlarmRecovery) -> Result<oneshot::Receiver<Result<(), LineError>>, AlarmRecoveryError> {
+        let alarm = self.alarm.borrow().clone().ok_or(AlarmRecoveryError::NoAlarm)?;
+        if alarm.requires_reset {
+            return Err(AlarmRecoveryError::ResetRequired);
+        }
+        if !alarm.recovery.contains(&recovery) {
+            return Err(AlarmRecoveryError::NotOffered);
+        }
+        if private.job_receiver.is_some() || is_halting {
+            return Err(AlarmRecoveryError::Busy);
+        }
+        let data = match recovery {
+            AlarmRecovery::Unlock => b"$X\n".to_vec(),
+            AlarmRecovery::Home => b"$H\n".to_vec(),
+        };
+        let (tx, rx) = oneshot::channel();
+        self.mutate_and_advance(|inner| inner.waiting_writes.push(WriteRequest::Plain { data, result: tx }))
+            .map_err(|_| AlarmRecoveryError::Busy)?;
+        Ok(rx)
+    }
+
     async fn send_immediate_request(&self, mut request: ImmediateRequest) {
         loop {
             let result = self.mutate_and_advance(move |inner|
@@ -318,6 +479,9 @@ impl Handler for StandardHandler {
         let halt_future = FutureOrPending::new(None);
         pin_mut!(halt_future);
         loop {
+            if self.stop_for_alarm.replace(false) {
+                self.stop_job(private);
+            }
             select! {
                 biased;
                 immediate = private.immediate_receiver.recv(), if !self.mutate(|inner| inner.waiting_immediate.is_full()) => {
@@ -377,6 +541,21 @@ impl Handler for StandardHandler {
                                 inner.waiting_immediate.push(ImmediateRequest::OverrideSpeed(speed_override)).unwrap()
                             );
                         }
+                        Some(ImmediateMessage::GetAlarm(tx)) => {
+                            drop(tx.send(self.alarm.subscribe()))
+                        }
+                        Some(ImmediateMessage::RecoverFromAlarm(recovery, tx)) => {
+                            drop(tx.send(self.start_alarm_recovery(private, halt_future.is_some(), recovery)))
+                        }
+                        Some(ImmediateMessage::Realtime(command, fresh_state, tx)) => {
+                            let result = check_realtime_command(command, fresh_state.as_ref().or(self.last_state.borrow().as_ref()));
+                            if result.is_ok() {
+                                self.mutate_and_advance(|inner|
+                                    inner.waiting_immediate.push(ImmediateRequest::Realtime(command)).unwrap()
+                                );
+                            }
+                            drop(tx.send(result))
+                        }
                         None => ()
                     }
                 }
@@ -395,9 +574,28 @@ impl Handler for StandardHandler {
                         Some(Message::Comment(message)) => {
                             self.debug_stream.send(MachineDebugEvent::Comment(Local::now(), message));
                         },
-                        Some(Message::SetStatus(message)) => {
+                        Some(Message::SetStatus(mut message)) => {
+                            // Progress is reported separately, so keep whatever was reported last.
+                            message.progress = self.job_status.borrow().as_ref().and_then(|status| status.progress.clone());
                             drop(self.job_status.send(Some(message)));
                         }
+                        Some(Message::SetProgress(progress)) => {
+                            self.job_status.send_modify(|status| if let Some(status) = status {
+                                status.progress = Some(progress);
+                            });
+                        }
+                        Some(Message::Pause) => {
+                            self.mutate_and_advance(|inner|
+                                inner.waiting_immediate.push(ImmediateRequest::FeedHold).unwrap()
+                            );
+                        }
+                        Some(Message::Stop) => {
+                            self.stop_job(private);
+                            self.mutate_and_advance(|inner|
+                                inner.waiting_immediate.push(ImmediateRequest::FeedHold).unwrap()
+                            );
+                            halt_future.set(Some(self.graceful_halt()).into());
+                        }
                         None => {
                             drop(self.job_status.send(None));
                             private.job_receiver = None;  // job hung up - must be done
@@ -419,4 +617,209 @@ impl Handler for StandardHandler {
     fn warn(&self, message: String) {
         self.debug_stream.send(MachineDebugEvent::Warning(Local::now(), message))
     }
-}
\ No newline at end of file
+    fn after_status(&self, state: &GrblStateInfo) {
+        // Status reports are the source of truth for whether an alarm has been cleared (or was
+        // raised without our seeing why, as when the controller starts up locked).
+        *self.last_state.borrow_mut() = Some(state.state.clone());
+        let in_alarm = state.state == GrblState::Alarm;
+        self.alarm.send_if_modified(|alarm| match (in_alarm, alarm.is_some()) {
+            (false, true) => {
+                *alarm = None;
+                true
+            }
+            (true, false) => {
+                *alarm = Some(describe_unexplained_alarm());
+                true
+            }
+            _ => false,
+        });
+    }
+    async fn on_alarm(&self, index: u64) {
+        // The controller has stopped and will reject further lines; end the job rather than letting it stream into errors.
+        self.alarm.send_replace(Some(describe_alarm(index)));
+        self.stop_for_alarm.set(true);
+        self.mutate_and_advance(|inner| inner.waiting_writes.clear());
+    }
+    async fn after_reset(&self) {
+        self.alarm.send_if_modified(|alarm| match alarm {
+            Some(alarm) if alarm.requires_reset => {
+                alarm.requires_reset = false;
+                true
+            }
+            _ => false,
+        });
+    }
+}
+#[cfg(test)]
+pub(crate) mod test {
+    use machine_mock::{in_process::port_to_machine, simulated::{simulated_machine, SimulatedMachineConfig, ProbeSurface}};
+    use tokio::io::BufReader;
+
+    use crate::cnc::{gcode::{AxisValues, GCodeCommand, GCodeModal, ProbeDirection, ProbeRequirement}, grbl::new_machine::run_machine_with_handler, machine_writer::BufferCountingWriter};
+
+    use super::*;
+
+    // Runs a handler against an in-process simulated machine for as long as the test body runs.
+    // Tests that wait for motion run with paused time, so their sleeps step machine time exactly instead of racing the clock.
+    pub(crate) async fn with_simulated_machine<T, Fut: Future<Output=T>>(config: SimulatedMachineConfig, test: impl FnOnce(ImmediateHandle) -> Fut) -> T {
+        let (reader, writer) = port_to_machine(move |input, output| simulated_machine(config, input, output));
+        let parts = StandardHandler::create(GCodeFormatSpecification {
+            axis_letters: b"XYZ".to_vec(),
+            offset_axis_letters: b"IJK".to_vec(),
+            float_digits: 3,
+        });
+        let machine = run_machine_with_handler(parts.handler, BufferCountingWriter::new(writer, 112), BufReader::new(reader));
+        select! {
+            _ = machine => panic!("machine loop exited"),
+            result = test(parts.immediate_handle) => result,
+        }
+    }
+    async fn wait_for_state(machine: &ImmediateHandle, reached: impl Fn(&GrblStateInfo) -> bool) -> GrblStateInfo {
+        let poll = async {
+            loop {
+                let state = machine.get_state().await;
+                if reached(&state) {
+                    return state;
+                }
+                sleep(Duration::from_millis(10)).await;
+            }
+        };
+        timeout(Duration::from_secs(5), poll).await.expect("machine never reached the expected state")
+    }
+    pub(crate) async fn send_line(job: &JobHandle, line: &str) -> Result<(), LineError> {
+        unsafe { job.send_gcode_raw(format!("{}\n", line).into_bytes()).await }.unwrap().await
+    }
+
+    #[tokio::test]
+    async fn job_lines_report_results() {
+        with_simulated_machine(Default::default(), |machine| async move {
+            let job = machine.get_job_handle().await.unwrap();
+            assert!(machine.get_job_handle().await.is_none());
+            send_line(&job, "G1 X-1 F600").await.unwrap();
+            assert!(matches!(send_line(&job, "G7").await, Err(LineError::Grbl(20))));
+            send_line(&job, "G4 P0.01").await.unwrap();
+            assert_eq!(machine.get_state().await.machine_position[0], -1.0);
+        }).await
+    }
+
+    #[tokio::test]
+    async fn settings_are_listed_and_changed() {
+        with_simulated_machine(Default::default(), |machine| async move {
+            let job = machine.get_job_handle().await.unwrap();
+            send_line(&job, "$110=1234").await.unwrap();
+            let settings = job.read_settings().await.unwrap().await.unwrap();
+            let max_rate = settings.iter().find(|setting| setting.index == 110).unwrap();
+            assert_eq!(max_rate.value.parse::<f64>().unwrap(), 1234.0);
+            assert!(settings.iter().any(|setting| setting.index == 0));
+            assert!(matches!(send_line(&job, "$999=1").await, Err(LineError::Grbl(3))));
+        }).await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pause_holds_until_resumed() {
+        with_simulated_machine(Default::default(), |machine| async move {
+            let job = machine.get_job_handle().await.unwrap();
+            send_line(&job, "G1 X-100 F600").await.unwrap();
+            sleep(Duration::from_millis(100)).await;
+            machine.pause().await;
+            sleep(Duration::from_millis(100)).await;
+            let held = machine.get_state().await;
+            assert_eq!(held.state, GrblState::Hold { code: 0 });
+            sleep(Duration::from_millis(100)).await;
+            assert_eq!(machine.get_state().await.machine_position, held.machine_position);
+            machine.resume().await;
+            sleep(Duration::from_millis(100)).await;
+            assert_eq!(machine.get_state().await.state, GrblState::Run);
+        }).await
+    }
+
+    // Stopping polls the status without pause until the hold completes, which paused time would never
+    // advance past, so this one waits on the machine's state with real time instead.
+    #[tokio::test]
+    async fn stop_halts_motion_and_ends_job() {
+        with_simulated_machine(Default::default(), |machine| async move {
+            let job = machine.get_job_handle().await.unwrap();
+            send_line(&job, "G1 X-100 F600").await.unwrap();
+            wait_for_state(&machine, |state| state.state == GrblState::Run && state.machine_position[0] < 0.0).await;
+            machine.stop().await;
+            let state = wait_for_state(&machine, |state| state.state == GrblState::Idle).await;
+            assert!(state.machine_position[0] > -100.0);
+            assert!(job.send_comment("still running?".to_string()).await.is_err());
+            assert!(machine.get_job_handle().await.is_some());
+        }).await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reset_during_motion_alarms() {
+        with_simulated_machine(Default::default(), |machine| async move {
+            let job = machine.get_job_handle().await.unwrap();
+            send_line(&job, "G1 X-100 F600").await.unwrap();
+            sleep(Duration::from_millis(100)).await;
+            machine.reset().await;
+            sleep(Duration::from_millis(100)).await;
+            assert_eq!(machine.get_state().await.state, GrblState::Alarm);
+        }).await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn alarm_ends_job_until_recovered() {
+        with_simulated_machine(Default::default(), |machine| async move {
+            let job = machine.get_job_handle().await.unwrap();
+            let mut alarm = machine.subscribe_alarm().await;
+            let pending = unsafe { job.send_gcode_raw(b"G0 X10\n".to_vec()).await }.unwrap();
+            alarm.changed().await.unwrap();
+            let active = alarm.borrow().clone().unwrap();
+            assert_eq!((active.code, active.requires_reset), (Some(2), true));
+            sleep(Duration::from_millis(50)).await;
+            assert!(job.send_comment("still running?".to_string()).await.is_err());
+            assert!(matches!(machine.recover_from_alarm(AlarmRecovery::Unlock).await, Err(AlarmRecoveryError::ResetRequired)));
+            machine.reset().await;
+            assert!(matches!(pending.await, Err(LineError::Reset)));
+            assert_eq!(machine.get_state().await.state, GrblState::Alarm);
+            assert!(!alarm.borrow().as_ref().unwrap().requires_reset);
+            assert!(matches!(machine.recover_from_alarm(AlarmRecovery::Unlock).await, Ok(Ok(()))));
+            assert_eq!(machine.get_state().await.state, GrblState::Idle);
+            assert!(alarm.borrow().is_none());
+        }).await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn spindle_stop_only_while_held() {
+        with_simulated_machine(Default::default(), |machine| async move {
+            assert_eq!(machine.send_realtime(RealtimeCommand::ToggleFloodCoolant).await, Err(RealtimeCommandError::UnknownState));
+            // Spindle stop asks for its own status report, so needs no earlier one.
+            assert_eq!(machine.send_realtime(RealtimeCommand::ToggleSpindleStop).await, Err(RealtimeCommandError::NotAllowed(GrblState::Idle)));
+            assert_eq!(machine.send_realtime(RealtimeCommand::FeedHold).await, Err(RealtimeCommandError::Unsupported));
+            assert_eq!(machine.send_realtime(RealtimeCommand::ToggleFloodCoolant).await, Ok(()));
+            let job = machine.get_job_handle().await.unwrap();
+            send_line(&job, "G1 X-100 F600").await.unwrap();
+            sleep(Duration::from_millis(100)).await;
+            machine.pause().await;
+            // Still slowing down.
+            assert_eq!(machine.send_realtime(RealtimeCommand::ToggleSpindleStop).await, Err(RealtimeCommandError::NotAllowed(GrblState::Hold { code: 1 })));
+            sleep(Duration::from_millis(100)).await;
+            assert_eq!(machine.send_realtime(RealtimeCommand::ToggleSpindleStop).await, Ok(()));
+        }).await
+    }
+
+    #[tokio::test]
+    async fn probe_reports_contact() {
+        let config = SimulatedMachineConfig { probe_surface: ProbeSurface::flat(-20.0), ..Default::default() };
+        with_simulated_machine(config, |machine| async move {
+            let job = machine.get_job_handle().await.unwrap();
+            let (line, probe) = job.send_probe_gcode(GCodeLine {
+                modals: vec![GCodeModal::SetFeedrate(6000.0)],
+                command: Some(GCodeCommand::Probe {
+                    position: AxisValues(vec![(2, -50.0)]),
+                    mode: ProbeDirection::Towards,
+                    requirement: ProbeRequirement::Require,
+                }),
+            }).await.unwrap();
+            let event = probe.await.unwrap();
+            line.await.unwrap();
+            assert!(event.success);
+            assert!((event.position[2] + 20.0).abs() < 1e-3);
+            assert!(machine.get_state().await.probe);
+        }).await
+    }
+}
@@ -1,55 +1,164 @@
-use std::pin::Pin;
+use std::{pin::Pin, sync::Mutex};
 
-use futures::{Stream, StreamExt, pin_mut, Future, FutureExt, SinkExt};
-use tokio::sync::mpsc;
+use common::api::{JobErrorPolicy, JobLineError, JobOutcome, JobProgress};
+use futures::{Stream, StreamExt, pin_mut, Future, FutureExt, future::BoxFuture, try_join};
+use tokio::sync::{mpsc, oneshot};
 
 use crate::cnc::gcode::{GCodeLine, GCodeCommand};
 
-use super::{gcode::parser::GeneralizedLineOwned, grbl::{standard_handler::{JobHandle, JobFail}, messages::ProbeEvent}};
+use super::{gcode::parser::GeneralizedLineOwned, grbl::{standard_handler::{JobHandle, JobFail}, messages::{ProbeEvent, GrblMessage}, new_machine::LineError}};
 
-pub fn sized_stream_to_job<S>(stream: S, total_lines: usize, results: mpsc::Sender<ProbeEvent>) -> impl FnOnce(JobHandle) -> Pin<Box<dyn Future<Output=()> + Send + 'static>> + Send + 'static
+// Lines that have been handed to the machine but whose "ok" or "error" hasn't been checked yet.
+const PENDING_LINES: usize = 64;
+
+struct SentLine {
+    line_number: usize,
+    bytes: Vec<u8>,
+    result: BoxFuture<'static, Result<(), LineError>>,
+}
+
+pub struct JobResults {
+    pub probes: mpsc::Sender<ProbeEvent>,
+    pub errors: mpsc::Sender<JobLineError>,
+    pub finished: oneshot::Sender<JobOutcome>, // Completed if every line was sent and the job ran to its end.
+}
+
+fn line_error(line_number: usize, code: u64, bytes: &[u8]) -> JobLineError {
+    JobLineError {
+        line_number,
+        code,
+        text: GrblMessage::get_error_text(code).into_owned(),
+        sent: String::from_utf8_lossy(bytes.strip_suffix(b"\n").unwrap_or(bytes)).into_owned(),
+    }
+}
+
+async fn report_progress(job_handle: &JobHandle, progress: &Mutex<JobProgress>, change: impl FnOnce(&mut JobProgress)) -> Result<(), JobFail> {
+    let progress = {
+        let mut progress = progress.lock().unwrap();
+        change(&mut progress);
+        progress.clone()
+    };
+    job_handle.set_progress(progress).await
+}
+
+// Logs and records an error, then applies the policy. Returns Err if the job should end.
+async fn handle_line_error(job_handle: &JobHandle, policy: JobErrorPolicy, error: JobLineError, results: &JobResults, note: &Mutex<Option<String>>, ending: &Mutex<Option<JobOutcome>>) -> Result<(), JobFail> {
+    job_handle.send_comment(format!(
+        "ERROR AT LINE {}: error:{} {} (sent {:?})",
+        error.line_number, error.code, error.text, error.sent
+    )).await?;
+    let summary = format!("error:{} at line {}: {}", error.code, error.line_number, error.text);
+    drop(results.errors.send(error).await);
+    match policy {
+        JobErrorPolicy::Abort => {
+            *ending.lock().unwrap() = Some(JobOutcome::Error);
+            job_handle.set_status(format!("Stopping after {}", summary)).await?;
+            job_handle.stop().await?;
+            Err(JobFail)
+        }
+        JobErrorPolicy::Pause => {
+            job_handle.pause().await?;
+            *note.lock().unwrap() = Some(format!("paused after {}; resume to continue or stop the job", summary));
+            job_handle.set_status(format!("Paused after {}. Resume to continue or stop the job.", summary)).await?;
+            Ok(())
+        }
+        JobErrorPolicy::Continue => {
+            *note.lock().unwrap() = Some(format!("continued after {}", summary));
+            Ok(())
+        }
+    }
+}
+
+// Each item of the stream is taken to be one line of the file at path, for reporting progress.
+pub fn sized_stream_to_job<S>(stream: S, path: Option<String>, total_lines: usize, error_policy: JobErrorPolicy, results: JobResults) -> impl FnOnce(JobHandle) -> Pin<Box<dyn Future<Output=()> + Send + 'static>> + Send + 'static
 where
     S: Stream<Item=GeneralizedLineOwned> + Send + 'static
 {
     move |job_handle| Box::pin(async move {
         job_handle.set_status("Starting job...".into()).await?;
-        let mut line_num = 0;
-        pin_mut!(stream);
-        loop {
-            match stream.next().await {
-                Some(v) => {
-                    line_num += 1;
-                    job_handle.set_status(format!("At line {}/{}", line_num, total_lines)).await?;
-                    match v {
-                        // If we wished, the next line could have its future handled for whether we get "ok" or "error".
-                        GeneralizedLineOwned::Line(line) => {
-                            if line.command.as_ref().is_some_and(|v| if let GCodeCommand::Probe { .. } = &v { true } else { false }) {
-                                let (line_result, probe_result) = job_handle.send_probe_gcode(line).await?;
-                                line_result.await.map_err(|_| JobFail)?;
-                                let probe_event = probe_result.await.map_err(|_| JobFail)?;
-                                job_handle.send_comment(format!(
-                                    "PROBE RESULT: {}",
-                                    serde_json::to_string(&probe_event).unwrap()
-                                )).await?;
-                                drop(results.send(probe_event).await);
-                            } else {
-                                drop(job_handle.send_gcode(line).await?)
-                            }
-                        },
-                        GeneralizedLineOwned::Comment(comment) => job_handle.send_comment(comment.to_string()).await?,
-                        GeneralizedLineOwned::Empty => {},
+        // The most recent error that the job kept going after, shown alongside the progress.
+        let note = Mutex::new(None);
+        // Set when the job ends itself because of an error; otherwise an early end was a stop or reset.
+        let ending = Mutex::new(None);
+        let progress = Mutex::new(JobProgress { path, total_lines, ..Default::default() });
+        let (pending_tx, mut pending_rx) = mpsc::channel::<SentLine>(PENDING_LINES);
+        let sending = async {
+            let mut line_num = 0;
+            pin_mut!(stream);
+            loop {
+                match stream.next().await {
+                    Some(v) => {
+                        line_num += 1;
+                        let status = match &*note.lock().unwrap() {
+                            Some(note) => format!("At line {}/{} ({})", line_num, total_lines, note),
+                            None => format!("At line {}/{}", line_num, total_lines),
+                        };
+                        job_handle.set_status(status).await?;
+                        report_progress(&job_handle, &progress, |progress| progress.sent_line = line_num).await?;
+                        match v {
+                            GeneralizedLineOwned::Line(line) => {
+                                let bytes = job_handle.format_gcode(&line);
+                                if line.command.as_ref().is_some_and(|v| if let GCodeCommand::Probe { .. } = &v { true } else { false }) {
+                                    let (line_result, probe_result) = job_handle.send_probe_gcode(line).await?;
+                                    match line_result.await {
+                                        Ok(()) => (),
+                                        // Grbl won't report a probe for a rejected line, so the job can't go on.
+                                        Err(LineError::Grbl(code)) => {
+                                            drop(handle_line_error(&job_handle, JobErrorPolicy::Abort, line_error(line_num, code, &bytes), &results, &note, &ending).await);
+                                            return Err(JobFail);
+                                        }
+                                        Err(LineError::Reset) => return Err(JobFail),
+                                    }
+                                    let probe_event = probe_result.await.map_err(|_| JobFail)?;
+                                    report_progress(&job_handle, &progress, |progress| progress.acknowledged_line = line_num).await?;
+                                    job_handle.send_comment(format!(
+                                        "PROBE RESULT: {}",
+                                        serde_json::to_string(&probe_event).unwrap()
+                                    )).await?;
+                                    drop(results.probes.send(probe_event).await);
+                                } else {
+                                    let result = job_handle.send_gcode(line).await?.boxed();
+                                    pending_tx.send(SentLine { line_number: line_num, bytes, result }).await.map_err(|_| JobFail)?;
+                                }
+                            },
+                            GeneralizedLineOwned::Comment(comment) => job_handle.send_comment(comment.to_string()).await?,
+                            GeneralizedLineOwned::Empty => {},
+                        }
+                    }
+                    None => {
+                        // Send a dwell to synchronize at end.
+                        job_handle.set_status(format!("All lines sent. Waiting for machine to finish.")).await?;
+                        let dwell = job_handle.send_gcode(GCodeLine {
+                            modals: Vec::new(),
+                            command: Some(GCodeCommand::Dwell { duration: 0.01 }),
+                        }).await?;
+                        drop(pending_tx);
+                        drop(dwell.await);
+                        report_progress(&job_handle, &progress, |progress| progress.acknowledged_line = total_lines).await?;
+                        return Ok(())
                     }
                 }
-                None => {
-                    // Send a dwell to synchronize at end.
-                    job_handle.set_status(format!("All lines sent. Waiting for machine to finish.")).await?;
-                    drop(job_handle.send_gcode(GCodeLine {
-                        modals: Vec::new(),
-                        command: Some(GCodeCommand::Dwell { duration: 0.01 }),
-                    }).await?.await);
-                    return Ok(())
+            }
+        };
+        let checking = async {
+            while let Some(sent) = pending_rx.recv().await {
+                match sent.result.await {
+                    Ok(()) => (),
+                    Err(LineError::Grbl(code)) => {
+                        handle_line_error(&job_handle, error_policy, line_error(sent.line_number, code, &sent.bytes), &results, &note, &ending).await?
+                    }
+                    Err(LineError::Reset) => return Err(JobFail),
                 }
+                report_progress(&job_handle, &progress, |progress| progress.acknowledged_line = progress.acknowledged_line.max(sent.line_number)).await?;
             }
-        }
+            Ok(())
+        };
+        let result = try_join!(sending, checking).map(|_| ());
+        let outcome = match result {
+            Ok(()) => JobOutcome::Completed,
+            Err(_) => ending.lock().unwrap().unwrap_or(JobOutcome::Stopped),
+        };
+        let _ = results.finished.send(outcome);
+        result
     }.map(|_: Result<(), JobFail>| ()))  // catch and ignore the error!
-}
\ No newline at end of file
+}
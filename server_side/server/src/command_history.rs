@@ -0,0 +1,116 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{Router, Extension, Json, routing::{get, post}};
+use chrono::{DateTime, Utc};
+use common::api::{CommandHistory, CommandHistoryEntry, QueryCommandHistory, SendCommand, SetFavouriteCommand};
+
+use crate::{cnc::grbl::standard_handler::ImmediateHandle, util::{file_backed_json::FileBackedValue, exclusive_extension::ExclusiveExtension}, Config, server_result::{ServerError, ServerResult}};
+
+/*
+    Commands typed into the debug console, kept on the server under the name of whoever typed them so
+that the history follows people between devices. A command sent again moves to the front instead of
+being listed twice.
+*/
+
+const MAX_ENTRIES: usize = 500;
+
+pub async fn get_service(config: &Config) -> anyhow::Result<Router> {
+    let histories: FileBackedValue<HashMap<String, CommandHistory>> = FileBackedValue::new(
+        config.data_folder.join("command_history.json"), Default::default
+    ).await?;
+    let router = Router::new()
+        .route("/", post(get_history))
+        .route("/users", get(list_users))
+        .route("/send", post(send_command))
+        .route("/favourite", post(set_favourite))
+        .layer(ExclusiveExtension::new(histories));
+    Ok(router)
+}
+
+type HistoryInfo = ExclusiveExtension<FileBackedValue<HashMap<String, CommandHistory>>>;
+
+fn normalize_user(user: &str) -> String {
+    user.trim().to_string()
+}
+fn record(history: &mut CommandHistory, command: String, time: DateTime<Utc>) {
+    history.entries.retain(|entry| entry.command != command);
+    history.entries.insert(0, CommandHistoryEntry { command, time });
+    history.entries.truncate(MAX_ENTRIES);
+}
+fn mark_favourite(history: &mut CommandHistory, command: String, favourite: bool) {
+    let present = history.favourites.contains(&command);
+    if favourite && !present {
+        history.favourites.push(command);
+    } else if !favourite {
+        history.favourites.retain(|existing| existing != &command);
+    }
+}
+
+async fn list_users(history_info: HistoryInfo) -> Json<Vec<String>> {
+    let mut users: Vec<String> = history_info.read().await.get().keys().filter(|user| !user.is_empty()).cloned().collect();
+    users.sort();
+    Json(users)
+}
+async fn get_history(history_info: HistoryInfo, input: Json<QueryCommandHistory>) -> Json<CommandHistory> {
+    let user = normalize_user(&input.user);
+    Json(history_info.read().await.get().get(&user).cloned().unwrap_or_default())
+}
+async fn send_command(
+    history_info: HistoryInfo,
+    machine: Extension<Arc<ImmediateHandle>>,
+    input: Json<SendCommand>,
+) -> ServerResult<Json<CommandHistory>> {
+    let input = input.0;
+    let command = input.command.trim().to_string();
+    if command.is_empty() {
+        return Err(ServerError::bad_request("the command is empty".to_string()));
+    }
+    crate::send_raw_line(&machine, command.clone().into_bytes()).await?;
+    let user = normalize_user(&input.user);
+    let updated = history_info.write().await.mutate(move |histories| {
+        let history = histories.entry(user).or_default();
+        record(history, command, Utc::now());
+        Ok(history.clone())
+    }).await?;
+    Ok(Json(updated))
+}
+async fn set_favourite(history_info: HistoryInfo, input: Json<SetFavouriteCommand>) -> ServerResult<Json<CommandHistory>> {
+    let input = input.0;
+    let user = normalize_user(&input.user);
+    let updated = history_info.write().await.mutate(move |histories| {
+        let history = histories.entry(user).or_default();
+        mark_favourite(history, input.command, input.favourite);
+        Ok(history.clone())
+    }).await?;
+    Ok(Json(updated))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn repeated_commands_move_to_the_front() {
+        let mut history = CommandHistory::default();
+        let time = Utc::now();
+        for command in ["G0 X1", "$H", "G0 X1"] {
+            record(&mut history, command.to_string(), time);
+        }
+        let commands: Vec<_> = history.entries.iter().map(|entry| entry.command.as_str()).collect();
+        assert_eq!(commands, ["G0 X1", "$H"]);
+        for index in 0..MAX_ENTRIES + 10 {
+            record(&mut history, format!("G0 X{}", index), time);
+        }
+        assert_eq!(history.entries.len(), MAX_ENTRIES);
+    }
+
+    #[test]
+    fn favourites_are_listed_once() {
+        let mut history = CommandHistory::default();
+        mark_favourite(&mut history, "$X".to_string(), true);
+        mark_favourite(&mut history, "$X".to_string(), true);
+        assert_eq!(history.favourites, ["$X"]);
+        mark_favourite(&mut history, "$X".to_string(), false);
+        assert!(history.favourites.is_empty());
+    }
+}
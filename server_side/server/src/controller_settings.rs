@@ -0,0 +1,166 @@
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use axum::{Router, Extension, Json, response::IntoResponse, routing::get};
+use chrono::{Local, Utc};
+use common::{api::SettingsSnapshot, grbl::{format_settings_text, validate_setting, GrblSetting}};
+
+use crate::{cnc::{gcode::analysis::ASSUMED_RAPID_RATE, grbl::{messages::GrblMessage, new_machine::LineError, standard_handler::{ImmediateHandle, JobHandle}}}, util::{file_backed_json::FileBackedValue, exclusive_extension::ExclusiveExtension}, Config, run_job_for_result, server_result::{ServerError, ServerResult}};
+
+/*
+    The controller's "$" settings. Reading and writing both run as a job, so they never interleave with a
+program. Writes are validated before anything is sent and stop at the first setting the controller
+refuses; the settings before it stay written, so the answer is always the list read back afterwards.
+*/
+
+// The settings as the controller last listed them, for what needs them without running a job.
+pub struct LastRead(FileBackedValue<Option<SettingsSnapshot>>);
+pub type LastReadInfo = ExclusiveExtension<LastRead>;
+impl LastRead {
+    pub fn snapshot(&self) -> Option<&SettingsSnapshot> {
+        self.0.get().as_ref()
+    }
+    // $110 to $112, in units per minute, assuming a typical rate for any not read yet.
+    pub fn rapid_rates(&self) -> [f64; 3] {
+        let rate = |index| self.snapshot()
+            .and_then(|snapshot| snapshot.settings.iter().find(|setting| setting.index == index))
+            .and_then(|setting| setting.value.parse().ok())
+            .unwrap_or(ASSUMED_RAPID_RATE);
+        [rate(110), rate(111), rate(112)]
+    }
+}
+pub async fn load_last_read(config: &Config) -> anyhow::Result<LastReadInfo> {
+    let path = config.data_folder.join("controller_settings/last_read.json");
+    Ok(ExclusiveExtension::new(LastRead(FileBackedValue::new(path, Default::default).await?)))
+}
+async fn remember(last_read: &LastReadInfo, settings: &[GrblSetting]) -> anyhow::Result<()> {
+    let snapshot = SettingsSnapshot { time: Utc::now(), settings: settings.to_vec() };
+    last_read.write().await.0.set(Some(snapshot)).await
+}
+
+pub async fn get_service(config: &Config) -> anyhow::Result<Router> {
+    let snapshot: FileBackedValue<Option<SettingsSnapshot>> = FileBackedValue::new(
+        config.data_folder.join("controller_settings/snapshot.json"), Default::default
+    ).await?;
+    let router = Router::new()
+        .route("/", get(read_settings).post(write_settings))
+        .route("/snapshot", get(get_snapshot).put(save_snapshot))
+        .route("/backup", get(download_backup))
+        .layer(ExclusiveExtension::new(snapshot));
+    Ok(router)
+}
+
+type SnapshotInfo = ExclusiveExtension<FileBackedValue<Option<SettingsSnapshot>>>;
+
+fn describe_line_error(error: LineError) -> String {
+    match error {
+        LineError::Grbl(code) => GrblMessage::get_error_text(code).into_owned(),
+        LineError::Reset => "the controller was reset".to_string(),
+    }
+}
+fn validated(settings: Vec<GrblSetting>) -> Result<Vec<GrblSetting>, String> {
+    settings.into_iter()
+        .map(|setting| Ok(GrblSetting { value: validate_setting(setting.index, &setting.value)?, index: setting.index }))
+        .collect()
+}
+
+async fn list_settings(handle: &JobHandle) -> Result<Vec<GrblSetting>, String> {
+    let settings = handle.read_settings().await.map_err(|_| "the job ended early".to_string())?;
+    settings.await.map_err(|error| format!("the controller couldn't list its settings: {}", describe_line_error(error)))
+}
+async fn apply_settings(handle: &JobHandle, settings: Vec<GrblSetting>) -> Result<(), String> {
+    for setting in settings {
+        let line = format!("${}={}\n", setting.index, setting.value);
+        let result = unsafe {  // Safe because validated values are plain numbers.
+            handle.send_gcode_raw(line.into_bytes()).await
+        };
+        let result = result.map_err(|_| "the job ended early".to_string())?.await;
+        if let Err(error) = result {
+            return Err(format!("${} was not written: {}", setting.index, describe_line_error(error)));
+        }
+    }
+    Ok(())
+}
+// Runs f as a job and waits for the settings it gives back.
+async fn run_settings_job<F, Fut>(machine: &ImmediateHandle, f: F) -> ServerResult<Vec<GrblSetting>>
+where
+    F: FnOnce(JobHandle) -> Fut + Send + 'static,
+    Fut: std::future::Future<Output=Result<Vec<GrblSetting>, String>> + Send + 'static,
+{
+    match run_job_for_result(machine, f).await? {
+        Some(result) => result.map_err(ServerError::bad_request),
+        None => Err(anyhow!("Settings job not run!").into()),
+    }
+}
+
+async fn read_settings(machine: Extension<Arc<ImmediateHandle>>, last_read: LastReadInfo) -> ServerResult<Json<Vec<GrblSetting>>> {
+    let settings = run_settings_job(&machine, |handle| async move { list_settings(&handle).await }).await?;
+    remember(&last_read, &settings).await?;
+    Ok(Json(settings))
+}
+async fn write_settings(machine: Extension<Arc<ImmediateHandle>>, last_read: LastReadInfo, input: Json<Vec<GrblSetting>>) -> ServerResult<Json<Vec<GrblSetting>>> {
+    let settings = validated(input.0).map_err(ServerError::bad_request)?;
+    let settings = run_settings_job(&machine, |handle| async move {
+        let applied = apply_settings(&handle, settings).await;
+        let current = list_settings(&handle).await?;
+        applied.map(|()| current)
+    }).await?;
+    remember(&last_read, &settings).await?;
+    Ok(Json(settings))
+}
+async fn download_backup(machine: Extension<Arc<ImmediateHandle>>, last_read: LastReadInfo) -> ServerResult<impl IntoResponse> {
+    let settings = run_settings_job(&machine, |handle| async move { list_settings(&handle).await }).await?;
+    remember(&last_read, &settings).await?;
+    let disposition = format!("attachment; filename=\"grbl_settings_{}.txt\"", Local::now().format("%Y-%m-%d_%H%M"));
+    Ok((
+        [(hyper::header::CONTENT_TYPE, "text/plain".to_string()), (hyper::header::CONTENT_DISPOSITION, disposition)],
+        format_settings_text(&settings),
+    ))
+}
+
+async fn get_snapshot(snapshot_info: SnapshotInfo) -> Json<Option<SettingsSnapshot>> {
+    Json(snapshot_info.read().await.get().clone())
+}
+async fn save_snapshot(snapshot_info: SnapshotInfo, input: Json<Vec<GrblSetting>>) -> ServerResult<Json<Option<SettingsSnapshot>>> {
+    let settings = input.0;
+    let updated = snapshot_info.write().await.mutate(move |snapshot| {
+        *snapshot = Some(SettingsSnapshot { time: Utc::now(), settings });
+        Ok(snapshot.clone())
+    }).await?;
+    Ok(Json(updated))
+}
+
+#[cfg(test)]
+mod test {
+    use tempdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn validation_normalizes_values_and_rejects_bad_ones() {
+        let settings = validated(vec![
+            GrblSetting { index: 22, value: " 1.0 ".to_string() },
+            GrblSetting { index: 110, value: "2500.5".to_string() },
+        ]).unwrap();
+        assert_eq!(settings[0].value, "1");
+        assert_eq!(settings[1].value, "2500.5");
+        assert!(validated(vec![GrblSetting { index: 0, value: "1".to_string() }]).is_err());
+        assert!(validated(vec![GrblSetting { index: 110, value: "10\n$X".to_string() }]).is_err());
+    }
+
+    #[tokio::test]
+    async fn rapid_rates_come_from_the_last_read() {
+        let directory = TempDir::new("controller_settings").unwrap();
+        let config = Config::in_folder(directory.path());
+        let last_read = load_last_read(&config).await.unwrap();
+        assert_eq!(last_read.read().await.rapid_rates(), [ASSUMED_RAPID_RATE; 3]);
+        remember(&last_read, &[
+            GrblSetting { index: 110, value: "2500.5".to_string() },
+            GrblSetting { index: 112, value: "fast".to_string() },
+        ]).await.unwrap();
+        let expected = [2500.5, ASSUMED_RAPID_RATE, ASSUMED_RAPID_RATE];
+        assert_eq!(last_read.read().await.rapid_rates(), expected);
+        let reloaded = load_last_read(&config).await.unwrap();
+        assert_eq!(reloaded.read().await.rapid_rates(), expected);
+    }
+}
@@ -1,10 +1,11 @@
 use std::{collections::{HashMap, VecDeque}, hash::Hash, sync::Arc, ops::DerefMut};
 
+use anyhow::anyhow;
 use axum::{Router, Extension, Json, routing::{get, post, delete}};
-use common::api::{Offsets, SetCoordinateOffset, DeleteCoordinateOffset, OffsetKind, SavedPosition};
+use common::api::{Offsets, SetCoordinateOffset, DeleteCoordinateOffset, OffsetKind, SavedPosition, AlignCoordinateOffset, ApplyCoordinateOffsets, Vec3};
 use serde::{Serialize, Deserialize};
 
-use crate::{util::{file_backed_json::FileBackedValue, exclusive_extension::ExclusiveExtension}, Config, server_result::ServerResult};
+use crate::{cnc::grbl::standard_handler::{ImmediateHandle, JobHandle}, util::{file_backed_json::FileBackedValue, exclusive_extension::ExclusiveExtension}, Config, run_job_for_result, server_result::{ServerError, ServerResult}};
 use tokio::sync::RwLock;
 
 
@@ -17,6 +18,8 @@ pub async fn get_service(config: &Config) -> anyhow::Result<Router> {
     ).await?;
     let router = Router::new()
         .route("/offsets", get(list_offsets).delete(remove_offset).put(set_offset))
+        .route("/align", post(align_offset))
+        .route("/apply", post(apply_offsets))
         .route("/positions", get(list_positions).post(add_position)
         .layer(ExclusiveExtension::new(positions)))
         .layer(ExclusiveExtension::new(coordinates));
@@ -54,6 +57,58 @@ async fn remove_offset(coordinate_info: CoordinateInfo, input: Json<DeleteCoordi
     Ok(Json(updated))
 }
 
+fn find_offset<'a>(map: &'a HashMap<String, Vec3>, name: &str) -> ServerResult<&'a Vec3> {
+    map.get(name).ok_or_else(|| ServerError::bad_request(format!("no offset named {:?}", name)))
+}
+// The offset that, added to the machine position and the other offset, gives the reference.
+fn aligned_offset(machine_position: &Vec3, other: &Vec3, current: Option<&Vec3>, reference: &[Option<f64>; 3]) -> Vec3 {
+    Vec3(std::array::from_fn(|axis| match reference[axis] {
+        Some(reference) => reference - machine_position.0[axis] - other.0[axis],
+        None => current.map_or(0.0, |current| current.0[axis]),
+    }))
+}
+async fn align_offset(coordinate_info: CoordinateInfo, input: Json<AlignCoordinateOffset>) -> ServerResult<Json<Offsets>> {
+    let input = input.0;
+    let mut coordinates = coordinate_info.write().await;
+    let (name, other) = match input.update {
+        OffsetKind::Tool => (input.tool, find_offset(&coordinates.get().workpieces, &input.workpiece)?.clone()),
+        OffsetKind::Workpiece => (input.workpiece, find_offset(&coordinates.get().tools, &input.tool)?.clone()),
+    };
+    let updated = coordinates.mutate(move |offsets| {
+        let map = match input.update {
+            OffsetKind::Tool => &mut offsets.tools,
+            OffsetKind::Workpiece => &mut offsets.workpieces,
+        };
+        let offset = aligned_offset(&input.machine_position, &other, map.get(&name), &input.reference);
+        map.insert(name, offset);
+        Ok(offsets.clone())
+    }).await?;
+    Ok(Json(updated))
+}
+async fn apply_offsets(coordinate_info: CoordinateInfo, machine: Extension<Arc<ImmediateHandle>>, input: Json<ApplyCoordinateOffsets>) -> ServerResult<String> {
+    let total = {
+        let coordinates = coordinate_info.read().await;
+        let tool = find_offset(&coordinates.get().tools, &input.tool)?;
+        let workpiece = find_offset(&coordinates.get().workpieces, &input.workpiece)?;
+        [0, 1, 2].map(|axis| tool.0[axis] + workpiece.0[axis])
+    };
+    // Grbl subtracts G54 from machine positions, where the offsets here are added.
+    let line = format!("G10 L2 P1 X{:.4} Y{:.4} Z{:.4}\n", -total[0], -total[1], -total[2]);
+    let result = run_job_for_result(&machine, move |handle: JobHandle| async move {
+        unsafe {  // Safe because the line is well formed.
+            match handle.send_gcode_raw(line.into_bytes()).await {
+                Ok(line_result) => Some(line_result.await),
+                Err(_) => None,
+            }
+        }
+    }).await?;
+    match result.flatten() {
+        Some(Ok(())) => Ok("Ok".to_string()),
+        Some(Err(error)) => Err(anyhow!("The controller rejected the offsets: {:?}", error).into()),
+        None => Err(anyhow!("Offsets not sent!").into()),
+    }
+}
+
 fn get_position_output(positions: &VecDeque<SavedPosition>) -> Json<Vec<SavedPosition>> {
     Json(positions.iter().cloned().collect())
 }
@@ -87,4 +142,20 @@ Then: can also choose a coordinate system by specifying a pair of tool + work po
 
 Maybe allow recording positions? Perhaps with names? Perhaps also via probing? Always recorded in machine position - probably
 also noting the name of the active tool system, if present.
- */
\ No newline at end of file
+ */
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn aligns_only_the_given_axes() {
+        let machine_position = Vec3([-100.0, -50.0, -20.0]);
+        let tool = Vec3([1.0, 2.0, 3.0]);
+        let current = Vec3([7.0, 8.0, 9.0]);
+        let aligned = aligned_offset(&machine_position, &tool, Some(&current), &[Some(0.0), None, Some(5.0)]);
+        assert_eq!(aligned.0, [99.0, 8.0, 22.0]);
+        assert_eq!(machine_position.0[2] + tool.0[2] + aligned.0[2], 5.0);
+        assert_eq!(aligned_offset(&machine_position, &tool, None, &[None; 3]).0, [0.0; 3]);
+    }
+}
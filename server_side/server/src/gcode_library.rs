@@ -0,0 +1,336 @@
+use std::{cmp::Reverse, path::{Path, PathBuf}, sync::Arc, time::SystemTime};
+
+use anyhow::anyhow;
+use axum::{Router, Extension, Json, routing::{get, post}};
+use chrono::{DateTime, Duration, Utc};
+use common::api::{self, CopyGcodeFile, MoveGcodeFile, PurgeTrash, RestoreTrashEntry, TrashEntry};
+use serde::{Serialize, Deserialize};
+use tokio::fs::{copy, create_dir, create_dir_all, read_dir, read_to_string, remove_dir_all, rename, try_exists, write, metadata};
+
+use crate::{gcode_metadata, gcode_versions, paths::{lexically_normal_path, library_path}, server_result::{ServerError, ServerResult}, Config};
+
+/*
+    Moving files around the G-code library, and the trash that deleted files go to. Each deletion gets
+its own folder in deleted_gcode/, holding the deleted file or directory and a note of where it came from.
+A file's cached analysis and version history go wherever it goes, so in the trash they sit beside it
+in that folder just as they would in the library.
+*/
+
+pub const TRASH_INFO: &str = ".trash_info.json";
+
+#[derive(Serialize, Deserialize)]
+struct TrashInfo {
+    original_path: String,
+    deleted_at: DateTime<Utc>,
+}
+
+pub fn get_trash_service() -> Router {
+    Router::new()
+        .route("/", get(list_trash))
+        .route("/restore", post(restore_from_trash))
+        .route("/purge", post(purge_trash))
+}
+
+fn trash_root(config: &Config) -> PathBuf {
+    config.data_folder.join("deleted_gcode")
+}
+// A path in the library that isn't the library itself.
+fn library_entry_path(config: &Config, path: &str) -> ServerResult<PathBuf> {
+    match library_path(Path::new(path)) {
+        Some(normal) if normal != PathBuf::new() => Ok(config.gcode_root().join(normal)),
+        Some(_) => Err(ServerError::bad_request("cannot use the root directory".to_string())),
+        None => Err(ServerError::bad_request(format!("invalid path {:?}", path))),
+    }
+}
+// Trash ids are the names of folders directly inside the trash.
+fn is_valid_trash_id(id: &str) -> bool {
+    let mut components = Path::new(id).components();
+    matches!((components.next(), components.next()), (Some(std::path::Component::Normal(_)), None))
+}
+// Whether moving or copying from into to would put a directory inside itself.
+fn is_inside(from: &Path, to: &Path) -> bool {
+    to.starts_with(from)
+}
+
+async fn check_destination_free(to: &Path) -> ServerResult<()> {
+    if try_exists(to).await? {
+        return Err(ServerError::bad_request(format!("{:?} already exists", to.file_name().unwrap_or_default())));
+    }
+    if let Some(parent) = to.parent() {
+        create_dir_all(parent).await?;
+    }
+    Ok(())
+}
+
+async fn move_sidecars(from: &Path, to: &Path) -> anyhow::Result<()> {
+    gcode_metadata::move_cache(from, to).await?;
+    gcode_versions::move_history(from, to).await
+}
+async fn copy_sidecars(from: &Path, to: &Path) -> anyhow::Result<()> {
+    gcode_metadata::copy_cache(from, to).await?;
+    gcode_versions::copy_history(from, to).await
+}
+
+pub async fn move_file(config: Extension<Arc<Config>>, info: Json<MoveGcodeFile>) -> ServerResult<String> {
+    let from = library_entry_path(&config, &info.from)?;
+    let to = library_entry_path(&config, &info.to)?;
+    if is_inside(&from, &to) {
+        return Err(ServerError::bad_request("cannot move a directory into itself".to_string()));
+    }
+    check_destination_free(&to).await?;
+    rename(&from, &to).await?;
+    move_sidecars(&from, &to).await?;
+    Ok("Ok".to_string())
+}
+
+async fn copy_recursive(from: &Path, to: &Path) -> anyhow::Result<()> {
+    if !metadata(from).await?.is_dir() {
+        copy(from, to).await?;
+        return Ok(());
+    }
+    let mut pending = vec![(from.to_path_buf(), to.to_path_buf())];
+    while let Some((from, to)) = pending.pop() {
+        create_dir(&to).await?;
+        let mut entries = read_dir(&from).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let target = to.join(entry.file_name());
+            if entry.file_type().await?.is_dir() {
+                pending.push((entry.path(), target));
+            } else {
+                copy(entry.path(), target).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub async fn copy_file(config: Extension<Arc<Config>>, info: Json<CopyGcodeFile>) -> ServerResult<String> {
+    let from = library_entry_path(&config, &info.from)?;
+    let to = library_entry_path(&config, &info.to)?;
+    if is_inside(&from, &to) {
+        return Err(ServerError::bad_request("cannot copy a directory into itself".to_string()));
+    }
+    check_destination_free(&to).await?;
+    copy_recursive(&from, &to).await?;
+    copy_sidecars(&from, &to).await?;
+    Ok("Ok".to_string())
+}
+
+pub async fn delete_file(config: Extension<Arc<Config>>, info: Json<api::DeleteGcodeFile>) -> ServerResult<String> {
+    let old_path = library_entry_path(&config, &info.path)?;
+    let deleted_at = Utc::now();
+    let new_folder = trash_root(&config).join(deleted_at.to_string());
+    create_dir_all(&new_folder).await?;
+    let new_path = new_folder.join(old_path.file_name().unwrap_or(std::ffi::OsStr::new("Unknown")));
+    if let Err(error) = rename(&old_path, &new_path).await {
+        drop(remove_dir_all(&new_folder).await);
+        return Err(error.into());
+    }
+    move_sidecars(&old_path, &new_path).await?;
+    // The file is in the trash by now; without this note it's still listed, just without its origin.
+    let trash_info = TrashInfo {
+        original_path: lexically_normal_path(Path::new(&info.path)).unwrap().to_string_lossy().into_owned(),
+        deleted_at,
+    };
+    write(new_folder.join(TRASH_INFO), serde_json::to_vec(&trash_info)?).await?;
+    Ok("Ok".to_string())
+}
+
+// The deleted item in a trash folder; None if it has been emptied somehow.
+async fn trash_item(folder: &Path) -> anyhow::Result<Option<PathBuf>> {
+    let mut entries = read_dir(folder).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        if name != TRASH_INFO && name != gcode_metadata::CACHE_DIRECTORY && name != gcode_versions::HISTORY_DIRECTORY {
+            return Ok(Some(entry.path()));
+        }
+    }
+    Ok(None)
+}
+async fn read_trash_entry(folder: &Path) -> anyhow::Result<Option<TrashEntry>> {
+    let Some(item) = trash_item(folder).await? else { return Ok(None) };
+    let id = folder.file_name().and_then(|name| name.to_str()).ok_or_else(|| anyhow!("Bad trash folder {:?}", folder))?;
+    // Older deletions didn't record anything, so fall back on when the folder was made.
+    let info = match read_to_string(folder.join(TRASH_INFO)).await {
+        Ok(text) => Some(serde_json::from_str::<TrashInfo>(&text)?),
+        Err(_) => None,
+    };
+    let deleted_at = match &info {
+        Some(info) => info.deleted_at,
+        None => metadata(folder).await?.modified().unwrap_or(SystemTime::UNIX_EPOCH).into(),
+    };
+    Ok(Some(TrashEntry {
+        id: id.to_string(),
+        name: item.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+        original_path: info.map(|info| info.original_path),
+        deleted_at,
+        is_directory: metadata(&item).await?.is_dir(),
+    }))
+}
+async fn read_trash(config: &Config) -> anyhow::Result<Vec<TrashEntry>> {
+    let root = trash_root(config);
+    if !try_exists(&root).await? {
+        return Ok(Vec::new());
+    }
+    let mut entries = read_dir(root).await?;
+    let mut values = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_type().await?.is_dir() {
+            if let Some(value) = read_trash_entry(&entry.path()).await? {
+                values.push(value);
+            }
+        }
+    }
+    values.sort_by_key(|entry| Reverse(entry.deleted_at));
+    Ok(values)
+}
+
+async fn list_trash(config: Extension<Arc<Config>>) -> ServerResult<Json<Vec<TrashEntry>>> {
+    Ok(Json(read_trash(&config).await?))
+}
+
+async fn restore_from_trash(config: Extension<Arc<Config>>, info: Json<RestoreTrashEntry>) -> ServerResult<String> {
+    if !is_valid_trash_id(&info.id) {
+        return Err(ServerError::bad_request(format!("invalid trash id {:?}", info.id)));
+    }
+    let folder = trash_root(&config).join(&info.id);
+    let entry = read_trash_entry(&folder).await.ok().flatten()
+        .ok_or_else(|| ServerError::bad_request(format!("nothing in the trash with id {:?}", info.id)))?;
+    let destination = info.destination.as_ref().or(entry.original_path.as_ref())
+        .ok_or_else(|| ServerError::bad_request("original location unknown; give a destination".to_string()))?;
+    let to = library_entry_path(&config, destination)?;
+    check_destination_free(&to).await?;
+    let item = folder.join(&entry.name);
+    rename(&item, &to).await?;
+    move_sidecars(&item, &to).await?;
+    remove_dir_all(folder).await?;
+    Ok("Ok".to_string())
+}
+
+async fn purge_trash(config: Extension<Arc<Config>>, info: Json<PurgeTrash>) -> ServerResult<String> {
+    let cutoff = Utc::now() - Duration::days(info.older_than_days.into());
+    let mut purged = 0;
+    for entry in read_trash(&config).await? {
+        if entry.deleted_at < cutoff {
+            remove_dir_all(trash_root(&config).join(&entry.id)).await?;
+            purged += 1;
+        }
+    }
+    Ok(format!("Purged {} entries", purged))
+}
+
+#[cfg(test)]
+mod test {
+    use tempdir::TempDir;
+    use tokio::fs::read;
+
+    use crate::cnc::gcode::analysis::ASSUMED_RAPID_RATE;
+
+    use super::*;
+
+    // A library holding a.nc, with a version history.
+    async fn library() -> (TempDir, Extension<Arc<Config>>) {
+        let directory = TempDir::new("gcode_library").unwrap();
+        let config = Config::in_folder(directory.path());
+        create_dir_all(config.gcode_root()).await.unwrap();
+        let file = config.gcode_root().join("a.nc");
+        write(&file, "G0 X1\n").await.unwrap();
+        gcode_versions::record_current(&file).await.unwrap();
+        (directory, Extension(Arc::new(config)))
+    }
+    async fn has_history(config: &Config, path: &str) -> bool {
+        let file = config.gcode_root().join(path);
+        let directory = file.parent().unwrap().join(gcode_versions::HISTORY_DIRECTORY).join(file.file_name().unwrap());
+        try_exists(directory).await.unwrap()
+    }
+    async fn delete(config: &Extension<Arc<Config>>, path: &str) {
+        delete_file(config.clone(), Json(api::DeleteGcodeFile { path: path.to_string(), is_directory: false })).await.unwrap();
+    }
+
+    #[test]
+    fn trash_ids_are_single_names() {
+        assert!(is_valid_trash_id("2023-05-01 12:00:00.123 UTC"));
+        assert!(!is_valid_trash_id(""));
+        assert!(!is_valid_trash_id("."));
+        assert!(!is_valid_trash_id(".."));
+        assert!(!is_valid_trash_id("a/b"));
+        assert!(!is_valid_trash_id("/a"));
+    }
+
+    #[test]
+    fn directories_cannot_go_inside_themselves() {
+        assert!(is_inside(Path::new("gcode/a"), Path::new("gcode/a/b")));
+        assert!(is_inside(Path::new("gcode/a"), Path::new("gcode/a")));
+        assert!(!is_inside(Path::new("gcode/a"), Path::new("gcode/ab")));
+        assert!(!is_inside(Path::new("gcode/a/b"), Path::new("gcode/a")));
+    }
+
+    #[tokio::test]
+    async fn moves_take_the_history_and_analysis() {
+        let (_directory, config) = library().await;
+        gcode_metadata::refresh(config.gcode_root().join("a.nc"), [ASSUMED_RAPID_RATE; 3]).await;
+        move_file(config.clone(), Json(MoveGcodeFile { from: "a.nc".to_string(), to: "sub/b.nc".to_string() })).await.unwrap();
+        assert!(!try_exists(config.gcode_root().join("a.nc")).await.unwrap());
+        assert_eq!(read(config.gcode_root().join("sub/b.nc")).await.unwrap(), b"G0 X1\n");
+        assert!(!has_history(&config, "a.nc").await);
+        assert!(has_history(&config, "sub/b.nc").await);
+        assert!(!try_exists(config.gcode_root().join(".analysis/a.nc.json")).await.unwrap());
+        assert!(try_exists(config.gcode_root().join("sub/.analysis/b.nc.json")).await.unwrap());
+        let taken = move_file(config.clone(), Json(MoveGcodeFile { from: "sub".to_string(), to: "sub/inner".to_string() })).await;
+        assert!(taken.is_err());
+    }
+
+    #[tokio::test]
+    async fn the_librarys_own_files_cannot_be_moved_or_deleted() {
+        let (_directory, config) = library().await;
+        let history = move_file(config.clone(), Json(MoveGcodeFile { from: ".versions/a.nc".to_string(), to: "old".to_string() })).await;
+        assert!(history.is_err());
+        let over_history = copy_file(config.clone(), Json(CopyGcodeFile { from: "a.nc".to_string(), to: ".versions/b.nc".to_string() })).await;
+        assert!(over_history.is_err());
+        let deleted = delete_file(config.clone(), Json(api::DeleteGcodeFile { path: ".versions".to_string(), is_directory: true })).await;
+        assert!(deleted.is_err());
+        assert!(has_history(&config, "a.nc").await);
+    }
+
+    #[tokio::test]
+    async fn copies_keep_the_original_and_share_its_history() {
+        let (_directory, config) = library().await;
+        copy_file(config.clone(), Json(CopyGcodeFile { from: "a.nc".to_string(), to: "b.nc".to_string() })).await.unwrap();
+        assert_eq!(read(config.gcode_root().join("b.nc")).await.unwrap(), b"G0 X1\n");
+        assert!(has_history(&config, "a.nc").await);
+        assert!(has_history(&config, "b.nc").await);
+        let existing = copy_file(config.clone(), Json(CopyGcodeFile { from: "a.nc".to_string(), to: "b.nc".to_string() })).await;
+        assert!(existing.is_err());
+    }
+
+    #[tokio::test]
+    async fn trash_restores_files_with_their_history() {
+        let (_directory, config) = library().await;
+        delete(&config, "a.nc").await;
+        assert!(!try_exists(config.gcode_root().join("a.nc")).await.unwrap());
+        assert!(!has_history(&config, "a.nc").await);
+        let trash = read_trash(&config).await.unwrap();
+        assert_eq!(trash.len(), 1);
+        assert_eq!((trash[0].name.as_str(), trash[0].original_path.as_deref()), ("a.nc", Some("a.nc")));
+
+        restore_from_trash(config.clone(), Json(RestoreTrashEntry { id: trash[0].id.clone(), destination: None })).await.unwrap();
+        assert_eq!(read(config.gcode_root().join("a.nc")).await.unwrap(), b"G0 X1\n");
+        assert!(has_history(&config, "a.nc").await);
+        assert!(read_trash(&config).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn purge_removes_only_old_deletions() {
+        let (_directory, config) = library().await;
+        write(config.gcode_root().join("b.nc"), "G0 X2\n").await.unwrap();
+        delete(&config, "a.nc").await;
+        delete(&config, "b.nc").await;
+        let old = read_trash(&config).await.unwrap().into_iter().find(|entry| entry.name == "a.nc").unwrap();
+        let info = TrashInfo { original_path: "a.nc".to_string(), deleted_at: Utc::now() - Duration::days(10) };
+        write(trash_root(&config).join(&old.id).join(TRASH_INFO), serde_json::to_vec(&info).unwrap()).await.unwrap();
+
+        purge_trash(config.clone(), Json(PurgeTrash { older_than_days: 5 })).await.unwrap();
+        let remaining = read_trash(&config).await.unwrap();
+        assert_eq!(remaining.iter().map(|entry| entry.name.as_str()).collect::<Vec<_>>(), vec!["b.nc"]);
+    }
+}
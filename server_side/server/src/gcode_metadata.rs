@@ -0,0 +1,209 @@
+use std::{collections::hash_map::DefaultHasher, hash::Hasher, path::{Path, PathBuf}};
+
+use chrono::{DateTime, Utc};
+use common::api::{GcodeAnalysis, GcodeFile, LastRun};
+use serde::{Serialize, Deserialize};
+use tokio::{fs::{copy, create_dir_all, metadata, read, read_dir, read_to_string, rename, try_exists, write}, task::spawn_blocking};
+
+use crate::{cnc::gcode::analysis::GcodeAnalyzer, default_settings, gcode_versions::HISTORY_DIRECTORY};
+
+/*
+    Analysis of each G-code file is cached in a .analysis directory beside it, keyed by the file's
+size and modification time and the rapid rates the estimate assumed. If the file's size or time
+change but the contents hash the same, the analysis is kept. The cache also remembers how the file's
+last run went, until its contents change.
+*/
+
+pub const CACHE_DIRECTORY: &str = ".analysis";
+
+#[derive(Serialize, Deserialize)]
+struct CachedMetadata {
+    size: u64,
+    modified: Option<DateTime<Utc>>,
+    hash: u64, // DefaultHasher can change between Rust releases; that only costs a reanalysis.
+    analysis: GcodeAnalysis,
+    #[serde(default)]
+    rapid_rates: Option<[f64; 3]>,
+    last_run: Option<LastRun>,
+}
+
+fn cache_path(file: &Path) -> Option<PathBuf> {
+    let name = file.file_name()?.to_str()?;
+    Some(file.parent()?.join(CACHE_DIRECTORY).join(format!("{}.json", name)))
+}
+async fn read_cache(file: &Path) -> Option<CachedMetadata> {
+    let text = read_to_string(cache_path(file)?).await.ok()?;
+    serde_json::from_str(&text).ok()
+}
+async fn write_cache(file: &Path, cached: &CachedMetadata) -> anyhow::Result<()> {
+    let path = cache_path(file).ok_or_else(|| anyhow::anyhow!("No cache for {:?}", file))?;
+    create_dir_all(path.parent().unwrap()).await?;
+    write(path, serde_json::to_vec(cached)?).await?;
+    Ok(())
+}
+
+fn hash_contents(contents: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(contents);
+    hasher.finish()
+}
+pub fn analyze(contents: &[u8], rapid_rates: [f64; 3]) -> GcodeAnalysis {
+    let spec = default_settings();
+    let mut analyzer = GcodeAnalyzer::new(&spec, rapid_rates);
+    for line in String::from_utf8_lossy(contents).lines() {
+        analyzer.line(line);
+    }
+    analyzer.finish()
+}
+
+// Brings the cache up to date with the file, analyzing it again if its contents changed.
+async fn cached_metadata(file: &Path, rapid_rates: [f64; 3]) -> anyhow::Result<CachedMetadata> {
+    let file_metadata = metadata(file).await?;
+    let size = file_metadata.len();
+    let modified = file_metadata.modified().ok().map(DateTime::<Utc>::from);
+    let cached = match read_cache(file).await {
+        Some(cached) if cached.size == size && cached.modified == modified && cached.rapid_rates == Some(rapid_rates) => return Ok(cached),
+        cached => cached,
+    };
+    let contents = read(file).await?;
+    let hash = hash_contents(&contents);
+    // The last run only describes these contents if they haven't changed since.
+    let cached = cached.filter(|cached| cached.hash == hash);
+    let (analysis, last_run) = match cached {
+        Some(cached) if cached.rapid_rates == Some(rapid_rates) => (cached.analysis, cached.last_run),
+        cached => (spawn_blocking(move || analyze(&contents, rapid_rates)).await?, cached.and_then(|cached| cached.last_run)),
+    };
+    let updated = CachedMetadata { size, modified, hash, analysis, rapid_rates: Some(rapid_rates), last_run };
+    write_cache(file, &updated).await?;
+    Ok(updated)
+}
+
+// Keep a file's cached analysis with it when it's moved or copied. Directories have no cache entry of
+// their own; their files' entries are inside them.
+pub async fn move_cache(from: &Path, to: &Path) -> anyhow::Result<()> {
+    let (Some(from), Some(to)) = (cache_path(from), cache_path(to)) else { return Ok(()) };
+    if try_exists(&from).await? {
+        create_dir_all(to.parent().unwrap()).await?;
+        rename(from, to).await?;
+    }
+    Ok(())
+}
+pub async fn copy_cache(from: &Path, to: &Path) -> anyhow::Result<()> {
+    let (Some(from), Some(to)) = (cache_path(from), cache_path(to)) else { return Ok(()) };
+    if try_exists(&from).await? {
+        create_dir_all(to.parent().unwrap()).await?;
+        copy(from, to).await?;
+    }
+    Ok(())
+}
+
+// For after uploads; errors are only logged, since listing the file will try again.
+pub async fn refresh(file: PathBuf, rapid_rates: [f64; 3]) {
+    if let Err(error) = cached_metadata(&file, rapid_rates).await {
+        println!("Couldn't analyze {:?}: {:?}", file, error);
+    }
+}
+
+pub async fn record_last_run(file: &Path, last_run: LastRun, rapid_rates: [f64; 3]) -> anyhow::Result<()> {
+    let mut cached = cached_metadata(file, rapid_rates).await?;
+    cached.last_run = Some(last_run);
+    write_cache(file, &cached).await
+}
+
+pub async fn list_directory(directory: &Path, rapid_rates: [f64; 3]) -> anyhow::Result<Vec<GcodeFile>> {
+    let mut entries = read_dir(directory).await?;
+    let mut values = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_name() == CACHE_DIRECTORY || entry.file_name() == HISTORY_DIRECTORY {
+            continue;
+        }
+        let is_file = entry.file_type().await?.is_file();
+        let mut value = GcodeFile {
+            name: entry.file_name().into_string().map_err(|name| anyhow::anyhow!("Bad file name {:?}", name))?,
+            is_file,
+            size: None,
+            modified: None,
+            analysis: None,
+            last_run: None,
+        };
+        if is_file {
+            match cached_metadata(&entry.path(), rapid_rates).await {
+                Ok(cached) => {
+                    value.size = Some(cached.size);
+                    value.modified = cached.modified;
+                    value.analysis = Some(cached.analysis);
+                    value.last_run = cached.last_run;
+                }
+                Err(error) => println!("Couldn't analyze {:?}: {:?}", entry.path(), error),
+            }
+        }
+        values.push(value);
+    }
+    values.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(values)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs::File, time::{Duration, SystemTime}};
+
+    use tempdir::TempDir;
+
+    use crate::cnc::gcode::analysis::ASSUMED_RAPID_RATE;
+
+    use super::*;
+
+    const RATES: [f64; 3] = [ASSUMED_RAPID_RATE; 3];
+
+    fn last_run() -> LastRun {
+        LastRun { time: Utc::now(), completed: true, error_count: 0, version: None }
+    }
+    fn touch(file: &Path) {
+        File::options().write(true).open(file).unwrap().set_modified(SystemTime::now() + Duration::from_secs(60)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn touched_files_keep_their_analysis_and_last_run() {
+        let directory = TempDir::new("gcode_metadata").unwrap();
+        let file = directory.path().join("a.nc");
+        write(&file, "G0 X1\n").await.unwrap();
+        record_last_run(&file, last_run(), RATES).await.unwrap();
+        // Stands in for an analysis that couldn't have come from the file, so a reanalysis would show.
+        let mut cached = read_cache(&file).await.unwrap();
+        cached.analysis.line_count = 100;
+        write_cache(&file, &cached).await.unwrap();
+
+        touch(&file);
+        let touched = cached_metadata(&file, RATES).await.unwrap();
+        assert_eq!(touched.analysis.line_count, 100);
+        assert!(touched.last_run.is_some());
+        assert_ne!(touched.modified, cached.modified);
+    }
+
+    #[tokio::test]
+    async fn changed_contents_are_analyzed_again_and_lose_their_last_run() {
+        let directory = TempDir::new("gcode_metadata").unwrap();
+        let file = directory.path().join("a.nc");
+        write(&file, "G0 X1\n").await.unwrap();
+        record_last_run(&file, last_run(), RATES).await.unwrap();
+
+        write(&file, "G0 X1\nG0 X2\n").await.unwrap();
+        touch(&file);
+        let changed = cached_metadata(&file, RATES).await.unwrap();
+        assert_eq!(changed.analysis.line_count, 2);
+        assert!(changed.last_run.is_none());
+    }
+
+    #[tokio::test]
+    async fn new_rapid_rates_keep_the_last_run() {
+        let directory = TempDir::new("gcode_metadata").unwrap();
+        let file = directory.path().join("a.nc");
+        write(&file, "G0 X0\nX1000\n").await.unwrap();
+        record_last_run(&file, last_run(), RATES).await.unwrap();
+        let before = read_cache(&file).await.unwrap();
+
+        let faster = cached_metadata(&file, [2.0 * ASSUMED_RAPID_RATE; 3]).await.unwrap();
+        assert!(faster.analysis.estimated_seconds < before.analysis.estimated_seconds);
+        assert!(faster.last_run.is_some());
+    }
+}
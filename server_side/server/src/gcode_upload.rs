@@ -0,0 +1,320 @@
+use std::{fs, io::{self, Read}, path::{Path, PathBuf}, sync::Arc};
+
+use anyhow::anyhow;
+use axum::{extract::{multipart::Field, Multipart}, Extension, Json};
+use common::api::{GcodeAnalysis, UploadReport, UploadedFile};
+use tempdir::TempDir;
+use tokio::{fs::{create_dir_all, read, rename, File}, io::AsyncWriteExt, spawn, task::spawn_blocking};
+
+use crate::{controller_settings::LastReadInfo, gcode_metadata, gcode_versions, paths::library_path, server_result::{ServerError, ServerResult}, Config};
+
+/*
+    Uploads to the G-code library. Every G-code file is checked as it arrives, and the answer says
+what was wrong with each. Zip and tar archives are unpacked into a directory named after the archive,
+so that a whole folder of CAM output can be sent at once.
+*/
+
+const GCODE_EXTENSIONS: [&str; 6] = ["nc", "gcode", "ngc", "tap", "gc", "cnc"];
+// Guards against archives that unpack to far more than was uploaded.
+const MAX_UNPACKED_SIZE: u64 = 512 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+}
+// The kind of archive a file name suggests, and the name without its extension.
+fn archive_kind(name: &str) -> Option<(ArchiveKind, &str)> {
+    let lowercase = name.to_ascii_lowercase();
+    [(".tar.gz", ArchiveKind::TarGz), (".tgz", ArchiveKind::TarGz), (".tar", ArchiveKind::Tar), (".zip", ArchiveKind::Zip)]
+        .into_iter()
+        .find(|(extension, _)| lowercase.ends_with(extension) && lowercase.len() > extension.len())
+        .map(|(extension, kind)| (kind, &name[..name.len() - extension.len()]))
+}
+fn is_gcode(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| GCODE_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str()))
+}
+
+struct Unpacked {
+    files: Vec<PathBuf>, // Relative to the directory unpacked into.
+    skipped: Vec<String>,
+}
+fn unpack_file(mut reader: impl Read, size: u64, relative: PathBuf, into: &Path, unpacked: &mut Unpacked, total: &mut u64) -> anyhow::Result<()> {
+    // Otherwise one entry would silently replace another once they're stored.
+    if unpacked.files.contains(&relative) {
+        return Err(anyhow!("Archive has more than one entry for {:?}!", relative));
+    }
+    *total += size;
+    if *total > MAX_UNPACKED_SIZE {
+        return Err(anyhow!("Archive unpacks to more than {} bytes!", MAX_UNPACKED_SIZE));
+    }
+    let path = into.join(&relative);
+    fs::create_dir_all(path.parent().unwrap())?;
+    io::copy(&mut reader.by_ref().take(size), &mut fs::File::create(path)?)?;
+    unpacked.files.push(relative);
+    Ok(())
+}
+fn unpack_tar(reader: impl Read, into: &Path) -> anyhow::Result<Unpacked> {
+    let mut unpacked = Unpacked { files: Vec::new(), skipped: Vec::new() };
+    let mut total = 0;
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let name = entry.path()?.to_string_lossy().into_owned();
+        if entry.header().entry_type().is_dir() {
+            continue;
+        }
+        match library_path(Path::new(&name)).filter(|_| entry.header().entry_type().is_file()) {
+            Some(relative) if relative != PathBuf::new() => {
+                let size = entry.header().size()?;
+                unpack_file(entry, size, relative, into, &mut unpacked, &mut total)?
+            }
+            _ => unpacked.skipped.push(name),
+        }
+    }
+    Ok(unpacked)
+}
+fn unpack_zip(archive: &Path, into: &Path) -> anyhow::Result<Unpacked> {
+    let mut unpacked = Unpacked { files: Vec::new(), skipped: Vec::new() };
+    let mut total = 0;
+    let mut archive = zip::ZipArchive::new(fs::File::open(archive)?)?;
+    for index in 0..archive.len() {
+        let file = archive.by_index(index)?;
+        if file.is_dir() {
+            continue;
+        }
+        let name = file.name().to_string();
+        let is_symlink = file.unix_mode().is_some_and(|mode| mode & 0o170000 == 0o120000);
+        match file.enclosed_name().and_then(library_path).filter(|_| !is_symlink) {
+            Some(relative) if relative != PathBuf::new() => {
+                let size = file.size();
+                unpack_file(file, size, relative, into, &mut unpacked, &mut total)?
+            }
+            _ => unpacked.skipped.push(name),
+        }
+    }
+    Ok(unpacked)
+}
+fn unpack(kind: ArchiveKind, archive: &Path, into: &Path) -> anyhow::Result<Unpacked> {
+    match kind {
+        ArchiveKind::Zip => unpack_zip(archive, into),
+        ArchiveKind::Tar => unpack_tar(fs::File::open(archive)?, into),
+        ArchiveKind::TarGz => unpack_tar(flate2::read::GzDecoder::new(fs::File::open(archive)?), into),
+    }
+}
+
+// Checks a file, then moves it into the library unless it's rejected.
+async fn store_file(from: &Path, to: PathBuf, name: String, reject_invalid: bool, uploader: &Option<String>, rapid_rates: [f64; 3]) -> anyhow::Result<UploadedFile> {
+    let analysis = if is_gcode(&to) {
+        let contents = read(from).await?;
+        Some(spawn_blocking(move || gcode_metadata::analyze(&contents, rapid_rates)).await?)
+    } else {
+        None
+    };
+    let is_gcode = analysis.is_some();
+    let (errors, error_count, warnings) = match analysis {
+        Some(GcodeAnalysis { parse_errors, parse_error_count, warnings, .. }) => (parse_errors, parse_error_count, warnings),
+        None => (Vec::new(), 0, Vec::new()),
+    };
+    let stored = !(reject_invalid && error_count > 0);
+    if stored {
+        create_dir_all(to.parent().ok_or(anyhow!("Cannot specify top level as filename!"))?).await?;
+        gcode_versions::record_upload(from, &to, uploader.clone()).await?;
+        rename(from, &to).await?;
+        if is_gcode {
+            spawn(gcode_metadata::refresh(to, rapid_rates));
+        }
+    }
+    Ok(UploadedFile { path: name, stored, is_gcode, errors, error_count, warnings })
+}
+
+async fn dump_field_to_file(mut file: File, mut field: Field<'_>) -> anyhow::Result<()> {
+    while let Some(bytes) = field.chunk().await? {
+        file.write_all(&bytes).await?
+    }
+    Ok(())
+}
+
+// Limits file size to 128 MiB.
+pub async fn upload(config: Extension<Arc<Config>>, last_read: LastReadInfo, mut multipart: Multipart) -> ServerResult<Json<UploadReport>> {
+    let mut file_name = None::<String>;
+    let mut reject_invalid = false;
+    let mut uploader = None;
+    let tmp_dir = TempDir::new("file_download")?;
+    let tmp_path = tmp_dir.path().join("file");
+    let mut has_file = false;
+    while let Some(field) = multipart.next_field().await? {
+        let name = field.name().unwrap_or_default().to_string();
+        if name == "file" {
+            if has_file {
+                return Err(anyhow!("Multiple files given!").into());
+            }
+            has_file = true;
+            dump_field_to_file(
+                File::create(tmp_path.clone()).await?,
+                field
+            ).await?;
+        } else if name == "filename" {
+            file_name = Some(field.text().await?);
+        } else if name == "reject_invalid" {
+            reject_invalid = field.text().await? == "true";
+        } else if name == "uploader" {
+            uploader = Some(field.text().await?).filter(|uploader| !uploader.is_empty());
+        }
+    }
+    if !has_file {
+        return Err(anyhow!("No file given!").into());
+    }
+    let file_name = file_name.ok_or_else(|| anyhow!("No filename given!"))?;
+    let normal_name = library_path(Path::new(&file_name))
+        .filter(|path| path != &PathBuf::new())
+        .ok_or_else(|| ServerError::bad_request(format!("invalid filename {:?}", file_name)))?;
+    let normal_name = normal_name.to_string_lossy().into_owned();
+    let rapid_rates = last_read.read().await.rapid_rates();
+    match archive_kind(&normal_name) {
+        None => {
+            let file = store_file(&tmp_path, config.gcode_path(&normal_name)?, normal_name, reject_invalid, &uploader, rapid_rates).await?;
+            Ok(Json(UploadReport { files: vec![file], skipped: Vec::new() }))
+        }
+        Some((kind, directory)) => {
+            let unpacked_root = tmp_dir.path().join("unpacked");
+            let Unpacked { files, skipped } = {
+                let (archive, into) = (tmp_path.clone(), unpacked_root.clone());
+                spawn_blocking(move || unpack(kind, &archive, &into)).await?
+                    .map_err(|error| ServerError::bad_request(format!("couldn't unpack {:?}: {}", file_name, error)))?
+            };
+            let mut report = UploadReport { files: Vec::new(), skipped };
+            for relative in files {
+                let name = Path::new(directory).join(&relative).to_string_lossy().into_owned();
+                let to = config.gcode_path(&name)?;
+                report.files.push(store_file(&unpacked_root.join(&relative), to, name, reject_invalid, &uploader, rapid_rates).await?);
+            }
+            Ok(Json(report))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn recognizes_archives() {
+        assert_eq!(archive_kind("parts/job.zip"), Some((ArchiveKind::Zip, "parts/job")));
+        assert_eq!(archive_kind("job.TAR.GZ"), Some((ArchiveKind::TarGz, "job")));
+        assert_eq!(archive_kind("job.tgz"), Some((ArchiveKind::TarGz, "job")));
+        assert_eq!(archive_kind("job.tar"), Some((ArchiveKind::Tar, "job")));
+        assert_eq!(archive_kind("job.nc"), None);
+        assert_eq!(archive_kind(".zip"), None);
+    }
+
+    #[test]
+    fn recognizes_gcode() {
+        assert!(is_gcode(Path::new("a/b.nc")));
+        assert!(is_gcode(Path::new("b.GCODE")));
+        assert!(!is_gcode(Path::new("setup.html")));
+        assert!(!is_gcode(Path::new("nc")));
+    }
+
+    // Names are written as given, since the tar builder refuses the unsafe ones these tests need.
+    fn tar_entry(builder: &mut tar::Builder<Vec<u8>>, name: &str, kind: tar::EntryType, contents: &[u8]) {
+        let mut header = tar::Header::new_gnu();
+        header.as_gnu_mut().unwrap().name[..name.len()].copy_from_slice(name.as_bytes());
+        header.set_entry_type(kind);
+        header.set_size(contents.len() as u64);
+        if kind == tar::EntryType::Symlink {
+            header.set_link_name("/etc/passwd").unwrap();
+        }
+        header.set_cksum();
+        builder.append(&header, contents).unwrap();
+    }
+    fn unpack_zip_entries(into: &Path, build: impl FnOnce(&mut zip::ZipWriter<fs::File>)) -> anyhow::Result<Unpacked> {
+        let archive = into.join("archive.zip");
+        let mut writer = zip::ZipWriter::new(fs::File::create(&archive).unwrap());
+        build(&mut writer);
+        writer.finish().unwrap();
+        unpack_zip(&archive, &into.join("unpacked"))
+    }
+    fn zip_file(writer: &mut zip::ZipWriter<fs::File>, name: &str, contents: &[u8]) {
+        writer.start_file(name, zip::write::FileOptions::default()).unwrap();
+        io::Write::write_all(writer, contents).unwrap();
+    }
+
+    #[test]
+    fn tar_entries_stay_inside() {
+        let directory = TempDir::new("gcode_upload").unwrap();
+        let mut builder = tar::Builder::new(Vec::new());
+        tar_entry(&mut builder, "parts/a.nc", tar::EntryType::Regular, b"G0 X1\n");
+        tar_entry(&mut builder, "../escape.nc", tar::EntryType::Regular, b"G0 X2\n");
+        tar_entry(&mut builder, "/etc/absolute.nc", tar::EntryType::Regular, b"G0 X3\n");
+        tar_entry(&mut builder, "link.nc", tar::EntryType::Symlink, b"");
+        let unpacked = unpack_tar(&builder.into_inner().unwrap()[..], directory.path()).unwrap();
+        assert_eq!(unpacked.files, vec![PathBuf::from("parts/a.nc")]);
+        assert_eq!(unpacked.skipped, vec!["../escape.nc", "/etc/absolute.nc", "link.nc"]);
+        assert_eq!(fs::read(directory.path().join("parts/a.nc")).unwrap(), b"G0 X1\n");
+        assert!(!directory.path().join("link.nc").exists());
+    }
+
+    #[test]
+    fn zip_entries_stay_inside() {
+        let directory = TempDir::new("gcode_upload").unwrap();
+        let unpacked = unpack_zip_entries(directory.path(), |writer| {
+            zip_file(writer, "parts/a.nc", b"G0 X1\n");
+            zip_file(writer, "../escape.nc", b"G0 X2\n");
+            zip_file(writer, "/etc/absolute.nc", b"G0 X3\n");
+            writer.add_symlink("link.nc", "/etc/passwd", zip::write::FileOptions::default()).unwrap();
+        }).unwrap();
+        assert_eq!(unpacked.files, vec![PathBuf::from("parts/a.nc")]);
+        assert_eq!(unpacked.skipped, vec!["../escape.nc", "/etc/absolute.nc", "link.nc"]);
+        assert_eq!(fs::read(directory.path().join("unpacked/parts/a.nc")).unwrap(), b"G0 X1\n");
+    }
+
+    #[test]
+    fn entries_for_the_librarys_own_files_are_skipped() {
+        let directory = TempDir::new("gcode_upload").unwrap();
+        let mut builder = tar::Builder::new(Vec::new());
+        tar_entry(&mut builder, "a.nc", tar::EntryType::Regular, b"G0 X1\n");
+        tar_entry(&mut builder, ".versions/a.nc/history.json", tar::EntryType::Regular, b"[]");
+        tar_entry(&mut builder, "parts/.analysis/b.nc.json", tar::EntryType::Regular, b"{}");
+        let unpacked = unpack_tar(&builder.into_inner().unwrap()[..], &directory.path().join("tar")).unwrap();
+        assert_eq!(unpacked.files, vec![PathBuf::from("a.nc")]);
+        assert_eq!(unpacked.skipped, vec![".versions/a.nc/history.json", "parts/.analysis/b.nc.json"]);
+        let unpacked = unpack_zip_entries(directory.path(), |writer| {
+            zip_file(writer, "a.nc", b"G0 X1\n");
+            zip_file(writer, ".trash_info.json", b"{}");
+        }).unwrap();
+        assert_eq!(unpacked.files, vec![PathBuf::from("a.nc")]);
+        assert_eq!(unpacked.skipped, vec![".trash_info.json"]);
+    }
+
+    #[test]
+    fn entries_for_the_same_path_are_refused() {
+        let directory = TempDir::new("gcode_upload").unwrap();
+        let mut builder = tar::Builder::new(Vec::new());
+        tar_entry(&mut builder, "a.nc", tar::EntryType::Regular, b"G0 X1\n");
+        tar_entry(&mut builder, "parts/../a.nc", tar::EntryType::Regular, b"G0 X2\n");
+        assert!(unpack_tar(&builder.into_inner().unwrap()[..], &directory.path().join("tar")).is_err());
+        let unpacked = unpack_zip_entries(directory.path(), |writer| {
+            zip_file(writer, "a.nc", b"G0 X1\n");
+            zip_file(writer, "./a.nc", b"G0 X2\n");
+        });
+        assert!(unpacked.is_err());
+    }
+
+    #[test]
+    fn unpacked_size_is_limited() {
+        let directory = TempDir::new("gcode_upload").unwrap();
+        // Only the header claims the size, so the limit must be checked before anything is read.
+        let mut header = tar::Header::new_gnu();
+        header.set_path("huge.nc").unwrap();
+        header.set_size(MAX_UNPACKED_SIZE + 1);
+        header.set_cksum();
+        let mut builder = tar::Builder::new(Vec::new());
+        builder.append(&header, io::empty()).unwrap();
+        assert!(unpack_tar(&builder.into_inner().unwrap()[..], directory.path()).is_err());
+        assert!(!directory.path().join("huge.nc").exists());
+    }
+}
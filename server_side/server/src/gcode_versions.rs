@@ -0,0 +1,279 @@
+use std::{path::{Path, PathBuf}, sync::Arc};
+
+use anyhow::anyhow;
+use axum::{body::{BoxBody, StreamBody}, extract, response::Response, Extension, Json};
+use chrono::{DateTime, Utc};
+use common::api::{DiffGcodeVersions, GcodeVersion, RestoreGcodeVersion};
+use sha2::{Digest, Sha256};
+use similar::TextDiff;
+use tokio::fs::{copy, create_dir_all, metadata, read, read_dir, read_to_string, rename, try_exists, write, File};
+use tokio_util::io::ReaderStream;
+
+use crate::{server_result::{ServerError, ServerResult}, Config};
+
+/*
+    Every version of a G-code file is kept in .versions/<file name>/ beside it: a copy of each distinct
+content, named by its hash, and history.json listing the versions oldest first. The last entry is
+the file as it was when last recorded; if the file changed without the server knowing, that change
+becomes a version the next time the file is uploaded over, run, diffed or restored. Until then the
+history is listed with the unrecorded contents last, and they can be downloaded from the file itself.
+*/
+
+pub const HISTORY_DIRECTORY: &str = ".versions";
+const HISTORY_FILE: &str = "history.json";
+
+fn history_directory(file: &Path) -> anyhow::Result<PathBuf> {
+    let name = file.file_name().ok_or_else(|| anyhow!("No history for {:?}", file))?;
+    Ok(file.parent().ok_or_else(|| anyhow!("No history for {:?}", file))?.join(HISTORY_DIRECTORY).join(name))
+}
+async fn read_history(directory: &Path) -> anyhow::Result<Vec<GcodeVersion>> {
+    match read_to_string(directory.join(HISTORY_FILE)).await {
+        Ok(text) => Ok(serde_json::from_str(&text)?),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(error) => Err(error.into()),
+    }
+}
+async fn write_history(directory: &Path, history: &[GcodeVersion]) -> anyhow::Result<()> {
+    create_dir_all(directory).await?;
+    write(directory.join(HISTORY_FILE), serde_json::to_vec_pretty(history)?).await?;
+    Ok(())
+}
+// Adds a version whose contents are at `contents`, unless it's already the newest.
+async fn push_version(directory: &Path, contents: &Path, time: DateTime<Utc>, uploader: Option<String>, restored_from: Option<String>) -> anyhow::Result<String> {
+    let bytes = read(contents).await?;
+    let hash = hash_contents(&bytes);
+    let mut history = read_history(directory).await?;
+    if history.last().is_some_and(|version| version.hash == hash) {
+        return Ok(hash);
+    }
+    create_dir_all(directory).await?;
+    let stored = directory.join(&hash);
+    if !try_exists(&stored).await? {
+        write(&stored, &bytes).await?;
+    }
+    history.push(GcodeVersion { hash: hash.clone(), time, uploader, restored_from, size: bytes.len() as u64 });
+    write_history(directory, &history).await?;
+    Ok(hash)
+}
+
+pub fn hash_contents(contents: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(contents))
+}
+
+// Makes sure the file as it is now is the newest version, and returns its hash.
+pub async fn record_current(file: &Path) -> anyhow::Result<String> {
+    let modified = metadata(file).await?.modified().map(DateTime::<Utc>::from).unwrap_or_else(|_| Utc::now());
+    push_version(&history_directory(file)?, file, modified, None, None).await
+}
+// Call before `new_contents` replaces `file`.
+pub async fn record_upload(new_contents: &Path, file: &Path, uploader: Option<String>) -> anyhow::Result<()> {
+    if try_exists(file).await? {
+        record_current(file).await?;
+    }
+    push_version(&history_directory(file)?, new_contents, Utc::now(), uploader, None).await?;
+    Ok(())
+}
+// Keeps a file's history with it when it's renamed or moved.
+pub async fn move_history(from: &Path, to: &Path) -> anyhow::Result<()> {
+    let from = history_directory(from)?;
+    if try_exists(&from).await? {
+        let to = history_directory(to)?;
+        create_dir_all(to.parent().unwrap()).await?;
+        rename(from, to).await?;
+    }
+    Ok(())
+}
+
+// Gives a copy of a file the same history as the original.
+pub async fn copy_history(from: &Path, to: &Path) -> anyhow::Result<()> {
+    let from = history_directory(from)?;
+    if try_exists(&from).await? {
+        let to = history_directory(to)?;
+        create_dir_all(&to).await?;
+        let mut entries = read_dir(&from).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            copy(entry.path(), to.join(entry.file_name())).await?;
+        }
+    }
+    Ok(())
+}
+
+fn is_valid_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+async fn version_contents(file: &Path, hash: &str) -> ServerResult<String> {
+    if !is_valid_hash(hash) {
+        return Err(ServerError::bad_request(format!("invalid version {:?}", hash)));
+    }
+    let stored = history_directory(file)?.join(hash);
+    if !try_exists(&stored).await? {
+        return Err(ServerError::bad_request(format!("no version {} of this file", hash)));
+    }
+    Ok(String::from_utf8_lossy(&read(stored).await?).into_owned())
+}
+
+// The newest version, recorded or not.
+async fn current_version(file: &Path) -> anyhow::Result<GcodeVersion> {
+    let bytes = read(file).await?;
+    let time = metadata(file).await?.modified().map(DateTime::<Utc>::from).unwrap_or_else(|_| Utc::now());
+    Ok(GcodeVersion { hash: hash_contents(&bytes), time, uploader: None, restored_from: None, size: bytes.len() as u64 })
+}
+
+// Only reads, so the file's unrecorded changes are listed but not kept.
+pub async fn list_versions(path: extract::Path<String>, config: Extension<Arc<Config>>) -> ServerResult<Json<Vec<GcodeVersion>>> {
+    let file = config.gcode_path(&*path)?;
+    let mut history = read_history(&history_directory(&file)?).await?;
+    let current = current_version(&file).await?;
+    if history.last().is_none_or(|version| version.hash != current.hash) {
+        history.push(current);
+    }
+    Ok(Json(history))
+}
+
+pub async fn download_version(path: extract::Path<(String, String)>, config: Extension<Arc<Config>>) -> ServerResult<Response> {
+    let (hash, name) = path.0;
+    if !is_valid_hash(&hash) {
+        return Err(ServerError::bad_request(format!("invalid version {:?}", hash)));
+    }
+    let path = config.gcode_path(&name)?;
+    let stored = history_directory(&path)?.join(&hash);
+    let file = if try_exists(&stored).await? {
+        File::open(stored).await?
+    } else if current_version(&path).await.is_ok_and(|current| current.hash == hash) {
+        File::open(path).await?
+    } else {
+        return Err(ServerError::bad_request(format!("no version {} of {:?}", hash, name)));
+    };
+    let body = BoxBody::new(StreamBody::new(ReaderStream::new(file)));
+    let response = Response::builder()
+        .header("Content-Type", "text/plain;")
+        .header("Content-Disposition", "inline;")
+        .body(body)?;
+    Ok(response)
+}
+
+pub async fn diff_versions(config: Extension<Arc<Config>>, info: Json<DiffGcodeVersions>) -> ServerResult<String> {
+    let file = config.gcode_path(&info.path)?;
+    let current = record_current(&file).await?;
+    let to = info.to.clone().unwrap_or(current);
+    let (old, new) = (version_contents(&file, &info.from).await?, version_contents(&file, &to).await?);
+    let diff = TextDiff::from_lines(&old, &new)
+        .unified_diff()
+        .header(&info.from, &to)
+        .to_string();
+    Ok(diff)
+}
+
+pub async fn restore_version(config: Extension<Arc<Config>>, info: Json<RestoreGcodeVersion>) -> ServerResult<String> {
+    let file = config.gcode_path(&info.path)?;
+    let directory = history_directory(&file)?;
+    record_current(&file).await?;
+    version_contents(&file, &info.hash).await?;  // Checks that the version exists.
+    copy(directory.join(&info.hash), &file).await?;
+    push_version(&directory, &file, Utc::now(), None, Some(info.hash.clone())).await?;
+    Ok("Ok".to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use axum::response::IntoResponse;
+    use hyper::StatusCode;
+    use tempdir::TempDir;
+
+    use super::*;
+
+    async fn hashes(file: &Path) -> Vec<String> {
+        read_history(&history_directory(file).unwrap()).await.unwrap().into_iter().map(|version| version.hash).collect()
+    }
+
+    #[test]
+    fn hashes_are_hex_sha256() {
+        let hash = hash_contents(b"G0 X0\n");
+        assert!(is_valid_hash(&hash));
+        assert_eq!(hash_contents(b""), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert!(!is_valid_hash("../history.json"));
+    }
+
+    #[tokio::test]
+    async fn versions_are_added_only_when_contents_change() {
+        let directory = TempDir::new("gcode_versions").unwrap();
+        let (file, history) = (directory.path().join("a.nc"), directory.path().join("history"));
+        write(&file, "G0 X1\n").await.unwrap();
+        let first = push_version(&history, &file, Utc::now(), Some("ann".to_string()), None).await.unwrap();
+        assert_eq!(push_version(&history, &file, Utc::now(), None, None).await.unwrap(), first);
+        write(&file, "G0 X2\n").await.unwrap();
+        let second = push_version(&history, &file, Utc::now(), None, None).await.unwrap();
+        write(&file, "G0 X1\n").await.unwrap();
+        assert_eq!(push_version(&history, &file, Utc::now(), None, None).await.unwrap(), first);
+        let versions = read_history(&history).await.unwrap();
+        assert_eq!(versions.iter().map(|version| &version.hash).collect::<Vec<_>>(), vec![&first, &second, &first]);
+        assert_eq!(versions[0].uploader.as_deref(), Some("ann"));
+        assert_eq!(read(history.join(&second)).await.unwrap(), b"G0 X2\n");
+    }
+
+    #[tokio::test]
+    async fn uploads_keep_changes_made_behind_the_servers_back() {
+        let directory = TempDir::new("gcode_versions").unwrap();
+        let (file, upload) = (directory.path().join("a.nc"), directory.path().join("upload"));
+        write(&upload, "G0 X1\n").await.unwrap();
+        record_upload(&upload, &file, Some("ann".to_string())).await.unwrap();
+        rename(&upload, &file).await.unwrap();
+        write(&file, "G0 X2\n").await.unwrap();
+        write(&upload, "G0 X2\n").await.unwrap();
+        record_upload(&upload, &file, Some("bob".to_string())).await.unwrap();
+        let versions = read_history(&history_directory(&file).unwrap()).await.unwrap();
+        assert_eq!(versions.len(), 2);
+        assert_eq!(versions[1].uploader, None);
+        write(&upload, "G0 X3\n").await.unwrap();
+        record_upload(&upload, &file, Some("bob".to_string())).await.unwrap();
+        assert_eq!(hashes(&file).await, ["G0 X1\n", "G0 X2\n", "G0 X3\n"].map(|contents| hash_contents(contents.as_bytes())));
+    }
+
+    #[tokio::test]
+    async fn histories_move_with_their_files() {
+        let directory = TempDir::new("gcode_versions").unwrap();
+        let (from, to) = (directory.path().join("a.nc"), directory.path().join("sub/b.nc"));
+        write(&from, "G0 X1\n").await.unwrap();
+        let hash = record_current(&from).await.unwrap();
+        move_history(&from, &to).await.unwrap();
+        assert!(hashes(&from).await.is_empty());
+        assert_eq!(hashes(&to).await, vec![hash]);
+        move_history(&from, &to).await.unwrap(); // Nothing left to move.
+    }
+
+    #[tokio::test]
+    async fn listing_leaves_the_history_alone_and_restoring_adds_a_version() {
+        let directory = TempDir::new("gcode_versions").unwrap();
+        let config = Extension(Arc::new(Config::in_folder(directory.path())));
+        let file = config.gcode_path("a.nc").unwrap();
+        create_dir_all(file.parent().unwrap()).await.unwrap();
+        write(&file, "G0 X1\n").await.unwrap();
+        let first = record_current(&file).await.unwrap();
+        write(&file, "G0 X2\n").await.unwrap();
+        let listed = list_versions(extract::Path("a.nc".to_string()), config.clone()).await.unwrap();
+        let second = hash_contents(b"G0 X2\n");
+        assert_eq!(listed.iter().map(|version| &version.hash).collect::<Vec<_>>(), vec![&first, &second]);
+        assert_eq!(hashes(&file).await, vec![first.clone()]);
+        let restore = RestoreGcodeVersion { path: "a.nc".to_string(), hash: first.clone() };
+        restore_version(config.clone(), Json(restore)).await.unwrap();
+        assert_eq!(read(&file).await.unwrap(), b"G0 X1\n");
+        let versions = read_history(&history_directory(&file).unwrap()).await.unwrap();
+        assert_eq!(versions.iter().map(|version| &version.hash).collect::<Vec<_>>(), vec![&first, &second, &first]);
+        assert_eq!(versions[2].restored_from.as_ref(), Some(&first));
+        let unknown = RestoreGcodeVersion { path: "a.nc".to_string(), hash: hash_contents(b"G0 X3\n") };
+        assert!(restore_version(config, Json(unknown)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn unknown_versions_are_bad_requests() {
+        let directory = TempDir::new("gcode_versions").unwrap();
+        let config = Extension(Arc::new(Config::in_folder(directory.path())));
+        let file = config.gcode_path("a.nc").unwrap();
+        create_dir_all(file.parent().unwrap()).await.unwrap();
+        write(&file, "G0 X1\n").await.unwrap();
+        let download = |hash: String| download_version(extract::Path((hash, "a.nc".to_string())), config.clone());
+        assert!(download(hash_contents(b"G0 X1\n")).await.is_ok());
+        let unknown = download(hash_contents(b"G0 X2\n")).await.unwrap_err();
+        assert_eq!(unknown.into_response().status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,242 @@
+use std::{path::Path, sync::Arc};
+
+use anyhow::anyhow;
+use axum::{Router, Extension, Json, extract, body::{boxed, BoxBody, StreamBody, Full}, response::Response, routing::get};
+use chrono::{DateTime, Utc};
+use common::api::{DepthMapPoint, HeightMap, JobArtifact, ProbeExportFormat};
+use itertools::Itertools;
+use tokio::fs::{create_dir_all, read_dir, read_to_string, write, File};
+use tokio_util::io::ReaderStream;
+
+use crate::{cnc::grbl::messages::ProbeEvent, controller_settings::LastReadInfo, job_history::RECORD_FILE, machine_log, server_result::{ServerError, ServerResult}, Config};
+
+/*
+    Files saved with a job, kept beside its record in the job's results directory. Each is served with a
+content type picked from its name. Probe results can also be converted on the way out, for tools that
+want them in another shape.
+*/
+
+pub const PROBES_FILE: &str = "probes.json";
+pub const HEIGHT_MAP_FILE: &str = "height_map.json";
+pub const SETTINGS_FILE: &str = "settings.json";
+pub const MACHINE_LOG_FILE: &str = "machine.log";
+// Probes this close together on an axis are taken to be on the same grid line.
+const GRID_TOLERANCE: f64 = 1e-3;
+
+pub fn get_service() -> Router {
+    Router::new()
+        .route("/", get(list_jobs))
+        .route("/download/*path", get(download_artifact))
+        .route("/artifacts/:job", get(list_job_artifacts))
+        .route("/probes/:job/:format", get(export_probes))
+}
+
+pub fn content_type(name: &str) -> &'static str {
+    match name.rsplit_once('.').map(|(_, extension)| extension) {
+        Some("json") => "application/json",
+        Some("jsonl") => "application/x-ndjson",
+        Some("csv") => "text/csv",
+        Some("txt" | "log" | "nc" | "gcode") => "text/plain",
+        _ => "application/octet-stream",
+    }
+}
+// Artifacts are plain file names, so none can reach outside its job's directory.
+fn check_name(name: &str) -> ServerResult<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) || name == RECORD_FILE {
+        return Err(ServerError::bad_request(format!("Not an artifact: {:?}", name)));
+    }
+    Ok(())
+}
+
+/*
+    Saving
+*/
+pub async fn save_artifact(dirname: &Path, name: &str, contents: impl AsRef<[u8]>) -> anyhow::Result<()> {
+    create_dir_all(dirname).await?;
+    write(dirname.join(name), contents).await?;
+    Ok(())
+}
+// Also saves a height map when the probes were taken on a grid.
+pub async fn save_probe_results(dirname: &Path, probes: &[ProbeEvent]) -> anyhow::Result<()> {
+    if probes.is_empty() {
+        return Ok(());
+    }
+    save_artifact(dirname, PROBES_FILE, serde_json::to_vec(probes)?).await?;
+    if let Ok(height_map) = height_map(probes) {
+        if height_map.xs.len() > 1 && height_map.ys.len() > 1 {
+            save_artifact(dirname, HEIGHT_MAP_FILE, serde_json::to_vec(&height_map)?).await?;
+        }
+    }
+    Ok(())
+}
+// The controller's settings as last read before the job started, if they have been.
+pub async fn save_settings_snapshot(dirname: &Path, last_read: &LastReadInfo) -> anyhow::Result<()> {
+    if let Some(snapshot) = last_read.read().await.snapshot() {
+        save_artifact(dirname, SETTINGS_FILE, serde_json::to_vec(snapshot)?).await?;
+    }
+    Ok(())
+}
+// What passed between the server and the machine while the job ran.
+pub async fn save_machine_log(dirname: &Path, config: &Config, start: DateTime<Utc>, end: DateTime<Utc>) -> anyhow::Result<()> {
+    create_dir_all(dirname).await?;
+    machine_log::save_slice(&machine_log::log_root(config), start, end, &dirname.join(MACHINE_LOG_FILE)).await
+}
+pub async fn list_artifacts(dirname: &Path) -> anyhow::Result<Vec<JobArtifact>> {
+    let mut entries = read_dir(dirname).await?;
+    let mut artifacts = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let Ok(name) = entry.file_name().into_string() else { continue };
+        let metadata = entry.metadata().await?;
+        if name != RECORD_FILE && metadata.is_file() {
+            artifacts.push(JobArtifact { content_type: content_type(&name).to_string(), size: metadata.len(), name });
+        }
+    }
+    artifacts.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(artifacts)
+}
+
+/*
+    Probe exports
+*/
+fn probes_csv(probes: &[ProbeEvent]) -> String {
+    let mut text = "x,y,z,success\n".to_string();
+    for probe in probes {
+        let position = probe.position.iter().take(3).format(",");
+        text += &format!("{},{}\n", position, probe.success);
+    }
+    text
+}
+fn successful_points(probes: &[ProbeEvent]) -> Vec<[f64; 3]> {
+    probes.iter()
+        .filter(|probe| probe.success && probe.position.len() >= 3)
+        .map(|probe| [probe.position[0], probe.position[1], probe.position[2]])
+        .collect()
+}
+fn depth_map(probes: &[ProbeEvent]) -> Result<Vec<DepthMapPoint>, String> {
+    let points = successful_points(probes);
+    let reference = points.first().ok_or_else(|| "No probe succeeded".to_string())?[2];
+    Ok(points.iter().map(|[x, y, z]| DepthMapPoint { x: *x, y: *y, depth_offset: z - reference }).collect())
+}
+// The distinct values, increasing, with any within the tolerance of the one before merged into it.
+fn grid_lines(values: impl Iterator<Item=f64>) -> Vec<f64> {
+    let mut values = values.collect_vec();
+    values.sort_by(f64::total_cmp);
+    values.dedup_by(|value, kept| *value - *kept < GRID_TOLERANCE);
+    values
+}
+fn height_map(probes: &[ProbeEvent]) -> Result<HeightMap, String> {
+    let points = successful_points(probes);
+    if points.is_empty() {
+        return Err("No probe succeeded".to_string());
+    }
+    let xs = grid_lines(points.iter().map(|point| point[0]));
+    let ys = grid_lines(points.iter().map(|point| point[1]));
+    let not_grid = || format!("The {} successful probes don't form a grid", points.len());
+    if xs.len() * ys.len() != points.len() {
+        return Err(not_grid());
+    }
+    let find = |lines: &[f64], value: f64| lines.iter().rposition(|line| value - line > -GRID_TOLERANCE);
+    let mut heights = vec![vec![None; xs.len()]; ys.len()];
+    for [x, y, z] in points.iter() {
+        let (Some(column), Some(row)) = (find(&xs, *x), find(&ys, *y)) else { return Err(not_grid()) };
+        if heights[row][column].replace(*z).is_some() {
+            return Err(not_grid());
+        }
+    }
+    let heights = heights.into_iter()
+        .map(|row| row.into_iter().collect::<Option<Vec<f64>>>())
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(not_grid)?;
+    Ok(HeightMap { xs, ys, heights })
+}
+
+/*
+    Handlers
+*/
+fn file_response(body: BoxBody, name: &str, content_type: &str) -> ServerResult<Response> {
+    let response = Response::builder()
+        .header("Content-Type", content_type)
+        .header("Content-Disposition", format!("inline; filename=\"{}\"", name))
+        .body(body)?;
+    Ok(response)
+}
+async fn list_jobs(config: Extension<Arc<Config>>) -> ServerResult<Json<Vec<String>>> {
+    let mut entries = read_dir(config.jobs_root()).await?;
+    let mut values = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        values.push(entry.file_name().into_string().map_err(|_| anyhow!("Failed to unwrap file name"))?);
+    }
+    values.sort_by(|a, b| b.cmp(a));
+    Ok(Json(values))
+}
+async fn list_job_artifacts(config: Extension<Arc<Config>>, job: extract::Path<String>) -> ServerResult<Json<Vec<JobArtifact>>> {
+    Ok(Json(list_artifacts(&config.job_path(&*job)?).await?))
+}
+async fn download_artifact(config: Extension<Arc<Config>>, path: extract::Path<String>) -> ServerResult<Response> {
+    let path = path.trim_start_matches('/');
+    let (job, name) = path.split_once('/').unwrap_or((path, PROBES_FILE));
+    check_name(name)?;
+    let file = File::open(config.job_path(job)?.join(name)).await?;
+    let body = BoxBody::new(StreamBody::new(ReaderStream::new(file)));
+    file_response(body, name, content_type(name))
+}
+async fn export_probes(config: Extension<Arc<Config>>, path: extract::Path<(String, ProbeExportFormat)>) -> ServerResult<Response> {
+    let (job, format) = &*path;
+    let text = read_to_string(config.job_path(job)?.join(PROBES_FILE)).await
+        .map_err(|_| ServerError::bad_request(format!("Job {:?} saved no probe results", job)))?;
+    let probes: Vec<ProbeEvent> = serde_json::from_str(&text)?;
+    let (contents, extension) = match format {
+        ProbeExportFormat::Csv => (probes_csv(&probes), "csv"),
+        ProbeExportFormat::DepthMap => (serde_json::to_string(&depth_map(&probes).map_err(ServerError::bad_request)?)?, "json"),
+        ProbeExportFormat::HeightMap => (serde_json::to_string(&height_map(&probes).map_err(ServerError::bad_request)?)?, "json"),
+    };
+    let name = format!("{}_{}.{}", job, format.as_str(), extension);
+    file_response(boxed(Full::from(contents)), &name, content_type(&name))
+}
+
+#[cfg(test)]
+mod test {
+    use ndarray::array;
+
+    use super::*;
+
+    fn probe(x: f64, y: f64, z: f64) -> ProbeEvent {
+        ProbeEvent { success: true, position: array![x, y, z] }
+    }
+
+    #[test]
+    fn content_types_follow_extensions() {
+        assert_eq!(content_type(PROBES_FILE), "application/json");
+        assert_eq!(content_type("probes.csv"), "text/csv");
+        assert_eq!(content_type("machine.log"), "text/plain");
+        assert_eq!(content_type("snapshot"), "application/octet-stream");
+        assert!(check_name("../record.json").is_err());
+        assert!(check_name(RECORD_FILE).is_err());
+        assert!(check_name(HEIGHT_MAP_FILE).is_ok());
+    }
+
+    #[test]
+    fn exports_csv_and_depth_map() {
+        let probes = vec![probe(0.0, 0.0, -1.0), ProbeEvent { success: false, position: array![5.0, 0.0, -3.0] }, probe(10.0, 0.0, -1.5)];
+        assert_eq!(probes_csv(&probes), "x,y,z,success\n0,0,-1,true\n5,0,-3,false\n10,0,-1.5,true\n");
+        assert_eq!(depth_map(&probes).unwrap(), vec![
+            DepthMapPoint { x: 0.0, y: 0.0, depth_offset: 0.0 },
+            DepthMapPoint { x: 10.0, y: 0.0, depth_offset: -0.5 },
+        ]);
+        assert!(depth_map(&probes[1..2]).is_err());
+    }
+
+    #[test]
+    fn height_map_needs_a_full_grid() {
+        // Out of order, and off the grid lines by less than the tolerance.
+        let probes = vec![probe(10.0, 5.0, -1.3), probe(0.0, 0.0, -1.0), probe(10.0002, 0.0, -1.1), probe(0.0, 4.9999, -1.2)];
+        assert_eq!(height_map(&probes).unwrap(), HeightMap {
+            xs: vec![0.0, 10.0],
+            ys: vec![0.0, 4.9999],
+            heights: vec![vec![-1.0, -1.1], vec![-1.2, -1.3]],
+        });
+        assert!(height_map(&probes[..3]).is_err());
+        let repeated = vec![probe(0.0, 0.0, -1.0), probe(0.0, 0.0, -1.1), probe(10.0, 5.0, -1.0), probe(10.0, 5.0, -1.1)];
+        assert!(height_map(&repeated).is_err());
+    }
+}
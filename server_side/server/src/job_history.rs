@@ -0,0 +1,300 @@
+use std::{cmp::Reverse, io::ErrorKind, path::Path, sync::Arc};
+
+use axum::{Router, Extension, Json, extract, routing::{get, post}};
+use chrono::{DateTime, Utc};
+use common::{api::{JobErrorPolicy, JobLineError, JobOutcome, JobRecord, JobSource, JobSummary, OverrideChange, QueryJobHistory}, grbl::ActiveAlarm};
+use tokio::{fs::{read_dir, read_to_string, write}, pin, select, sync::{oneshot, watch}};
+
+use crate::{cnc::grbl::messages::GrblStateInfo, job_artifacts::list_artifacts, server_result::{ServerError, ServerResult}, Config};
+
+/*
+    Every job run from a file leaves a record.json in its results directory. It's written as the job
+starts, so a job the server never saw finish stays Running, and rewritten once it ends with the
+errors, override changes and alarm seen along the way.
+*/
+
+pub const RECORD_FILE: &str = "record.json";
+const DEFAULT_QUERY_LIMIT: usize = 500;
+
+pub fn get_service() -> Router {
+    Router::new()
+        .route("/", post(query_history))
+        .route("/record/:id", get(get_record))
+}
+
+/*
+    Recording
+*/
+pub async fn start_record(dirname: &Path, source: JobSource, error_policy: JobErrorPolicy, start_time: DateTime<Utc>) -> anyhow::Result<JobRecord> {
+    let id = dirname.file_name().and_then(|name| name.to_str()).unwrap_or_default().to_string();
+    let record = JobRecord {
+        id,
+        source,
+        error_policy,
+        start_time,
+        end_time: None,
+        outcome: JobOutcome::Running,
+        alarm: None,
+        errors: Vec::new(),
+        overrides: Vec::new(),
+        artifacts: Vec::new(),
+    };
+    write(dirname.join(RECORD_FILE), serde_json::to_vec(&record)?).await?;
+    Ok(record)
+}
+
+pub struct JobObservation {
+    ended: Option<JobOutcome>, // As the job itself reported, if it got to
+    alarm: Option<ActiveAlarm>,
+    overrides: Vec<OverrideChange>,
+}
+
+fn note_overrides(overrides: &mut Vec<OverrideChange>, last: &mut (u8, u8, u8), status: &GrblStateInfo) {
+    let current = (status.feed_override, status.rapid_override, status.spindle_override);
+    if current != *last {
+        *last = current;
+        overrides.push(OverrideChange { time: Utc::now(), feed: current.0, rapid: current.1, spindle: current.2 });
+    }
+}
+
+// Watches the machine until the job says how it ended. The receivers should be subscribed before the
+// job starts: an alarm already seen then isn't the job's, and one raised since is, however soon.
+pub async fn observe_job(mut status: watch::Receiver<GrblStateInfo>, mut alarm: watch::Receiver<Option<ActiveAlarm>>, finished: oneshot::Receiver<JobOutcome>) -> JobObservation {
+    let mut observation = JobObservation { ended: None, alarm: None, overrides: Vec::new() };
+    let mut last = (100, 100, 100);
+    note_overrides(&mut observation.overrides, &mut last, &status.borrow_and_update());
+    pin!(finished);
+    loop {
+        select! {
+            ended = &mut finished => {
+                observation.ended = ended.ok();
+                break;
+            }
+            changed = status.changed() => match changed {
+                Ok(()) => note_overrides(&mut observation.overrides, &mut last, &status.borrow_and_update()),
+                Err(_) => {
+                    observation.ended = (&mut finished).await.ok();
+                    break;
+                }
+            },
+            changed = alarm.changed() => match changed {
+                Ok(()) => if observation.alarm.is_none() {
+                    observation.alarm = alarm.borrow_and_update().clone();
+                },
+                Err(_) => {
+                    observation.ended = (&mut finished).await.ok();
+                    break;
+                }
+            },
+        }
+    }
+    // The alarm that ended the job may only show up once it has.
+    if observation.alarm.is_none() && alarm.has_changed().unwrap_or(false) {
+        observation.alarm = alarm.borrow().clone();
+    }
+    observation
+}
+
+// A job can't tell an alarm or reset from a stop, since all of them just end it, so the alarm decides.
+fn final_outcome(ended: Option<JobOutcome>, alarm: Option<&ActiveAlarm>) -> JobOutcome {
+    match (ended, alarm) {
+        (Some(JobOutcome::Completed), _) => JobOutcome::Completed,
+        (Some(JobOutcome::Error), _) => JobOutcome::Error,
+        (_, Some(alarm)) if alarm.code == Some(3) => JobOutcome::Reset, // Reset while in motion
+        (_, Some(_)) => JobOutcome::Alarm,
+        (ended, None) => ended.unwrap_or(JobOutcome::Stopped),
+    }
+}
+
+pub async fn finish_record(dirname: &Path, mut record: JobRecord, observation: JobObservation, errors: Vec<JobLineError>) -> anyhow::Result<JobRecord> {
+    record.end_time = Some(Utc::now());
+    record.outcome = final_outcome(observation.ended, observation.alarm.as_ref());
+    record.alarm = observation.alarm.map(|alarm| match alarm.code {
+        Some(code) => format!("ALARM:{} {}", code, alarm.text),
+        None => alarm.text,
+    });
+    record.errors = errors;
+    record.overrides = observation.overrides;
+    record.artifacts = list_artifacts(dirname).await?;
+    write(dirname.join(RECORD_FILE), serde_json::to_vec(&record)?).await?;
+    Ok(record)
+}
+
+/*
+    Queries
+*/
+pub async fn read_record(dirname: &Path) -> anyhow::Result<JobRecord> {
+    Ok(serde_json::from_str(&read_to_string(dirname.join(RECORD_FILE)).await?)?)
+}
+fn matches_query(query: &QueryJobHistory, record: &JobRecord) -> bool {
+    query.path.as_ref().is_none_or(|path| record.source.path.contains(path.as_str()))
+        && query.outcomes.as_ref().is_none_or(|outcomes| outcomes.contains(&record.outcome))
+        && query.start.is_none_or(|start| record.start_time >= start)
+        && query.end.is_none_or(|end| record.start_time < end)
+}
+fn summarize(record: JobRecord) -> JobSummary {
+    JobSummary {
+        id: record.id,
+        source: record.source,
+        start_time: record.start_time,
+        end_time: record.end_time,
+        outcome: record.outcome,
+        error_count: record.errors.len(),
+    }
+}
+async fn query_history(config: Extension<Arc<Config>>, query: Json<QueryJobHistory>) -> ServerResult<Json<Vec<JobSummary>>> {
+    let mut entries = match read_dir(config.jobs_root()).await {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(Json(Vec::new())),
+        Err(error) => return Err(error.into()),
+    };
+    let mut records = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        // Results saved before jobs were recorded have no record, and are left out.
+        let Ok(record) = read_record(&entry.path()).await else { continue };
+        if matches_query(&query, &record) {
+            records.push(record);
+        }
+    }
+    records.sort_by_key(|record| Reverse(record.start_time));
+    records.truncate(query.limit.unwrap_or(DEFAULT_QUERY_LIMIT));
+    Ok(Json(records.into_iter().map(summarize).collect()))
+}
+async fn get_record(config: Extension<Arc<Config>>, id: extract::Path<String>) -> ServerResult<Json<JobRecord>> {
+    let dirname = config.job_path(&*id)?;
+    match read_record(&dirname).await {
+        Ok(record) => Ok(Json(record)),
+        Err(_) => Err(ServerError::bad_request(format!("No record of job {:?}", *id))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Duration;
+    use common::grbl::GrblState;
+    use ndarray::arr1;
+    use tempdir::TempDir;
+    use tokio::{fs::create_dir_all, spawn, time::sleep};
+
+    use crate::cnc::grbl::messages::{GrblPosition, GrblResidualStatus, GrblStatus};
+
+    use super::*;
+
+    fn record(path: &str, outcome: JobOutcome, start_time: DateTime<Utc>) -> JobRecord {
+        JobRecord {
+            id: "job_1".into(),
+            source: JobSource { path: path.into(), version: String::new() },
+            error_policy: JobErrorPolicy::Abort,
+            start_time,
+            end_time: None,
+            outcome,
+            alarm: None,
+            errors: Vec::new(),
+            overrides: Vec::new(),
+            artifacts: Vec::new(),
+        }
+    }
+    fn alarm(code: u64) -> ActiveAlarm {
+        ActiveAlarm { code: Some(code), text: String::new(), requires_reset: false, recovery: Vec::new() }
+    }
+    fn status(feed_override: u8) -> GrblStateInfo {
+        let mut status = GrblStatus::new(GrblState::Run, GrblPosition::Machine(arr1(&[0.0, 0.0, 0.0])));
+        status.work_coordinate_offset = Some(arr1(&[0.0, 0.0, 0.0]));
+        status.feed_override = Some(feed_override);
+        status.to_state_with_residual(&mut GrblResidualStatus::new())
+    }
+
+    #[test]
+    fn query_filters_by_path_outcome_and_time() {
+        let time = Utc::now();
+        let record = record("parts/bracket.nc", JobOutcome::Completed, time);
+        assert!(matches_query(&Default::default(), &record));
+        assert!(matches_query(&QueryJobHistory { path: Some("bracket".into()), ..Default::default() }, &record));
+        assert!(!matches_query(&QueryJobHistory { path: Some("plate".into()), ..Default::default() }, &record));
+        assert!(matches_query(&QueryJobHistory { outcomes: Some(vec![JobOutcome::Stopped, JobOutcome::Completed]), ..Default::default() }, &record));
+        assert!(!matches_query(&QueryJobHistory { outcomes: Some(vec![JobOutcome::Error]), ..Default::default() }, &record));
+        assert!(matches_query(&QueryJobHistory { start: Some(time), end: Some(time + Duration::seconds(1)), ..Default::default() }, &record));
+        assert!(!matches_query(&QueryJobHistory { end: Some(time), ..Default::default() }, &record));
+    }
+
+    #[test]
+    fn outcome_prefers_what_the_job_knew_then_the_alarm() {
+        assert_eq!(final_outcome(Some(JobOutcome::Completed), None), JobOutcome::Completed);
+        assert_eq!(final_outcome(Some(JobOutcome::Error), Some(&alarm(2))), JobOutcome::Error);
+        assert_eq!(final_outcome(Some(JobOutcome::Stopped), None), JobOutcome::Stopped);
+        assert_eq!(final_outcome(Some(JobOutcome::Stopped), Some(&alarm(3))), JobOutcome::Reset);
+        assert_eq!(final_outcome(Some(JobOutcome::Stopped), Some(&alarm(1))), JobOutcome::Alarm);
+        assert_eq!(final_outcome(None, None), JobOutcome::Stopped);
+    }
+
+    #[tokio::test]
+    async fn observation_notes_overrides_until_the_job_ends() {
+        let (status_tx, status_rx) = watch::channel(status(100));
+        let (_alarm_tx, alarm_rx) = watch::channel(None);
+        let (finished_tx, finished_rx) = oneshot::channel();
+        let observing = spawn(observe_job(status_rx, alarm_rx, finished_rx));
+        // Each change is given time to be seen, since a watch only keeps the newest.
+        for feed_override in [100, 120, 80] {
+            status_tx.send(status(feed_override)).unwrap();
+            sleep(std::time::Duration::from_millis(20)).await;
+        }
+        finished_tx.send(JobOutcome::Completed).unwrap();
+        let observation = observing.await.unwrap();
+        assert_eq!(observation.ended, Some(JobOutcome::Completed));
+        assert!(observation.alarm.is_none());
+        assert_eq!(observation.overrides.iter().map(|change| change.feed).collect::<Vec<_>>(), vec![120, 80]);
+    }
+
+    #[tokio::test]
+    async fn observation_keeps_an_alarm_raised_as_the_job_ends() {
+        let (_status_tx, status_rx) = watch::channel(status(100));
+        let (alarm_tx, alarm_rx) = watch::channel(Some(alarm(1)));
+        let (finished_tx, finished_rx) = oneshot::channel();
+        let observing = spawn(observe_job(status_rx, alarm_rx, finished_rx));
+        finished_tx.send(JobOutcome::Stopped).unwrap();
+        alarm_tx.send(Some(alarm(3))).unwrap();
+        let observation = observing.await.unwrap();
+        assert_eq!(observation.ended, Some(JobOutcome::Stopped));
+        assert_eq!(observation.alarm.and_then(|alarm| alarm.code), Some(3));
+        assert!(observation.overrides.is_empty());
+    }
+
+    #[tokio::test]
+    async fn observation_waits_for_the_end_once_the_machine_is_gone() {
+        let (status_tx, status_rx) = watch::channel(status(100));
+        let (_alarm_tx, alarm_rx) = watch::channel(None);
+        let (finished_tx, finished_rx) = oneshot::channel();
+        let observing = spawn(observe_job(status_rx, alarm_rx, finished_rx));
+        drop(status_tx);
+        sleep(std::time::Duration::from_millis(20)).await;
+        assert!(!observing.is_finished());
+        drop(finished_tx);
+        let observation = observing.await.unwrap();
+        assert_eq!(observation.ended, None);
+        assert_eq!(final_outcome(observation.ended, observation.alarm.as_ref()), JobOutcome::Stopped);
+    }
+
+    #[tokio::test]
+    async fn finished_records_are_rewritten_with_what_was_seen() {
+        let directory = TempDir::new("job_history").unwrap();
+        let dirname = directory.path().join("job_1");
+        create_dir_all(&dirname).await.unwrap();
+        let source = JobSource { path: "a.nc".into(), version: String::new() };
+        let started = start_record(&dirname, source, JobErrorPolicy::Continue, Utc::now()).await.unwrap();
+        assert_eq!(read_record(&dirname).await.unwrap().outcome, JobOutcome::Running);
+        write(dirname.join("probes.csv"), "x,y,z\n").await.unwrap();
+        let observation = JobObservation {
+            ended: Some(JobOutcome::Stopped),
+            alarm: Some(ActiveAlarm { text: "Reset while in motion".into(), ..alarm(3) }),
+            overrides: Vec::new(),
+        };
+        let errors = vec![JobLineError { line_number: 2, code: 20, text: "Unsupported command".into(), sent: "G5".into() }];
+        let finished = finish_record(&dirname, started, observation, errors).await.unwrap();
+        assert_eq!(finished.outcome, JobOutcome::Reset);
+        assert_eq!(finished.alarm.as_deref(), Some("ALARM:3 Reset while in motion"));
+        assert_eq!(finished.errors.len(), 1);
+        assert_eq!(finished.artifacts.iter().map(|artifact| artifact.name.as_str()).collect::<Vec<_>>(), vec!["probes.csv"]);
+        assert!(finished.end_time.is_some());
+        assert_eq!(read_record(&dirname).await.unwrap(), finished);
+    }
+}
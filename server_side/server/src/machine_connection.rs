@@ -0,0 +1,286 @@
+/*
+    A single websocket exposing the machine as a websocket::MachineConnection: watchable machine and
+job status, state changes, the debug stream and a callback for commands, multiplexed over
+protocol_util channels. Messages on the socket are "<channel>:<json>".
+*/
+use std::sync::{Arc, Mutex};
+
+use async_stream::stream;
+use axum::{extract::ws::{Message, WebSocketUpgrade}, response::Response, Extension};
+use common::{
+    grbl::AlarmRecovery,
+    websocket::{MachineCommand, MachineConnectionTx, OverrideCommand, WatchableTx, MACHINE_CONNECTION_CHANNEL},
+};
+use futures::{channel::mpsc, join, SinkExt, StreamExt};
+use protocol_util::{
+    channel_allocator::TypedChannelAllocator,
+    communication_context::Context,
+    generic::DefaultSendable,
+    receiver::{create_listener_full, FullListenerCreation},
+    sender::Sender,
+    spawner::Spawner,
+};
+use tokio::{select, sync::oneshot};
+
+use crate::{
+    cnc::grbl::{
+        handler::SpeedOverride,
+        messages::GrblMessage,
+        new_machine::LineError,
+        realtime::RealtimeCommand,
+        standard_handler::{ImmediateHandle, MachineDebugEvent},
+    },
+    machine_log::to_entry,
+    status_stream::{full_info, StatusStreamInfo},
+    util::history_broadcast::{self, ReceiverError},
+};
+
+fn speed_override(command: OverrideCommand) -> SpeedOverride {
+    match command {
+        OverrideCommand::FeedReset => SpeedOverride::FeedReset,
+        OverrideCommand::FeedPlus10 => SpeedOverride::FeedIncrease10,
+        OverrideCommand::FeedPlus1 => SpeedOverride::FeedIncrease1,
+        OverrideCommand::FeedMinus1 => SpeedOverride::FeedDecrease1,
+        OverrideCommand::FeedMinus10 => SpeedOverride::FeedDecrease10,
+        OverrideCommand::SpindleReset => SpeedOverride::SpindleReset,
+        OverrideCommand::SpindlePlus10 => SpeedOverride::SpindleIncrease10,
+        OverrideCommand::SpindlePlus1 => SpeedOverride::SpindleIncrease1,
+        OverrideCommand::SpindleMinus1 => SpeedOverride::SpindleDecrease1,
+        OverrideCommand::SpindleMinus10 => SpeedOverride::SpindleDecrease10,
+        OverrideCommand::RapidReset => SpeedOverride::RapidReset,
+        OverrideCommand::RapidHalf => SpeedOverride::RapidHalf,
+        OverrideCommand::RapidQuarter => SpeedOverride::RapidQuarter,
+    }
+}
+
+pub async fn recover_from_alarm(machine: &ImmediateHandle, recovery: AlarmRecovery) -> Result<(), String> {
+    match machine.recover_from_alarm(recovery).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(LineError::Grbl(code))) => Err(GrblMessage::get_error_text(code).into_owned()),
+        Ok(Err(LineError::Reset)) => Err("Machine was reset during recovery.".to_string()),
+        Err(error) => Err(error.to_string()),
+    }
+}
+
+async fn run_command(machine: &ImmediateHandle, command: MachineCommand) -> Result<(), String> {
+    let realtime = |command| async move { machine.send_realtime(command).await.map_err(|error| error.to_string()) };
+    match command {
+        MachineCommand::Pause => machine.pause().await,
+        MachineCommand::Resume => machine.resume().await,
+        MachineCommand::Stop => machine.stop().await,
+        MachineCommand::Reset => machine.reset().await,
+        MachineCommand::RecoverFromAlarm(recovery) => return recover_from_alarm(machine, recovery).await,
+        MachineCommand::Override(command) => machine.override_speed(speed_override(command)).await,
+        MachineCommand::ToggleSpindleStop => return realtime(RealtimeCommand::ToggleSpindleStop).await,
+        MachineCommand::ToggleFloodCoolant => return realtime(RealtimeCommand::ToggleFloodCoolant).await,
+        MachineCommand::ToggleMistCoolant => return realtime(RealtimeCommand::ToggleMistCoolant).await,
+        MachineCommand::SafetyDoor => return realtime(RealtimeCommand::SafetyDoor).await,
+        MachineCommand::Macro(index) => {
+            let command = RealtimeCommand::macro_index(index).ok_or_else(|| format!("No macro {}; expected 0 to 3.", index))?;
+            return realtime(command).await
+        }
+    }
+    Ok(())
+}
+
+// Sends the connection's root object; everything else hangs off of its channels.
+async fn send_machine_connection(
+    context: Context,
+    machine: Arc<ImmediateHandle>,
+    status_stream: Arc<StatusStreamInfo>,
+    debug_rx: Arc<history_broadcast::Receiver<MachineDebugEvent>>,
+) {
+    let mut status = status_stream.subscribe();
+    let mut alarm = machine.subscribe_alarm().await;
+    let mut job = machine.subscribe_job_status().await;
+    let mut state_changes = status_stream.subscribe_state_changes();
+    let mut debug_events = debug_rx.subscribe_with_history_count(100);
+    let (stop_status_tx, mut stop_status_rx) = oneshot::channel::<()>();
+    let (stop_job_tx, mut stop_job_rx) = oneshot::channel::<()>();
+
+    let status_value = full_info(&mut status, &mut alarm);
+    let job_value = job.borrow_and_update().clone();
+    let connection = MachineConnectionTx {
+        status: WatchableTx {
+            value: status_value,
+            update_stream: DefaultSendable(Box::pin(stream! {
+                loop {
+                    select! {
+                        changed = status.changed() => if changed.is_err() { break },
+                        changed = alarm.changed() => if changed.is_err() { break },
+                        _ = &mut stop_status_rx => break,
+                    }
+                    yield full_info(&mut status, &mut alarm);
+                }
+            })),
+            stop: DefaultSendable(move |_: Option<()>| { let _ = stop_status_tx.send(()); }),
+        },
+        job: WatchableTx {
+            value: job_value,
+            update_stream: DefaultSendable(Box::pin(stream! {
+                loop {
+                    select! {
+                        changed = job.changed() => if changed.is_err() { break },
+                        _ = &mut stop_job_rx => break,
+                    }
+                    let value = job.borrow_and_update().clone();
+                    yield value;
+                }
+            })),
+            stop: DefaultSendable(move |_: Option<()>| { let _ = stop_job_tx.send(()); }),
+        },
+        state_changes: DefaultSendable(Box::pin(stream! {
+            loop {
+                match state_changes.recv().await {
+                    Ok(change) => yield change,
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        })),
+        debug_stream: DefaultSendable(Box::pin(stream! {
+            loop {
+                match debug_events.recv().await {
+                    Ok(event) => yield to_entry(event),
+                    Err(ReceiverError::Lagged(_)) => continue,
+                    Err(ReceiverError::Closed) => break,
+                }
+            }
+        })),
+        command: move |command: MachineCommand| {
+            let machine = machine.clone();
+            async move { run_command(&machine, command).await }
+        },
+    };
+    let root = context.channel_allocator.outgoing();
+    debug_assert_eq!(root.0, MACHINE_CONNECTION_CHANNEL);
+    context.send_in_context(&root, Some(connection));
+}
+
+pub async fn machine_connection(
+    ws: WebSocketUpgrade,
+    machine: Extension<Arc<ImmediateHandle>>,
+    status_stream: Extension<Arc<StatusStreamInfo>>,
+    debug_rx: Extension<Arc<history_broadcast::Receiver<MachineDebugEvent>>>,
+) -> Response {
+    ws.on_upgrade(|ws| async move {
+        let (out_sender, mut out_receiver) = mpsc::unbounded();
+        let (mut ws_send, mut ws_receive) = ws.split();
+        let FullListenerCreation { future, controller, sender: receiver } = create_listener_full();
+        // Tasks spawned for this connection; aborted (and no more started) once it closes.
+        let handles = Arc::new(Mutex::new(Some(Vec::new())));
+        let context = Context {
+            channel_allocator: Arc::new(TypedChannelAllocator::new()),
+            controller,
+            sender: Sender::new(out_sender),
+            spawner: Spawner::new({
+                let handles = handles.clone();
+                move |future| {
+                    let join_handle = tokio::spawn(future);
+                    match &mut *handles.lock().unwrap() {
+                        Some(handles) => handles.push(join_handle),
+                        None => join_handle.abort(),
+                    }
+                }
+            }),
+        };
+        context.spawner.spawn(future);
+        context.spawner.spawn(send_machine_connection(context.clone(), machine.0, status_stream.0, debug_rx.0));
+        let (ws_send, ws_receive) = join!(
+            async move {
+                while let Some(message) = ws_receive.next().await {
+                    match message {
+                        Ok(Message::Text(text)) => {
+                            if let Some((channel, message)) = text.split_once(':') {
+                                if let Ok(channel) = channel.parse() {
+                                    receiver.send(channel, message.into());
+                                }
+                            }
+                        }
+                        Ok(Message::Close(_)) | Err(_) => break,
+                        Ok(_) => (),
+                    }
+                }
+                ws_receive
+            },
+            async move {
+                while let Some((channel, message)) = out_receiver.next().await {
+                    if ws_send.send(Message::Text(format!("{}:{}", channel, message))).await.is_err() {
+                        break;
+                    }
+                }
+                ws_send
+            },
+        );
+        for task in handles.lock().unwrap().take().unwrap() {
+            task.abort();
+        }
+        if let Ok(ws) = ws_send.reunite(ws_receive) {
+            drop(ws.close().await);
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::{marker::PhantomData, time::Duration};
+
+    use common::{grbl::GrblState, websocket::MachineConnection};
+    use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
+    use protocol_util::{base::{Channel, ChannelFuture}, generic::Receivable, receiver::create_listener};
+    use tokio::time::timeout;
+
+    use crate::{cnc::grbl::standard_handler::test::with_simulated_machine, status_stream::{status_stream_task, StatusPollRates}};
+
+    use super::*;
+
+    // One end of an in-memory connection, standing in for either side of the websocket.
+    fn context(outgoing: UnboundedSender<(u64, String)>, incoming: UnboundedReceiver<(u64, String)>) -> Context {
+        let listener = create_listener(incoming);
+        tokio::spawn(listener.future);
+        Context {
+            channel_allocator: Arc::new(TypedChannelAllocator::new()),
+            controller: listener.controller,
+            sender: Sender::new(outgoing),
+            spawner: Spawner::new(|future| drop(tokio::spawn(future))),
+        }
+    }
+
+    #[tokio::test]
+    async fn connection_carries_status_job_and_commands() {
+        with_simulated_machine(Default::default(), |machine| async move {
+            let machine = Arc::new(machine);
+            let rates = StatusPollRates { active: Duration::from_millis(10), idle: Duration::from_millis(10) };
+            let status_stream = Arc::new(status_stream_task(machine.clone(), rates).await);
+            let debug_tx = history_broadcast::Sender::new(16);
+            let debug_rx = Arc::new(debug_tx.subscribe_with_history_count(0));
+            let (server_tx, server_rx) = mpsc::unbounded();
+            let (client_tx, client_rx) = mpsc::unbounded();
+            let server = context(server_tx, client_rx);
+            let client = context(client_tx, server_rx);
+            server.spawner.spawn(send_machine_connection(server.clone(), machine.clone(), status_stream, debug_rx));
+
+            let root = ChannelFuture::<MachineConnection>(Channel(MACHINE_CONNECTION_CHANNEL, PhantomData));
+            let mut connection = root.receive_in_context(&client).await.unwrap();
+            assert_eq!(connection.status.value.state, GrblState::Idle);
+            assert!(connection.job.value.is_none());
+
+            assert_eq!(connection.command.call(MachineCommand::Override(OverrideCommand::FeedPlus10)).await.unwrap(), Ok(()));
+            let overridden = timeout(Duration::from_secs(5), async {
+                while let Some(status) = connection.status.update_stream.next().await {
+                    if status.feed_override == 110 {
+                        return true;
+                    }
+                }
+                false
+            }).await.unwrap();
+            assert!(overridden);
+            assert!(connection.command.call(MachineCommand::Macro(7)).await.unwrap().is_err());
+
+            let job = machine.get_job_handle().await.unwrap();
+            job.set_status("Cutting".to_string()).await.unwrap();
+            let status = timeout(Duration::from_secs(5), connection.job.update_stream.next()).await.unwrap().unwrap();
+            assert_eq!(status.map(|status| status.message), Some("Cutting".to_string()));
+        }).await
+    }
+}
@@ -0,0 +1,358 @@
+use std::{path::{Path, PathBuf}, sync::Arc};
+
+use async_stream::try_stream;
+use axum::{Router, Extension, Json, extract, body::{BoxBody, StreamBody}, response::Response, routing::{get, post}};
+use chrono::{DateTime, Local, NaiveDate, Utc, Duration};
+use common::api::{MachineLogEntry, MachineLogFile, MachineLogKind, MachineLogQueryResult, QueryMachineLog};
+use futures::{pin_mut, Stream, TryStreamExt};
+use tokio::{fs::{create_dir_all, read_dir, remove_file, File, OpenOptions}, io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter}, spawn};
+use tokio_util::io::ReaderStream;
+
+use crate::{cnc::grbl::standard_handler::MachineDebugEvent, server_result::{ServerError, ServerResult}, util::{format_bytes::format_byte_string, history_broadcast::{self, ReceiverError}}, Config};
+
+/*
+    Persistent log of everything on the machine's debug stream. Entries are written as JSON lines to
+one file per local day, with a new part started whenever a file reaches its size limit. The oldest
+files are deleted once the folder as a whole grows too large.
+*/
+
+#[derive(Clone, Copy, Debug)]
+pub struct LogLimits {
+    pub max_file_size: u64,
+    pub max_total_size: u64,
+}
+const DEFAULT_LIMITS: LogLimits = LogLimits {
+    max_file_size: 16 * 1024 * 1024,
+    max_total_size: 512 * 1024 * 1024,
+};
+const DEFAULT_QUERY_LIMIT: usize = 5000;
+
+pub fn log_root(config: &Config) -> PathBuf {
+    config.data_folder.join("machine_log")
+}
+
+pub async fn get_service(config: &Config, debug_rx: &history_broadcast::Receiver<MachineDebugEvent>) -> anyhow::Result<Router> {
+    let root = log_root(config);
+    create_dir_all(&root).await?;
+    let events = debug_rx.subscribe_with_history_count(0);
+    let writer_root = root.clone();
+    spawn(async move {
+        if let Err(error) = write_machine_log(events, writer_root, DEFAULT_LIMITS).await {
+            println!("Machine log stopped: {:?}", error);
+        }
+    });
+    let router = Router::new()
+        .route("/", get(list_logs))
+        .route("/download/:name", get(download_log))
+        .route("/query", post(query_logs))
+        .layer(Extension(Arc::new(MachineLogRoot(root))));
+    Ok(router)
+}
+
+struct MachineLogRoot(PathBuf);
+type LogRoot = Extension<Arc<MachineLogRoot>>;
+
+/*
+    File naming
+*/
+fn log_file_name(date: NaiveDate, part: u32) -> String {
+    if part == 0 {
+        format!("{}.jsonl", date.format("%Y-%m-%d"))
+    } else {
+        format!("{}.{}.jsonl", date.format("%Y-%m-%d"), part)
+    }
+}
+// Also serves to validate names given by clients, since no name with a path separator parses.
+fn parse_log_file_name(name: &str) -> Option<(NaiveDate, u32)> {
+    let stem = name.strip_suffix(".jsonl")?;
+    let (date, part) = match stem.split_once('.') {
+        Some((date, part)) => (date, part.parse::<u32>().ok().filter(|part| *part > 0)?),
+        None => (stem, 0),
+    };
+    Some((NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()?, part))
+}
+// Existing log files, oldest first.
+async fn list_log_files(root: &Path) -> anyhow::Result<Vec<((NaiveDate, u32), MachineLogFile)>> {
+    let mut entries = read_dir(root).await?;
+    let mut files = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let Ok(name) = entry.file_name().into_string() else { continue };
+        if let Some(key) = parse_log_file_name(&name) {
+            let size = entry.metadata().await?.len();
+            files.push((key, MachineLogFile { name, size }));
+        }
+    }
+    files.sort_by_key(|(key, _)| *key);
+    Ok(files)
+}
+
+pub(crate) fn to_entry(event: MachineDebugEvent) -> MachineLogEntry {
+    let (time, kind, message) = match event {
+        MachineDebugEvent::Sent(time, bytes) => (time, MachineLogKind::Sent, format_byte_string(bytes)),
+        MachineDebugEvent::Received(time, line) => (time, MachineLogKind::Received, line),
+        MachineDebugEvent::Warning(time, message) => (time, MachineLogKind::Warning, message),
+        MachineDebugEvent::Comment(time, message) => (time, MachineLogKind::Comment, message),
+    };
+    MachineLogEntry { time: time.with_timezone(&Utc), kind, message }
+}
+
+/*
+    Writing
+*/
+struct OpenLog {
+    date: NaiveDate,
+    part: u32,
+    size: u64,
+    file: BufWriter<File>,
+}
+struct LogWriter {
+    root: PathBuf,
+    limits: LogLimits,
+    current: Option<OpenLog>,
+}
+impl LogWriter {
+    async fn write(&mut self, entry: &MachineLogEntry) -> anyhow::Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        let date = entry.time.with_timezone(&Local).date_naive();
+        let needs_rotation = match &self.current {
+            Some(log) => log.date != date || log.size + line.len() as u64 > self.limits.max_file_size,
+            None => true,
+        };
+        if needs_rotation {
+            self.rotate(date, line.len() as u64).await?;
+        }
+        let log = self.current.as_mut().unwrap();
+        log.file.write_all(line.as_bytes()).await?;
+        log.file.flush().await?;
+        log.size += line.len() as u64;
+        Ok(())
+    }
+    async fn rotate(&mut self, date: NaiveDate, incoming: u64) -> anyhow::Result<()> {
+        if let Some(mut log) = self.current.take() {
+            log.file.flush().await?;
+        }
+        // Append to the latest part for the day, unless it has no room.
+        let mut part = list_log_files(&self.root).await?
+            .into_iter()
+            .filter(|((file_date, _), _)| *file_date == date)
+            .map(|((_, part), _)| part)
+            .max()
+            .unwrap_or(0);
+        let mut size;
+        loop {
+            let path = self.root.join(log_file_name(date, part));
+            size = match tokio::fs::metadata(&path).await {
+                Ok(metadata) => metadata.len(),
+                Err(_) => 0,
+            };
+            if size == 0 || size + incoming <= self.limits.max_file_size {
+                break;
+            }
+            part += 1;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(self.root.join(log_file_name(date, part))).await?;
+        self.current = Some(OpenLog { date, part, size, file: BufWriter::new(file) });
+        self.prune().await
+    }
+    async fn prune(&mut self) -> anyhow::Result<()> {
+        let files = list_log_files(&self.root).await?;
+        let mut total: u64 = files.iter().map(|(_, file)| file.size).sum();
+        let current = self.current.as_ref().map(|log| (log.date, log.part));
+        // Leave room for the current file to fill up before the next rotation.
+        let current_size = self.current.as_ref().map_or(0, |log| log.size);
+        let budget = self.limits.max_total_size.saturating_sub(self.limits.max_file_size - current_size.min(self.limits.max_file_size));
+        for (key, file) in files {
+            if total <= budget {
+                break;
+            }
+            if Some(key) == current {
+                continue;
+            }
+            remove_file(self.root.join(&file.name)).await?;
+            total -= file.size;
+        }
+        Ok(())
+    }
+}
+
+pub async fn write_machine_log(
+    mut events: history_broadcast::Receiver<MachineDebugEvent>,
+    root: PathBuf,
+    limits: LogLimits,
+) -> anyhow::Result<()> {
+    let mut writer = LogWriter { root, limits, current: None };
+    loop {
+        let entry = match events.recv().await {
+            Ok(event) => to_entry(event),
+            // Leave a note of the gap rather than failing; the rest of the log is still useful.
+            Err(ReceiverError::Lagged(count)) => to_entry(MachineDebugEvent::Warning(Local::now(), format!("Machine log missed {} events", count))),
+            Err(ReceiverError::Closed) => return Ok(()),
+        };
+        writer.write(&entry).await?;
+    }
+}
+
+/*
+    Endpoints
+*/
+async fn list_logs(root: LogRoot) -> ServerResult<Json<Vec<MachineLogFile>>> {
+    let mut files: Vec<MachineLogFile> = list_log_files(&root.0.0).await?.into_iter().map(|(_, file)| file).collect();
+    files.reverse();
+    Ok(Json(files))
+}
+async fn download_log(root: LogRoot, name: extract::Path<String>) -> ServerResult<Response> {
+    if parse_log_file_name(&name).is_none() {
+        return Err(ServerError::bad_request(format!("Not a log file: {:?}", *name)));
+    }
+    let file = File::open(root.0.0.join(&*name)).await?;
+    let body = BoxBody::new(StreamBody::new(ReaderStream::new(file)));
+    let response = Response::builder()
+        .header("Content-Type", "application/x-ndjson")
+        .header("Content-Disposition", format!("attachment; filename=\"{}\"", *name))
+        .body(body)?;
+    Ok(response)
+}
+fn matches_query(query: &QueryMachineLog, entry: &MachineLogEntry) -> bool {
+    query.start.is_none_or(|start| entry.time >= start)
+        && query.end.is_none_or(|end| entry.time < end)
+        && query.kinds.as_ref().is_none_or(|kinds| kinds.contains(&entry.kind))
+}
+// Files are named by local date; allow a day of slack either side so no file with matching entries is skipped.
+fn may_contain(query: &QueryMachineLog, date: NaiveDate) -> bool {
+    let local_date = |time: DateTime<Utc>| time.with_timezone(&Local).date_naive();
+    query.start.is_none_or(|start| date >= local_date(start - Duration::days(1)))
+        && query.end.is_none_or(|end| date <= local_date(end + Duration::days(1)))
+}
+// Every matching entry, oldest first; the limit is left to the caller.
+fn matching_entries<'a>(root: &'a Path, query: &'a QueryMachineLog) -> impl Stream<Item = anyhow::Result<MachineLogEntry>> + 'a {
+    try_stream! {
+        for ((date, _), file) in list_log_files(root).await? {
+            if !may_contain(query, date) {
+                continue;
+            }
+            let mut lines = BufReader::new(File::open(root.join(&file.name)).await?).lines();
+            while let Some(line) = lines.next_line().await? {
+                // A partially written final line is skipped rather than failing the whole query.
+                let Ok(entry) = serde_json::from_str::<MachineLogEntry>(&line) else { continue };
+                if matches_query(query, &entry) {
+                    yield entry;
+                }
+            }
+        }
+    }
+}
+// The oldest matching entries up to the limit, noting whether any more matched after them.
+async fn read_matching(root: &Path, query: &QueryMachineLog) -> anyhow::Result<MachineLogQueryResult> {
+    let limit = query.limit.unwrap_or(DEFAULT_QUERY_LIMIT);
+    let mut entries = Vec::new();
+    let matching = matching_entries(root, query);
+    pin_mut!(matching);
+    while let Some(entry) = matching.try_next().await? {
+        if entries.len() >= limit {
+            return Ok(MachineLogQueryResult { entries, truncated: true });
+        }
+        entries.push(entry);
+    }
+    Ok(MachineLogQueryResult { entries, truncated: false })
+}
+// Writes what the log holds from `start` until `end` as text, one entry per line, for keeping with a job.
+pub async fn save_slice(root: &Path, start: DateTime<Utc>, end: DateTime<Utc>, to: &Path) -> anyhow::Result<()> {
+    let query = QueryMachineLog { start: Some(start), end: Some(end), ..Default::default() };
+    let mut file = BufWriter::new(File::create(to).await?);
+    let matching = matching_entries(root, &query);
+    pin_mut!(matching);
+    while let Some(entry) = matching.try_next().await? {
+        file.write_all(format!("{} {:?} {}\n", entry.time.to_rfc3339(), entry.kind, entry.message).as_bytes()).await?;
+    }
+    file.flush().await?;
+    Ok(())
+}
+async fn query_logs(root: LogRoot, query: Json<QueryMachineLog>) -> ServerResult<Json<MachineLogQueryResult>> {
+    Ok(Json(read_matching(&root.0.0, &query).await?))
+}
+
+#[cfg(test)]
+mod test {
+    use tempdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn log_file_names_round_trip() {
+        let date = NaiveDate::from_ymd_opt(2023, 6, 1).unwrap();
+        assert_eq!(parse_log_file_name(&log_file_name(date, 0)), Some((date, 0)));
+        assert_eq!(parse_log_file_name(&log_file_name(date, 12)), Some((date, 12)));
+        assert_eq!(parse_log_file_name("2023-06-01.0.jsonl"), None);
+        assert_eq!(parse_log_file_name("../2023-06-01.jsonl"), None);
+        assert_eq!(parse_log_file_name("2023-06-01.json"), None);
+    }
+
+    #[test]
+    fn query_filters_by_time_and_kind() {
+        let time = Utc::now();
+        let entry = MachineLogEntry { time, kind: MachineLogKind::Warning, message: String::new() };
+        assert!(matches_query(&Default::default(), &entry));
+        assert!(matches_query(&QueryMachineLog { start: Some(time), end: Some(time + Duration::seconds(1)), ..Default::default() }, &entry));
+        assert!(!matches_query(&QueryMachineLog { end: Some(time), ..Default::default() }, &entry));
+        assert!(!matches_query(&QueryMachineLog { kinds: Some(vec![MachineLogKind::Sent]), ..Default::default() }, &entry));
+    }
+
+    #[tokio::test]
+    async fn writer_rotates_and_prunes_by_size() {
+        let directory = TempDir::new("machine_log").unwrap();
+        let sender = history_broadcast::Sender::new(64);
+        let receiver = sender.subscribe_with_history_count(0);
+        for index in 0..20 {
+            sender.send(MachineDebugEvent::Received(Local::now(), format!("line {:02}", index)));
+        }
+        drop(sender);
+        let limits = LogLimits { max_file_size: 300, max_total_size: 1000 };
+        write_machine_log(receiver, directory.path().to_path_buf(), limits).await.unwrap();
+        let files = list_log_files(directory.path()).await.unwrap();
+        assert!(files.len() > 1);
+        assert!(files.iter().all(|(_, file)| file.size <= limits.max_file_size));
+        assert!(files.iter().map(|(_, file)| file.size).sum::<u64>() <= limits.max_total_size);
+        // The newest entries survive pruning.
+        let (_, newest) = files.last().unwrap();
+        let contents = tokio::fs::read_to_string(directory.path().join(&newest.name)).await.unwrap();
+        assert!(contents.contains("line 19"));
+    }
+
+    #[tokio::test]
+    async fn query_truncates_at_limit() {
+        let directory = TempDir::new("machine_log").unwrap();
+        let sender = history_broadcast::Sender::new(64);
+        let receiver = sender.subscribe_with_history_count(0);
+        for index in 0..5 {
+            sender.send(MachineDebugEvent::Received(Local::now(), format!("line {}", index)));
+        }
+        drop(sender);
+        write_machine_log(receiver, directory.path().to_path_buf(), DEFAULT_LIMITS).await.unwrap();
+        let limited = read_matching(directory.path(), &QueryMachineLog { limit: Some(3), ..Default::default() }).await.unwrap();
+        assert!(limited.truncated);
+        assert_eq!(limited.entries.iter().map(|entry| entry.message.as_str()).collect::<Vec<_>>(), vec!["line 0", "line 1", "line 2"]);
+        let exact = read_matching(directory.path(), &QueryMachineLog { limit: Some(5), ..Default::default() }).await.unwrap();
+        assert!(!exact.truncated);
+        assert_eq!(exact.entries.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn slices_hold_only_their_time() {
+        let directory = TempDir::new("machine_log").unwrap();
+        let sender = history_broadcast::Sender::new(64);
+        let receiver = sender.subscribe_with_history_count(0);
+        let start = Local::now();
+        for index in 0..3 {
+            sender.send(MachineDebugEvent::Received(start + Duration::seconds(10 * index), format!("line {}", index)));
+        }
+        drop(sender);
+        write_machine_log(receiver, directory.path().to_path_buf(), DEFAULT_LIMITS).await.unwrap();
+        let slice = directory.path().join("machine.log");
+        let (from, to) = (start + Duration::seconds(5), start + Duration::seconds(15));
+        save_slice(directory.path(), from.with_timezone(&Utc), to.with_timezone(&Utc), &slice).await.unwrap();
+        let contents = tokio::fs::read_to_string(&slice).await.unwrap();
+        assert_eq!(contents.lines().count(), 1);
+        assert!(contents.ends_with(" Received line 1\n"));
+    }
+}
@@ -2,28 +2,40 @@
 
 use std::{sync::Mutex, convert::Infallible, thread, collections::HashMap, borrow::Borrow, path::{PathBuf, Path}, fs::FileType, env};
 
-use axum::{response::{sse::Event, Sse}, extract::{multipart::Field, self, DefaultBodyLimit}, handler::Handler, body::{StreamBody, BoxBody}, routing::MethodRouter};
-use cnc::{grbl::{messages::{GrblStateInfo}, standard_handler::{StandardHandler, ImmediateHandle, MachineDebugEvent, ImmediateMessage, JobHandle}, new_machine::run_machine_with_handler}, stream_job::sized_stream_to_job, gcode::{geometry::{as_lines_simple, as_lines_from_best_start}, AxisValues}};
-use futures::{Stream, Future, pin_mut};
+use axum::{response::{sse::Event, Sse}, extract::{self, DefaultBodyLimit}, handler::Handler, body::{StreamBody, BoxBody}, routing::MethodRouter};
+use cnc::{grbl::{standard_handler::{StandardHandler, ImmediateHandle, MachineDebugEvent, ImmediateMessage, JobHandle}, new_machine::run_machine_with_handler, session_recording::record_session}, stream_job::{sized_stream_to_job, JobResults}, gcode::{geometry::{as_lines_simple, as_lines_from_best_start}, preview::{toolpath_preview, decimate}, AxisValues}};
+use futures::Future;
 use hyper::{server, Body};
-use paths::lexically_normal_path;
-use serde::Serialize;
-use tempdir::TempDir;
-use tokio::{sync::{mpsc, broadcast, watch}, spawn, time::MissedTickBehavior, io::AsyncWriteExt, fs::{read_dir, remove_file, create_dir_all, remove_dir_all, rename}};
+use paths::{lexically_normal_path, library_path};
+use tokio::{sync::{mpsc, broadcast}, spawn, fs::{remove_file, create_dir_all, remove_dir_all}};
 use chrono::{offset::Local, Utc};
 use cnc::machine_writer::BufferCountingWriter;
+use machine_mock::simulated::SimulatedMachineConfig;
 mod cnc;
 mod paths;
 mod server_result;
 mod util;
 mod oneway_websocket;
 mod coordinates;
+mod machine_log;
+mod status_stream;
+mod machine_connection;
+mod gcode_library;
+mod gcode_metadata;
+mod gcode_upload;
+mod gcode_versions;
+mod command_history;
+mod controller_settings;
+mod probing;
+mod job_history;
+mod job_artifacts;
 use oneway_websocket::send_stream;
+use status_stream::{status_stream_task, StatusPollRates, StatusStreamInfo};
 use tokio::runtime::{Runtime, Builder};
 use tokio_util::io::{StreamReader, ReaderStream};
 use tower_http::{catch_panic::CatchPanicLayer, trace::TraceLayer};
-use util::{history_broadcast, format_bytes::format_byte_string, force_output_type};
-use common::api;
+use util::{history_broadcast, format_bytes::format_byte_string};
+use common::{api::{self, LastRun}, grbl::AlarmRecovery};
 use clap::Parser;
 use anyhow::{anyhow, Context};
 use server_result::{ServerResult, ServerError};
@@ -32,14 +44,27 @@ use server_result::{ServerResult, ServerError};
 #[command(author = "Milo Brandt", version = "0.1.0", about = "Run a server connected to the given port.", long_about = None)]
 struct Args {
     /// Name of the person to greet
-    #[arg(short, long)]
-    port: String,
+    #[arg(short, long, required_unless_present = "simulated")]
+    port: Option<String>,
+    /// Run against an in-process simulated machine instead of a serial port
+    #[arg(long, conflicts_with = "port")]
+    simulated: bool,
     #[arg(short, long)]
     data_folder: String,
+    /// Record everything sent to and received from the machine to this file, for later replay
+    #[arg(long)]
+    record_session: Option<PathBuf>,
+    /// How often to poll the machine's status while it's moving, in milliseconds
+    #[arg(long, default_value_t = 50)]
+    status_poll_active_ms: u64,
+    /// How often to poll the machine's status while it's still, in milliseconds
+    #[arg(long, default_value_t = 500)]
+    status_poll_idle_ms: u64,
 }
 
 pub struct Config {
-    data_folder: PathBuf
+    data_folder: PathBuf,
+    status_poll: StatusPollRates,
 }
 
 impl Config {
@@ -47,7 +72,7 @@ impl Config {
         self.data_folder.join("gcode")
     }
     pub fn gcode_path(&self, path: impl AsRef<Path>) -> anyhow::Result<PathBuf> {
-        match lexically_normal_path(path.as_ref()) {
+        match library_path(path.as_ref()) {
             None => Err(anyhow!("Invalid path! {:?}", path.as_ref())),
             Some(path) => {
                 let mut result = self.gcode_root();
@@ -73,15 +98,25 @@ impl Config {
         }
     }
 }
+#[cfg(test)]
+impl Config {
+    // Keeps everything in the given folder, as with --data-folder.
+    pub fn in_folder(data_folder: &Path) -> Self {
+        Config {
+            data_folder: data_folder.to_path_buf(),
+            status_poll: StatusPollRates { active: Duration::from_millis(50), idle: Duration::from_millis(500) },
+        }
+    }
+}
 
 
-use crate::cnc::grbl::handler::SpeedOverride;
+use crate::cnc::grbl::{handler::SpeedOverride, realtime::RealtimeCommand};
 use {
     async_stream::stream,
     axum::{
         extract::{
             ws::{Message, WebSocket, WebSocketUpgrade},
-            Json, RawBody, Multipart
+            Json, RawBody
         },
         response::Response,
         routing::{get, post, delete},
@@ -95,92 +130,25 @@ use {
             },
             GCodeFormatSpecification,
         },
-        grbl::new_machine::{
-            ImmediateRequest, WriteRequest,
-        },
+        grbl::new_machine::{ImmediateRequest, WriteRequest},
     },
     futures::{
         sink::SinkExt,
-        stream::{SplitStream, StreamExt},
+        stream::SplitStream,
     },
     itertools::Itertools,
     serde::Deserialize,
     std::{str::from_utf8_unchecked, sync::Arc, time::Duration},
     tokio::{
         fs::{File, OpenOptions},
-        io::{AsyncBufReadExt, BufReader},
+        io::{AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader},
         join, select,
         sync::oneshot,
-        time::{sleep, interval},
+        time::sleep,
     },
     tower_http::cors::{Any, CorsLayer},
 };
 
-fn make_status_stream(machine: Arc<ImmediateHandle>) -> impl Stream<Item=GrblStateInfo> {
-    stream! {
-        let mut interval = interval(Duration::from_millis(250));
-        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
-        loop {
-            interval.tick().await;
-            yield machine.get_state().await;
-        }
-    }
-}
-
-struct StatusStreamInfo {
-    subscriber_sender: mpsc::Sender<oneshot::Sender<watch::Receiver<GrblStateInfo>>>
-}
-impl StatusStreamInfo {
-    async fn subscribe(&self) -> watch::Receiver<GrblStateInfo> {
-        let (sender, receiver) = oneshot::channel();
-        self.subscriber_sender.send(sender).await.unwrap();
-        receiver.await.unwrap()
-    }
-}
-async fn status_stream_task(machine: Arc<ImmediateHandle>) -> StatusStreamInfo {
-    let (sender, _) = watch::channel(machine.get_state().await);
-    let (subscriber_sender, mut subscriber_receiver) = mpsc::channel::<oneshot::Sender<watch::Receiver<GrblStateInfo>>>(16);
-    spawn(async move {
-        let mut subscribers_closed = false;
-        while !subscribers_closed {
-            match subscriber_receiver.recv().await {
-                Some(subscriber) => {
-                    drop(subscriber.send(sender.subscribe()));
-                }
-                None => return
-            }
-            let stream = make_status_stream(machine.clone());
-            pin_mut!(stream);
-            let mut next_stream = stream.next();
-            loop {
-                select! {
-                    new_subscriber = subscriber_receiver.recv(), if !subscribers_closed => {
-                        match new_subscriber {
-                            Some(new_subscriber) => drop(new_subscriber.send(sender.subscribe())),
-                            None => {
-                                subscribers_closed = true;
-                            }
-                        }
-                    },
-                    new_value = &mut next_stream => {
-                        next_stream = stream.next();
-                        match new_value {
-                            Some(new_value) => match sender.send(new_value) {
-                                Ok(()) => {}
-                                Err(_) => break // No listeners - stop computation...
-                            },
-                            None => return // Stream is done???
-                        }
-                    }
-                }
-            }
-        }
-    });
-    StatusStreamInfo {
-        subscriber_sender
-    }
-}
-
 fn immediate_command<'a, F, Fut>(action: F) -> MethodRouter<(), Body, Infallible>
 where
     F: Fn(Arc<ImmediateHandle>) -> Fut + Clone + Send + 'static,
@@ -195,6 +163,19 @@ where
     })
 }
 
+fn realtime_command(command: RealtimeCommand) -> MethodRouter<(), Body, Infallible> {
+    post(move |machine: Extension<Arc<ImmediateHandle>>| async move {
+        machine.send_realtime(command).await.map_err(|error| ServerError::bad_request(error.to_string()))?;
+        ServerResult::Ok("Ok!".to_string())
+    })
+}
+
+async fn run_macro(machine: Extension<Arc<ImmediateHandle>>, extract::Path(index): extract::Path<u8>) -> ServerResult<String> {
+    let command = RealtimeCommand::macro_index(index).ok_or_else(|| ServerError::bad_request(format!("No macro {}; expected 0 to 3.", index)))?;
+    machine.send_realtime(command).await.map_err(|error| ServerError::bad_request(error.to_string()))?;
+    Ok("Ok!".to_string())
+}
+
 struct CoordinateOffsets {
     offsets: Mutex<HashMap<String, Vec<f64>>>
 }
@@ -221,28 +202,34 @@ async fn run_server(machine: ImmediateHandle, debug_rx: history_broadcast::Recei
     let machine_arc= Arc::new(machine);
     let app = Router::new()
         .route(api::RUN_GCODE_FILE, post(run_gcode_file))
-        .route(api::UPLOAD_GCODE_FILE, post(upload))
+        .route(api::UPLOAD_GCODE_FILE, post(gcode_upload::upload))
         .route(api::CREATE_GCODE_DIRECTORY, post(create_directory))
-        .route(api::DELETE_GCODE_FILE, delete(delete_file))
+        .route(api::DELETE_GCODE_FILE, delete(gcode_library::delete_file))
+        .route(api::MOVE_GCODE_FILE, post(gcode_library::move_file))
+        .route(api::COPY_GCODE_FILE, post(gcode_library::copy_file))
         .route(api::LIST_GCODE_FILES, post(get_gcode_list))
         .route(api::EXAMINE_LINES_IN_GCODE_FILE, post(get_gcode_file_positions))
         .route(&format!("{}/*path", api::DOWNLOAD_GCODE), get(download_gcode_file))
+        .route(&format!("{}/*path", api::LIST_GCODE_VERSIONS), get(gcode_versions::list_versions))
+        .route(&format!("{}/:hash/*path", api::DOWNLOAD_GCODE_VERSION), get(gcode_versions::download_version))
+        .route(api::DIFF_GCODE_VERSIONS, post(gcode_versions::diff_versions))
+        .route(api::RESTORE_GCODE_VERSION, post(gcode_versions::restore_version))
         .route("/job/list/*path", get(get_gcode_list_better))
         .route("/job/list/", get(get_gcode_list_better))
         .route("/job/examine/*path", get(get_gcode_file_positions_better))
+        .route(api::PREVIEW_GCODE_FILE, post(preview_gcode_file))
 
-        .route(&format!("{}/*path", api::DOWNLOAD_RESULTS), get(download_job_results))
-        .route(api::LIST_RESULTS, get(get_results_list))
 
         .route(api::SEND_RAW_GCODE, post(run_gcode_unchecked))
         .route(api::LISTEN_TO_RAW_MACHINE, get(listen_raw))
         .route(api::LISTEN_TO_JOB_STATUS, get(listen_status))
-        .route(api::LISTEN_TO_MACHINE_STATUS, get(listen_position))
+        .route(api::MACHINE_CONNECTION, get(machine_connection::machine_connection))
         
         .route(api::COMMAND_PAUSE, (immediate_command(|handle| async move { handle.pause().await; })))
         .route(api::COMMAND_RESUME, (immediate_command(|handle| async move { handle.resume().await; })))
         .route(api::COMMAND_STOP, (immediate_command(|handle| async move { handle.stop().await; })))
         .route(api::COMMAND_RESET, (immediate_command(|handle| async move { handle.reset().await; })))
+        .route(api::COMMAND_RECOVER_FROM_ALARM, post(recover_from_alarm))
 
         .route(api::FEED_OVERRIDE.reset, (immediate_command(|handle| async move { handle.override_speed(SpeedOverride::FeedReset).await; })))
         .route(api::FEED_OVERRIDE.plus_10, (immediate_command(|handle| async move { handle.override_speed(SpeedOverride::FeedIncrease10).await; })))
@@ -260,11 +247,25 @@ async fn run_server(machine: ImmediateHandle, debug_rx: history_broadcast::Recei
         .route(api::RAPID_OVERRIDE.half, (immediate_command(|handle| async move { handle.override_speed(SpeedOverride::RapidHalf).await; })))
         .route(api::RAPID_OVERRIDE.quarter, (immediate_command(|handle| async move { handle.override_speed(SpeedOverride::RapidQuarter).await; })))
 
+        .route(api::COMMAND_TOGGLE_SPINDLE_STOP, realtime_command(RealtimeCommand::ToggleSpindleStop))
+        .route(api::COMMAND_TOGGLE_FLOOD_COOLANT, realtime_command(RealtimeCommand::ToggleFloodCoolant))
+        .route(api::COMMAND_TOGGLE_MIST_COOLANT, realtime_command(RealtimeCommand::ToggleMistCoolant))
+        .route(api::COMMAND_SAFETY_DOOR, realtime_command(RealtimeCommand::SafetyDoor))
+        .route(api::COMMAND_JOG_CANCEL, realtime_command(RealtimeCommand::JogCancel))
+        .route(&format!("{}/:index", api::COMMAND_MACRO), post(run_macro))
+
         .route("/command/home", (immediate_command(|handle| async move { handle.override_speed(SpeedOverride::RapidQuarter).await; })))
 
         .route(api::SHUTDOWN, post(shutdown))
 
         .nest("/coords", coordinates::get_service(&config).await.unwrap())
+        .nest(api::LIST_TRASH, gcode_library::get_trash_service())
+        .nest(api::LIST_MACHINE_LOGS, machine_log::get_service(&config, &debug_rx).await.unwrap())
+        .nest(api::COMMAND_HISTORY, command_history::get_service(&config).await.unwrap())
+        .nest(api::CONTROLLER_SETTINGS, controller_settings::get_service(&config).await.unwrap())
+        .nest("/probe", probing::get_service())
+        .nest(api::JOB_HISTORY, job_history::get_service())
+        .nest(api::LIST_RESULTS, job_artifacts::get_service())
 
         .layer(TraceLayer::new_for_http())
         .layer(CatchPanicLayer::new())
@@ -272,8 +273,9 @@ async fn run_server(machine: ImmediateHandle, debug_rx: history_broadcast::Recei
         .layer(DefaultBodyLimit::max(10_000_000))
         .layer(Extension(machine_arc.clone()))
         .layer(Extension(Arc::new(debug_rx)))
-        .layer(Extension(Arc::new(status_stream_task(machine_arc).await)))
+        .layer(Extension(Arc::new(status_stream_task(machine_arc, config.status_poll).await)))
         .layer(Extension(Arc::new(CoordinateOffsets::new())))
+        .layer(controller_settings::load_last_read(&config).await.unwrap())
         .layer(Extension(Arc::new(config)));
 
     // run it with hyper on localhost:3000
@@ -286,6 +288,22 @@ async fn run_server(machine: ImmediateHandle, debug_rx: history_broadcast::Recei
 
 
 
+fn spawn_machine_thread<R, W>(handler: StandardHandler, reader: R, writer: W)
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    thread::spawn(move || { // put the machine on a dedicated thread that loves to look at IO
+        let machine_runtime = Builder::new_current_thread().enable_all().event_interval(1).build().unwrap();
+        let routine = run_machine_with_handler(
+            handler,
+            BufferCountingWriter::new(writer, 112),
+            BufReader::new(reader)
+        );
+        machine_runtime.block_on(routine);
+    });
+}
+
 fn main() {
     let args = Args::parse();
     println!("Starting CNC server with configuration: {:?}", args);
@@ -294,41 +312,50 @@ fn main() {
     }));
     tracing_subscriber::fmt::init();
     let server_runtime = Builder::new_multi_thread().worker_threads(3).enable_all().build().unwrap();
-    println!("Opening port...");
-    let (reader, writer) = server_runtime.block_on(cnc::connection::open_and_reset_arduino_like_serial(&args.port));
     let handler_parts = StandardHandler::create(default_settings());
     let handler = handler_parts.handler;
+    if let Some(path) = args.record_session.clone() {
+        let events = handler_parts.debug_rx.clone();
+        server_runtime.spawn(async move {
+            if let Err(error) = record_session(events, &path).await {
+                println!("Session recording to {:?} stopped: {:?}", path, error);
+            }
+        });
+    }
     println!("Starting threads for machine and web communication...");
-    thread::spawn(move || { // put the machine on a dedicated thread that loves to look at IO
-        let machine_runtime = Builder::new_current_thread().enable_all().event_interval(1).build().unwrap();
-        let routine = run_machine_with_handler(
-            handler,
-            BufferCountingWriter::new(writer, 112),
-            BufReader::new(reader)
-        );
-        machine_runtime.block_on(routine);
-    });
+    match &args.port {
+        Some(port) => {
+            println!("Opening port...");
+            let (reader, writer) = server_runtime.block_on(cnc::connection::open_and_reset_arduino_like_serial(port));
+            spawn_machine_thread(handler, reader, writer);
+        }
+        None => {
+            println!("Starting simulated machine...");
+            let (reader, writer) = server_runtime.block_on(async {
+                cnc::connection::open_simulated_machine(simulated_machine_config())
+            });
+            spawn_machine_thread(handler, reader, writer);
+        }
+    }
     server_runtime.block_on(run_server(
         handler_parts.immediate_handle,
         handler_parts.debug_rx,
-        Config{ data_folder: PathBuf::from(args.data_folder) }
+        Config{
+            data_folder: PathBuf::from(args.data_folder),
+            status_poll: StatusPollRates {
+                active: Duration::from_millis(args.status_poll_active_ms),
+                idle: Duration::from_millis(args.status_poll_idle_ms),
+            },
+        }
     )
     );
 }
 
-//TODO: Put this somewhere it can be serialized and deserialized in common between front and back ends!
-async fn listen_position(ws: WebSocketUpgrade, status_stream: Extension<Arc<StatusStreamInfo>>) -> Response {
-    let mut receiver = status_stream.subscribe().await;
-    send_stream(ws, stream! {
-        loop {
-            let data = {
-                let info = receiver.borrow().clone();
-                serde_json::to_string(&info.to_full_info()).unwrap()
-            };
-            yield Message::Text(data);
-            drop(receiver.changed().await);
-        }
-    })
+async fn recover_from_alarm(machine: Extension<Arc<ImmediateHandle>>, recovery: Json<AlarmRecovery>) -> ServerResult<String> {
+    match machine_connection::recover_from_alarm(&machine, recovery.0).await {
+        Ok(()) => Ok("Ok!".to_string()),
+        Err(error) => Err(ServerError::bad_request(error)),
+    }
 }
 
 async fn listen_raw(ws: WebSocketUpgrade, debug_receiver: Extension<Arc<history_broadcast::Receiver<MachineDebugEvent>>>) -> Response {
@@ -354,16 +381,26 @@ fn default_settings() -> GCodeFormatSpecification {
         float_digits: 3,
     }
 }
+fn simulated_machine_config() -> SimulatedMachineConfig {
+    // Travel limits only cover XYZ; the A axis is left unlimited.
+    SimulatedMachineConfig {
+        axis_letters: default_settings().axis_letters,
+        ..Default::default()
+    }
+}
 async fn run_gcode_unchecked(
     // Runs the line *if* no job is scheduled yet.
     machine: Extension<Arc<ImmediateHandle>>,
     message: RawBody,
 ) -> ServerResult<String> {
-    let mut body_bytes = hyper::body::to_bytes(message.0).await.unwrap().to_vec();
-    body_bytes.push(b'\n');
+    let body_bytes = hyper::body::to_bytes(message.0).await.unwrap().to_vec();
+    send_raw_line(&machine, body_bytes).await
+}
+pub(crate) async fn send_raw_line(machine: &ImmediateHandle, mut line: Vec<u8>) -> ServerResult<String> {
+    line.push(b'\n');
     let result = machine.try_send_job(move |handle: JobHandle| async move {
         unsafe { // Really is unsafe!
-            drop(handle.send_gcode_raw(body_bytes).await.unwrap());
+            drop(handle.send_gcode_raw(line).await.unwrap());
         }
     }).await;
     match result {
@@ -371,6 +408,22 @@ async fn run_gcode_unchecked(
         Err(_) => Err(anyhow!("Job not sent!").into()),
     }
 }
+// Runs f as a job and waits for what it gives back; None if the job ended before f could finish.
+pub(crate) async fn run_job_for_result<T, F, Fut>(machine: &ImmediateHandle, f: F) -> ServerResult<Option<T>>
+where
+    T: Send + 'static,
+    F: FnOnce(JobHandle) -> Fut + Send + 'static,
+    Fut: Future<Output=T> + Send + 'static,
+{
+    let (result_tx, result_rx) = oneshot::channel();
+    let sent = machine.try_send_job(move |handle: JobHandle| async move {
+        drop(result_tx.send(f(handle).await));
+    }).await;
+    if sent.is_err() {
+        return Err(ServerError::bad_request("the machine is busy with a job".to_string()));
+    }
+    Ok(result_rx.await.ok())
+}
 
 
 fn axis_value_to_array(v: &AxisValues) -> [f32; 3] {
@@ -478,10 +531,37 @@ async fn get_gcode_file_positions_better(
     Ok(Json(lines.iter().map(axis_value_to_array).collect()))
 } 
 
+// Enough to look right in the viewer without making the browser struggle.
+const DEFAULT_PREVIEW_POINTS: usize = 200_000;
+
+async fn preview_gcode_file(
+    config: Extension<Arc<Config>>,
+    message: Json<api::PreviewGcodeFile>,
+) -> ServerResult<([(hyper::header::HeaderName, &'static str); 1], Vec<u8>)> {
+    let contents = tokio::fs::read(config.gcode_path(&message.path)?).await?;
+    let max_points = message.max_points.unwrap_or(DEFAULT_PREVIEW_POINTS);
+    let preview = tokio::task::spawn_blocking(move || {
+        let spec = default_settings();
+        let mut program = Vec::new();
+        for (index, line) in String::from_utf8_lossy(&contents).lines().enumerate() {
+            match parse_generalized_line(&spec, line) {
+                Ok(GeneralizedLine::Line(line)) => program.push((index as u32 + 1, line)),
+                Ok(_) => {}
+                Err(e) => return Err(anyhow!("Error on line {}: {}", index + 1, e.description)),
+            }
+        }
+        let preview = toolpath_preview(&spec, &program).map_err(|e| anyhow!("Error! {:?}", e))?;
+        Ok(decimate(preview, max_points))
+    }).await??;
+    Ok(([(hyper::header::CONTENT_TYPE, "application/octet-stream")], preview.to_bytes()))
+}
+
 
 async fn run_gcode_file(
     machine: Extension<Arc<ImmediateHandle>>,
+    status_stream: Extension<Arc<StatusStreamInfo>>,
     config: Extension<Arc<Config>>,
+    last_read: controller_settings::LastReadInfo,
     message: Json<api::RunGcodeFile>,
 ) -> ServerResult<String> {
     let path = config.gcode_path(&message.path)?;
@@ -519,7 +599,24 @@ async fn run_gcode_file(
             ).into());
         }
     }
-    let (results_tx, mut results_rx) = mpsc::channel(128);
+    let version = gcode_versions::record_current(&path).await?;
+    let (probes_tx, probes_rx) = mpsc::channel(128);
+    let (errors_tx, errors_rx) = mpsc::channel(128);
+    let (finished_tx, finished_rx) = oneshot::channel();
+    let start_time = Utc::now();
+    // Recorded before the job is sent, so a job that runs always has a record.
+    let dirname = config.new_job_path();
+    let file_path = config.gcode_path(&message.path)?;
+    let rapid_rates = last_read.read().await.rapid_rates();
+    let source = api::JobSource { path: message.path.clone(), version: version.clone() };
+    create_dir_all(&dirname).await?;
+    let record = job_history::start_record(&dirname, source, message.error_policy, start_time).await?;
+    if let Err(error) = job_artifacts::save_settings_snapshot(&dirname, &last_read).await {
+        println!("Couldn't save the settings snapshot with job {:?}: {:?}", dirname, error);
+    }
+    // Subscribed before the job starts, so nothing it does goes unseen.
+    let status_rx = status_stream.subscribe();
+    let alarm_rx = machine.subscribe_alarm().await;
     let result = machine.try_send_job(
         sized_stream_to_job(
             stream! {
@@ -545,30 +642,69 @@ async fn run_gcode_file(
                     }
                 }
             },
+            Some(message.path.clone()),
             line_count,
-            results_tx,
+            message.error_policy,
+            JobResults { probes: probes_tx, errors: errors_tx, finished: finished_tx },
         )
     ).await;
-    let dirname = config.new_job_path();
-    spawn(force_output_type::<anyhow::Result<()>>(async move {
-        let mut result = Vec::new();
-        while let Some(v) = results_rx.recv().await {
-            result.push(v);
+    // Nothing ran, so there's nothing to keep.
+    let record = if result.is_ok() {
+        Some(record)
+    } else {
+        if let Err(error) = remove_dir_all(&dirname).await {
+            println!("Couldn't remove the results of unsent job {:?}: {:?}", dirname, error);
         }
-        if !result.is_empty() {
-            let filename = dirname.join("probes.json");
-            create_dir_all(dirname).await?;
-            let mut file = File::create(filename).await?;
-            file.write_all(serde_json::to_string(&result)?.as_bytes()).await?;
+        None
+    };
+    let config = config.0.clone();
+    spawn(async move {
+        let (probes, errors, observation) = join!(
+            collect_job_results(probes_rx),
+            collect_job_results(errors_rx),
+            job_history::observe_job(status_rx, alarm_rx, finished_rx),
+        );
+        if let Some(record) = record {
+            if let Err(error) = job_artifacts::save_probe_results(&dirname, &probes).await {
+                println!("Couldn't save the probe results of job {:?}: {:?}", dirname, error);
+            }
+            if let Err(error) = job_artifacts::save_machine_log(&dirname, &config, start_time, Utc::now()).await {
+                println!("Couldn't save the machine log of job {:?}: {:?}", dirname, error);
+            }
+            let error_count = errors.len();
+            let record = match job_history::finish_record(&dirname, record, observation, errors).await {
+                Ok(record) => record,
+                Err(error) => {
+                    println!("Couldn't finish the record of job {:?}: {:?}", dirname, error);
+                    return;
+                }
+            };
+            let last_run = LastRun {
+                time: start_time,
+                completed: record.outcome == api::JobOutcome::Completed,
+                error_count,
+                version: Some(version),
+            };
+            if let Err(error) = gcode_metadata::record_last_run(&file_path, last_run, rapid_rates).await {
+                println!("Couldn't record the run of {:?}: {:?}", file_path, error);
+            }
         }
-        Ok(())
-    }));
+    });
     match result {
         Ok(()) => Ok("Job sent!".to_string()),
         Err(_) => Err(anyhow!("Job not sent!").into()),
     }
 }
 
+// Everything the job reports, once it has ended.
+async fn collect_job_results<T>(mut results_rx: mpsc::Receiver<T>) -> Vec<T> {
+    let mut result = Vec::new();
+    while let Some(v) = results_rx.recv().await {
+        result.push(v);
+    }
+    result
+}
+
 async fn listen_status(ws: WebSocketUpgrade, machine: Extension<Arc<ImmediateHandle>>) -> Response {
     let mut debug_receiver = machine.subscribe_job_status().await;
     send_stream(ws, stream! {
@@ -584,94 +720,18 @@ async fn listen_status(ws: WebSocketUpgrade, machine: Extension<Arc<ImmediateHan
 }
 
 
-async fn dump_field_to_file(mut file: File, mut field: Field<'_>) -> anyhow::Result<()> {
-    while let Some(bytes) = field.chunk().await? {
-        file.write_all(&bytes).await?
-    }
-    Ok(())
-}
-
-// Limits file size to 128 MiB.
-async fn upload(config: Extension<Arc<Config>>, mut multipart: Multipart) -> ServerResult<String> {
-    let mut file_name = None::<PathBuf>;
-    let tmp_dir = TempDir::new("file_download")?;
-    let tmp_path = tmp_dir.path().join("file");
-    let mut has_file = false;
-    while let Some(field) = multipart.next_field().await.unwrap() {
-        let name = field.name().unwrap().to_string();
-        if name == "file" {
-            if(has_file) {
-                return Err(anyhow!("Multiple files given!").into());
-            }
-            has_file = true;
-            dump_field_to_file(
-                File::create(tmp_path.clone()).await?,
-                field
-            ).await?;
-        } else if name == "filename" {
-            let presumptive_name = field.text().await?;
-            file_name = Some(
-                config.gcode_path(&presumptive_name)?
-            );
-        }
-    }
-    if !has_file {
-        return Err(anyhow!("No file given!").into());
-    }
-    let file_name = file_name.ok_or_else(|| anyhow!("No filename given!"))?;
-    // This is not quite right... but it's probably fine
-    let directory = file_name.parent().ok_or(anyhow!("Cannot specify top level as filename!"))?;
-    create_dir_all(directory).await?;
-    rename(tmp_path, file_name).await?;
-    Ok("Uploaded!".into())
-}
 async fn create_directory(config: Extension<Arc<Config>>, info: Json<api::CreateGcodeDirectory>) -> ServerResult<String> {
     create_dir_all(config.gcode_path(&info.directory)?).await?;
     Ok("Ok".to_string())
 }
-async fn delete_file(config: Extension<Arc<Config>>, info: Json<api::DeleteGcodeFile>) -> ServerResult<String> {
-    let old_path = config.gcode_path(&info.path)?;
-    if old_path == PathBuf::new() {
-        return Err(ServerError::bad_request("cannot delete root directory".to_string()));
-    }
-    let new_folder = config.data_folder.join("deleted_gcode").join(Utc::now().to_string());
-    create_dir_all(&new_folder).await?;
-    let new_path = new_folder.join(old_path.file_name().unwrap_or(std::ffi::OsStr::new("Unknown")));
-    println!("MOVING {:?} to {:?}", old_path, new_path);
-    rename(old_path, new_path).await?;
-    /*
-    if(info.is_directory) {
-        remove_dir_all(config.gcode_path(&info.path)?).await?;
-    } else {
-        remove_file(config.gcode_path(&info.path)?).await?;
-    }
-    */
-    Ok("Ok".to_string())
-}
-
-async fn get_gcode_list(config: Extension<Arc<Config>>, info: Json<api::ListGcodeFiles>) -> ServerResult<Json<Vec<api::GcodeFile>>> {
-    let mut entries = read_dir(config.gcode_path(&info.prefix)?).await?;
-    let mut values = Vec::new();
-    while let Some(entry) = entries.next_entry().await.unwrap() {
-        values.push(api::GcodeFile {
-            name: entry.file_name().into_string().unwrap(),
-            is_file: entry.file_type().await?.is_file(),
-        });
-    }
-    values.sort_by(|a, b| a.name.cmp(&b.name));
-    Ok(Json(values))
+async fn get_gcode_list(config: Extension<Arc<Config>>, last_read: controller_settings::LastReadInfo, info: Json<api::ListGcodeFiles>) -> ServerResult<Json<Vec<api::GcodeFile>>> {
+    let rapid_rates = last_read.read().await.rapid_rates();
+    Ok(Json(gcode_metadata::list_directory(&config.gcode_path(&info.prefix)?, rapid_rates).await?))
 }
-async fn get_gcode_list_better(path: Option<extract::Path<String>>, config: Extension<Arc<Config>>) -> ServerResult<Json<Vec<api::GcodeFile>>> {
-    let mut entries = read_dir(config.gcode_path(path.as_ref().map_or("", |path| path.as_str()))?).await?;
-    let mut values = Vec::new();
-    while let Some(entry) = entries.next_entry().await.unwrap() {
-        values.push(api::GcodeFile {
-            name: entry.file_name().into_string().unwrap(),
-            is_file: entry.file_type().await?.is_file(),
-        });
-    }
-    values.sort_by(|a, b| a.name.cmp(&b.name));
-    Ok(Json(values))
+async fn get_gcode_list_better(path: Option<extract::Path<String>>, config: Extension<Arc<Config>>, last_read: controller_settings::LastReadInfo) -> ServerResult<Json<Vec<api::GcodeFile>>> {
+    let directory = config.gcode_path(path.as_ref().map_or("", |path| path.as_str()))?;
+    let rapid_rates = last_read.read().await.rapid_rates();
+    Ok(Json(gcode_metadata::list_directory(&directory, rapid_rates).await?))
 }
 
 async fn shutdown() -> String {
@@ -685,27 +745,3 @@ async fn shutdown() -> String {
     }
 }
 
-async fn download_job_results(
-    path: extract::Path<String>,
-    config: Extension<Arc<Config>>,
-) -> ServerResult<Response> {
-    let path = config.job_path(&*path)?.join("probes.json");
-    let file = File::open(path).await?;
-    let body = BoxBody::new(StreamBody::new(ReaderStream::new(file)));
-    /* */
-    let response = Response::builder()
-        .header("Content-Type", "text/plain;")
-        .header("Content-Disposition", "inline;")
-        .body(body)?;
-    Ok(response)
-}
-
-async fn get_results_list(config: Extension<Arc<Config>>) -> ServerResult<Json<Vec<String>>> {
-    let mut entries = read_dir(config.jobs_root()).await?;
-    let mut values = Vec::new();
-    while let Some(entry) = entries.next_entry().await.unwrap() {
-        values.push(entry.file_name().into_string().map_err(|_| anyhow!("Failed to unwrap file name"))?);
-    }
-    values.sort_by(|a, b| b.cmp(&a));
-    Ok(Json(values))
-}
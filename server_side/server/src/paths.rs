@@ -1,5 +1,10 @@
 use std::path::{Path, PathBuf};
 
+use crate::{gcode_library::TRASH_INFO, gcode_metadata::CACHE_DIRECTORY, gcode_versions::HISTORY_DIRECTORY};
+
+// Names the G-code library keeps for its own files: cached analysis, version history and trash notes.
+const RESERVED_LIBRARY_NAMES: [&str; 3] = [CACHE_DIRECTORY, HISTORY_DIRECTORY, TRASH_INFO];
+
 pub fn lexically_normal_path(input: &Path) -> Option<PathBuf> {
     let mut result = PathBuf::new();
     for component in input.components() {
@@ -21,6 +26,14 @@ pub fn lexically_normal_path(input: &Path) -> Option<PathBuf> {
     Some(result)
 }
 
+// Like lexically_normal_path, but refuses paths that reach into the library's own files, so they can't
+// be uploaded over, moved or deleted like G-code.
+pub fn library_path(input: &Path) -> Option<PathBuf> {
+    let normal = lexically_normal_path(input)?;
+    let is_reserved = normal.components().any(|component| RESERVED_LIBRARY_NAMES.iter().any(|name| component.as_os_str() == *name));
+    (!is_reserved).then_some(normal)
+}
+
 #[cfg(test)]
 mod test {
     use std::assert_eq;
@@ -82,6 +95,15 @@ mod test {
         );
     }
 
+    #[test]
+    fn library_paths_leave_out_the_librarys_own_files() {
+        assert_eq!(library_path(Path::new("a/b.nc")), Some(PathBuf::from("a/b.nc")));
+        assert_eq!(library_path(Path::new("a/.versions/b.nc/history.json")), None);
+        assert_eq!(library_path(Path::new(".analysis")), None);
+        assert_eq!(library_path(Path::new("a/./.trash_info.json")), None);
+        assert_eq!(library_path(Path::new("a/.versions/../b.nc")), Some(PathBuf::from("a/b.nc")));
+    }
+
     #[test]
     fn rejects_paths_that_leave_directory() {
         assert_eq!(
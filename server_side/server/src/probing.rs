@@ -0,0 +1,243 @@
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use axum::{Router, Extension, Json, routing::post};
+use common::api::{ProbeAxis, ProbeRoutine, ProbeRoutineResult, RunProbeRoutine, Vec3};
+
+use crate::{cnc::{gcode::{AxisValues, CoordinateMode, GCodeCommand, GCodeLine, GCodeModal, MoveMode, ProbeDirection, ProbeRequirement}, grbl::{new_machine::{LineError, ProbeError}, standard_handler::{ImmediateHandle, JobHandle}}}, run_job_for_result, server_result::{ServerError, ServerResult}};
+
+/*
+    Guided probing. Every search is a fast G38.2 pass to find the surface followed by a slow one to measure
+it, backing off by the retract distance after each. Searches are incremental (G91) and every other move
+is in machine coordinates (G53), so the work offsets in effect don't matter; G90 is restored at the end.
+*/
+
+pub fn get_service() -> Router {
+    Router::new()
+        .route("/run", post(run_probe_routine))
+}
+
+const X: usize = 0;
+const Y: usize = 1;
+const Z: usize = 2;
+
+fn axis_index(axis: ProbeAxis) -> usize {
+    match axis {
+        ProbeAxis::X => X,
+        ProbeAxis::Y => Y,
+    }
+}
+
+struct Prober<'a> {
+    handle: &'a JobHandle,
+    request: &'a RunProbeRoutine,
+    contacts: Vec<Vec3>,
+}
+impl<'a> Prober<'a> {
+    async fn send(&self, line: GCodeLine) -> Result<(), String> {
+        let result = self.handle.send_gcode(line).await.map_err(|_| "the job ended early".to_string())?.await;
+        result.map_err(|error| match error {
+            LineError::Grbl(code) => format!("the controller rejected a move: error:{}", code),
+            LineError::Reset => "the controller was reset".to_string(),
+        })
+    }
+    async fn move_to(&self, axis: usize, position: f64) -> Result<(), String> {
+        self.send(GCodeLine {
+            modals: vec![GCodeModal::SetCoordinateMode(CoordinateMode::Absolute), GCodeModal::SetFeedrate(self.request.fast_feed)],
+            command: Some(GCodeCommand::Move { mode: MoveMode::Controlled, position: AxisValues(vec![(axis, position)]), machine_coordinates: true }),
+        }).await
+    }
+    // Searches up to distance along the axis, giving the machine position where the probe triggered.
+    async fn probe(&self, axis: usize, distance: f64, feed: f64) -> Result<[f64; 3], String> {
+        let (line, probe) = self.handle.send_probe_gcode(GCodeLine {
+            modals: vec![GCodeModal::SetCoordinateMode(CoordinateMode::Incremental), GCodeModal::SetFeedrate(feed)],
+            command: Some(GCodeCommand::Probe {
+                position: AxisValues(vec![(axis, distance)]),
+                mode: ProbeDirection::Towards,
+                requirement: ProbeRequirement::Require,
+            }),
+        }).await.map_err(|_| "the job ended early".to_string())?;
+        line.await.map_err(|error| format!("the controller rejected a probe move: {:?}", error))?;
+        let event = probe.await.map_err(|error| match error {
+            ProbeError::Alarm => format!("the probe didn't trigger within {} mm", distance.abs()),
+            ProbeError::Grbl(code) => format!("probing failed with error:{}", code),
+            ProbeError::Reset => "the controller was reset".to_string(),
+        })?;
+        if !event.success {
+            return Err(format!("the probe didn't trigger within {} mm", distance.abs()));
+        }
+        Ok(std::array::from_fn(|axis| event.position.get(axis).copied().unwrap_or(0.0)))
+    }
+    // Finds the surface in the given direction and backs off from it, giving the position along the axis.
+    async fn touch(&mut self, axis: usize, sign: f64) -> Result<f64, String> {
+        let request = self.request;
+        let found = self.probe(axis, sign * request.max_travel, request.fast_feed).await?;
+        self.move_to(axis, found[axis] - sign * request.retract).await?;
+        let measured = self.probe(axis, sign * 2.0 * request.retract, request.slow_feed).await?;
+        self.move_to(axis, measured[axis] - sign * request.retract).await?;
+        self.contacts.push(Vec3(measured));
+        Ok(measured[axis])
+    }
+    async fn status(&self, status: &str) -> Result<(), String> {
+        self.handle.set_status(format!("Probing: {}", status)).await.map_err(|_| "the job ended early".to_string())
+    }
+}
+
+// Where the tool's edge was when the probe triggered.
+fn edge(contact: f64, sign: f64, tool_diameter: f64) -> f64 {
+    contact + sign * tool_diameter / 2.0
+}
+
+async fn run_routine(prober: &mut Prober<'_>) -> Result<ProbeRoutineResult, String> {
+    let request = prober.request;
+    let start = prober.handle.get_state().await.map_err(|_| "the job ended early".to_string())?.machine_position;
+    let start: [f64; 3] = std::array::from_fn(|axis| start.get(axis).copied().unwrap_or(0.0));
+    let mut found = [None; 3];
+    let mut diameter = None;
+    match &request.routine {
+        ProbeRoutine::ZTouchOff { plate_thickness } => {
+            prober.status("touching off Z").await?;
+            found[Z] = Some(prober.touch(Z, -1.0).await? - plate_thickness);
+        }
+        ProbeRoutine::Edge { axis, approach } => {
+            let axis = axis_index(*axis);
+            prober.status("finding the edge").await?;
+            found[axis] = Some(edge(prober.touch(axis, approach.sign()).await?, approach.sign(), request.tool_diameter));
+            prober.move_to(axis, start[axis]).await?;
+        }
+        ProbeRoutine::Corner { x_approach, y_approach, inset } => {
+            let (x_sign, y_sign) = (x_approach.sign(), y_approach.sign());
+            prober.status("finding the X face").await?;
+            prober.move_to(Y, start[Y] + y_sign * inset).await?;
+            let x_edge = edge(prober.touch(X, x_sign).await?, x_sign, request.tool_diameter);
+            prober.move_to(X, start[X]).await?;
+            prober.move_to(Y, start[Y]).await?;
+            prober.status("finding the Y face").await?;
+            prober.move_to(X, x_edge + x_sign * inset).await?;
+            let y_edge = edge(prober.touch(Y, y_sign).await?, y_sign, request.tool_diameter);
+            prober.move_to(Y, start[Y]).await?;
+            prober.move_to(X, start[X]).await?;
+            found[X] = Some(x_edge);
+            found[Y] = Some(y_edge);
+        }
+        ProbeRoutine::BoreCenter => {
+            let mut widths = Vec::new();
+            for (axis, name) in [(X, "X"), (Y, "Y")] {
+                prober.status(&format!("measuring the bore along {}", name)).await?;
+                let positive = prober.touch(axis, 1.0).await?;
+                prober.move_to(axis, start[axis]).await?;
+                let negative = prober.touch(axis, -1.0).await?;
+                let center = (positive + negative) / 2.0;
+                prober.move_to(axis, center).await?;
+                found[axis] = Some(center);
+                widths.push(positive - negative + request.tool_diameter);
+            }
+            diameter = Some(widths.iter().sum::<f64>() / widths.len() as f64);
+        }
+    }
+    Ok(ProbeRoutineResult { found, contacts: std::mem::take(&mut prober.contacts), diameter })
+}
+
+fn check_request(request: &RunProbeRoutine) -> Result<(), String> {
+    let positive = [("max travel", request.max_travel), ("fast feed", request.fast_feed), ("slow feed", request.slow_feed), ("retract", request.retract)];
+    if let Some((name, _)) = positive.iter().find(|(_, value)| !value.is_finite() || *value <= 0.0) {
+        return Err(format!("the {} must be more than 0", name));
+    }
+    if !request.tool_diameter.is_finite() || request.tool_diameter < 0.0 {
+        return Err("the tool diameter can't be negative".to_string());
+    }
+    match request.routine {
+        ProbeRoutine::ZTouchOff { plate_thickness } if !plate_thickness.is_finite() || plate_thickness < 0.0 => Err("the plate thickness can't be negative".to_string()),
+        ProbeRoutine::Corner { inset, .. } if !inset.is_finite() || inset <= request.tool_diameter / 2.0 => Err("the inset must be more than the tool radius".to_string()),
+        _ => Ok(()),
+    }
+}
+
+async fn run_probe_routine(machine: Extension<Arc<ImmediateHandle>>, input: Json<RunProbeRoutine>) -> ServerResult<Json<ProbeRoutineResult>> {
+    let request = input.0;
+    check_request(&request).map_err(ServerError::bad_request)?;
+    let result = run_job_for_result(&machine, move |handle: JobHandle| async move {
+        let mut prober = Prober { handle: &handle, request: &request, contacts: Vec::new() };
+        let result = run_routine(&mut prober).await;
+        // Leave the machine in absolute mode whatever happened.
+        drop(prober.send(GCodeLine { modals: vec![GCodeModal::SetCoordinateMode(CoordinateMode::Absolute)], command: None }).await);
+        result
+    }).await?;
+    match result {
+        Some(Ok(result)) => Ok(Json(result)),
+        Some(Err(error)) => Err(anyhow!("Probing stopped: {}", error).into()),
+        None => Err(anyhow!("Probing job not run!").into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use common::api::ProbeApproach;
+    use machine_mock::simulated::{SimulatedMachineConfig, ProbeSurface};
+
+    use crate::cnc::grbl::standard_handler::test::{send_line, with_simulated_machine};
+
+    use super::*;
+
+    // Runs the routine on a simulated machine from the given machine XY position, with a 6 mm tool.
+    async fn probe_simulated(surface: ProbeSurface, start: [f64; 2], routine: ProbeRoutine) -> Result<ProbeRoutineResult, String> {
+        let config = SimulatedMachineConfig { probe_surface: surface, ..Default::default() };
+        let request = RunProbeRoutine { routine, tool_diameter: 6.0, max_travel: 40.0, fast_feed: 3000.0, slow_feed: 300.0, retract: 1.0 };
+        with_simulated_machine(config, |machine| async move {
+            let handle = machine.get_job_handle().await.unwrap();
+            send_line(&handle, &format!("G53 G0 X{} Y{}", start[0], start[1])).await.unwrap();
+            send_line(&handle, "G4 P0.01").await.unwrap();
+            let mut prober = Prober { handle: &handle, request: &request, contacts: Vec::new() };
+            run_routine(&mut prober).await
+        }).await
+    }
+    fn assert_near(value: Option<f64>, expected: f64) {
+        let value = value.unwrap();
+        assert!((value - expected).abs() < 0.01, "{} is not {}", value, expected);
+    }
+
+    #[tokio::test]
+    async fn z_touch_off_subtracts_the_plate() {
+        let result = probe_simulated(ProbeSurface::flat(-20.0), [-10.0, -10.0], ProbeRoutine::ZTouchOff { plate_thickness: 5.0 }).await.unwrap();
+        assert_near(result.found[Z], -25.0);
+        assert_eq!(result.found[X], None);
+        assert_eq!(result.contacts.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn edges_account_for_the_tool_radius() {
+        // A wall everywhere below X = -30, as tall as the tool is high.
+        let wall = ProbeSurface::height_map(|x, _| if x <= -30.0 { 0.0 } else { f64::NEG_INFINITY });
+        let result = probe_simulated(wall, [-10.0, -10.0], ProbeRoutine::Edge { axis: ProbeAxis::X, approach: ProbeApproach::Negative }).await.unwrap();
+        assert_near(result.found[X], -33.0);
+        assert_eq!(result.found[Y], None);
+    }
+
+    #[tokio::test]
+    async fn bores_are_centered() {
+        let bore = ProbeSurface::height_map(|x, y| if (x + 50.0).powi(2) + (y + 60.0).powi(2) >= 8.0f64.powi(2) { 0.0 } else { f64::NEG_INFINITY });
+        let result = probe_simulated(bore, [-48.0, -58.0], ProbeRoutine::BoreCenter).await.unwrap();
+        assert_near(result.found[X], -50.0);
+        assert_near(result.found[Y], -60.0);
+        assert_eq!(result.contacts.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn missing_the_surface_is_reported() {
+        let result = probe_simulated(ProbeSurface::none(), [-10.0, -10.0], ProbeRoutine::ZTouchOff { plate_thickness: 0.0 }).await;
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn bad_parameters_are_refused() {
+        let mut request = RunProbeRoutine {
+            routine: ProbeRoutine::Corner { x_approach: ProbeApproach::Positive, y_approach: ProbeApproach::Positive, inset: 2.0 },
+            tool_diameter: 6.0, max_travel: 20.0, fast_feed: 500.0, slow_feed: 50.0, retract: 1.0,
+        };
+        assert!(check_request(&request).is_err());
+        request.routine = ProbeRoutine::BoreCenter;
+        assert!(check_request(&request).is_ok());
+        request.slow_feed = 0.0;
+        assert!(check_request(&request).is_err());
+    }
+}
@@ -11,6 +11,7 @@ The right way to handle this is probably to make the endpoints slim - e.g. valid
 
 ...but I guess there could be 500 errors in validation, so perhaps we really do need to handle a mixture.
  */
+#[derive(Debug)]
 pub struct ServerError {
     status_code: StatusCode,
     message: String,
@@ -0,0 +1,76 @@
+/*
+    Polls the machine's status on behalf of every status subscriber. Polling is fast while the machine
+is moving, slow while it sits still, and stops entirely while nobody is subscribed. Changes of state
+are also broadcast as soon as a poll sees them.
+*/
+use std::{mem::discriminant, sync::Arc, time::Duration};
+
+use chrono::Utc;
+use common::grbl::{ActiveAlarm, GrblFullInfo, GrblState, MachineStateChange};
+use tokio::{select, spawn, sync::{broadcast, watch, Notify}, time::sleep};
+
+use crate::cnc::grbl::{messages::GrblStateInfo, standard_handler::ImmediateHandle};
+
+#[derive(Debug, Clone, Copy)]
+pub struct StatusPollRates {
+    pub active: Duration, // While running, jogging or homing.
+    pub idle: Duration,
+}
+impl StatusPollRates {
+    fn period(&self, state: &GrblState) -> Duration {
+        match state {
+            GrblState::Run | GrblState::Jog | GrblState::Home => self.active,
+            _ => self.idle,
+        }
+    }
+}
+
+pub struct StatusStreamInfo {
+    status: Arc<watch::Sender<GrblStateInfo>>,
+    state_changes: broadcast::Sender<MachineStateChange>,
+    subscribed: Arc<Notify>,
+}
+impl StatusStreamInfo {
+    pub fn subscribe(&self) -> watch::Receiver<GrblStateInfo> {
+        let receiver = self.status.subscribe();
+        self.subscribed.notify_one();  // Wake the poller if it was waiting for a listener.
+        receiver
+    }
+    pub fn subscribe_state_changes(&self) -> broadcast::Receiver<MachineStateChange> {
+        self.state_changes.subscribe()
+    }
+}
+
+// The latest status as sent to clients, marking both receivers as seen.
+pub fn full_info(status: &mut watch::Receiver<GrblStateInfo>, alarm: &mut watch::Receiver<Option<ActiveAlarm>>) -> GrblFullInfo {
+    let mut info = status.borrow_and_update().clone().to_full_info();
+    info.alarm = alarm.borrow_and_update().clone();
+    info
+}
+
+pub async fn status_stream_task(machine: Arc<ImmediateHandle>, rates: StatusPollRates) -> StatusStreamInfo {
+    let status = Arc::new(watch::channel(machine.get_state().await).0);
+    let (state_changes, _) = broadcast::channel(16);
+    let subscribed = Arc::new(Notify::new());
+    let info = StatusStreamInfo { status: status.clone(), state_changes: state_changes.clone(), subscribed: subscribed.clone() };
+    spawn(async move {
+        loop {
+            if status.receiver_count() == 0 {
+                subscribed.notified().await;
+                continue;
+            }
+            let state = machine.get_state().await;
+            let previous = status.borrow().state.clone();
+            if discriminant(&previous) != discriminant(&state.state) {
+                drop(state_changes.send(MachineStateChange { time: Utc::now(), from: previous, to: state.state.clone() }));
+            }
+            let period = rates.period(&state.state);
+            status.send_replace(state);
+            select! {
+                _ = sleep(period) => {},
+                _ = status.closed() => {},  // Nobody is listening any more; wait for the next subscriber.
+            }
+        }
+    });
+    info
+}
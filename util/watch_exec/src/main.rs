@@ -1,4 +1,5 @@
 use async_stream::try_stream;
+use api_client::{Client, UploadGcodeFile, native::{self, ReqwestConnection}};
 use clap::Parser;
 use common::api;
 use futures::{
@@ -17,6 +18,12 @@ struct Args {
     local_directory: String,
     #[arg()]
     remote_directory: String,
+    /// Address of the cnc server
+    #[arg(long, default_value = "http://cnc:3000")]
+    server: String,
+    /// Name recorded in the version history of each file uploaded
+    #[arg(long, default_value = "watch_exec")]
+    uploader: String,
 }
 
 // All paths should be relative to the watch.
@@ -25,26 +32,27 @@ enum Change {
     Deleted(PathBuf),
 }
 
-async fn upload_file(client: &mut reqwest::Client, local_path: PathBuf, remote_path: PathBuf) {
-    let form = reqwest::multipart::Form::new()
-        .text("filename", remote_path.to_string_lossy().to_string())
-        .part("file", reqwest::multipart::Part::bytes(tokio::fs::read(local_path.clone()).await.unwrap()).file_name("file.nc"));
-    let response = client.post(format!("http://cnc:3000{}", api::UPLOAD_GCODE_FILE))
-        .multipart(form)
-        .send()
-        .await.unwrap();
-    println!("Uploaded ({}): {} > {}", response.status(), local_path.to_string_lossy(), remote_path.to_string_lossy())
+async fn upload_file(client: &Client<ReqwestConnection>, local_path: PathBuf, remote_path: PathBuf, uploader: &str) {
+    let upload = UploadGcodeFile {
+        path: remote_path.to_string_lossy().to_string(),
+        contents: tokio::fs::read(local_path.clone()).await.unwrap(),
+        reject_invalid: false,
+        uploader: Some(uploader.to_string()),
+    };
+    match client.upload_gcode_file(upload).await {
+        Ok(_) => println!("Uploaded: {} > {}", local_path.to_string_lossy(), remote_path.to_string_lossy()),
+        Err(error) => println!("Failed to upload {}: {}", local_path.to_string_lossy(), error),
+    }
 }
-async fn delete_file(client: &mut reqwest::Client, remote_path: PathBuf) {
+async fn delete_file(client: &Client<ReqwestConnection>, remote_path: PathBuf) {
     let data = api::DeleteGcodeFile {
         path: remote_path.to_string_lossy().to_string(),
         is_directory: false,
     };
-    let response = client.delete(format!("http://cnc:3000{}", api::DELETE_GCODE_FILE))
-        .json(&data)
-        .send()
-        .await.unwrap();
-    println!("Deleted ({}): {}", response.status(), remote_path.to_string_lossy())
+    match client.delete_gcode_file(&data).await {
+        Ok(()) => println!("Deleted: {}", remote_path.to_string_lossy()),
+        Err(error) => println!("Failed to delete {}: {}", remote_path.to_string_lossy(), error),
+    }
 }
 
 fn is_path_actionable(path: &Path) -> bool {
@@ -85,7 +93,7 @@ async fn main() {
 
     let (events_tx, mut events_rx) = mpsc::unbounded();
     tokio::spawn(async_watch(local_directory.clone(), events_tx));
-    let mut client = reqwest::Client::new();
+    let client = native::client(args.server.clone());
     // First: upload anything interesting already in the directory.
     let mut existing_paths = pin!(files_in_directory_recursive(local_directory.clone())
         .try_filter(|path| ready(is_path_actionable(&path))));
@@ -97,7 +105,7 @@ async fn main() {
         println!("Uploading existing file {}", path.to_string_lossy());
         let local_path = local_directory.clone().join(&path);
         let remote_path = PathBuf::from(args.remote_directory.clone()).join(&path);
-        upload_file(&mut client, local_path, remote_path).await;
+        upload_file(&client, local_path, remote_path, &args.uploader).await;
     }
     loop {
         let next = events_rx.next().await.unwrap();
@@ -108,13 +116,13 @@ async fn main() {
                 println!("Uploading {}", path.to_string_lossy());
                 let local_path = local_directory.clone().join(&path);
                 let remote_path = PathBuf::from(args.remote_directory.clone()).join(&path);
-                upload_file(&mut client, local_path, remote_path).await;
+                upload_file(&client, local_path, remote_path, &args.uploader).await;
             },
             Change::Deleted(path) => {
                 if !is_path_actionable(&path) { println!("Irrelevant {:?}", path); continue; }
                 println!("Deleting {}", path.to_string_lossy());
                 let remote_path = PathBuf::from(args.remote_directory.clone()).join(&path);
-                delete_file(&mut client, remote_path).await;
+                delete_file(&client, remote_path).await;
             }
         }
     }